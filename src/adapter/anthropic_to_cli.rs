@@ -1,5 +1,10 @@
+use crate::adapter::{CliRequest, place_system_prompt, trim_in_place};
+use crate::adapter::history::{HistoryLimits, estimate_tokens, trim_history};
 use crate::adapter::openai_to_cli::extract_model;
-use crate::types::anthropic::{ContentInput, MessagesRequest};
+use crate::adapter::tools::{self, ToolRequirement, ToolSpec};
+use crate::config::SystemPromptMode;
+use crate::types::anthropic::{ContentBlockInput, ContentInput, MessagesRequest};
+use crate::types::openai::ModelInfo;
 
 /// Extract text from an Anthropic ContentInput (string or array of blocks).
 fn extract_text(content: &ContentInput) -> String {
@@ -9,51 +14,192 @@ fn extract_text(content: &ContentInput) -> String {
             .iter()
             .filter(|b| b.block_type == "text")
             .filter_map(|b| b.text.as_deref())
-            .collect::<Vec<_>>()
-            .join(""),
+            .fold(String::new(), |mut text, block| {
+                text.push_str(block);
+                text
+            }),
     }
 }
 
+/// The blocks of `content` with type `block_type`.
+fn blocks_of<'a>(
+    content: &'a ContentInput,
+    block_type: &'a str,
+) -> impl Iterator<Item = &'a ContentBlockInput> {
+    let blocks = match content {
+        ContentInput::Text(_) => &[][..],
+        ContentInput::Blocks(blocks) => blocks.as_slice(),
+    };
+    blocks.iter().filter(move |b| b.block_type == block_type)
+}
+
+/// Count the text blocks in the top-level system field (a plain string is one).
+pub fn count_system_blocks(system: Option<&ContentInput>) -> usize {
+    match system {
+        None => 0,
+        Some(ContentInput::Text(_)) => 1,
+        Some(ContentInput::Blocks(blocks)) => {
+            blocks.iter().filter(|b| b.block_type == "text").count()
+        }
+    }
+}
+
+/// Whether `content` holds an `image` block, directly or in a `tool_result`.
+fn has_image(content: &ContentInput) -> bool {
+    let ContentInput::Blocks(blocks) = content else {
+        return false;
+    };
+    blocks.iter().any(|b| {
+        b.block_type == "image" || b.content.as_ref().is_some_and(has_image)
+    })
+}
+
+/// Whether the system field or any message carries an image, which the
+/// prompt can't hold.
+pub fn has_image_content(
+    system: Option<&ContentInput>,
+    messages: &[crate::types::anthropic::MessageInput],
+) -> bool {
+    system.is_some_and(has_image) || messages.iter().any(|m| has_image(&m.content))
+}
+
 /// Convert Anthropic messages (with optional top-level system) to a CLI prompt string.
 ///
-/// - System text is wrapped in `<system>` tags at the top
-/// - User messages are included as bare text
-/// - Assistant messages are wrapped in `<previous_response>` tags
-fn messages_to_prompt(system: Option<&ContentInput>, messages: &[crate::types::anthropic::MessageInput]) -> String {
-    let mut parts: Vec<String> = Vec::new();
+/// - System text, if given, is wrapped in `<system>` tags at the top
+/// - User messages are included as bare text, after any `tool_result`
+///   blocks they carry as `<tool_result>` tags
+/// - Assistant messages are wrapped in `<previous_response>` tags, with
+///   their `tool_use` blocks as `<tool_call>` blocks
+pub fn messages_to_prompt(system: Option<&ContentInput>, messages: &[crate::types::anthropic::MessageInput]) -> String {
+    // Built in a single buffer: prompts can carry very many small parts
+    let mut prompt = String::new();
 
     if let Some(sys) = system {
         let sys_text = extract_text(sys);
         if !sys_text.is_empty() {
-            parts.push(format!("<system>\n{}\n</system>\n", sys_text));
+            prompt.push_str("<system>\n");
+            prompt.push_str(&sys_text);
+            prompt.push_str("\n</system>\n");
         }
     }
 
-    for msg in messages {
+    for (i, msg) in messages.iter().enumerate() {
+        if i > 0 || !prompt.is_empty() {
+            prompt.push('\n');
+        }
         let text = extract_text(&msg.content);
         match msg.role.as_str() {
-            "user" => parts.push(text),
+            "user" => {
+                for result in blocks_of(&msg.content, "tool_result") {
+                    let output = result.content.as_ref().map(extract_text).unwrap_or_default();
+                    let id = result.tool_use_id.as_deref().unwrap_or_default();
+                    tools::push_tool_result(&mut prompt, id, &output);
+                }
+                prompt.push_str(&text);
+            }
             "assistant" => {
-                parts.push(format!("<previous_response>\n{}\n</previous_response>\n", text));
+                prompt.push_str("<previous_response>\n");
+                prompt.push_str(&text);
+                for call in blocks_of(&msg.content, "tool_use") {
+                    prompt.push('\n');
+                    let id = call.id.as_deref().unwrap_or_default();
+                    let name = call.name.as_deref().unwrap_or_default();
+                    let input = call.input.clone().unwrap_or_else(|| serde_json::json!({}));
+                    tools::push_tool_call(&mut prompt, id, name, &input);
+                }
+                prompt.push_str("\n</previous_response>\n");
             }
-            _ => parts.push(text),
+            _ => prompt.push_str(&text),
         }
     }
 
-    parts.join("\n").trim().to_string()
+    trim_in_place(&mut prompt);
+    prompt
+}
+
+/// The client tools the model may call: none with `tool_choice: none`.
+fn offered_tools(request: &MessagesRequest) -> Vec<ToolSpec<'_>> {
+    if request.tool_choice.as_ref().is_some_and(|c| c.choice_type == "none") {
+        return Vec::new();
+    }
+    request
+        .tools
+        .iter()
+        .flatten()
+        .filter(|t| t.tool_type.as_deref().is_none_or(|t| t == "custom"))
+        .map(|t| ToolSpec {
+            name: &t.name,
+            description: t.description.as_deref(),
+            parameters: t.input_schema.as_ref(),
+        })
+        .collect()
 }
 
-/// Convert an Anthropic MessagesRequest to CLI arguments.
-/// Returns (model_alias, prompt, optional_session_id).
-pub fn anthropic_to_cli(request: &MessagesRequest) -> (&'static str, String, Option<String>) {
-    let model = extract_model(&request.model);
-    let prompt = messages_to_prompt(request.system.as_ref(), &request.messages);
+/// Whether the prompt offers the model tools, so its reply may hold
+/// `<tool_call>` blocks (see `adapter::tools`).
+pub fn offers_tools(request: &MessagesRequest) -> bool {
+    !offered_tools(request).is_empty()
+}
+
+/// Describe the request's `tools` to the model along with how to call them.
+fn tool_instructions(request: &MessagesRequest) -> Option<String> {
+    let requirement = match &request.tool_choice {
+        Some(choice) if choice.choice_type == "any" => ToolRequirement::Any,
+        Some(choice) if choice.choice_type == "tool" => match &choice.name {
+            Some(name) => ToolRequirement::Named(name),
+            None => ToolRequirement::Any,
+        },
+        _ => ToolRequirement::Auto,
+    };
+    tools::tool_instructions(&offered_tools(request), requirement)
+}
+
+/// Convert an Anthropic MessagesRequest to CLI arguments, dropping the oldest
+/// messages as needed to stay within `limits`. The system text and tool
+/// instructions are placed according to `system_mode`.
+pub fn anthropic_to_cli(
+    request: &MessagesRequest,
+    limits: HistoryLimits,
+    models: &[ModelInfo],
+    system_mode: SystemPromptMode,
+) -> CliRequest {
+    let model = extract_model(&request.model, models);
+    // The system prompt lives outside `messages`, so every message is a turn
+    // and the system text is taken out of the token budget up front
+    let system_tokens = request.system.as_ref().map_or(0, |s| estimate_tokens(&extract_text(s)));
+    let limits = HistoryLimits {
+        max_tokens: limits.max_tokens.map(|budget| budget.saturating_sub(system_tokens)),
+        ..limits
+    };
+    let dropped_turns = trim_history(
+        &request.messages,
+        |_| false,
+        |m| estimate_tokens(&extract_text(&m.content)),
+        limits,
+    );
+    let inline = system_mode == SystemPromptMode::Inline;
+    let prompt = messages_to_prompt(
+        request.system.as_ref().filter(|_| inline),
+        &request.messages[dropped_turns.len()..],
+    );
+    let mut system = Vec::new();
+    system.extend(tool_instructions(request));
+    if !inline {
+        system.extend(request.system.as_ref().map(extract_text));
+    }
+    let (prompt, system_prompt) = place_system_prompt(system_mode, system, prompt);
     let session_id = request
         .metadata
         .as_ref()
         .and_then(|m| m.user_id.clone());
 
-    (model, prompt, session_id)
+    CliRequest {
+        model,
+        prompt,
+        session_id,
+        dropped_turns,
+        system_prompt,
+    }
 }
 
 #[cfg(test)]
@@ -75,14 +221,17 @@ mod tests {
             ContentBlockInput {
                 block_type: "text".to_string(),
                 text: Some("hello ".to_string()),
+                ..Default::default()
             },
             ContentBlockInput {
                 block_type: "image".to_string(),
                 text: None,
+                ..Default::default()
             },
             ContentBlockInput {
                 block_type: "text".to_string(),
                 text: Some("world".to_string()),
+                ..Default::default()
             },
         ]);
         assert_eq!(extract_text(&content), "hello world");
@@ -94,6 +243,58 @@ mod tests {
         assert_eq!(extract_text(&content), "");
     }
 
+    // ── count_system_blocks ───────────────────────────────────
+
+    #[test]
+    fn count_system_blocks_variants() {
+        assert_eq!(count_system_blocks(None), 0);
+        assert_eq!(
+            count_system_blocks(Some(&ContentInput::Text("sys".to_string()))),
+            1
+        );
+        let blocks = ContentInput::Blocks(vec![
+            ContentBlockInput {
+                block_type: "text".to_string(),
+                text: Some("a".to_string()),
+                ..Default::default()
+            },
+            ContentBlockInput {
+                block_type: "text".to_string(),
+                text: Some("b".to_string()),
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(count_system_blocks(Some(&blocks)), 2);
+    }
+
+    // ── has_image_content ─────────────────────────────────────
+
+    #[test]
+    fn detects_image_blocks() {
+        let messages = |content: serde_json::Value| {
+            serde_json::from_value::<Vec<MessageInput>>(
+                serde_json::json!([{"role": "user", "content": content}]),
+            )
+            .unwrap()
+        };
+        let image = serde_json::json!({
+            "type": "image",
+            "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0"}
+        });
+        assert!(has_image_content(None, &messages(serde_json::json!([image]))));
+        assert!(has_image_content(
+            None,
+            &messages(serde_json::json!([
+                {"type": "tool_result", "tool_use_id": "toolu_1", "content": [image]}
+            ])),
+        ));
+        assert!(!has_image_content(None, &messages(serde_json::json!("image"))));
+        assert!(!has_image_content(
+            Some(&ContentInput::Text("sys".to_string())),
+            &messages(serde_json::json!([{"type": "text", "text": "hi"}])),
+        ));
+    }
+
     // ── messages_to_prompt ────────────────────────────────────
 
     #[test]
@@ -162,6 +363,11 @@ mod tests {
 
     // ── anthropic_to_cli ─────────────────────────────────────
 
+    /// `anthropic_to_cli` with the system prompt inline, as most tests expect.
+    fn inline_cli(request: &MessagesRequest, limits: HistoryLimits) -> CliRequest {
+        anthropic_to_cli(request, limits, &[], SystemPromptMode::Inline)
+    }
+
     #[test]
     fn anthropic_to_cli_full() {
         let request = MessagesRequest {
@@ -176,12 +382,52 @@ mod tests {
             metadata: Some(RequestMetadata {
                 user_id: Some("user-42".to_string()),
             }),
+            temperature: None,
+            top_p: None,
+            stop_sequences: None,
+            tools: None,
+            tool_choice: None,
+        };
+        let cli = inline_cli(&request, HistoryLimits::default());
+        assert_eq!(cli.model, "sonnet");
+        assert!(cli.prompt.contains("<system>"));
+        assert!(cli.prompt.contains("test"));
+        assert_eq!(cli.session_id, Some("user-42".to_string()));
+    }
+
+    #[test]
+    fn flag_mode_passes_system_text_separately() {
+        let request = MessagesRequest {
+            model: "opus".to_string(),
+            max_tokens: 50,
+            messages: vec![MessageInput {
+                role: "user".to_string(),
+                content: ContentInput::Text("test".to_string()),
+            }],
+            stream: false,
+            system: Some(ContentInput::Text("system prompt".to_string())),
+            metadata: None,
+            temperature: None,
+            top_p: None,
+            stop_sequences: None,
+            tools: None,
+            tool_choice: None,
+        };
+        let limits = HistoryLimits::default();
+        let cli = anthropic_to_cli(&request, limits, &[], SystemPromptMode::Flag);
+        assert_eq!(cli.prompt, "test");
+        assert_eq!(cli.system_prompt.as_deref(), Some("system prompt"));
+
+        let inline = inline_cli(&request, limits);
+        assert_eq!(inline.prompt, "<system>\nsystem prompt\n</system>\n\ntest");
+        assert_eq!(inline.system_prompt, None);
+
+        let empty = MessagesRequest {
+            system: Some(ContentInput::Text(String::new())),
+            ..request
         };
-        let (model, prompt, session_id) = anthropic_to_cli(&request);
-        assert_eq!(model, "sonnet");
-        assert!(prompt.contains("<system>"));
-        assert!(prompt.contains("test"));
-        assert_eq!(session_id, Some("user-42".to_string()));
+        let cli = anthropic_to_cli(&empty, limits, &[], SystemPromptMode::Flag);
+        assert_eq!(cli.system_prompt, None);
     }
 
     #[test]
@@ -196,10 +442,141 @@ mod tests {
             stream: true,
             system: None,
             metadata: None,
+            temperature: None,
+            top_p: None,
+            stop_sequences: None,
+            tools: None,
+            tool_choice: None,
+        };
+        let cli = inline_cli(&request, HistoryLimits::default());
+        assert_eq!(cli.model, "opus");
+        assert_eq!(cli.prompt, "hi");
+        assert_eq!(cli.session_id, None);
+    }
+
+    #[test]
+    fn anthropic_to_cli_trims_oldest_turns() {
+        let msg = |role: &str, text: &str| MessageInput {
+            role: role.to_string(),
+            content: ContentInput::Text(text.to_string()),
+        };
+        let request = MessagesRequest {
+            model: "opus".to_string(),
+            max_tokens: 50,
+            messages: vec![msg("user", "first"), msg("assistant", "reply"), msg("user", "second")],
+            stream: false,
+            system: Some(ContentInput::Text("Be brief".to_string())),
+            metadata: None,
+            temperature: None,
+            top_p: None,
+            stop_sequences: None,
+            tools: None,
+            tool_choice: None,
+        };
+        let limits = HistoryLimits {
+            max_turns: Some(1),
+            ..Default::default()
+        };
+        let cli = inline_cli(&request, limits);
+        assert_eq!(cli.dropped_turns, vec![0, 1]);
+        assert!(cli.prompt.starts_with("<system>\nBe brief"));
+        assert!(!cli.prompt.contains("first"));
+        assert!(cli.prompt.ends_with("second"));
+    }
+
+    #[test]
+    fn anthropic_to_cli_budget_counts_system_text() {
+        let msg = |role: &str, text: &str| MessageInput {
+            role: role.to_string(),
+            content: ContentInput::Text(text.to_string()),
+        };
+        // System 4 tokens; messages 3 + 3 + 2
+        let request = MessagesRequest {
+            model: "opus".to_string(),
+            max_tokens: 50,
+            messages: vec![
+                msg("user", "first turn"),
+                msg("assistant", "the reply"),
+                msg("user", "second"),
+            ],
+            stream: false,
+            system: Some(ContentInput::Text("Be brief, please".to_string())),
+            metadata: None,
+            temperature: None,
+            top_p: None,
+            stop_sequences: None,
+            tools: None,
+            tool_choice: None,
+        };
+        let budget = |max_tokens| HistoryLimits {
+            max_tokens: Some(max_tokens),
+            ..Default::default()
         };
-        let (model, prompt, session_id) = anthropic_to_cli(&request);
-        assert_eq!(model, "opus");
-        assert_eq!(prompt, "hi");
-        assert_eq!(session_id, None);
+
+        assert!(inline_cli(&request, budget(12)).dropped_turns.is_empty());
+        assert_eq!(inline_cli(&request, budget(11)).dropped_turns, vec![0]);
+        let cli = inline_cli(&request, budget(8));
+        assert_eq!(cli.dropped_turns, vec![0, 1]);
+        assert!(cli.prompt.starts_with("<system>\nBe brief, please"));
+        assert!(cli.prompt.ends_with("second"));
+    }
+
+    #[test]
+    fn anthropic_to_cli_offers_tools_and_renders_tool_turns() {
+        let request: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "opus",
+            "max_tokens": 50,
+            "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+            "tool_choice": {"type": "tool", "name": "get_weather"},
+            "messages": [
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Checking."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "get_weather",
+                     "input": {"city": "Paris"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "Sunny"},
+                    {"type": "text", "text": "Thanks"}
+                ]}
+            ]
+        }))
+        .unwrap();
+        assert!(offers_tools(&request));
+        let cli = inline_cli(&request, HistoryLimits::default());
+        assert!(cli.prompt.starts_with("<system>\nYou can call the following tools"));
+        assert!(cli.prompt.contains("You must call the `get_weather` tool."));
+        assert!(cli.prompt.contains(
+            "<previous_response>\nChecking.\n<tool_call>{\"arguments\":{\"city\":\"Paris\"},\
+             \"id\":\"toolu_1\",\"name\":\"get_weather\"}</tool_call>\n</previous_response>"
+        ));
+        assert!(cli.prompt.ends_with(
+            "<tool_result tool_call_id=\"toolu_1\">\nSunny\n</tool_result>\nThanks"
+        ));
+    }
+
+    #[test]
+    fn tool_choice_none_and_server_tools_offer_nothing() {
+        let request = |tools: serde_json::Value, choice: serde_json::Value| {
+            serde_json::from_value::<MessagesRequest>(serde_json::json!({
+                "model": "opus",
+                "max_tokens": 50,
+                "tools": tools,
+                "tool_choice": choice,
+                "messages": [{"role": "user", "content": "hi"}]
+            }))
+            .unwrap()
+        };
+        let custom = serde_json::json!([{"name": "a", "input_schema": {"type": "object"}}]);
+        let server = serde_json::json!([{"type": "web_search_20250305", "name": "web_search"}]);
+        assert!(offers_tools(&request(custom.clone(), serde_json::json!({"type": "auto"}))));
+        assert!(!offers_tools(&request(custom, serde_json::json!({"type": "none"}))));
+        assert!(!offers_tools(&request(server, serde_json::Value::Null)));
+
+        let cli = inline_cli(
+            &request(serde_json::Value::Null, serde_json::Value::Null),
+            HistoryLimits::default(),
+        );
+        assert_eq!(cli.prompt, "hi");
     }
 }
@@ -1,60 +1,99 @@
-use crate::adapter::cli_to_openai::normalize_model_name;
+use crate::adapter::cli_to_openai::result_model_name;
+use crate::adapter::stop_reason::StopReason;
+use crate::adapter::tools;
 use crate::types::anthropic::*;
 use crate::types::claude_cli::ResultMessage;
 
-/// Convert a CLI ResultMessage to an Anthropic MessagesResponse.
-pub fn cli_result_to_anthropic(result: &ResultMessage, message_id: &str) -> MessagesResponse {
-    let content_text = result.result.clone().unwrap_or_default();
-
-    let model = result
-        .model_usage
-        .as_ref()
-        .and_then(|mu| mu.keys().next())
-        .map(|m| normalize_model_name(m))
-        .unwrap_or("claude-sonnet-4");
-
-    let (input_tokens, output_tokens, cache_write, cache_read) =
-        result
-            .model_usage
-            .as_ref()
-            .map(|mu| {
-                let mut inp = 0u64;
-                let mut out = 0u64;
-                let mut cw = 0u64;
-                let mut cr = 0u64;
-                for u in mu.values() {
-                    inp += u.input_tokens.unwrap_or(0);
-                    out += u.output_tokens.unwrap_or(0);
-                    cw += u.cache_write_tokens.unwrap_or(0);
-                    cr += u.cache_read_tokens.unwrap_or(0);
-                }
-                (inp, out, cw, cr)
-            })
-            .unwrap_or((0, 0, 0, 0));
+/// Map the CLI's stop reason to an Anthropic `stop_reason`.
+pub fn stop_reason(cli_stop_reason: Option<&str>) -> &'static str {
+    StopReason::from_cli(cli_stop_reason).anthropic()
+}
+
+/// Convert a CLI ResultMessage to an Anthropic MessagesResponse. A run with
+/// several assistant turns gets a text block per turn; otherwise the content
+/// is the result text as one block.
+pub fn cli_result_to_anthropic(
+    result: &ResultMessage,
+    message_id: &str,
+    requested_model: &str,
+) -> MessagesResponse {
+    let content = match result.turns.as_slice() {
+        [_, _, ..] => result
+            .turns
+            .iter()
+            .map(|text| ContentBlock::Text { text: text.clone() })
+            .collect(),
+        _ => vec![ContentBlock::Text {
+            text: result.result.clone().unwrap_or_default(),
+        }],
+    };
+    let model = result_model_name(result, requested_model);
 
     MessagesResponse {
         id: format!("msg_{}", message_id),
         response_type: "message".to_string(),
         role: "assistant".to_string(),
-        content: vec![ContentBlock {
-            block_type: "text".to_string(),
-            text: content_text,
-        }],
+        content,
         model: model.to_string(),
-        stop_reason: "end_turn".to_string(),
-        stop_sequence: None,
-        usage: ResponseUsage {
-            input_tokens,
-            output_tokens,
-            cache_creation_input_tokens: cache_write,
-            cache_read_input_tokens: cache_read,
-        },
+        stop_reason: stop_reason(result.stop_reason.as_deref()).to_string(),
+        stop_sequence: result.stop_sequence.clone(),
+        usage: usage(result),
+    }
+}
+
+/// Split the `<tool_call>` blocks out of the reply of message `message_id`
+/// (see `adapter::tools`): the remaining text and a `tool_use` block per call.
+pub fn extract_tool_use(text: &str, message_id: &str) -> (String, Vec<ContentBlock>) {
+    let (remaining, calls) = tools::extract_tool_calls(text);
+    let blocks = calls
+        .into_iter()
+        .enumerate()
+        .map(|(index, call)| ContentBlock::ToolUse {
+            id: call.id("toolu_", message_id, index),
+            name: call.name,
+            input: call.arguments,
+        })
+        .collect();
+    (remaining, blocks)
+}
+
+/// Turn `<tool_call>` blocks in a response's last text block into `tool_use`
+/// blocks following it, with `stop_reason: "tool_use"`. Text left over stays
+/// in that block; none drops it.
+pub fn apply_tool_use(response: &mut MessagesResponse) {
+    let Some(ContentBlock::Text { text }) = response.content.last() else {
+        return;
+    };
+    let (remaining, calls) = extract_tool_use(text, &response.id);
+    if calls.is_empty() {
+        return;
+    }
+    response.content.pop();
+    if !remaining.is_empty() {
+        response.content.push(ContentBlock::Text { text: remaining });
+    }
+    response.content.extend(calls);
+    response.stop_reason = "tool_use".to_string();
+}
+
+/// Token usage summed over the result's `modelUsage`; zeros if the CLI
+/// reported none.
+pub fn usage(result: &ResultMessage) -> ResponseUsage {
+    let mut usage = ResponseUsage::default();
+    for u in result.model_usage.iter().flat_map(|mu| mu.values()) {
+        usage.input_tokens += u.input_tokens.unwrap_or(0);
+        usage.output_tokens += u.output_tokens.unwrap_or(0);
+        usage.cache_creation_input_tokens += u.cache_write_tokens.unwrap_or(0);
+        usage.cache_read_input_tokens += u.cache_read_tokens.unwrap_or(0);
     }
+    usage
 }
 
 // ── Streaming event builders ───────────────────────────────────
 
-pub fn create_message_start(id: &str, model: &str) -> MessageStartEvent {
+/// `usage` is whatever is known when the stream starts: zeros before the
+/// result arrives, its input tokens when it already has.
+pub fn create_message_start(id: &str, model: &str, usage: ResponseUsage) -> MessageStartEvent {
     MessageStartEvent {
         event_type: "message_start".to_string(),
         message: MessageStartPayload {
@@ -65,12 +104,7 @@ pub fn create_message_start(id: &str, model: &str) -> MessageStartEvent {
             model: model.to_string(),
             stop_reason: None,
             stop_sequence: None,
-            usage: ResponseUsage {
-                input_tokens: 0,
-                output_tokens: 0,
-                cache_creation_input_tokens: 0,
-                cache_read_input_tokens: 0,
-            },
+            usage,
         },
     }
 }
@@ -79,13 +113,26 @@ pub fn create_content_block_start() -> ContentBlockStartEvent {
     ContentBlockStartEvent {
         event_type: "content_block_start".to_string(),
         index: 0,
-        content_block: ContentBlock {
-            block_type: "text".to_string(),
+        content_block: ContentBlock::Text {
             text: String::new(),
         },
     }
 }
 
+/// Start a `tool_use` block at `index`. As in the API, its input starts
+/// empty and follows in `input_json_delta` events.
+pub fn create_tool_use_block_start(index: u32, id: &str, name: &str) -> ContentBlockStartEvent {
+    ContentBlockStartEvent {
+        event_type: "content_block_start".to_string(),
+        index,
+        content_block: ContentBlock::ToolUse {
+            id: id.to_string(),
+            name: name.to_string(),
+            input: serde_json::json!({}),
+        },
+    }
+}
+
 pub fn create_ping() -> PingEvent {
     PingEvent {
         event_type: "ping".to_string(),
@@ -96,28 +143,42 @@ pub fn create_content_block_delta(text: &str) -> ContentBlockDeltaEvent {
     ContentBlockDeltaEvent {
         event_type: "content_block_delta".to_string(),
         index: 0,
-        delta: TextDelta {
-            delta_type: "text_delta".to_string(),
+        delta: BlockDelta::TextDelta {
             text: text.to_string(),
         },
     }
 }
 
-pub fn create_content_block_stop() -> ContentBlockStopEvent {
+pub fn create_input_json_delta(index: u32, partial_json: &str) -> ContentBlockDeltaEvent {
+    ContentBlockDeltaEvent {
+        event_type: "content_block_delta".to_string(),
+        index,
+        delta: BlockDelta::InputJsonDelta {
+            partial_json: partial_json.to_string(),
+        },
+    }
+}
+
+pub fn create_content_block_stop(index: u32) -> ContentBlockStopEvent {
     ContentBlockStopEvent {
         event_type: "content_block_stop".to_string(),
-        index: 0,
+        index,
     }
 }
 
-pub fn create_message_delta(output_tokens: u64) -> MessageDeltaEvent {
+pub fn create_message_delta(
+    usage: ResponseUsage,
+    stop_reason: &str,
+    stop_sequence: Option<&str>,
+) -> MessageDeltaEvent {
     MessageDeltaEvent {
         event_type: "message_delta".to_string(),
         delta: MessageDeltaPayload {
-            stop_reason: "end_turn".to_string(),
-            stop_sequence: None,
+            stop_reason: stop_reason.to_string(),
+            stop_sequence: stop_sequence.map(str::to_string),
         },
-        usage: OutputUsage { output_tokens },
+        usage,
+        full_content: None,
     }
 }
 
@@ -138,20 +199,25 @@ mod tests {
     #[test]
     fn result_to_anthropic_basic() {
         let result = ResultMessage {
+            subtype: None,
+            is_error: None,
             result: Some("Hello".to_string()),
             exit_code: Some(0),
             duration_ms: None,
             duration_api_ms: None,
             num_turns: None,
             model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
         };
-        let resp = cli_result_to_anthropic(&result, "msg1");
+        let resp = cli_result_to_anthropic(&result, "msg1", "sonnet");
         assert_eq!(resp.id, "msg_msg1");
         assert_eq!(resp.response_type, "message");
         assert_eq!(resp.role, "assistant");
         assert_eq!(resp.content.len(), 1);
-        assert_eq!(resp.content[0].block_type, "text");
-        assert_eq!(resp.content[0].text, "Hello");
+        assert_eq!(resp.content[0], ContentBlock::Text { text: "Hello".to_string() });
         assert_eq!(resp.stop_reason, "end_turn");
         assert_eq!(resp.stop_sequence, None);
     }
@@ -169,14 +235,20 @@ mod tests {
             },
         );
         let result = ResultMessage {
+            subtype: None,
+            is_error: None,
             result: Some("test".to_string()),
             exit_code: Some(0),
             duration_ms: None,
             duration_api_ms: None,
             num_turns: None,
             model_usage: Some(usage),
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
         };
-        let resp = cli_result_to_anthropic(&result, "id");
+        let resp = cli_result_to_anthropic(&result, "id", "sonnet");
         assert_eq!(resp.model, "claude-sonnet-4");
         assert_eq!(resp.usage.input_tokens, 200);
         assert_eq!(resp.usage.output_tokens, 100);
@@ -187,24 +259,91 @@ mod tests {
     #[test]
     fn result_to_anthropic_empty() {
         let result = ResultMessage {
+            subtype: None,
+            is_error: None,
             result: None,
             exit_code: Some(1),
             duration_ms: None,
             duration_api_ms: None,
             num_turns: None,
             model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
         };
-        let resp = cli_result_to_anthropic(&result, "x");
-        assert_eq!(resp.content[0].text, "");
+        let resp = cli_result_to_anthropic(&result, "x", "sonnet");
+        assert_eq!(resp.content[0], ContentBlock::Text { text: String::new() });
         assert_eq!(resp.usage.input_tokens, 0);
         assert_eq!(resp.usage.output_tokens, 0);
     }
 
+    #[test]
+    fn truncated_result_stops_with_max_tokens() {
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("Hel".to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: Some("max_tokens".to_string()),
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        let resp = cli_result_to_anthropic(&result, "x", "sonnet");
+        assert_eq!(resp.stop_reason, "max_tokens");
+    }
+
+    #[test]
+    fn stopped_result_reports_sequence() {
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("Hello".to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: Some("stop_sequence".to_string()),
+            stop_sequence: Some("END".to_string()),
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        let resp = cli_result_to_anthropic(&result, "x", "sonnet");
+        assert_eq!(resp.stop_reason, "stop_sequence");
+        assert_eq!(resp.stop_sequence.as_deref(), Some("END"));
+    }
+
+    #[test]
+    fn result_only_uses_requested_model() {
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: None,
+            exit_code: Some(1),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        let resp = cli_result_to_anthropic(&result, "x", "opus");
+        assert_eq!(resp.model, "claude-opus-4");
+    }
+
     // ── streaming event builders ─────────────────────────────
 
     #[test]
     fn message_start_event() {
-        let event = create_message_start("req1", "claude-opus-4");
+        let event = create_message_start("req1", "claude-opus-4", ResponseUsage::default());
         assert_eq!(event.event_type, "message_start");
         assert_eq!(event.message.id, "msg_req1");
         assert_eq!(event.message.role, "assistant");
@@ -217,8 +356,7 @@ mod tests {
         let event = create_content_block_start();
         assert_eq!(event.event_type, "content_block_start");
         assert_eq!(event.index, 0);
-        assert_eq!(event.content_block.block_type, "text");
-        assert_eq!(event.content_block.text, "");
+        assert_eq!(event.content_block, ContentBlock::Text { text: String::new() });
     }
 
     #[test]
@@ -231,23 +369,46 @@ mod tests {
     fn content_block_delta_event() {
         let event = create_content_block_delta("hello");
         assert_eq!(event.event_type, "content_block_delta");
-        assert_eq!(event.delta.delta_type, "text_delta");
-        assert_eq!(event.delta.text, "hello");
+        assert_eq!(event.delta, BlockDelta::TextDelta { text: "hello".to_string() });
     }
 
     #[test]
     fn content_block_stop_event() {
-        let event = create_content_block_stop();
+        let event = create_content_block_stop(2);
         assert_eq!(event.event_type, "content_block_stop");
-        assert_eq!(event.index, 0);
+        assert_eq!(event.index, 2);
     }
 
     #[test]
     fn message_delta_event() {
-        let event = create_message_delta(42);
+        let usage = ResponseUsage {
+            input_tokens: 10,
+            output_tokens: 42,
+            ..ResponseUsage::default()
+        };
+        let event = create_message_delta(usage, "end_turn", None);
         assert_eq!(event.event_type, "message_delta");
         assert_eq!(event.delta.stop_reason, "end_turn");
-        assert_eq!(event.usage.output_tokens, 42);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["usage"]["input_tokens"], 10);
+        assert_eq!(json["usage"]["output_tokens"], 42);
+    }
+
+    #[test]
+    fn stop_reason_mapping() {
+        assert_eq!(stop_reason(None), "end_turn");
+        assert_eq!(stop_reason(Some("end_turn")), "end_turn");
+        assert_eq!(stop_reason(Some("max_tokens")), "max_tokens");
+        assert_eq!(stop_reason(Some("stop_sequence")), "stop_sequence");
+        assert_eq!(stop_reason(Some("refusal")), "refusal");
+    }
+
+    #[test]
+    fn message_delta_reports_stop_sequence() {
+        let event = create_message_delta(ResponseUsage::default(), "stop_sequence", Some("END"));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["delta"]["stop_reason"], "stop_sequence");
+        assert_eq!(json["delta"]["stop_sequence"], "END");
     }
 
     #[test]
@@ -260,7 +421,7 @@ mod tests {
 
     #[test]
     fn message_start_serializes_correctly() {
-        let event = create_message_start("abc", "claude-sonnet-4");
+        let event = create_message_start("abc", "claude-sonnet-4", ResponseUsage::default());
         let json = serde_json::to_value(&event).unwrap();
         assert_eq!(json["type"], "message_start");
         assert_eq!(json["message"]["type"], "message");
@@ -279,14 +440,20 @@ mod tests {
     #[test]
     fn result_response_serializes_correctly() {
         let result = ResultMessage {
+            subtype: None,
+            is_error: None,
             result: Some("response text".to_string()),
             exit_code: Some(0),
             duration_ms: None,
             duration_api_ms: None,
             num_turns: None,
             model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
         };
-        let resp = cli_result_to_anthropic(&result, "test-id");
+        let resp = cli_result_to_anthropic(&result, "test-id", "sonnet");
         let json = serde_json::to_value(&resp).unwrap();
         assert_eq!(json["type"], "message");
         assert_eq!(json["role"], "assistant");
@@ -294,4 +461,108 @@ mod tests {
         assert_eq!(json["content"][0]["text"], "response text");
         assert_eq!(json["stop_reason"], "end_turn");
     }
+
+    fn text_response(text: &str) -> MessagesResponse {
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some(text.to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        cli_result_to_anthropic(&result, "id", "sonnet")
+    }
+
+    #[test]
+    fn apply_tool_use_moves_calls_after_text() {
+        let mut resp = text_response(
+            "Checking.\n<tool_call>{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\
+             </tool_call>",
+        );
+        apply_tool_use(&mut resp);
+        assert_eq!(resp.stop_reason, "tool_use");
+        assert_eq!(resp.content.len(), 2);
+        assert_eq!(resp.content[0], ContentBlock::Text { text: "Checking.".to_string() });
+        let ContentBlock::ToolUse { id, name, input } = &resp.content[1] else {
+            panic!("expected a tool_use block");
+        };
+        assert!(id.starts_with("toolu_"));
+        assert_eq!(name, "get_weather");
+        assert_eq!(*input, serde_json::json!({"city": "Paris"}));
+        // Derived from the message id, as a stream of the same message does
+        let ContentBlock::ToolUse { id: streamed, .. } = &extract_tool_use(
+            "<tool_call>{\"name\": \"get_weather\"}</tool_call>",
+            &resp.id,
+        )
+        .1[0] else {
+            panic!("expected a tool_use block");
+        };
+        assert_eq!(streamed, id);
+    }
+
+    #[test]
+    fn apply_tool_use_drops_empty_text_and_ignores_plain_replies() {
+        let mut resp = text_response("<tool_call>{\"name\": \"a\"}</tool_call>");
+        apply_tool_use(&mut resp);
+        assert_eq!(resp.content.len(), 1);
+        assert!(matches!(&resp.content[0], ContentBlock::ToolUse { name, .. } if name == "a"));
+
+        let mut resp = text_response("Just text");
+        apply_tool_use(&mut resp);
+        assert_eq!(resp.stop_reason, "end_turn");
+        assert_eq!(resp.content, vec![ContentBlock::Text { text: "Just text".to_string() }]);
+    }
+
+    #[test]
+    fn tool_use_stream_events_serialize() {
+        let start = serde_json::to_value(create_tool_use_block_start(1, "toolu_1", "a")).unwrap();
+        assert_eq!(start["index"], 1);
+        assert_eq!(
+            start["content_block"],
+            serde_json::json!({"type": "tool_use", "id": "toolu_1", "name": "a", "input": {}})
+        );
+        let delta = serde_json::to_value(create_input_json_delta(1, "{\"x\":1}")).unwrap();
+        assert_eq!(
+            delta["delta"],
+            serde_json::json!({"type": "input_json_delta", "partial_json": "{\"x\":1}"})
+        );
+    }
+
+    #[test]
+    fn several_turns_serialize_as_text_blocks() {
+        let mut result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("Done.".to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: Some(2),
+            model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: vec!["Let me look.".to_string(), "Done.".to_string()],
+            reasoning_tokens: 0,
+        };
+        let json = serde_json::to_value(cli_result_to_anthropic(&result, "id", "sonnet")).unwrap();
+        assert_eq!(
+            json["content"],
+            serde_json::json!([
+                {"type": "text", "text": "Let me look."},
+                {"type": "text", "text": "Done."}
+            ])
+        );
+
+        // A single turn is the result text as one block
+        result.turns.truncate(1);
+        let response = cli_result_to_anthropic(&result, "id", "sonnet");
+        assert_eq!(response.content, vec![ContentBlock::Text { text: "Done.".to_string() }]);
+    }
 }
@@ -0,0 +1,109 @@
+use crate::adapter::cli_to_openai::result_model_name;
+use crate::adapter::stop_reason::StopReason;
+use crate::types::anthropic::CompleteResponse;
+use crate::types::claude_cli::ResultMessage;
+
+/// Map the CLI's stop reason to a legacy `stop_reason`. Unless the token cap
+/// was hit, the CLI runs to the end of its turn.
+pub fn stop_reason(cli_stop_reason: Option<&str>) -> &'static str {
+    StopReason::from_cli(cli_stop_reason).legacy()
+}
+
+/// Convert a CLI ResultMessage to a legacy CompleteResponse.
+pub fn cli_result_to_complete(
+    result: &ResultMessage,
+    completion_id: &str,
+    requested_model: &str,
+) -> CompleteResponse {
+    CompleteResponse {
+        response_type: "completion".to_string(),
+        id: format!("compl_{}", completion_id),
+        completion: result.result.clone().unwrap_or_default(),
+        stop_reason: Some(stop_reason(result.stop_reason.as_deref()).to_string()),
+        model: result_model_name(result, requested_model).to_string(),
+    }
+}
+
+// ── Streaming event builders ───────────────────────────────────
+
+/// A `completion` event carrying a text delta.
+pub fn create_completion_chunk(id: &str, model: &str, text: &str) -> CompleteResponse {
+    CompleteResponse {
+        response_type: "completion".to_string(),
+        id: format!("compl_{}", id),
+        completion: text.to_string(),
+        stop_reason: None,
+        model: model.to_string(),
+    }
+}
+
+/// The final `completion` event: empty text with the stop reason set.
+pub fn create_completion_stop(id: &str, model: &str, stop_reason: &str) -> CompleteResponse {
+    CompleteResponse {
+        stop_reason: Some(stop_reason.to_string()),
+        ..create_completion_chunk(id, model, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(text: Option<&str>) -> ResultMessage {
+        ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: text.map(str::to_string),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn result_to_complete() {
+        let resp = cli_result_to_complete(&result(Some("Hello!")), "abc", "sonnet");
+        let json = serde_json::to_value(&resp).unwrap();
+        assert_eq!(json["type"], "completion");
+        assert_eq!(json["id"], "compl_abc");
+        assert_eq!(json["completion"], "Hello!");
+        assert_eq!(json["stop_reason"], "stop_sequence");
+        assert_eq!(json["model"], "claude-sonnet-4");
+    }
+
+    #[test]
+    fn truncated_result_reports_max_tokens() {
+        let mut truncated = result(Some("Hel"));
+        truncated.stop_reason = Some("max_tokens".to_string());
+        let resp = cli_result_to_complete(&truncated, "abc", "sonnet");
+        assert_eq!(resp.stop_reason.as_deref(), Some("max_tokens"));
+    }
+
+    #[test]
+    fn empty_result_is_empty_completion() {
+        let resp = cli_result_to_complete(&result(None), "abc", "opus");
+        assert_eq!(resp.completion, "");
+    }
+
+    #[test]
+    fn stream_chunk_has_null_stop_reason() {
+        let json = serde_json::to_value(create_completion_chunk("abc", "claude-opus-4", "Hi"))
+            .unwrap();
+        assert_eq!(json["completion"], "Hi");
+        assert!(json["stop_reason"].is_null());
+    }
+
+    #[test]
+    fn stream_stop_has_stop_reason() {
+        let stop = create_completion_stop("abc", "claude-opus-4", "stop_sequence");
+        assert_eq!(stop.completion, "");
+        assert_eq!(stop.stop_reason.as_deref(), Some("stop_sequence"));
+        assert_eq!(stop.model, "claude-opus-4");
+    }
+}
@@ -0,0 +1,142 @@
+use crate::adapter::cli_to_openai::{
+    finish_reason, normalize_model_name, result_model_name, unix_epoch_secs, usage,
+};
+use crate::types::claude_cli::ResultMessage;
+use crate::types::openai::{TextChoice, TextCompletionResponse};
+
+/// Convert a CLI result message to a legacy `text_completion` response.
+pub fn cli_result_to_completion(
+    result: &ResultMessage,
+    request_id: &str,
+    requested_model: &str,
+) -> TextCompletionResponse {
+    TextCompletionResponse {
+        id: format!("cmpl-{}", request_id),
+        object: "text_completion".to_string(),
+        created: unix_epoch_secs(),
+        model: result_model_name(result, requested_model).to_string(),
+        choices: vec![TextChoice {
+            text: result.result.clone().unwrap_or_default(),
+            index: 0,
+            logprobs: None,
+            finish_reason: Some(finish_reason(result.stop_reason.as_deref()).to_string()),
+        }],
+        usage: usage(result),
+    }
+}
+
+// ── Streaming chunk builders ───────────────────────────────────
+
+/// A `text_completion.chunk` carrying a text delta.
+pub fn create_completion_chunk(
+    request_id: &str,
+    model: &str,
+    text: &str,
+) -> TextCompletionResponse {
+    TextCompletionResponse {
+        id: format!("cmpl-{}", request_id),
+        object: "text_completion.chunk".to_string(),
+        created: unix_epoch_secs(),
+        model: normalize_model_name(model).to_string(),
+        choices: vec![TextChoice {
+            text: text.to_string(),
+            index: 0,
+            logprobs: None,
+            finish_reason: None,
+        }],
+        usage: None,
+    }
+}
+
+/// The final chunk: empty text with the finish reason set.
+pub fn create_completion_done(
+    request_id: &str,
+    model: &str,
+    finish_reason: &str,
+) -> TextCompletionResponse {
+    let mut chunk = create_completion_chunk(request_id, model, "");
+    chunk.choices[0].finish_reason = Some(finish_reason.to_string());
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::claude_cli::ModelUsage;
+    use std::collections::HashMap;
+
+    fn result(text: Option<&str>) -> ResultMessage {
+        ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: text.map(str::to_string),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn result_to_completion() {
+        let resp = cli_result_to_completion(&result(Some("Hello!")), "abc", "sonnet");
+        let json = serde_json::to_value(&resp).unwrap();
+        assert_eq!(json["id"], "cmpl-abc");
+        assert_eq!(json["object"], "text_completion");
+        assert_eq!(json["model"], "claude-sonnet-4");
+        assert_eq!(json["choices"][0]["text"], "Hello!");
+        assert_eq!(json["choices"][0]["index"], 0);
+        assert!(json["choices"][0]["logprobs"].is_null());
+        assert_eq!(json["choices"][0]["finish_reason"], "stop");
+        assert!(json.get("usage").is_none());
+    }
+
+    #[test]
+    fn result_with_usage() {
+        let mut with_usage = result(Some("Hi"));
+        with_usage.model_usage = Some(HashMap::from([(
+            "claude-opus-4-20250514".to_string(),
+            ModelUsage {
+                input_tokens: Some(12),
+                output_tokens: Some(3),
+                cache_read_tokens: None,
+                cache_write_tokens: None,
+            },
+        )]));
+        let resp = cli_result_to_completion(&with_usage, "abc", "sonnet");
+        assert_eq!(resp.model, "claude-opus-4");
+        let usage = resp.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.total_tokens, 15);
+    }
+
+    #[test]
+    fn truncated_result_finishes_with_length() {
+        let mut truncated = result(Some("Hel"));
+        truncated.stop_reason = Some("max_tokens".to_string());
+        let resp = cli_result_to_completion(&truncated, "abc", "sonnet");
+        assert_eq!(resp.choices[0].finish_reason.as_deref(), Some("length"));
+    }
+
+    #[test]
+    fn stream_chunk_has_null_finish_reason() {
+        let json = serde_json::to_value(create_completion_chunk("abc", "opus", "Hi")).unwrap();
+        assert_eq!(json["object"], "text_completion.chunk");
+        assert_eq!(json["model"], "claude-opus-4");
+        assert_eq!(json["choices"][0]["text"], "Hi");
+        assert!(json["choices"][0]["finish_reason"].is_null());
+    }
+
+    #[test]
+    fn stream_done_has_finish_reason() {
+        let done = create_completion_done("abc", "claude-opus-4", "length");
+        assert_eq!(done.choices[0].text, "");
+        assert_eq!(done.choices[0].finish_reason.as_deref(), Some("length"));
+    }
+}
@@ -1,7 +1,10 @@
+use crate::adapter::stop_reason::StopReason;
+use crate::adapter::tools;
 use crate::types::claude_cli::ResultMessage;
 use crate::types::openai::{
-    ChatCompletionChunk, ChatCompletionResponse, Choice, ChunkChoice, ChunkDelta, ResponseMessage,
-    Usage,
+    ChatCompletionChunk, ChatCompletionResponse, Choice, ChunkChoice, ChunkDelta,
+    CompletionTokensDetails, FunctionCall, PromptTokensDetails, ResponseMessage, ToolCall,
+    ToolCallDelta, Usage,
 };
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -19,39 +22,85 @@ pub fn normalize_model_name(model: &str) -> &'static str {
     }
 }
 
-fn unix_epoch_secs() -> u64 {
+pub(crate) fn unix_epoch_secs() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
         .as_secs()
 }
 
-/// Convert a CLI result message to an OpenAI chat completion response.
-pub fn cli_result_to_openai(result: &ResultMessage, request_id: &str) -> ChatCompletionResponse {
-    let content = result.result.clone().unwrap_or_default();
-
-    // Get model from modelUsage (first key), default to "claude-sonnet-4"
-    let model = result
+/// The model a result ran on exactly as the CLI reported it: the first
+/// `modelUsage` key, if any.
+pub fn reported_model(result: &ResultMessage) -> Option<&str> {
+    result
         .model_usage
         .as_ref()
         .and_then(|mu| mu.keys().next())
-        .map(|m| normalize_model_name(m))
-        .unwrap_or("claude-sonnet-4");
+        .map(String::as_str)
+}
+
+/// Resolve the normalized model name for a result: the first `modelUsage` key
+/// when the CLI reported one, otherwise the model the client requested.
+pub fn result_model_name(result: &ResultMessage, requested_model: &str) -> &'static str {
+    normalize_model_name(reported_model(result).unwrap_or(requested_model))
+}
+
+/// The model name a response reports: the CLI's string as-is when `exact`
+/// (`--echo-exact-model`), otherwise its normalized name.
+pub fn response_model_name(model: &str, exact: bool) -> String {
+    if exact {
+        model.to_string()
+    } else {
+        normalize_model_name(model).to_string()
+    }
+}
+
+/// Map the CLI's stop reason to an OpenAI `finish_reason`.
+pub fn finish_reason(stop_reason: Option<&str>) -> &'static str {
+    StopReason::from_cli(stop_reason).openai()
+}
 
-    // Calculate usage from modelUsage
-    let usage = result.model_usage.as_ref().map(|mu| {
+/// Token usage summed over the result's `modelUsage`, if the CLI reported
+/// any. Reasoning tokens are the result's estimate, capped at the output.
+pub fn usage(result: &ResultMessage) -> Option<Usage> {
+    result.model_usage.as_ref().map(|mu| {
         let mut input_tokens = 0u64;
         let mut output_tokens = 0u64;
+        let mut cached_tokens = 0u64;
         for u in mu.values() {
             input_tokens += u.input_tokens.unwrap_or(0);
             output_tokens += u.output_tokens.unwrap_or(0);
+            cached_tokens += u.cache_read_tokens.unwrap_or(0);
         }
         Usage {
             prompt_tokens: input_tokens,
             completion_tokens: output_tokens,
             total_tokens: input_tokens + output_tokens,
+            prompt_tokens_details: (cached_tokens > 0)
+                .then_some(PromptTokensDetails { cached_tokens }),
+            completion_tokens_details: (result.reasoning_tokens > 0).then(|| {
+                CompletionTokensDetails {
+                    reasoning_tokens: result.reasoning_tokens.min(output_tokens),
+                }
+            }),
         }
-    });
+    })
+}
+
+/// Convert a CLI result message to an OpenAI chat completion response.
+pub fn cli_result_to_openai(
+    result: &ResultMessage,
+    request_id: &str,
+    requested_model: &str,
+) -> ChatCompletionResponse {
+    let text = result.result.clone().unwrap_or_default();
+    let model = result_model_name(result, requested_model);
+    // A refusal's text goes in `refusal`, with `content` left null
+    let (content, refusal) = if result.stop_reason.as_deref() == Some("refusal") {
+        (None, Some(text))
+    } else {
+        (Some(text), None)
+    };
 
     ChatCompletionResponse {
         id: format!("chatcmpl-{}", request_id),
@@ -63,10 +112,55 @@ pub fn cli_result_to_openai(result: &ResultMessage, request_id: &str) -> ChatCom
             message: ResponseMessage {
                 role: "assistant".to_string(),
                 content,
+                refusal,
+                tool_calls: None,
             },
-            finish_reason: "stop".to_string(),
+            finish_reason: finish_reason(result.stop_reason.as_deref()).to_string(),
+            stop_reason: result.stop_sequence.clone(),
         }],
-        usage,
+        usage: usage(result),
+        service_tier: None,
+    }
+}
+
+/// Split the `<tool_call>` blocks out of the reply of completion
+/// `completion_id` (see `adapter::tools`) as OpenAI tool calls with
+/// JSON-encoded arguments. Returns the remaining text and the calls.
+pub fn extract_tool_calls(text: &str, completion_id: &str) -> (String, Vec<ToolCall>) {
+    let (remaining, calls) = tools::extract_tool_calls(text);
+    let calls = calls
+        .into_iter()
+        .enumerate()
+        .map(|(index, call)| ToolCall {
+            id: call.id("call_", completion_id, index),
+            call_type: "function".to_string(),
+            function: FunctionCall {
+                name: call.name,
+                // Models sometimes write the arguments pre-encoded
+                arguments: match call.arguments {
+                    serde_json::Value::String(s) => s,
+                    value => value.to_string(),
+                },
+            },
+        })
+        .collect();
+    (remaining, calls)
+}
+
+/// Turn `<tool_call>` blocks in a response's content into `tool_calls` with
+/// `finish_reason: "tool_calls"`. Content left over is kept; none becomes null.
+pub fn apply_tool_calls(response: &mut ChatCompletionResponse) {
+    for choice in &mut response.choices {
+        let Some(content) = choice.message.content.as_deref() else {
+            continue;
+        };
+        let (remaining, calls) = extract_tool_calls(content, &response.id);
+        if calls.is_empty() {
+            continue;
+        }
+        choice.message.content = (!remaining.is_empty()).then_some(remaining);
+        choice.message.tool_calls = Some(calls);
+        choice.finish_reason = "tool_calls".to_string();
     }
 }
 
@@ -91,28 +185,102 @@ pub fn create_stream_chunk(
                     None
                 },
                 content: Some(text.to_string()),
+                tool_calls: None,
             },
             finish_reason: None,
+            stop_reason: None,
         }],
+        full_content: None,
+        service_tier: None,
+        usage: None,
     }
 }
 
-/// Create the final "done" chunk with finish_reason: "stop".
-pub fn create_done_chunk(request_id: &str, model: &str) -> ChatCompletionChunk {
-    let normalized = normalize_model_name(model);
+/// Create a streaming chunk carrying whole tool calls.
+pub fn create_tool_calls_chunk(
+    request_id: &str,
+    model: &str,
+    calls: Vec<ToolCall>,
+    is_first: bool,
+) -> ChatCompletionChunk {
+    let mut chunk = create_stream_chunk(request_id, model, "", is_first);
+    chunk.choices[0].delta.content = None;
+    chunk.choices[0].delta.tool_calls = Some(
+        (0u32..)
+            .zip(calls)
+            .map(|(index, call)| ToolCallDelta { index, call })
+            .collect(),
+    );
+    chunk
+}
+
+/// Create the final "done" chunk carrying the finish reason and, when output
+/// was cut at one, the stop sequence. Like the other chunk builders, `model`
+/// is reported as given (see `response_model_name`).
+pub fn create_done_chunk(
+    request_id: &str,
+    model: &str,
+    finish_reason: &str,
+    stop_sequence: Option<&str>,
+) -> ChatCompletionChunk {
     ChatCompletionChunk {
         id: format!("chatcmpl-{}", request_id),
         object: "chat.completion.chunk".to_string(),
         created: unix_epoch_secs(),
-        model: normalized.to_string(),
+        model: model.to_string(),
         choices: vec![ChunkChoice {
             index: 0,
             delta: ChunkDelta {
                 role: None,
                 content: None,
+                tool_calls: None,
             },
-            finish_reason: Some("stop".to_string()),
+            finish_reason: Some(finish_reason.to_string()),
+            stop_reason: stop_sequence.map(str::to_string),
         }],
+        full_content: None,
+        service_tier: None,
+        usage: None,
+    }
+}
+
+/// Create the opt-in trailer chunk carrying the whole streamed text. It has no
+/// choices, so clients that only read deltas skip it.
+pub fn create_full_content_chunk(request_id: &str, model: &str, text: &str) -> ChatCompletionChunk {
+    ChatCompletionChunk {
+        id: format!("chatcmpl-{}", request_id),
+        object: "chat.completion.chunk".to_string(),
+        created: unix_epoch_secs(),
+        model: model.to_string(),
+        choices: vec![],
+        full_content: Some(text.to_string()),
+        service_tier: None,
+        usage: None,
+    }
+}
+
+/// Create the final chunk requested with `stream_options.include_usage`:
+/// usage from the result (zero if the CLI reported none) and no choices.
+pub fn create_usage_chunk(
+    request_id: &str,
+    model: &str,
+    result: &ResultMessage,
+) -> ChatCompletionChunk {
+    ChatCompletionChunk {
+        id: format!("chatcmpl-{}", request_id),
+        object: "chat.completion.chunk".to_string(),
+        created: unix_epoch_secs(),
+        model: model.to_string(),
+        choices: vec![],
+        full_content: None,
+        service_tier: None,
+        usage: Some(usage(result).unwrap_or(Usage {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        })),
     }
 }
 
@@ -153,19 +321,26 @@ mod tests {
     #[test]
     fn result_to_openai_basic() {
         let result = ResultMessage {
+            subtype: None,
+            is_error: None,
             result: Some("Hello world".to_string()),
             exit_code: Some(0),
             duration_ms: Some(1000),
             duration_api_ms: Some(800),
             num_turns: Some(1),
             model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
         };
-        let resp = cli_result_to_openai(&result, "abc123");
+        let resp = cli_result_to_openai(&result, "abc123", "sonnet");
         assert_eq!(resp.id, "chatcmpl-abc123");
         assert_eq!(resp.object, "chat.completion");
         assert_eq!(resp.choices.len(), 1);
         assert_eq!(resp.choices[0].message.role, "assistant");
-        assert_eq!(resp.choices[0].message.content, "Hello world");
+        assert_eq!(resp.choices[0].message.content.as_deref(), Some("Hello world"));
+        assert_eq!(resp.choices[0].message.refusal, None);
         assert_eq!(resp.choices[0].finish_reason, "stop");
         assert!(resp.usage.is_none());
     }
@@ -183,33 +358,205 @@ mod tests {
             },
         );
         let result = ResultMessage {
+            subtype: None,
+            is_error: None,
             result: Some("test".to_string()),
             exit_code: Some(0),
             duration_ms: None,
             duration_api_ms: None,
             num_turns: None,
             model_usage: Some(usage),
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
         };
-        let resp = cli_result_to_openai(&result, "xyz");
+        let resp = cli_result_to_openai(&result, "xyz", "sonnet");
         assert_eq!(resp.model, "claude-opus-4");
         let u = resp.usage.unwrap();
         assert_eq!(u.prompt_tokens, 100);
         assert_eq!(u.completion_tokens, 50);
         assert_eq!(u.total_tokens, 150);
+        assert_eq!(u.prompt_tokens_details.map(|d| d.cached_tokens), Some(10));
+    }
+
+    #[test]
+    fn usage_reports_reasoning_tokens_within_output() {
+        let model_usage = HashMap::from([(
+            "claude-opus-4".to_string(),
+            ModelUsage {
+                input_tokens: Some(100),
+                output_tokens: Some(50),
+                cache_read_tokens: None,
+                cache_write_tokens: None,
+            },
+        )]);
+        let mut result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("test".to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: Some(model_usage),
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        assert!(usage(&result).unwrap().completion_tokens_details.is_none());
+
+        result.reasoning_tokens = 20;
+        let details = usage(&result).unwrap().completion_tokens_details.unwrap();
+        assert_eq!(details.reasoning_tokens, 20);
+
+        // An estimate above the reported output is capped at it
+        result.reasoning_tokens = 80;
+        let details = usage(&result).unwrap().completion_tokens_details.unwrap();
+        assert_eq!(details.reasoning_tokens, 50);
     }
 
     #[test]
     fn result_to_openai_empty_result() {
         let result = ResultMessage {
+            subtype: None,
+            is_error: None,
             result: None,
             exit_code: Some(0),
             duration_ms: None,
             duration_api_ms: None,
             num_turns: None,
             model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
         };
-        let resp = cli_result_to_openai(&result, "id");
-        assert_eq!(resp.choices[0].message.content, "");
+        let resp = cli_result_to_openai(&result, "id", "sonnet");
+        assert_eq!(resp.choices[0].message.content.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn result_only_uses_requested_model() {
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("error".to_string()),
+            exit_code: Some(1),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        let resp = cli_result_to_openai(&result, "id", "haiku");
+        assert_eq!(resp.model, "claude-haiku-4");
+    }
+
+    #[test]
+    fn truncated_result_finishes_with_length() {
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("Hel".to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: Some("max_tokens".to_string()),
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        let resp = cli_result_to_openai(&result, "id", "sonnet");
+        assert_eq!(resp.choices[0].finish_reason, "length");
+    }
+
+    #[test]
+    fn refused_result_sets_refusal() {
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("I can't help with that.".to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: Some("refusal".to_string()),
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        let resp = cli_result_to_openai(&result, "id", "sonnet");
+        assert_eq!(resp.choices[0].finish_reason, "content_filter");
+        let json = serde_json::to_value(&resp).unwrap();
+        let message = &json["choices"][0]["message"];
+        assert!(message["content"].is_null());
+        assert_eq!(message["refusal"], "I can't help with that.");
+    }
+
+    #[test]
+    fn stopped_result_reports_sequence() {
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("Hello".to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: Some("stop_sequence".to_string()),
+            stop_sequence: Some("END".to_string()),
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        let resp = cli_result_to_openai(&result, "id", "sonnet");
+        assert_eq!(resp.choices[0].finish_reason, "stop");
+        assert_eq!(resp.choices[0].stop_reason.as_deref(), Some("END"));
+    }
+
+    #[test]
+    fn result_model_prefers_model_usage() {
+        let mut usage = HashMap::new();
+        usage.insert(
+            "claude-opus-4-20250514".to_string(),
+            ModelUsage {
+                input_tokens: Some(1),
+                output_tokens: Some(1),
+                cache_read_tokens: None,
+                cache_write_tokens: None,
+            },
+        );
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: None,
+            exit_code: None,
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: Some(usage),
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        assert_eq!(result_model_name(&result, "haiku"), "claude-opus-4");
+    }
+
+    #[test]
+    fn response_model_exact_or_normalized() {
+        assert_eq!(response_model_name("claude-opus-4-20250514", false), "claude-opus-4");
+        assert_eq!(response_model_name("claude-opus-4-20250514", true), "claude-opus-4-20250514");
+        // Normalizing is idempotent, so fallbacks can be normalized up front
+        assert_eq!(response_model_name("claude-opus-4", false), "claude-opus-4");
     }
 
     // ── create_stream_chunk ──────────────────────────────────
@@ -235,10 +582,129 @@ mod tests {
 
     #[test]
     fn done_chunk() {
-        let chunk = create_done_chunk("req1", "claude-opus-4-20250514");
+        let chunk = create_done_chunk("req1", "claude-opus-4", "stop", None);
         assert_eq!(chunk.model, "claude-opus-4");
         assert_eq!(chunk.choices[0].finish_reason, Some("stop".to_string()));
         assert_eq!(chunk.choices[0].delta.content, None);
         assert_eq!(chunk.choices[0].delta.role, None);
     }
+
+    #[test]
+    fn done_chunk_length() {
+        let chunk = create_done_chunk("req1", "opus", "length", None);
+        assert_eq!(chunk.choices[0].finish_reason.as_deref(), Some("length"));
+    }
+
+    #[test]
+    fn done_chunk_reports_stop_sequence() {
+        let chunk = create_done_chunk("req1", "opus", "stop", Some("\n\nUser:"));
+        let json = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(json["choices"][0]["finish_reason"], "stop");
+        assert_eq!(json["choices"][0]["stop_reason"], "\n\nUser:");
+
+        let json = serde_json::to_value(create_done_chunk("req1", "opus", "stop", None)).unwrap();
+        assert!(json["choices"][0].get("stop_reason").is_none());
+    }
+
+    // ── create_full_content_chunk ────────────────────────────
+
+    #[test]
+    fn full_content_chunk_has_no_choices() {
+        let chunk = create_full_content_chunk("req1", "claude-opus-4", "Hello world");
+        let json = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(json["id"], "chatcmpl-req1");
+        assert_eq!(json["model"], "claude-opus-4");
+        assert_eq!(json["choices"], serde_json::json!([]));
+        assert_eq!(json["full_content"], "Hello world");
+    }
+
+    // ── create_usage_chunk ───────────────────────────────────
+
+    #[test]
+    fn usage_chunk_shape() {
+        let usage = ModelUsage {
+            input_tokens: Some(12),
+            output_tokens: Some(3),
+            cache_read_tokens: None,
+            cache_write_tokens: None,
+        };
+        let result = ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some("Hi".to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: Some(HashMap::from([("claude-opus-4".to_string(), usage)])),
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        };
+        let json = serde_json::to_value(create_usage_chunk("req1", "opus", &result)).unwrap();
+        assert_eq!(json["object"], "chat.completion.chunk");
+        assert_eq!(json["choices"], serde_json::json!([]));
+        assert_eq!(
+            json["usage"],
+            serde_json::json!({"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15})
+        );
+
+        // Other chunks leave usage out
+        let json = serde_json::to_value(create_stream_chunk("req1", "opus", "Hi", true)).unwrap();
+        assert!(json.get("usage").is_none());
+    }
+
+    // ── tool calls ────────────────────────────────────────────
+
+    #[test]
+    fn extract_tool_calls_splits_blocks_from_text() {
+        let text = "Checking both.\n\
+            <tool_call>{\"name\": \"a\", \"arguments\": {\"x\": 1}}</tool_call>\n\
+            <tool_call> {\"name\": \"b\"} </tool_call>";
+        let (remaining, calls) = extract_tool_calls(text, "chatcmpl-req1");
+        assert_eq!(remaining, "Checking both.");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].function.name, "a");
+        assert_eq!(calls[0].function.arguments, r#"{"x":1}"#);
+        assert_eq!(calls[1].function.name, "b");
+        assert_eq!(calls[1].function.arguments, "{}");
+        assert_ne!(calls[0].id, calls[1].id);
+        // The same reply of the same completion gets the same ids
+        assert_eq!(extract_tool_calls(text, "chatcmpl-req1").1[1].id, calls[1].id);
+    }
+
+    fn text_result(text: &str) -> ResultMessage {
+        ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some(text.to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: Some("end_turn".to_string()),
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn apply_tool_calls_sets_finish_reason() {
+        let result = text_result(r#"<tool_call>{"name": "a", "arguments": "{}"}</tool_call>"#);
+        let mut resp = cli_result_to_openai(&result, "id", "sonnet");
+        apply_tool_calls(&mut resp);
+        let choice = &resp.choices[0];
+        assert_eq!(choice.finish_reason, "tool_calls");
+        assert_eq!(choice.message.content, None);
+        let calls = choice.message.tool_calls.as_ref().unwrap();
+        assert_eq!(calls[0].function.arguments, "{}");
+
+        let mut resp = cli_result_to_openai(&text_result("plain"), "id", "sonnet");
+        apply_tool_calls(&mut resp);
+        assert_eq!(resp.choices[0].finish_reason, "stop");
+        assert!(resp.choices[0].message.tool_calls.is_none());
+    }
 }
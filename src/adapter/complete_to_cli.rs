@@ -0,0 +1,198 @@
+use crate::adapter::CliRequest;
+use crate::adapter::anthropic_to_cli::anthropic_to_cli;
+use crate::adapter::history::HistoryLimits;
+use crate::config::SystemPromptMode;
+use crate::types::anthropic::{
+    CompleteRequest, ContentInput, MessageInput, MessagesRequest, RequestMetadata,
+};
+use crate::types::openai::ModelInfo;
+
+const HUMAN: &str = "\n\nHuman:";
+const ASSISTANT: &str = "\n\nAssistant:";
+
+/// Split a legacy `\n\nHuman: ...\n\nAssistant: ...` prompt into an optional
+/// leading system text and `(role, text)` turns. A prompt without any markers
+/// is treated as a single user turn; the trailing empty `Assistant:` is dropped.
+fn split_turns(prompt: &str) -> (Option<String>, Vec<(&'static str, String)>) {
+    // Tolerate prompts that start with "Human:" instead of "\n\nHuman:"
+    let prompt = if prompt.starts_with("Human:") || prompt.starts_with("Assistant:") {
+        format!("\n\n{prompt}")
+    } else {
+        prompt.to_string()
+    };
+
+    let next_marker = |from: usize| -> Option<(usize, &'static str, usize)> {
+        let human = prompt[from..].find(HUMAN).map(|i| (from + i, "user", HUMAN.len()));
+        let assistant = prompt[from..]
+            .find(ASSISTANT)
+            .map(|i| (from + i, "assistant", ASSISTANT.len()));
+        match (human, assistant) {
+            (Some(h), Some(a)) => Some(if h.0 < a.0 { h } else { a }),
+            (h, a) => h.or(a),
+        }
+    };
+
+    let Some((first, _, _)) = next_marker(0) else {
+        return (None, vec![("user", prompt.trim().to_string())]);
+    };
+
+    let leading = prompt[..first].trim();
+    let system = (!leading.is_empty()).then(|| leading.to_string());
+
+    let mut turns = Vec::new();
+    let mut cursor = next_marker(0);
+    while let Some((start, role, len)) = cursor {
+        let body_start = start + len;
+        cursor = next_marker(body_start);
+        let body_end = cursor.map_or(prompt.len(), |(next, _, _)| next);
+        turns.push((role, prompt[body_start..body_end].trim().to_string()));
+    }
+
+    if turns.last().is_some_and(|(role, text)| *role == "assistant" && text.is_empty()) {
+        turns.pop();
+    }
+
+    (system, turns)
+}
+
+/// Rewrite a legacy completion request as a Messages API request.
+pub fn complete_to_messages(request: &CompleteRequest) -> MessagesRequest {
+    let (system, turns) = split_turns(&request.prompt);
+    MessagesRequest {
+        model: request.model.clone(),
+        max_tokens: request.max_tokens_to_sample,
+        messages: turns
+            .into_iter()
+            .map(|(role, text)| MessageInput {
+                role: role.to_string(),
+                content: ContentInput::Text(text),
+            })
+            .collect(),
+        stream: request.stream,
+        system: system.map(ContentInput::Text),
+        metadata: request.metadata.as_ref().map(|m| RequestMetadata {
+            user_id: m.user_id.clone(),
+        }),
+        temperature: None,
+        top_p: None,
+        stop_sequences: None,
+        tools: None,
+        tool_choice: None,
+    }
+}
+
+/// Convert a legacy CompleteRequest to CLI arguments via the Messages mapping.
+pub fn complete_to_cli(
+    request: &CompleteRequest,
+    limits: HistoryLimits,
+    models: &[ModelInfo],
+    system_mode: SystemPromptMode,
+) -> CliRequest {
+    anthropic_to_cli(&complete_to_messages(request), limits, models, system_mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prompt: &str) -> CompleteRequest {
+        CompleteRequest {
+            model: "claude-2".to_string(),
+            prompt: prompt.to_string(),
+            max_tokens_to_sample: 256,
+            stream: false,
+            metadata: None,
+        }
+    }
+
+    fn text(content: &ContentInput) -> &str {
+        match content {
+            ContentInput::Text(t) => t,
+            other => panic!("Expected Text, got {:?}", other),
+        }
+    }
+
+    // ── split_turns ───────────────────────────────────────────
+
+    #[test]
+    fn single_turn() {
+        let (system, turns) = split_turns("\n\nHuman: Hello\n\nAssistant:");
+        assert_eq!(system, None);
+        assert_eq!(turns, vec![("user", "Hello".to_string())]);
+    }
+
+    #[test]
+    fn multi_turn_keeps_order() {
+        let (_, turns) =
+            split_turns("\n\nHuman: Hi\n\nAssistant: Hello!\n\nHuman: How are you?\n\nAssistant:");
+        assert_eq!(
+            turns,
+            vec![
+                ("user", "Hi".to_string()),
+                ("assistant", "Hello!".to_string()),
+                ("user", "How are you?".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn leading_text_becomes_system() {
+        let (system, turns) = split_turns("Be terse.\n\nHuman: Hi\n\nAssistant:");
+        assert_eq!(system.as_deref(), Some("Be terse."));
+        assert_eq!(turns.len(), 1);
+    }
+
+    #[test]
+    fn prompt_without_leading_newlines() {
+        let (system, turns) = split_turns("Human: Hi\n\nAssistant:");
+        assert_eq!(system, None);
+        assert_eq!(turns, vec![("user", "Hi".to_string())]);
+    }
+
+    #[test]
+    fn prompt_without_markers_is_one_user_turn() {
+        let (system, turns) = split_turns("  just text  ");
+        assert_eq!(system, None);
+        assert_eq!(turns, vec![("user", "just text".to_string())]);
+    }
+
+    #[test]
+    fn assistant_prefill_is_kept() {
+        let (_, turns) = split_turns("\n\nHuman: Count\n\nAssistant: 1, 2,");
+        assert_eq!(turns.last(), Some(&("assistant", "1, 2,".to_string())));
+    }
+
+    // ── complete_to_messages ──────────────────────────────────
+
+    #[test]
+    fn maps_to_messages_request() {
+        let mut req = request("Be terse.\n\nHuman: Hi\n\nAssistant:");
+        req.metadata = Some(RequestMetadata {
+            user_id: Some("sess-1".to_string()),
+        });
+        let messages = complete_to_messages(&req);
+        assert_eq!(messages.max_tokens, 256);
+        assert_eq!(text(messages.system.as_ref().unwrap()), "Be terse.");
+        assert_eq!(messages.messages.len(), 1);
+        assert_eq!(messages.messages[0].role, "user");
+        assert_eq!(text(&messages.messages[0].content), "Hi");
+        assert_eq!(messages.metadata.unwrap().user_id.as_deref(), Some("sess-1"));
+    }
+
+    // ── complete_to_cli ───────────────────────────────────────
+
+    #[test]
+    fn builds_cli_prompt() {
+        let cli = complete_to_cli(
+            &request("\n\nHuman: Hi\n\nAssistant: Hello!\n\nHuman: Bye\n\nAssistant:"),
+            HistoryLimits::default(),
+            &[],
+            SystemPromptMode::Flag,
+        );
+        assert_eq!(cli.model, "opus");
+        assert_eq!(cli.system_prompt, None);
+        assert!(cli.prompt.starts_with("Hi"));
+        assert!(cli.prompt.contains("<previous_response>\nHello!\n</previous_response>"));
+        assert!(cli.prompt.ends_with("Bye"));
+    }
+}
@@ -0,0 +1,62 @@
+use crate::adapter::{CliRequest, trim_in_place};
+use crate::adapter::openai_to_cli::extract_model;
+use crate::types::openai::{CompletionRequest, ModelInfo};
+
+/// Convert a legacy OpenAI CompletionRequest to CLI arguments. The prompt is
+/// sent as-is; an array of prompts is joined with newlines into one prompt.
+pub fn completions_to_cli(request: &CompletionRequest, models: &[ModelInfo]) -> CliRequest {
+    let model = request
+        .model
+        .as_deref()
+        .map(|m| extract_model(m, models))
+        .unwrap_or("opus");
+    let mut prompt = request.prompt.as_deref().unwrap_or_default().join("\n");
+    trim_in_place(&mut prompt);
+
+    CliRequest {
+        model,
+        prompt,
+        session_id: request.user.clone(),
+        dropped_turns: Vec::new(),
+        system_prompt: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prompt: &[&str]) -> CompletionRequest {
+        CompletionRequest {
+            model: Some("claude-haiku-4".to_string()),
+            prompt: Some(prompt.iter().map(|p| p.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn single_prompt_is_sent_verbatim() {
+        let cli = completions_to_cli(&request(&["  Once upon a time"]), &[]);
+        assert_eq!(cli.model, "haiku");
+        assert_eq!(cli.prompt, "Once upon a time");
+        assert!(cli.dropped_turns.is_empty());
+    }
+
+    #[test]
+    fn prompt_array_is_joined() {
+        let cli = completions_to_cli(&request(&["first", "second"]), &[]);
+        assert_eq!(cli.prompt, "first\nsecond");
+    }
+
+    #[test]
+    fn defaults_to_opus_and_passes_user() {
+        let req = CompletionRequest {
+            prompt: Some(vec!["hi".to_string()]),
+            user: Some("session-1".to_string()),
+            ..Default::default()
+        };
+        let cli = completions_to_cli(&req, &[]);
+        assert_eq!(cli.model, "opus");
+        assert_eq!(cli.session_id.as_deref(), Some("session-1"));
+    }
+}
@@ -0,0 +1,159 @@
+/// Limits on how much conversation history is sent to the CLI.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistoryLimits {
+    /// Keep at most this many non-system turns
+    pub max_turns: Option<usize>,
+    /// Keep the estimated size of the kept messages within this many tokens
+    pub max_tokens: Option<usize>,
+}
+
+/// Rough token count for `text`: one token per four characters.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Select the oldest turns to drop so that at most `max_turns` non-system
+/// messages remain. System messages are never dropped, and the newest turn is
+/// always kept. Returns the (ascending) indices of the dropped messages.
+pub fn trim_turns<T>(
+    messages: &[T],
+    is_system: impl Fn(&T) -> bool,
+    max_turns: Option<usize>,
+) -> Vec<usize> {
+    let Some(max_turns) = max_turns else {
+        return Vec::new();
+    };
+    let max_turns = max_turns.max(1);
+
+    let turns: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| !is_system(m))
+        .map(|(i, _)| i)
+        .collect();
+
+    let excess = turns.len().saturating_sub(max_turns);
+    turns[..excess].to_vec()
+}
+
+/// Apply both limits: trim to `max_turns` first, then keep dropping the
+/// oldest remaining turns until the estimated tokens fit `max_tokens`. System
+/// messages and the newest turn are always kept, so the result may still be
+/// over budget. Returns the (ascending) indices of the dropped messages.
+pub fn trim_history<T>(
+    messages: &[T],
+    is_system: impl Fn(&T) -> bool,
+    tokens: impl Fn(&T) -> usize,
+    limits: HistoryLimits,
+) -> Vec<usize> {
+    let mut dropped = trim_turns(messages, &is_system, limits.max_turns);
+    let Some(budget) = limits.max_tokens else {
+        return dropped;
+    };
+
+    let kept = |i: &usize| dropped.binary_search(i).is_err();
+    let mut total: usize = (0..messages.len()).filter(kept).map(|i| tokens(&messages[i])).sum();
+    let mut droppable: Vec<usize> = (0..messages.len())
+        .filter(|i| kept(i) && !is_system(&messages[*i]))
+        .collect();
+    droppable.pop(); // the newest turn
+
+    let mut extra = Vec::new();
+    for i in droppable {
+        if total <= budget {
+            break;
+        }
+        total -= tokens(&messages[i]);
+        extra.push(i);
+    }
+    dropped.extend(extra);
+    dropped.sort_unstable();
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_sys(m: &&str) -> bool {
+        m.starts_with("sys")
+    }
+
+    #[test]
+    fn no_limit_keeps_everything() {
+        let msgs = ["sys", "u1", "a1", "u2"];
+        assert!(trim_turns(&msgs, is_sys, None).is_empty());
+    }
+
+    #[test]
+    fn under_limit_keeps_everything() {
+        let msgs = ["sys", "u1", "a1", "u2"];
+        assert!(trim_turns(&msgs, is_sys, Some(3)).is_empty());
+    }
+
+    #[test]
+    fn drops_oldest_turns_but_keeps_system() {
+        let msgs = ["sys", "u1", "a1", "sys2", "u2", "a2", "u3"];
+        assert_eq!(trim_turns(&msgs, is_sys, Some(2)), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn zero_limit_still_keeps_latest_turn() {
+        let msgs = ["u1", "a1", "u2"];
+        assert_eq!(trim_turns(&msgs, is_sys, Some(0)), vec![0, 1]);
+    }
+
+    // ── trim_history ──────────────────────────────────────────
+
+    /// Each message costs one token per character, so budgets are easy to read.
+    fn trim(msgs: &[&str], limits: HistoryLimits) -> Vec<usize> {
+        trim_history(msgs, is_sys, |m| m.len(), limits)
+    }
+
+    fn budget(max_tokens: usize) -> HistoryLimits {
+        HistoryLimits {
+            max_tokens: Some(max_tokens),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn estimate_is_chars_over_four_rounded_up() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("éééé"), 1);
+    }
+
+    #[test]
+    fn exactly_at_budget_keeps_everything() {
+        // 3 + 2 + 2 + 2 = 9
+        let msgs = ["sys", "u1", "a1", "u2"];
+        assert!(trim(&msgs, budget(9)).is_empty());
+    }
+
+    #[test]
+    fn one_over_budget_drops_oldest_turn() {
+        let msgs = ["sys", "u1", "a1", "u2"];
+        assert_eq!(trim(&msgs, budget(8)), vec![1]);
+        assert_eq!(trim(&msgs, budget(7)), vec![1]);
+        assert_eq!(trim(&msgs, budget(6)), vec![1, 2]);
+    }
+
+    #[test]
+    fn system_and_latest_turn_survive_tiny_budget() {
+        let msgs = ["sys", "u1", "sys2", "a1", "u2-long"];
+        assert_eq!(trim(&msgs, budget(0)), vec![1, 3]);
+    }
+
+    #[test]
+    fn turn_limit_applies_before_budget() {
+        let msgs = ["sys", "u1", "a1", "u2", "a2", "u3"];
+        let limits = HistoryLimits {
+            max_turns: Some(3),
+            max_tokens: Some(7),
+        };
+        // Turn limit drops u1 and a1; the budget then drops u2
+        assert_eq!(trim(&msgs, limits), vec![1, 2, 3]);
+    }
+}
@@ -1,4 +1,54 @@
 pub mod anthropic_to_cli;
 pub mod cli_to_anthropic;
+pub mod cli_to_complete;
+pub mod cli_to_completions;
 pub mod cli_to_openai;
+pub mod complete_to_cli;
+pub mod completions_to_cli;
+pub mod history;
 pub mod openai_to_cli;
+pub mod stop_reason;
+pub mod tools;
+
+use crate::config::SystemPromptMode;
+
+/// Trim leading and trailing whitespace without reallocating.
+pub(crate) fn trim_in_place(s: &mut String) {
+    s.truncate(s.trim_end().len());
+    let start = s.len() - s.trim_start().len();
+    s.drain(..start);
+}
+
+/// A client request translated into a CLI invocation.
+#[derive(Debug)]
+pub struct CliRequest {
+    /// CLI model alias (`opus`, `sonnet`, `haiku`)
+    pub model: &'static str,
+    pub prompt: String,
+    pub session_id: Option<String>,
+    /// Indices of the request messages dropped by history trimming
+    pub dropped_turns: Vec<usize>,
+    /// System prompt for `--append-system-prompt` (`SystemPromptMode::Flag`)
+    pub system_prompt: Option<String>,
+}
+
+/// Put the system prompt `parts` where `mode` says: as `<system>` blocks
+/// ahead of `prompt` in order, or joined into the returned system prompt.
+pub(crate) fn place_system_prompt(
+    mode: SystemPromptMode,
+    parts: Vec<String>,
+    prompt: String,
+) -> (String, Option<String>) {
+    match mode {
+        SystemPromptMode::Inline => {
+            let prompt = parts.iter().rev().fold(prompt, |prompt, part| {
+                format!("<system>\n{part}\n</system>\n\n{prompt}")
+            });
+            (prompt, None)
+        }
+        SystemPromptMode::Flag => {
+            let parts: Vec<_> = parts.into_iter().filter(|p| !p.is_empty()).collect();
+            (prompt, (!parts.is_empty()).then(|| parts.join("\n\n")))
+        }
+    }
+}
@@ -1,4 +1,11 @@
-use crate::types::openai::{ChatCompletionRequest, Message, MessageContent};
+use crate::adapter::{CliRequest, place_system_prompt, trim_in_place};
+use crate::adapter::history::{HistoryLimits, estimate_tokens, trim_history};
+use crate::adapter::tools::{self, ToolRequirement, ToolSpec};
+use crate::config::SystemPromptMode;
+use crate::types::openai::{
+    ChatCompletionRequest, Message, MessageContent, ModelInfo, ToolCall, ToolChoice,
+};
+use serde_json::json;
 use std::collections::HashMap;
 
 /// Maps OpenAI model names to Claude CLI model aliases
@@ -16,9 +23,14 @@ fn model_map() -> HashMap<&'static str, &'static str> {
     ])
 }
 
-/// Extract the CLI model alias from an OpenAI model name.
-/// Defaults to "opus" for unrecognized models.
-pub fn extract_model(model: &str) -> &'static str {
+/// Extract the CLI model alias from an OpenAI model name. Ids from the
+/// configured model list resolve to their `alias`; unrecognized models
+/// default to "opus".
+pub fn extract_model(model: &str, models: &[ModelInfo]) -> &'static str {
+    if let Some(info) = models.iter().find(|m| m.id == model) {
+        return extract_model(&info.alias, &[]);
+    }
+
     let map = model_map();
 
     if let Some(&alias) = map.get(model) {
@@ -26,10 +38,10 @@ pub fn extract_model(model: &str) -> &'static str {
     }
 
     // Try stripping "claude-code-cli/" prefix
-    if let Some(stripped) = model.strip_prefix("claude-code-cli/") {
-        if let Some(&alias) = map.get(stripped) {
-            return alias;
-        }
+    if let Some(stripped) = model.strip_prefix("claude-code-cli/")
+        && let Some(&alias) = map.get(stripped)
+    {
+        return alias;
     }
 
     // Substring fallback for date-suffixed model IDs (e.g. "claude-opus-4-20250514")
@@ -54,60 +66,183 @@ fn extract_text(content: &Option<MessageContent>) -> String {
             .iter()
             .filter(|p| p.part_type == "text")
             .filter_map(|p| p.text.as_deref())
-            .collect::<Vec<_>>()
-            .join(""),
+            .fold(String::new(), |mut text, part| {
+                text.push_str(part);
+                text
+            }),
         None => String::new(),
     }
 }
 
+/// Count the messages that will become `<system>` blocks in the prompt.
+pub fn count_system_messages(messages: &[Message]) -> usize {
+    messages.iter().filter(|m| m.role == "system").count()
+}
+
+/// Whether any message carries an `image_url` part, which the prompt can't hold.
+pub fn has_image_content(messages: &[Message]) -> bool {
+    messages.iter().any(|m| match &m.content {
+        Some(MessageContent::Parts(parts)) => parts.iter().any(|p| p.part_type == "image_url"),
+        _ => false,
+    })
+}
+
 /// Convert OpenAI messages to a CLI prompt string.
 ///
-/// - System messages are wrapped in `<system>` tags
+/// - System messages are wrapped in `<system>` tags (`openai_to_cli` leaves
+///   them out when they go to the CLI as a flag instead)
 /// - User messages are included as bare text
-/// - Assistant messages are wrapped in `<previous_response>` tags
-pub fn messages_to_prompt(messages: &[Message]) -> String {
-    let mut parts: Vec<String> = Vec::new();
-
-    for msg in messages {
+/// - Assistant messages are wrapped in `<previous_response>` tags, with any
+///   tool calls they made as `<tool_call>` blocks
+/// - Tool messages are wrapped in `<tool_result>` tags naming the call
+pub fn messages_to_prompt<'a>(messages: impl IntoIterator<Item = &'a Message>) -> String {
+    // Built in a single buffer: prompts can carry very many small parts
+    let mut prompt = String::new();
+
+    for (i, msg) in messages.into_iter().enumerate() {
+        if i > 0 {
+            prompt.push('\n');
+        }
         let text = extract_text(&msg.content);
         match msg.role.as_str() {
             "system" => {
-                parts.push(format!("<system>\n{}\n</system>\n", text));
+                prompt.push_str("<system>\n");
+                prompt.push_str(&text);
+                prompt.push_str("\n</system>\n");
             }
             "user" => {
-                parts.push(text);
+                prompt.push_str(&text);
             }
             "assistant" => {
-                parts.push(format!("<previous_response>\n{}\n</previous_response>\n", text));
+                prompt.push_str("<previous_response>\n");
+                prompt.push_str(&text);
+                for call in msg.tool_calls.iter().flatten() {
+                    prompt.push('\n');
+                    push_tool_call(&mut prompt, call);
+                }
+                prompt.push_str("\n</previous_response>\n");
+            }
+            "tool" if msg.tool_call_id.is_some() => {
+                let id = msg.tool_call_id.as_deref().unwrap_or_default();
+                tools::push_tool_result(&mut prompt, id, &text);
             }
             _ => {
                 // Treat unknown roles as user messages
-                parts.push(text);
+                prompt.push_str(&text);
             }
         }
     }
 
-    parts.join("\n").trim().to_string()
+    trim_in_place(&mut prompt);
+    prompt
+}
+
+/// Render an earlier tool call for the prompt.
+fn push_tool_call(prompt: &mut String, call: &ToolCall) {
+    // Arguments arrive JSON-encoded; embed them as JSON when they parse
+    let arguments = serde_json::from_str(&call.function.arguments)
+        .unwrap_or_else(|_| json!(call.function.arguments));
+    tools::push_tool_call(prompt, &call.id, &call.function.name, &arguments);
+}
+
+/// The function tools the model may call: none with `tool_choice: "none"`.
+fn offered_tools(request: &ChatCompletionRequest) -> Vec<ToolSpec<'_>> {
+    if let Some(ToolChoice::Mode(mode)) = &request.tool_choice
+        && mode == "none"
+    {
+        return Vec::new();
+    }
+    request
+        .tools
+        .iter()
+        .flatten()
+        .filter(|t| t.tool_type == "function")
+        .map(|t| ToolSpec {
+            name: &t.function.name,
+            description: t.function.description.as_deref(),
+            parameters: t.function.parameters.as_ref(),
+        })
+        .collect()
+}
+
+/// Whether the prompt offers the model tools, so its reply may hold
+/// `<tool_call>` blocks (see `adapter::tools`).
+pub fn offers_tools(request: &ChatCompletionRequest) -> bool {
+    !offered_tools(request).is_empty()
+}
+
+/// Describe the request's `tools` to the model along with how to call them.
+fn tool_instructions(request: &ChatCompletionRequest) -> Option<String> {
+    let requirement = match &request.tool_choice {
+        Some(ToolChoice::Mode(mode)) if mode == "required" => ToolRequirement::Any,
+        Some(ToolChoice::Function { function }) => ToolRequirement::Named(&function.name),
+        _ => ToolRequirement::Auto,
+    };
+    tools::tool_instructions(&offered_tools(request), requirement)
 }
 
-/// Convert an OpenAI request to CLI arguments and prompt.
-/// Returns (model_alias, prompt, optional_session_id).
-pub fn openai_to_cli(request: &ChatCompletionRequest) -> (&'static str, String, Option<String>) {
+/// Prompt guidance for `parallel_tool_calls`. Parallel calls are the default,
+/// so guidance is only added when the client explicitly disallows them (and,
+/// see `openai_to_cli`, offers tools at all).
+fn parallel_tool_guidance(parallel_tool_calls: Option<bool>) -> Option<&'static str> {
+    match parallel_tool_calls {
+        Some(false) => Some(
+            "Call tools one at a time. Wait for each tool result before making the next tool call; \
+             never issue multiple tool calls in parallel.",
+        ),
+        _ => None,
+    }
+}
+
+/// Convert an OpenAI request to CLI arguments and prompt, dropping the oldest
+/// non-system messages as needed to stay within `limits`. System messages
+/// and tool instructions are placed according to `system_mode`.
+pub fn openai_to_cli(
+    request: &ChatCompletionRequest,
+    limits: HistoryLimits,
+    models: &[ModelInfo],
+    system_mode: SystemPromptMode,
+) -> CliRequest {
     let model = request
         .model
         .as_deref()
-        .map(extract_model)
+        .map(|m| extract_model(m, models))
         .unwrap_or("opus");
 
-    let prompt = request
-        .messages
-        .as_ref()
-        .map(|msgs| messages_to_prompt(msgs))
-        .unwrap_or_default();
+    let messages = request.messages.as_deref().unwrap_or_default();
+    let dropped_turns = trim_history(
+        messages,
+        |m| m.role == "system",
+        |m| estimate_tokens(&extract_text(&m.content)),
+        limits,
+    );
+    let inline = system_mode == SystemPromptMode::Inline;
+    let kept = messages
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| dropped_turns.binary_search(i).is_err())
+        .map(|(_, m)| m);
+    let prompt = messages_to_prompt(kept.clone().filter(|m| inline || m.role != "system"));
+
+    let mut system = Vec::new();
+    if offers_tools(request) {
+        system.extend(parallel_tool_guidance(request.parallel_tool_calls).map(str::to_string));
+    }
+    system.extend(tool_instructions(request));
+    if !inline {
+        system.extend(kept.filter(|m| m.role == "system").map(|m| extract_text(&m.content)));
+    }
+    let (prompt, system_prompt) = place_system_prompt(system_mode, system, prompt);
 
     let session_id = request.user.clone();
 
-    (model, prompt, session_id)
+    CliRequest {
+        model,
+        prompt,
+        session_id,
+        dropped_turns,
+        system_prompt,
+    }
 }
 
 #[cfg(test)]
@@ -117,39 +252,100 @@ mod tests {
 
     // ── extract_model ─────────────────────────────────────────
 
+    #[test]
+    fn configured_model_resolves_to_alias() {
+        let models = vec![ModelInfo {
+            id: "claude-3-5-sonnet".to_string(),
+            object: "model".to_string(),
+            owned_by: "anthropic".to_string(),
+            created: 0,
+            context_window: 200_000,
+            max_tokens: 8192,
+            alias: "haiku".to_string(),
+        }];
+        // Without the list, "sonnet" in the id would win
+        assert_eq!(extract_model("claude-3-5-sonnet", &[]), "sonnet");
+        assert_eq!(extract_model("claude-3-5-sonnet", &models), "haiku");
+        assert_eq!(extract_model("claude-opus-4", &models), "opus");
+    }
+
     #[test]
     fn exact_model_names() {
-        assert_eq!(extract_model("claude-opus-4"), "opus");
-        assert_eq!(extract_model("claude-sonnet-4"), "sonnet");
-        assert_eq!(extract_model("claude-haiku-4"), "haiku");
+        assert_eq!(extract_model("claude-opus-4", &[]), "opus");
+        assert_eq!(extract_model("claude-sonnet-4", &[]), "sonnet");
+        assert_eq!(extract_model("claude-haiku-4", &[]), "haiku");
     }
 
     #[test]
     fn short_aliases() {
-        assert_eq!(extract_model("opus"), "opus");
-        assert_eq!(extract_model("sonnet"), "sonnet");
-        assert_eq!(extract_model("haiku"), "haiku");
+        assert_eq!(extract_model("opus", &[]), "opus");
+        assert_eq!(extract_model("sonnet", &[]), "sonnet");
+        assert_eq!(extract_model("haiku", &[]), "haiku");
     }
 
     #[test]
     fn prefixed_model_names() {
-        assert_eq!(extract_model("claude-code-cli/claude-opus-4"), "opus");
-        assert_eq!(extract_model("claude-code-cli/claude-sonnet-4"), "sonnet");
-        assert_eq!(extract_model("claude-code-cli/claude-haiku-4"), "haiku");
+        assert_eq!(extract_model("claude-code-cli/claude-opus-4", &[]), "opus");
+        assert_eq!(extract_model("claude-code-cli/claude-sonnet-4", &[]), "sonnet");
+        assert_eq!(extract_model("claude-code-cli/claude-haiku-4", &[]), "haiku");
     }
 
     #[test]
     fn date_suffixed_model_names() {
-        assert_eq!(extract_model("claude-opus-4-20250514"), "opus");
-        assert_eq!(extract_model("claude-sonnet-4-5-20250929"), "sonnet");
-        assert_eq!(extract_model("claude-haiku-4-5-20251001"), "haiku");
+        assert_eq!(extract_model("claude-opus-4-20250514", &[]), "opus");
+        assert_eq!(extract_model("claude-sonnet-4-5-20250929", &[]), "sonnet");
+        assert_eq!(extract_model("claude-haiku-4-5-20251001", &[]), "haiku");
     }
 
     #[test]
     fn unknown_model_defaults_to_opus() {
-        assert_eq!(extract_model("gpt-4"), "opus");
-        assert_eq!(extract_model("unknown-model"), "opus");
-        assert_eq!(extract_model(""), "opus");
+        assert_eq!(extract_model("gpt-4", &[]), "opus");
+        assert_eq!(extract_model("unknown-model", &[]), "opus");
+        assert_eq!(extract_model("", &[]), "opus");
+    }
+
+    // ── count_system_messages ─────────────────────────────────
+
+    #[test]
+    fn counts_only_system_messages() {
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: Some(MessageContent::Text("a".to_string())),
+                ..Default::default()
+            },
+            Message {
+                role: "user".to_string(),
+                content: Some(MessageContent::Text("b".to_string())),
+                ..Default::default()
+            },
+            Message {
+                role: "system".to_string(),
+                content: None,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(count_system_messages(&messages), 2);
+        assert_eq!(count_system_messages(&[]), 0);
+    }
+
+    #[test]
+    fn detects_image_parts() {
+        let message = |part_type: &str| Message {
+            role: "user".to_string(),
+            content: Some(MessageContent::Parts(vec![ContentPart {
+                part_type: part_type.to_string(),
+                text: None,
+            }])),
+            ..Default::default()
+        };
+        assert!(has_image_content(&[message("text"), message("image_url")]));
+        assert!(!has_image_content(&[message("text")]));
+        assert!(!has_image_content(&[Message {
+            role: "user".to_string(),
+            content: Some(MessageContent::Text("image_url".to_string())),
+            ..Default::default()
+        }]));
     }
 
     // ── messages_to_prompt ────────────────────────────────────
@@ -159,6 +355,7 @@ mod tests {
         let messages = vec![Message {
             role: "user".to_string(),
             content: Some(MessageContent::Text("Hello".to_string())),
+            ..Default::default()
         }];
         assert_eq!(messages_to_prompt(&messages), "Hello");
     }
@@ -169,10 +366,12 @@ mod tests {
             Message {
                 role: "system".to_string(),
                 content: Some(MessageContent::Text("You are helpful.".to_string())),
+                ..Default::default()
             },
             Message {
                 role: "user".to_string(),
                 content: Some(MessageContent::Text("Hi".to_string())),
+                ..Default::default()
             },
         ];
         let prompt = messages_to_prompt(&messages);
@@ -186,14 +385,17 @@ mod tests {
             Message {
                 role: "user".to_string(),
                 content: Some(MessageContent::Text("Hi".to_string())),
+                ..Default::default()
             },
             Message {
                 role: "assistant".to_string(),
                 content: Some(MessageContent::Text("Hello!".to_string())),
+                ..Default::default()
             },
             Message {
                 role: "user".to_string(),
                 content: Some(MessageContent::Text("How are you?".to_string())),
+                ..Default::default()
             },
         ];
         let prompt = messages_to_prompt(&messages);
@@ -219,6 +421,7 @@ mod tests {
                     text: None,
                 },
             ])),
+            ..Default::default()
         }];
         assert_eq!(messages_to_prompt(&messages), "Hello world");
     }
@@ -228,15 +431,55 @@ mod tests {
         let messages = vec![Message {
             role: "user".to_string(),
             content: None,
+            ..Default::default()
         }];
         assert_eq!(messages_to_prompt(&messages), "");
     }
 
+    #[test]
+    fn many_tiny_parts_scale_linearly() {
+        fn build(n: usize) -> Vec<Message> {
+            (0..n)
+                .map(|i| Message {
+                    role: if i % 2 == 0 { "user" } else { "assistant" }.to_string(),
+                    content: Some(MessageContent::Parts(vec![ContentPart {
+                        part_type: "text".to_string(),
+                        text: Some("x".to_string()),
+                    }])),
+                    ..Default::default()
+                })
+                .collect()
+        }
+        fn best_of_three(messages: &[Message]) -> std::time::Duration {
+            (0..3)
+                .map(|_| {
+                    let start = std::time::Instant::now();
+                    std::hint::black_box(messages_to_prompt(messages));
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        }
+
+        let small = build(10_000);
+        let large = build(100_000);
+        assert!(messages_to_prompt(&large).ends_with("</previous_response>"));
+
+        let t_small = best_of_three(&small);
+        let t_large = best_of_three(&large);
+        // 10x the input should cost ~10x the time; quadratic building would be ~100x
+        assert!(
+            t_large < t_small * 40 + std::time::Duration::from_millis(50),
+            "10k parts took {t_small:?}, 100k parts took {t_large:?}"
+        );
+    }
+
     #[test]
     fn unknown_role_treated_as_user() {
         let messages = vec![Message {
             role: "tool".to_string(),
             content: Some(MessageContent::Text("tool output".to_string())),
+            ..Default::default()
         }];
         assert_eq!(messages_to_prompt(&messages), "tool output");
     }
@@ -250,14 +493,17 @@ mod tests {
             messages: Some(vec![Message {
                 role: "user".to_string(),
                 content: Some(MessageContent::Text("test".to_string())),
+                ..Default::default()
             }]),
             stream: false,
             user: Some("session-123".to_string()),
+            ..Default::default()
         };
-        let (model, prompt, session_id) = openai_to_cli(&request);
-        assert_eq!(model, "sonnet");
-        assert_eq!(prompt, "test");
-        assert_eq!(session_id, Some("session-123".to_string()));
+        let cli = inline_cli(&request, HistoryLimits::default());
+        assert_eq!(cli.model, "sonnet");
+        assert_eq!(cli.prompt, "test");
+        assert_eq!(cli.session_id, Some("session-123".to_string()));
+        assert!(cli.dropped_turns.is_empty());
     }
 
     #[test]
@@ -267,13 +513,15 @@ mod tests {
             messages: Some(vec![Message {
                 role: "user".to_string(),
                 content: Some(MessageContent::Text("test".to_string())),
+                ..Default::default()
             }]),
             stream: false,
             user: None,
+            ..Default::default()
         };
-        let (model, _, session_id) = openai_to_cli(&request);
-        assert_eq!(model, "opus");
-        assert_eq!(session_id, None);
+        let cli = inline_cli(&request, HistoryLimits::default());
+        assert_eq!(cli.model, "opus");
+        assert_eq!(cli.session_id, None);
     }
 
     #[test]
@@ -283,8 +531,256 @@ mod tests {
             messages: None,
             stream: false,
             user: None,
+            ..Default::default()
+        };
+        let cli = inline_cli(&request, HistoryLimits::default());
+        assert_eq!(cli.prompt, "");
+    }
+
+    // ── parallel_tool_calls ──────────────────────────────────
+
+    fn request_with_parallel(parallel_tool_calls: Option<bool>) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            parallel_tool_calls,
+            ..request_with_tools(json!("auto"))
+        }
+    }
+
+    const SEQUENTIAL: &str = "Call tools one at a time.";
+
+    #[test]
+    fn parallel_tool_calls_default_adds_no_guidance() {
+        let request = request_with_parallel(None);
+        let prompt = inline_cli(&request, HistoryLimits::default()).prompt;
+        assert!(!prompt.contains(SEQUENTIAL));
+    }
+
+    #[test]
+    fn parallel_tool_calls_true_adds_no_guidance() {
+        let request = request_with_parallel(Some(true));
+        let prompt = inline_cli(&request, HistoryLimits::default()).prompt;
+        assert!(!prompt.contains(SEQUENTIAL));
+    }
+
+    #[test]
+    fn parallel_tool_calls_false_requests_sequential_calls() {
+        let request = request_with_parallel(Some(false));
+        let prompt = inline_cli(&request, HistoryLimits::default()).prompt;
+        assert!(prompt.starts_with(&format!("<system>\n{SEQUENTIAL}")));
+        assert!(prompt.ends_with("Weather in Paris?"));
+    }
+
+    #[test]
+    fn parallel_tool_calls_false_without_tools_leaves_prompt_unchanged() {
+        let request = ChatCompletionRequest {
+            messages: Some(vec![Message {
+                role: "user".to_string(),
+                content: Some(MessageContent::Text("list files".to_string())),
+                ..Default::default()
+            }]),
+            parallel_tool_calls: Some(false),
+            ..Default::default()
+        };
+        let prompt = inline_cli(&request, HistoryLimits::default()).prompt;
+        assert_eq!(prompt, "list files");
+    }
+
+    // ── tools ────────────────────────────────────────────────
+
+    fn request_with_tools(tool_choice: serde_json::Value) -> ChatCompletionRequest {
+        serde_json::from_value(json!({
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "tools": [{
+                "type": "function",
+                "function": {
+                    "name": "get_weather",
+                    "description": "Current weather for a city",
+                    "parameters": {"type": "object", "properties": {"city": {"type": "string"}}},
+                },
+            }],
+            "tool_choice": tool_choice,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn tools_are_described_in_a_system_block() {
+        let request = request_with_tools(json!("auto"));
+        assert!(offers_tools(&request));
+        let prompt = inline_cli(&request, HistoryLimits::default()).prompt;
+        assert!(prompt.starts_with("<system>\nYou can call the following tools"));
+        assert!(prompt.contains("\"name\": \"get_weather\""));
+        assert!(prompt.contains("Current weather for a city"));
+        assert!(prompt.contains("<tool_call>"));
+        assert!(!prompt.contains("You must call"));
+        assert!(prompt.ends_with("Weather in Paris?"));
+    }
+
+    #[test]
+    fn tool_choice_none_offers_no_tools() {
+        let request = request_with_tools(json!("none"));
+        assert!(!offers_tools(&request));
+        let prompt = inline_cli(&request, HistoryLimits::default()).prompt;
+        assert_eq!(prompt, "Weather in Paris?");
+    }
+
+    #[test]
+    fn tool_choice_can_require_calls() {
+        let prompt = |choice| {
+            inline_cli(&request_with_tools(choice), HistoryLimits::default()).prompt
         };
-        let (_, prompt, _) = openai_to_cli(&request);
-        assert_eq!(prompt, "");
+        assert!(prompt(json!("required")).contains("You must call at least one tool."));
+        let named = json!({"type": "function", "function": {"name": "get_weather"}});
+        assert!(prompt(named).contains("You must call the `get_weather` tool."));
+    }
+
+    #[test]
+    fn tool_calls_and_results_are_rendered() {
+        let messages: Vec<Message> = serde_json::from_value(json!([
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+            }]},
+            {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+        ]))
+        .unwrap();
+        let prompt = messages_to_prompt(&messages);
+        assert!(prompt.contains(
+            "<previous_response>\n\n<tool_call>\
+             {\"arguments\":{\"city\":\"Paris\"},\"id\":\"call_1\",\"name\":\"get_weather\"}\
+             </tool_call>\n</previous_response>"
+        ));
+        assert!(prompt.ends_with("<tool_result tool_call_id=\"call_1\">\nSunny\n</tool_result>"));
+    }
+
+    // ── system prompt mode ───────────────────────────────────
+
+    /// `openai_to_cli` with the system prompt inline, as most tests expect.
+    fn inline_cli(request: &ChatCompletionRequest, limits: HistoryLimits) -> CliRequest {
+        openai_to_cli(request, limits, &[], SystemPromptMode::Inline)
+    }
+
+    fn request_with_system() -> ChatCompletionRequest {
+        let msg = |role: &str, text: &str| Message {
+            role: role.to_string(),
+            content: Some(MessageContent::Text(text.to_string())),
+            ..Default::default()
+        };
+        ChatCompletionRequest {
+            messages: Some(vec![
+                msg("system", "Be brief"),
+                msg("user", "first"),
+                msg("assistant", "reply"),
+                msg("user", "second"),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn flag_mode_moves_system_messages_out_of_the_prompt() {
+        let request = request_with_system();
+        let flag = openai_to_cli(&request, HistoryLimits::default(), &[], SystemPromptMode::Flag);
+        assert_eq!(flag.system_prompt.as_deref(), Some("Be brief"));
+        assert!(!flag.prompt.contains("<system>") && !flag.prompt.contains("Be brief"));
+        assert!(flag.prompt.starts_with("first"));
+
+        let inline = inline_cli(&request, HistoryLimits::default());
+        assert_eq!(inline.system_prompt, None);
+        assert!(inline.prompt.starts_with("<system>\nBe brief\n</system>"));
+        assert!(inline.prompt.ends_with(&flag.prompt));
+    }
+
+    #[test]
+    fn flag_mode_carries_tool_guidance_in_the_system_prompt() {
+        let request = ChatCompletionRequest {
+            messages: request_with_system().messages,
+            ..request_with_parallel(Some(false))
+        };
+        let cli = openai_to_cli(&request, HistoryLimits::default(), &[], SystemPromptMode::Flag);
+        let system = cli.system_prompt.unwrap();
+        assert!(system.starts_with("Call tools one at a time."));
+        assert!(system.contains("You can call the following tools"));
+        assert!(system.ends_with("\n\nBe brief"));
+        assert!(!cli.prompt.contains("<system>"));
+    }
+
+    #[test]
+    fn flag_mode_without_system_text_has_no_system_prompt() {
+        let mut request = request_with_system();
+        request.messages.as_mut().unwrap().remove(0);
+        let cli = openai_to_cli(&request, HistoryLimits::default(), &[], SystemPromptMode::Flag);
+        assert_eq!(cli.system_prompt, None);
+        assert!(cli.prompt.starts_with("first"));
+    }
+
+    // ── history trimming ─────────────────────────────────────
+
+    fn turns(max_turns: usize) -> HistoryLimits {
+        HistoryLimits {
+            max_turns: Some(max_turns),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn openai_to_cli_trims_oldest_turns() {
+        let msg = |role: &str, text: &str| Message {
+            role: role.to_string(),
+            content: Some(MessageContent::Text(text.to_string())),
+            ..Default::default()
+        };
+        let request = ChatCompletionRequest {
+            messages: Some(vec![
+                msg("system", "Be brief"),
+                msg("user", "first"),
+                msg("assistant", "reply"),
+                msg("user", "second"),
+            ]),
+            ..Default::default()
+        };
+
+        let cli = inline_cli(&request, turns(1));
+        assert_eq!(cli.dropped_turns, vec![1, 2]);
+        assert!(cli.prompt.contains("Be brief"));
+        assert!(!cli.prompt.contains("first"));
+        assert!(!cli.prompt.contains("reply"));
+        assert!(cli.prompt.ends_with("second"));
+
+        let cli = inline_cli(&request, turns(3));
+        assert!(cli.dropped_turns.is_empty());
+        assert!(cli.prompt.contains("first"));
+    }
+
+    #[test]
+    fn openai_to_cli_trims_to_token_budget() {
+        let msg = |role: &str, text: &str| Message {
+            role: role.to_string(),
+            content: Some(MessageContent::Text(text.to_string())),
+            ..Default::default()
+        };
+        // 2 + 5 + 5 + 2 estimated tokens
+        let request = ChatCompletionRequest {
+            messages: Some(vec![
+                msg("system", "Be brief"),
+                msg("user", &"a".repeat(20)),
+                msg("assistant", &"b".repeat(20)),
+                msg("user", "second"),
+            ]),
+            ..Default::default()
+        };
+        let budget = |max_tokens| HistoryLimits {
+            max_tokens: Some(max_tokens),
+            ..Default::default()
+        };
+
+        assert!(inline_cli(&request, budget(14)).dropped_turns.is_empty());
+        assert_eq!(inline_cli(&request, budget(13)).dropped_turns, vec![1]);
+        let cli = inline_cli(&request, budget(1));
+        assert_eq!(cli.dropped_turns, vec![1, 2]);
+        assert!(cli.prompt.contains("Be brief"));
+        assert!(cli.prompt.ends_with("second"));
     }
 }
@@ -0,0 +1,112 @@
+//! The one place the CLI's stop reason is translated for clients, so the
+//! Anthropic, OpenAI and legacy adapters always agree on why a run ended.
+
+/// Why a CLI run stopped, as far as clients are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model finished its turn (also any reason the CLI reports that
+    /// has no closer match)
+    EndTurn,
+    /// The output token cap was hit
+    MaxTokens,
+    /// A stop sequence matched
+    StopSequence,
+    /// The model declined to answer
+    Refusal,
+}
+
+impl StopReason {
+    /// Every variant, for exhaustive checks.
+    #[cfg(test)]
+    const ALL: [StopReason; 4] = [
+        StopReason::EndTurn,
+        StopReason::MaxTokens,
+        StopReason::StopSequence,
+        StopReason::Refusal,
+    ];
+
+    /// Classify the CLI's `stop_reason`; none at all means the turn ended.
+    pub fn from_cli(cli_stop_reason: Option<&str>) -> Self {
+        match cli_stop_reason {
+            Some("max_tokens") => StopReason::MaxTokens,
+            Some("stop_sequence") => StopReason::StopSequence,
+            Some("refusal") => StopReason::Refusal,
+            _ => StopReason::EndTurn,
+        }
+    }
+
+    /// The Anthropic Messages `stop_reason`.
+    pub fn anthropic(self) -> &'static str {
+        match self {
+            StopReason::EndTurn => "end_turn",
+            StopReason::MaxTokens => "max_tokens",
+            StopReason::StopSequence => "stop_sequence",
+            StopReason::Refusal => "refusal",
+        }
+    }
+
+    /// The OpenAI `finish_reason`.
+    pub fn openai(self) -> &'static str {
+        match self {
+            StopReason::EndTurn | StopReason::StopSequence => "stop",
+            StopReason::MaxTokens => "length",
+            StopReason::Refusal => "content_filter",
+        }
+    }
+
+    /// The legacy Text Completions `stop_reason`, which only tells a hit
+    /// token cap from a finished turn.
+    pub fn legacy(self) -> &'static str {
+        match self {
+            StopReason::MaxTokens => "max_tokens",
+            _ => "stop_sequence",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CLI stop reason → (variant, Anthropic, OpenAI, legacy)
+    const TABLE: &[(Option<&str>, StopReason, &str, &str, &str)] = &[
+        (None, StopReason::EndTurn, "end_turn", "stop", "stop_sequence"),
+        (Some("end_turn"), StopReason::EndTurn, "end_turn", "stop", "stop_sequence"),
+        (Some("max_tokens"), StopReason::MaxTokens, "max_tokens", "length", "max_tokens"),
+        (
+            Some("stop_sequence"),
+            StopReason::StopSequence,
+            "stop_sequence",
+            "stop",
+            "stop_sequence",
+        ),
+        (Some("refusal"), StopReason::Refusal, "refusal", "content_filter", "stop_sequence"),
+        (Some("pause_turn"), StopReason::EndTurn, "end_turn", "stop", "stop_sequence"),
+        (Some(""), StopReason::EndTurn, "end_turn", "stop", "stop_sequence"),
+    ];
+
+    #[test]
+    fn mapping_table() {
+        for &(cli, reason, anthropic, openai, legacy) in TABLE {
+            assert_eq!(StopReason::from_cli(cli), reason, "{cli:?}");
+            assert_eq!(reason.anthropic(), anthropic, "{cli:?}");
+            assert_eq!(reason.openai(), openai, "{cli:?}");
+            assert_eq!(reason.legacy(), legacy, "{cli:?}");
+        }
+    }
+
+    #[test]
+    fn table_covers_every_variant() {
+        for reason in StopReason::ALL {
+            assert!(TABLE.iter().any(|row| row.1 == reason), "{reason:?}");
+        }
+    }
+
+    #[test]
+    fn anthropic_names_round_trip() {
+        // The CLI reports Anthropic stop reasons, so each maps back to itself
+        for reason in StopReason::ALL {
+            assert_eq!(StopReason::from_cli(Some(reason.anthropic())), reason);
+        }
+    }
+}
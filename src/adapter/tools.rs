@@ -0,0 +1,207 @@
+//! Client tool calling over the CLI. The CLI's own tools are its built-ins,
+//! so client tools are described to the model in the prompt and it calls
+//! them by writing `<tool_call>` blocks in its reply, which the response
+//! adapters turn into `tool_calls` (OpenAI) or `tool_use` blocks (Anthropic).
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+const CALL_OPEN: &str = "<tool_call>";
+const CALL_CLOSE: &str = "</tool_call>";
+
+/// A client tool as described to the model.
+#[derive(Debug, Serialize)]
+pub struct ToolSpec<'a> {
+    pub name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'a str>,
+    /// JSON Schema for the arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<&'a Value>,
+}
+
+/// What `tool_choice` asks of the model beyond being free to call tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolRequirement<'a> {
+    /// Call tools only if needed
+    Auto,
+    /// Call at least one tool
+    Any,
+    /// Call this tool
+    Named(&'a str),
+}
+
+/// The `<system>` text offering `tools`, or `None` when there are none.
+pub fn tool_instructions(tools: &[ToolSpec], requirement: ToolRequirement) -> Option<String> {
+    if tools.is_empty() {
+        return None;
+    }
+    let requirement = match requirement {
+        ToolRequirement::Auto => String::new(),
+        ToolRequirement::Any => "\nYou must call at least one tool.".to_string(),
+        ToolRequirement::Named(name) => format!("\nYou must call the `{name}` tool."),
+    };
+    let definitions = serde_json::to_string_pretty(tools).unwrap_or_default();
+    Some(format!(
+        "You can call the following tools, given as JSON Schema function definitions:\n\
+         <tools>\n{definitions}\n</tools>\n\
+         To call tools, reply with one block per call and nothing else:\n\
+         <tool_call>{{\"name\": \"<tool name>\", \"arguments\": {{<arguments>}}}}</tool_call>\n\
+         Results come back in <tool_result> blocks. \
+         If no tool is needed, answer normally.{requirement}"
+    ))
+}
+
+/// Render an earlier tool call the way the model is asked to write them,
+/// keeping its id so results can refer to it.
+pub fn push_tool_call(prompt: &mut String, id: &str, name: &str, arguments: &Value) {
+    let block = json!({"id": id, "name": name, "arguments": arguments});
+    prompt.push_str(&format!("{CALL_OPEN}{block}{CALL_CLOSE}"));
+}
+
+/// Render the result of the call with id `id`.
+pub fn push_tool_result(prompt: &mut String, id: &str, text: &str) {
+    prompt.push_str(&format!("<tool_result tool_call_id=\"{id}\">\n"));
+    prompt.push_str(text);
+    prompt.push_str("\n</tool_result>\n");
+}
+
+/// A tool call parsed from the model's reply.
+#[derive(Debug, Deserialize)]
+pub struct ParsedToolCall {
+    /// The id the model gave the call, if any (earlier calls are shown to it
+    /// with theirs, see `push_tool_call`)
+    #[serde(default)]
+    pub id: Option<String>,
+    pub name: String,
+    /// The arguments object; `{}` when the model left them out
+    #[serde(default = "empty_object")]
+    pub arguments: Value,
+}
+
+fn empty_object() -> Value {
+    json!({})
+}
+
+/// Split the `<tool_call>` blocks out of a reply. Returns the trimmed
+/// remaining text and the calls in order; blocks that aren't JSON with a
+/// `name` stay in the text.
+pub fn extract_tool_calls(text: &str) -> (String, Vec<ParsedToolCall>) {
+    let mut remaining = String::new();
+    let mut calls = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(CALL_OPEN) {
+        let body_start = start + CALL_OPEN.len();
+        let Some(len) = rest[body_start..].find(CALL_CLOSE) else {
+            break;
+        };
+        let end = body_start + len + CALL_CLOSE.len();
+        match serde_json::from_str::<ParsedToolCall>(rest[body_start..body_start + len].trim()) {
+            Ok(mut call) => {
+                remaining.push_str(&rest[..start]);
+                if call.arguments.is_null() {
+                    call.arguments = empty_object();
+                }
+                calls.push(call);
+            }
+            Err(_) => remaining.push_str(&rest[..end]),
+        }
+        rest = &rest[end..];
+    }
+    remaining.push_str(rest);
+    (remaining.trim().to_string(), calls)
+}
+
+impl ParsedToolCall {
+    /// The call's id, with the API's prefix (`call_`, `toolu_`) when made
+    /// up here: the model's own if it gave one, else derived from the
+    /// response's id and the call's position in it. A response's calls
+    /// thus keep their ids whether it is streamed or not, and a client's
+    /// `tool_result` can refer back to them.
+    pub fn id(&self, prefix: &str, response_id: &str, index: usize) -> String {
+        match self.id.as_deref() {
+            Some(id) if !id.trim().is_empty() => id.to_string(),
+            _ => {
+                let name = format!("{response_id}/{index}");
+                let id = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, name.as_bytes());
+                format!("{prefix}{}", id.simple())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_ids_are_the_models_own_or_derived_from_the_response() {
+        let (_, calls) = extract_tool_calls(
+            "<tool_call>{\"name\": \"a\"}</tool_call>\
+             <tool_call>{\"id\": \"toolu_mine\", \"name\": \"b\"}</tool_call>",
+        );
+        let first = calls[0].id("toolu_", "msg_req1", 0);
+        assert!(first.starts_with("toolu_"));
+        assert_eq!(calls[0].id("toolu_", "msg_req1", 0), first);
+        assert_ne!(calls[0].id("toolu_", "msg_req1", 1), first);
+        assert_ne!(calls[0].id("toolu_", "msg_req2", 0), first);
+        assert_eq!(calls[1].id("toolu_", "msg_req1", 1), "toolu_mine");
+    }
+
+    #[test]
+    fn instructions_list_tools_and_requirement() {
+        let schema = json!({"type": "object"});
+        let tools = [ToolSpec {
+            name: "get_weather",
+            description: Some("Current weather"),
+            parameters: Some(&schema),
+        }];
+        let text = tool_instructions(&tools, ToolRequirement::Auto).unwrap();
+        assert!(text.contains("\"name\": \"get_weather\""));
+        assert!(text.contains("Current weather"));
+        assert!(!text.contains("You must call"));
+
+        let any = tool_instructions(&tools, ToolRequirement::Any).unwrap();
+        assert!(any.ends_with("You must call at least one tool."));
+        let named = tool_instructions(&tools, ToolRequirement::Named("get_weather")).unwrap();
+        assert!(named.ends_with("You must call the `get_weather` tool."));
+
+        assert!(tool_instructions(&[], ToolRequirement::Any).is_none());
+    }
+
+    #[test]
+    fn extract_splits_blocks_from_text() {
+        let text = "Checking both.\n\
+            <tool_call>{\"name\": \"a\", \"arguments\": {\"x\": 1}}</tool_call>\n\
+            <tool_call> {\"name\": \"b\", \"arguments\": null} </tool_call>";
+        let (remaining, calls) = extract_tool_calls(text);
+        assert_eq!(remaining, "Checking both.");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "a");
+        assert_eq!(calls[0].arguments, json!({"x": 1}));
+        assert_eq!(calls[1].name, "b");
+        assert_eq!(calls[1].arguments, json!({}));
+    }
+
+    #[test]
+    fn extract_keeps_malformed_blocks() {
+        let text = "See <tool_call>not json</tool_call> and <tool_call>{\"name\": \"a\"}";
+        let (remaining, calls) = extract_tool_calls(text);
+        assert!(calls.is_empty());
+        assert_eq!(remaining, text);
+    }
+
+    #[test]
+    fn rendered_history_round_trips() {
+        let mut prompt = String::new();
+        push_tool_call(&mut prompt, "call_1", "a", &json!({"x": 1}));
+        let (_, calls) = extract_tool_calls(&prompt);
+        assert_eq!(calls[0].name, "a");
+        assert_eq!(calls[0].arguments, json!({"x": 1}));
+
+        let mut prompt = String::new();
+        push_tool_result(&mut prompt, "call_1", "Sunny");
+        assert_eq!(prompt, "<tool_result tool_call_id=\"call_1\">\nSunny\n</tool_result>\n");
+    }
+}
@@ -0,0 +1,246 @@
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, header};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::config::ApiKey;
+use crate::error::AppError;
+use crate::server::AppState;
+
+/// Extract the token from an `Authorization: Bearer <token>` header.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+/// Compare two byte strings in time that depends only on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Label of the key a request authenticated with, stored as a request extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyLabel(pub String);
+
+/// Find the key matching `token`. Every key is compared so the time taken
+/// doesn't reveal which one matched.
+fn match_key<'a>(keys: &'a [ApiKey], token: &str) -> Option<&'a ApiKey> {
+    keys.iter().fold(None, |found, key| {
+        let matches = constant_time_eq(token.as_bytes(), key.key.as_bytes());
+        if matches && found.is_none() { Some(key) } else { found }
+    })
+}
+
+/// Middleware rejecting requests without one of the configured bearer tokens.
+/// The matched key's label is attached as an [`ApiKeyLabel`] extension.
+pub async fn require_api_key(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if !state.config.api_keys.is_empty() {
+        let Some(token) = bearer_token(request.headers()) else {
+            return Err(AppError::Unauthorized(
+                "Missing bearer token in Authorization header".to_string(),
+            ));
+        };
+        let Some(key) = match_key(&state.config.api_keys, token) else {
+            return Err(AppError::Unauthorized("Invalid API key".to_string()));
+        };
+        let label = ApiKeyLabel(key.label.clone());
+        request.extensions_mut().insert(label);
+    }
+    Ok(next.run(request).await)
+}
+
+/// Middleware guarding `/v1/admin/*` routes with the `--admin-key` bearer
+/// token; the ordinary API keys don't open them.
+pub async fn require_admin_key(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(token) = bearer_token(request.headers()) else {
+        return Err(AppError::Unauthorized(
+            "Missing bearer token in Authorization header".to_string(),
+        ));
+    };
+    let admin_key = state.config.admin_key.as_deref().unwrap_or_default();
+    if admin_key.is_empty() || !constant_time_eq(token.as_bytes(), admin_key.as_bytes()) {
+        return Err(AppError::Unauthorized("Invalid admin key".to_string()));
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, DEFAULT_MAX_CONCURRENCY};
+    use crate::metrics::Metrics;
+    use crate::server::create_router;
+    use crate::session::SessionManager;
+    use axum::Extension;
+    use axum::body::Body;
+    use axum::http::{HeaderValue, StatusCode};
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
+    use tower::ServiceExt;
+
+    fn router(api_key: Option<&str>) -> axum::Router {
+        let keys = api_key
+            .map(|key| {
+                vec![ApiKey {
+                    label: "default".to_string(),
+                    key: key.to_string(),
+                }]
+            })
+            .unwrap_or_default();
+        router_with_keys(keys)
+    }
+
+    fn router_with_keys(api_keys: Vec<ApiKey>) -> axum::Router {
+        create_router(state(api_keys))
+    }
+
+    fn state(api_keys: Vec<ApiKey>) -> AppState {
+        let path = std::env::temp_dir().join(format!("auth-test-{}.json", uuid::Uuid::new_v4()));
+        AppState {
+            cwd: "/tmp".to_string(),
+            session_manager: SessionManager::with_path(path),
+            config: Arc::new(Config {
+                api_keys,
+                ..Default::default()
+            }),
+            metrics: Arc::new(Metrics::default()),
+            concurrency: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENCY)),
+            cli: Arc::new(crate::subprocess::RealCliRunner::default()),
+            cancellations: Default::default(),
+            shutdown: tokio::sync::watch::channel(false).1,
+        }
+    }
+
+    async fn get(router: axum::Router, uri: &str, auth: Option<&str>) -> Response {
+        let mut request = Request::builder().uri(uri);
+        if let Some(auth) = auth {
+            request = request.header(header::AUTHORIZATION, auth);
+        }
+        router
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    // ── bearer_token ──────────────────────────────────────────
+
+    #[test]
+    fn bearer_token_parsing() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer sk-1"));
+        assert_eq!(bearer_token(&headers), Some("sk-1"));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("bearer sk-1"));
+        assert_eq!(bearer_token(&headers), Some("sk-1"));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+        assert_eq!(bearer_token(&headers), None);
+    }
+
+    // ── constant_time_eq ──────────────────────────────────────
+
+    #[test]
+    fn constant_time_eq_matches_exactly() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+
+    // ── match_key ─────────────────────────────────────────────
+
+    fn keys() -> Vec<ApiKey> {
+        vec![
+            ApiKey {
+                label: "ci".to_string(),
+                key: "sk-ci".to_string(),
+            },
+            ApiKey {
+                label: "laptop".to_string(),
+                key: "sk-laptop".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn match_key_finds_label() {
+        let keys = keys();
+        assert_eq!(match_key(&keys, "sk-laptop").map(|k| k.label.as_str()), Some("laptop"));
+        assert_eq!(match_key(&keys, "sk-ci").map(|k| k.label.as_str()), Some("ci"));
+        assert_eq!(match_key(&keys, "sk-other"), None);
+    }
+
+    // ── require_api_key ───────────────────────────────────────
+
+    #[tokio::test]
+    async fn open_when_no_key_configured() {
+        let response = get(router(None), "/v1/models", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn missing_key_is_rejected() {
+        let response = get(router(Some("sk-test")), "/v1/models", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["code"], "authentication_error");
+    }
+
+    #[tokio::test]
+    async fn wrong_key_is_rejected() {
+        let response = get(router(Some("sk-test")), "/v1/models", Some("Bearer sk-nope")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn matching_key_is_accepted() {
+        let response = get(router(Some("sk-test")), "/v1/models", Some("Bearer sk-test")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn any_configured_key_is_accepted() {
+        let response = get(router_with_keys(keys()), "/v1/models", Some("Bearer sk-ci")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response =
+            get(router_with_keys(keys()), "/v1/models", Some("Bearer sk-laptop")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get(router_with_keys(keys()), "/v1/models", Some("Bearer sk-x")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn matched_label_is_attached() {
+        let whoami = |Extension(label): Extension<ApiKeyLabel>| async move { label.0 };
+        let app = axum::Router::new()
+            .route("/whoami", axum::routing::get(whoami))
+            .route_layer(axum::middleware::from_fn_with_state(state(keys()), require_api_key));
+
+        let response = get(app, "/whoami", Some("Bearer sk-laptop")).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"laptop");
+    }
+
+    #[tokio::test]
+    async fn health_stays_open() {
+        let response = get(router(Some("sk-test")), "/health", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
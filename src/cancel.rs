@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// In-flight requests by owner and request id, so `POST
+/// /v1/requests/{id}/cancel` can stop one. The owner is the API key label
+/// the request authenticated with (`None` without `--api-key`), and only that
+/// owner can cancel it. A request is registered before its first CLI run and
+/// removed when its `CancelGuard` drops, so its retries and fallback runs
+/// stay cancellable under the same id.
+#[derive(Clone, Default)]
+pub struct CancelRegistry {
+    runs: Arc<Mutex<Runs>>,
+}
+
+/// Who registered a request, and its id.
+type Key = (Option<String>, String);
+
+/// Owner and request id → the request's cancel signal.
+type Runs = HashMap<Key, watch::Sender<bool>>;
+
+/// Keeps a request registered until dropped.
+pub struct CancelGuard {
+    runs: Arc<Mutex<Runs>>,
+    key: Key,
+    cancelled: watch::Receiver<bool>,
+}
+
+impl CancelRegistry {
+    /// Register a request under its owner's `id`, or `None` if that owner
+    /// already has a request with this id in flight.
+    pub fn register(&self, owner: Option<&str>, id: &str) -> Option<CancelGuard> {
+        let key = (owner.map(str::to_string), id.to_string());
+        let mut runs = self.runs.lock().unwrap();
+        if runs.contains_key(&key) {
+            return None;
+        }
+        let (tx, cancelled) = watch::channel(false);
+        runs.insert(key.clone(), tx);
+        Some(CancelGuard {
+            runs: self.runs.clone(),
+            key,
+            cancelled,
+        })
+    }
+
+    /// Signal `owner`'s request registered under `id`. False if there is
+    /// none (or it belongs to someone else), or it was already cancelled.
+    pub fn cancel(&self, owner: Option<&str>, id: &str) -> bool {
+        let key = (owner.map(str::to_string), id.to_string());
+        let runs = self.runs.lock().unwrap();
+        runs.get(&key).is_some_and(|tx| !tx.send_replace(true))
+    }
+
+    /// Signal every registered request, whoever owns it, returning how many
+    /// weren't cancelled already.
+    pub fn cancel_all(&self) -> usize {
+        let runs = self.runs.lock().unwrap();
+        runs.values().filter(|tx| !tx.send_replace(true)).count()
+    }
+}
+
+impl CancelGuard {
+    /// Resolves once the request is cancelled; right away if it already was.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut cancelled = self.cancelled.clone();
+        async move {
+            if cancelled.wait_for(|cancelled| *cancelled).await.is_err() {
+                std::future::pending().await
+            }
+        }
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.runs.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_cancelled(guard: &CancelGuard) -> bool {
+        *guard.cancelled.borrow()
+    }
+
+    #[test]
+    fn cancel_signals_the_registered_request_once() {
+        let registry = CancelRegistry::default();
+        let guard = registry.register(None, "req-1").unwrap();
+        assert!(!is_cancelled(&guard));
+        assert!(registry.cancel(None, "req-1"));
+        assert!(is_cancelled(&guard));
+        assert!(!registry.cancel(None, "req-1"));
+    }
+
+    #[test]
+    fn unknown_or_finished_requests_are_not_cancelled() {
+        let registry = CancelRegistry::default();
+        assert!(!registry.cancel(None, "nope"));
+        let guard = registry.register(None, "req-1").unwrap();
+        drop(guard);
+        assert!(!registry.cancel(None, "req-1"));
+    }
+
+    #[test]
+    fn only_the_owner_can_cancel() {
+        let registry = CancelRegistry::default();
+        let guard = registry.register(Some("alice"), "req-1").unwrap();
+        assert!(!registry.cancel(Some("bob"), "req-1"));
+        assert!(!registry.cancel(None, "req-1"));
+        assert!(!is_cancelled(&guard));
+        assert!(registry.cancel(Some("alice"), "req-1"));
+        assert!(is_cancelled(&guard));
+    }
+
+    #[test]
+    fn duplicate_in_flight_ids_are_rejected_per_owner() {
+        let registry = CancelRegistry::default();
+        let first = registry.register(Some("alice"), "req-1").unwrap();
+        assert!(registry.register(Some("alice"), "req-1").is_none());
+        // Another owner's ids are separate
+        let _other = registry.register(Some("bob"), "req-1").unwrap();
+        drop(first);
+        assert!(registry.register(Some("alice"), "req-1").is_some());
+    }
+
+    #[test]
+    fn cancel_all_signals_every_request() {
+        let registry = CancelRegistry::default();
+        let a = registry.register(Some("alice"), "req-a").unwrap();
+        let b = registry.register(None, "req-b").unwrap();
+        assert_eq!(registry.cancel_all(), 2);
+        assert!(is_cancelled(&a) && is_cancelled(&b));
+        assert_eq!(registry.cancel_all(), 0);
+        assert!(!registry.cancel(None, "req-b"));
+    }
+}
@@ -0,0 +1,1162 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::types::openai::ModelInfo;
+
+/// Default claude binary, looked up on `PATH`.
+pub const DEFAULT_CLAUDE_BIN: &str = "claude";
+
+/// Default inactivity timeout for a CLI subprocess (no stdout/stderr activity).
+pub const DEFAULT_INACTIVITY_TIMEOUT: Duration = Duration::from_secs(30 * 60); // 30 minutes
+
+/// Default time a shutdown waits for in-flight requests before killing them.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Default interval for the "Still running" progress log, in seconds.
+pub const DEFAULT_PROGRESS_LOG_SECS: u64 = 30;
+
+/// Default cap on system messages (OpenAI) or system blocks (Anthropic) per request.
+pub const DEFAULT_MAX_SYSTEM_MESSAGES: usize = 32;
+
+/// Default number of distinct models one result's `modelUsage` may list
+/// before it is logged and counted as anomalous.
+pub const DEFAULT_MAX_USAGE_MODELS: usize = 8;
+
+/// Default number of CLI subprocesses allowed to run at once.
+pub const DEFAULT_MAX_CONCURRENCY: usize = 8;
+
+/// Default time a request waits for a free subprocess slot before a 503, in milliseconds.
+pub const DEFAULT_CONCURRENCY_WAIT_MS: u64 = 1000;
+
+/// Default time a request waits for an earlier run of its session to finish
+/// before a 409, in seconds.
+pub const DEFAULT_SESSION_WAIT_SECS: u64 = 120;
+
+/// Default cap on the length of a client-supplied session id.
+pub const DEFAULT_MAX_SESSION_ID_LEN: usize = 128;
+
+/// Default cap on stored session mappings; the least recently used go first.
+pub const DEFAULT_MAX_SESSIONS: usize = 10_000;
+
+/// Default size above which the sessions file is set aside instead of loaded, in MiB.
+pub const DEFAULT_MAX_SESSIONS_FILE_MB: u64 = 64;
+
+/// Default longest a session change waits before it is written to disk, in seconds.
+pub const DEFAULT_SESSION_SAVE_SECS: u64 = 5;
+
+/// Default header carrying the request id, both inbound and outbound.
+pub const DEFAULT_REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest header value forwarded into the subprocess environment.
+const MAX_FORWARDED_VALUE_LEN: usize = 1024;
+
+/// JSON shape of error events on OpenAI streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum StreamErrorShape {
+    /// `{"error": {"message", "type", "code"}}`
+    #[default]
+    Openai,
+    /// `{"type": "error", "error": {"type", "message"}}`, as on Anthropic streams
+    Anthropic,
+}
+
+/// Format of the log lines written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable, with timestamps and colors; each event spans several
+    /// lines, its fields and enclosing spans indented below it
+    #[default]
+    Pretty,
+    /// One line per event, without timestamps or colors, for journald and
+    /// the like
+    Compact,
+    /// One JSON object per event, with the `cli_request` span's fields
+    Json,
+}
+
+/// What happens to trailing whitespace in streamed text deltas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum TrailingWhitespace {
+    /// Send deltas exactly as the CLI produced them
+    #[default]
+    Preserve,
+    /// Hold trailing whitespace back until more text follows, dropping it at
+    /// the end of the stream
+    Trim,
+}
+
+/// How the CLI's stdout and stderr bytes are decoded into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputEncoding {
+    /// UTF-8; a line with invalid bytes is decoded lossily, with a warning
+    #[default]
+    Utf8,
+    /// UTF-8 with invalid bytes always replaced by U+FFFD, without warnings
+    Lossy,
+    /// ISO-8859-1, where every byte is one character
+    Latin1,
+}
+
+/// How a request's system prompt (system messages, the Anthropic `system`
+/// field and tool instructions) reaches the CLI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SystemPromptMode {
+    /// As `<system>` blocks in the prompt text, as earlier versions did
+    Inline,
+    /// Through `--append-system-prompt`, keeping the prompt to the turns
+    #[default]
+    Flag,
+}
+
+/// The CLI's `--permission-mode`: what it may do without asking. A run
+/// can't answer a permission prompt, so a tool use needing one is denied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum PermissionMode {
+    /// Ask before edits and commands, i.e. deny them unless allow-listed
+    #[default]
+    #[value(name = "default")]
+    Default,
+    /// Allow file edits, ask for commands
+    #[value(name = "acceptEdits")]
+    AcceptEdits,
+    /// Allow everything, including shell commands
+    #[value(name = "bypassPermissions")]
+    BypassPermissions,
+    /// Read-only planning; nothing is changed
+    #[value(name = "plan")]
+    Plan,
+}
+
+impl PermissionMode {
+    /// The value passed to the CLI.
+    pub fn as_cli(self) -> &'static str {
+        match self {
+            PermissionMode::Default => "default",
+            PermissionMode::AcceptEdits => "acceptEdits",
+            PermissionMode::BypassPermissions => "bypassPermissions",
+            PermissionMode::Plan => "plan",
+        }
+    }
+}
+
+/// Which tools the CLI may use, from `--permission-mode`, `--allowed-tools`
+/// and `--disallowed-tools`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CliPermissions {
+    pub mode: PermissionMode,
+    /// Tools (or rules like `Bash(git diff:*)`) allowed without asking
+    pub allowed_tools: Vec<String>,
+    /// Tools the CLI may not use at all
+    pub disallowed_tools: Vec<String>,
+}
+
+/// Parse a tool name or rule for `--allowed-tools` / `--disallowed-tools`.
+pub fn parse_tool_rule(s: &str) -> Result<String, String> {
+    let rule = s.trim();
+    if rule.is_empty() || rule.starts_with('-') || rule.contains(',') {
+        return Err(format!("expected a tool name like Bash or Bash(git diff:*), got '{s}'"));
+    }
+    Ok(rule.to_string())
+}
+
+/// An accepted bearer token and the client label it identifies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub label: String,
+    pub key: String,
+}
+
+/// Which browser origins may call the API (`--cors-origin`, `--cors-disabled`).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum CorsPolicy {
+    /// Any origin; the default when bound to loopback
+    #[default]
+    Permissive,
+    /// Only these origins
+    Origins(Vec<HeaderValue>),
+    /// No CORS headers, so browsers refuse cross-origin calls
+    Disabled,
+}
+
+impl CorsPolicy {
+    /// The policy for the command line: explicit origins if any were given,
+    /// otherwise permissive only on a loopback address.
+    pub fn resolve(origins: Vec<HeaderValue>, disabled: bool, loopback: bool) -> Self {
+        if disabled {
+            CorsPolicy::Disabled
+        } else if !origins.is_empty() {
+            CorsPolicy::Origins(origins)
+        } else if loopback {
+            CorsPolicy::Permissive
+        } else {
+            CorsPolicy::Disabled
+        }
+    }
+}
+
+/// Parse a `--cors-origin` value: `scheme://host[:port]`, without a path.
+pub fn parse_cors_origin(s: &str) -> Result<HeaderValue, String> {
+    let origin = s.trim().trim_end_matches('/');
+    let host = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+        .ok_or_else(|| format!("expected http(s)://host[:port], got '{s}'"))?;
+    if host.is_empty() || host.contains('/') {
+        return Err(format!("expected http(s)://host[:port], got '{s}'"));
+    }
+    HeaderValue::from_str(origin).map_err(|_| format!("invalid origin '{s}'"))
+}
+
+/// Parse a `--sse-content-type`: an SSE media type, optionally with
+/// parameters, e.g. `text/event-stream; charset=utf-8`.
+pub fn parse_sse_content_type(s: &str) -> Result<HeaderValue, String> {
+    let value = s.trim();
+    let media_type = value.split(';').next().unwrap_or_default().trim();
+    if !media_type.eq_ignore_ascii_case("text/event-stream") {
+        return Err(format!("expected text/event-stream[; params], got '{s}'"));
+    }
+    HeaderValue::from_str(value).map_err(|_| format!("invalid content type '{s}'"))
+}
+
+/// Label given to the key passed with `--api-key`.
+pub const DEFAULT_API_KEY_LABEL: &str = "default";
+
+/// Runtime settings resolved from the command line at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The claude binary to run, a name on `PATH` or a path.
+    pub claude_bin: String,
+    /// What `claude --version` reported at startup.
+    pub cli_version: Option<String>,
+    /// Attach diagnostic `x-debug-*` headers to responses.
+    pub debug_features: bool,
+    /// Kill the subprocess after this long without output.
+    pub inactivity_timeout: Duration,
+    /// Kill the subprocess this long after it started, however active it is.
+    pub max_request_duration: Option<Duration>,
+    /// How long a shutdown lets in-flight requests finish before killing them.
+    pub shutdown_grace: Duration,
+    /// Inbound headers copied into the subprocess environment.
+    pub forward_headers: Vec<HeaderEnvMapping>,
+    /// Reject requests carrying more system messages/blocks than this.
+    pub max_system_messages: usize,
+    /// Keep only the newest N non-system turns of the conversation in the prompt.
+    pub max_prompt_turns: Option<usize>,
+    /// Drop the oldest turns until the prompt's estimated token count fits.
+    pub max_prompt_tokens: Option<usize>,
+    /// Send `X-Accel-Buffering: no` on streaming responses.
+    pub sse_no_buffering: bool,
+    /// Content-Type of streaming responses; `None` keeps `text/event-stream`.
+    pub sse_content_type: Option<HeaderValue>,
+    /// Interval for the "Still running" log of long requests; `None` disables it.
+    pub progress_log_interval: Option<Duration>,
+    /// Accept allow-listed CLI flags from the `X-Claude-Extra-Args` header.
+    pub allow_extra_args: bool,
+    /// Permission mode and tool lists every CLI run starts with.
+    pub permissions: CliPermissions,
+    /// Where the system prompt goes: the prompt text or a CLI flag.
+    pub system_prompt_mode: SystemPromptMode,
+    /// Honor `X-Debug: true` by logging that request at trace level.
+    pub allow_debug_header: bool,
+    /// Leave `usage` out of non-streaming OpenAI responses.
+    pub omit_usage: bool,
+    /// Bearer tokens accepted on `/v1/*` routes; empty leaves them open.
+    pub api_keys: Vec<ApiKey>,
+    /// Bearer token required on `/v1/admin/*` routes, which are only served
+    /// when one is set.
+    pub admin_key: Option<String>,
+    /// Shape of error events sent on OpenAI streams.
+    pub openai_stream_error_shape: StreamErrorShape,
+    /// Trailing whitespace handling in streamed deltas.
+    pub stream_trailing_whitespace: TrailingWhitespace,
+    /// How the CLI's output bytes are decoded.
+    pub output_encoding: OutputEncoding,
+    /// Merge streamed deltas arriving within this window; `None` sends each as is.
+    pub stream_coalesce: Option<Duration>,
+    /// Header honored as an inbound request id and used to echo it back.
+    pub request_id_header: HeaderName,
+    /// Browser origins allowed to call the API.
+    pub cors: CorsPolicy,
+    /// Maximum number of CLI subprocesses running at once.
+    pub max_concurrency: usize,
+    /// How long a request waits for a free subprocess slot before getting a 503.
+    pub concurrency_wait: Duration,
+    /// How long a request waits for its session's previous run before a 409.
+    pub session_wait: Duration,
+    /// Start a non-streaming run once more if the CLI crashes before any output.
+    pub retry_on_crash: bool,
+    /// Models a non-streaming run switches to when its own is unavailable.
+    pub model_fallbacks: Vec<ModelFallback>,
+    /// Per-model token prices for the cost estimate in completion logs.
+    pub model_prices: Vec<ModelPrice>,
+    /// Idle CLI processes kept spawned ahead of requests; 0 disables the pool.
+    pub warm_pool: usize,
+    /// Distinct models in one result's usage beyond which a warning is logged.
+    pub max_usage_models: usize,
+    /// Kill a run once its streamed content exceeds this many bytes.
+    pub max_output_bytes: Option<usize>,
+    /// Longest accepted session id (`user` / `metadata.user_id`).
+    pub max_session_id_len: usize,
+    /// Salt namespacing client session ids before they reach the CLI.
+    pub session_salt: Option<String>,
+    /// `service_tier` reported on chat completions and their chunks.
+    pub service_tier: Option<String>,
+    /// Report the CLI's exact model string instead of the normalized name.
+    pub echo_exact_model: bool,
+    /// Per-model working directories overriding the global cwd.
+    pub model_cwds: Vec<ModelCwd>,
+    /// Resolved directories an `X-Claude-Cwd` header may point into; empty
+    /// rejects the header.
+    pub allowed_cwd_roots: Vec<String>,
+    /// Models advertised on `/v1/models` and resolved to CLI aliases.
+    pub models: Vec<ModelInfo>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            claude_bin: DEFAULT_CLAUDE_BIN.to_string(),
+            cli_version: None,
+            debug_features: false,
+            inactivity_timeout: DEFAULT_INACTIVITY_TIMEOUT,
+            max_request_duration: None,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            forward_headers: Vec::new(),
+            max_system_messages: DEFAULT_MAX_SYSTEM_MESSAGES,
+            max_prompt_turns: None,
+            max_prompt_tokens: None,
+            sse_no_buffering: true,
+            sse_content_type: None,
+            progress_log_interval: progress_interval(DEFAULT_PROGRESS_LOG_SECS),
+            allow_extra_args: false,
+            permissions: CliPermissions::default(),
+            system_prompt_mode: SystemPromptMode::default(),
+            allow_debug_header: false,
+            omit_usage: false,
+            api_keys: Vec::new(),
+            admin_key: None,
+            openai_stream_error_shape: StreamErrorShape::default(),
+            stream_trailing_whitespace: TrailingWhitespace::default(),
+            output_encoding: OutputEncoding::default(),
+            stream_coalesce: None,
+            request_id_header: HeaderName::from_static(DEFAULT_REQUEST_ID_HEADER),
+            cors: CorsPolicy::default(),
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            concurrency_wait: Duration::from_millis(DEFAULT_CONCURRENCY_WAIT_MS),
+            session_wait: Duration::from_secs(DEFAULT_SESSION_WAIT_SECS),
+            retry_on_crash: false,
+            model_fallbacks: Vec::new(),
+            model_prices: Vec::new(),
+            warm_pool: 0,
+            max_usage_models: DEFAULT_MAX_USAGE_MODELS,
+            max_output_bytes: None,
+            max_session_id_len: DEFAULT_MAX_SESSION_ID_LEN,
+            session_salt: None,
+            service_tier: None,
+            echo_exact_model: false,
+            model_cwds: Vec::new(),
+            allowed_cwd_roots: Vec::new(),
+            models: default_models(),
+        }
+    }
+}
+
+/// Convert `--progress-log-secs` into an interval, where 0 disables the log.
+pub fn progress_interval(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Maps an inbound request header to an environment variable for the CLI,
+/// e.g. `X-Claude-Profile=CLAUDE_PROFILE`.
+#[derive(Debug, Clone)]
+pub struct HeaderEnvMapping {
+    pub header: HeaderName,
+    pub env: String,
+}
+
+/// Parse a `HEADER=ENV_VAR` mapping from the command line.
+pub fn parse_header_env(s: &str) -> Result<HeaderEnvMapping, String> {
+    let (header, env) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected HEADER=ENV_VAR, got '{s}'"))?;
+
+    let header = HeaderName::from_bytes(header.trim().as_bytes())
+        .map_err(|_| format!("invalid header name '{}'", header.trim()))?;
+
+    let env = env.trim();
+    let valid_env = env
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_uppercase() || c == '_')
+        && env
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+    if !valid_env {
+        return Err(format!(
+            "invalid environment variable name '{env}' (use A-Z, 0-9 and _)"
+        ));
+    }
+
+    Ok(HeaderEnvMapping {
+        header,
+        env: env.to_string(),
+    })
+}
+
+/// Collect the environment variables to set for a request from its headers.
+/// Values have control characters stripped and are capped in length.
+pub fn forwarded_env(mappings: &[HeaderEnvMapping], headers: &HeaderMap) -> Vec<(String, String)> {
+    mappings
+        .iter()
+        .filter_map(|m| {
+            let raw = headers.get(&m.header)?;
+            let value: String = String::from_utf8_lossy(raw.as_bytes())
+                .chars()
+                .filter(|c| !c.is_control())
+                .take(MAX_FORWARDED_VALUE_LEN)
+                .collect();
+            let value = value.trim();
+            if value.is_empty() {
+                None
+            } else {
+                Some((m.env.clone(), value.to_string()))
+            }
+        })
+        .collect()
+}
+
+/// Parse a header name given on the command line.
+pub fn parse_header_name(s: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(s.trim().as_bytes()).map_err(|_| format!("invalid header name '{s}'"))
+}
+
+/// Parse a `--session-salt`: a plain token (ASCII letters, digits, `-` and
+/// `_`) so the namespaced session id stays a valid `--session-id`.
+pub fn parse_session_salt(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!(
+            "invalid salt '{s}': use ASCII letters, digits, '-' and '_'"
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Namespace a client session id with the configured salt, so proxies
+/// sharing a CLI home don't reuse each other's CLI session ids. The result is a
+/// name-based (v5) UUID of the id within a namespace derived from the salt:
+/// stable across restarts, and a valid `--session-id`.
+pub fn namespace_session_id(salt: Option<&str>, session_id: Option<String>) -> Option<String> {
+    match salt {
+        Some(salt) => {
+            let namespace = Uuid::new_v5(&Uuid::NAMESPACE_OID, salt.as_bytes());
+            session_id.map(|id| Uuid::new_v5(&namespace, id.as_bytes()).to_string())
+        }
+        None => session_id,
+    }
+}
+
+/// Parse an `--api-keys-file` document: a JSON object mapping labels to keys.
+pub fn parse_api_keys(json: &str) -> Result<Vec<ApiKey>, String> {
+    let map: std::collections::BTreeMap<String, String> = serde_json::from_str(json)
+        .map_err(|e| format!("expected a JSON object of {{\"label\": \"key\"}}: {e}"))?;
+    if map.is_empty() {
+        return Err("no keys defined".to_string());
+    }
+    map.into_iter()
+        .map(|(label, key)| {
+            if key.is_empty() {
+                Err(format!("key for '{label}' is empty"))
+            } else {
+                Ok(ApiKey { label, key })
+            }
+        })
+        .collect()
+}
+
+/// Read and parse an `--api-keys-file`.
+pub fn load_api_keys(path: &std::path::Path) -> Result<Vec<ApiKey>, String> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+    parse_api_keys(&json).map_err(|e| format!("invalid {}: {e}", path.display()))
+}
+
+fn unix_epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Models listed when no `--models-file` is given.
+pub fn default_models() -> Vec<ModelInfo> {
+    let model = |id: &str, alias: &str, context_window, max_tokens| ModelInfo {
+        id: id.to_string(),
+        object: "model".to_string(),
+        owned_by: "anthropic".to_string(),
+        created: unix_epoch_secs(),
+        context_window,
+        max_tokens,
+        alias: alias.to_string(),
+    };
+    vec![
+        model("claude-opus-4", "opus", 1_000_000, 128_000),
+        model("claude-sonnet-4", "sonnet", 200_000, 64_000),
+        model("claude-haiku-4", "haiku", 200_000, 64_000),
+    ]
+}
+
+/// Parse the JSON array of a `--models-file`. Entries without `created` get
+/// the current time.
+pub fn parse_models(json: &str) -> Result<Vec<ModelInfo>, String> {
+    let mut models: Vec<ModelInfo> = serde_json::from_str(json)
+        .map_err(|e| format!("expected a JSON array of models: {e}"))?;
+    if models.is_empty() {
+        return Err("no models defined".to_string());
+    }
+    let now = unix_epoch_secs();
+    for (i, model) in models.iter_mut().enumerate() {
+        if model.id.is_empty() {
+            return Err(format!("model {i} has an empty id"));
+        }
+        if !MODEL_ALIASES.contains(&model.alias.as_str()) {
+            return Err(format!(
+                "model '{}' has unknown alias '{}' (use one of {})",
+                model.id,
+                model.alias,
+                MODEL_ALIASES.join(", ")
+            ));
+        }
+        if model.created == 0 {
+            model.created = now;
+        }
+    }
+    for (i, model) in models.iter().enumerate() {
+        if models[..i].iter().any(|prev| prev.id == model.id) {
+            return Err(format!("model '{}' is listed twice", model.id));
+        }
+    }
+    Ok(models)
+}
+
+/// Read and parse a `--models-file`.
+pub fn load_models(path: &std::path::Path) -> Result<Vec<ModelInfo>, String> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+    parse_models(&json).map_err(|e| format!("invalid {}: {e}", path.display()))
+}
+
+/// CLI model aliases accepted in `--cwd-per-model` and `--models-file`.
+const MODEL_ALIASES: &[&str] = &["opus", "sonnet", "haiku"];
+
+/// Working directory used instead of `--cwd` for one model alias,
+/// e.g. `haiku=~/docs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelCwd {
+    pub model: String,
+    pub cwd: String,
+}
+
+/// Parse a `MODEL=DIR` mapping from the command line.
+pub fn parse_model_cwd(s: &str) -> Result<ModelCwd, String> {
+    let (model, cwd) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected MODEL=DIR, got '{s}'"))?;
+    let model = model.trim().to_ascii_lowercase();
+    if !MODEL_ALIASES.contains(&model.as_str()) {
+        return Err(format!(
+            "unknown model '{model}' (use one of {})",
+            MODEL_ALIASES.join(", ")
+        ));
+    }
+    let cwd = cwd.trim();
+    if cwd.is_empty() {
+        return Err(format!("missing directory for '{model}'"));
+    }
+    Ok(ModelCwd {
+        model,
+        cwd: cwd.to_string(),
+    })
+}
+
+/// Resolve a working directory to an absolute path, failing if it doesn't
+/// exist, isn't a directory or can't be listed.
+pub fn resolve_cwd(cwd: &str) -> Result<String, String> {
+    let path = std::fs::canonicalize(cwd).map_err(|e| e.to_string())?;
+    if !path.is_dir() {
+        return Err("not a directory".to_string());
+    }
+    std::fs::read_dir(&path).map_err(|e| format!("not readable: {e}"))?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Resolve each mapping's directory like `resolve_cwd`.
+pub fn resolve_model_cwds(mappings: Vec<ModelCwd>) -> Result<Vec<ModelCwd>, String> {
+    mappings
+        .into_iter()
+        .map(|m| {
+            let cwd = resolve_cwd(&m.cwd).map_err(|e| format!("{}={}: {e}", m.model, m.cwd))?;
+            Ok(ModelCwd {
+                model: m.model,
+                cwd,
+            })
+        })
+        .collect()
+}
+
+/// Resolve a requested working directory like `resolve_cwd`, failing unless
+/// it is one of `roots` or lies beneath one.
+pub fn resolve_allowed_cwd(cwd: &str, roots: &[String]) -> Result<String, String> {
+    // Canonicalized first, so `..` and symlinks can't escape a root
+    let resolved = resolve_cwd(cwd)?;
+    let path = std::path::Path::new(&resolved);
+    if !roots.iter().any(|root| path.starts_with(root)) {
+        return Err("outside the allowed cwd roots".to_string());
+    }
+    Ok(resolved)
+}
+
+/// The working directory for a CLI model alias: the last matching
+/// `--cwd-per-model` entry, otherwise `default`.
+pub fn cwd_for_model<'a>(mappings: &'a [ModelCwd], model: &str, default: &'a str) -> &'a str {
+    mappings
+        .iter()
+        .rev()
+        .find(|m| m.model == model)
+        .map_or(default, |m| m.cwd.as_str())
+}
+
+/// Model alias a run switches to when the CLI reports its own model
+/// unavailable, e.g. `opus=sonnet`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelFallback {
+    pub model: String,
+    pub fallback: String,
+}
+
+/// Parse a `MODEL=FALLBACK` mapping from the command line.
+pub fn parse_model_fallback(s: &str) -> Result<ModelFallback, String> {
+    let (model, fallback) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected MODEL=FALLBACK, got '{s}'"))?;
+    let model = model.trim().to_ascii_lowercase();
+    let fallback = fallback.trim().to_ascii_lowercase();
+    for alias in [&model, &fallback] {
+        if !MODEL_ALIASES.contains(&alias.as_str()) {
+            return Err(format!(
+                "unknown model '{alias}' (use one of {})",
+                MODEL_ALIASES.join(", ")
+            ));
+        }
+    }
+    if model == fallback {
+        return Err(format!("'{model}' can't fall back to itself"));
+    }
+    Ok(ModelFallback { model, fallback })
+}
+
+/// The model to retry with when `model` is unavailable: the last matching
+/// `--model-fallback` entry, if any.
+pub fn fallback_for_model<'a>(mappings: &'a [ModelFallback], model: &str) -> Option<&'a str> {
+    mappings
+        .iter()
+        .rev()
+        .find(|m| m.model == model)
+        .map(|m| m.fallback.as_str())
+}
+
+/// What a model alias costs, in USD per million input and output tokens,
+/// e.g. `opus=15,75`. Used only to estimate the cost of each request in its
+/// completion log line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub model: &'static str,
+    pub input: f64,
+    pub output: f64,
+}
+
+/// Parse a `MODEL=INPUT,OUTPUT` price from the command line.
+pub fn parse_model_price(s: &str) -> Result<ModelPrice, String> {
+    let (model, prices) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected MODEL=INPUT,OUTPUT, got '{s}'"))?;
+    let model = model.trim().to_ascii_lowercase();
+    let Some(model) = MODEL_ALIASES.iter().find(|alias| **alias == model) else {
+        return Err(format!(
+            "unknown model '{model}' (use one of {})",
+            MODEL_ALIASES.join(", ")
+        ));
+    };
+    let (input, output) = prices
+        .split_once(',')
+        .ok_or_else(|| format!("expected MODEL=INPUT,OUTPUT, got '{s}'"))?;
+    let price = |p: &str| match p.trim().parse::<f64>() {
+        Ok(price) if price.is_finite() && price >= 0.0 => Ok(price),
+        _ => Err(format!("invalid price '{}' in '{s}'", p.trim())),
+    };
+    Ok(ModelPrice {
+        model,
+        input: price(input)?,
+        output: price(output)?,
+    })
+}
+
+/// The price for a model as the CLI reports it (e.g. `claude-opus-4-1`):
+/// the last `--model-price` entry whose alias the name contains, if any.
+pub fn price_for_model(prices: &[ModelPrice], model: &str) -> Option<ModelPrice> {
+    prices.iter().rev().find(|p| model.contains(p.model)).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    // ── parse_header_name ─────────────────────────────────────
+
+    #[test]
+    fn parse_header_name_normalizes_case() {
+        assert_eq!(parse_header_name("X-Correlation-Id").unwrap(), "x-correlation-id");
+        assert!(parse_header_name("bad header").is_err());
+        assert!(parse_header_name("").is_err());
+    }
+
+    // ── session salt ──────────────────────────────────────────
+
+    #[test]
+    fn parse_session_salt_accepts_plain_tokens() {
+        assert_eq!(parse_session_salt(" team_a-1 ").unwrap(), "team_a-1");
+        assert!(parse_session_salt("").is_err());
+        assert!(parse_session_salt("a b").is_err());
+        assert!(parse_session_salt("../x").is_err());
+    }
+
+    #[test]
+    fn namespace_session_id_derives_uuid_per_salt() {
+        let id = || Some("user-42".to_string());
+        assert_eq!(namespace_session_id(None, id()).as_deref(), Some("user-42"));
+        let salted = namespace_session_id(Some("a"), id()).unwrap();
+        let uuid = Uuid::parse_str(&salted).unwrap();
+        assert_eq!(uuid.get_version_num(), 5);
+        assert_eq!(namespace_session_id(Some("a"), id()).unwrap(), salted);
+        assert_ne!(namespace_session_id(Some("b"), id()).unwrap(), salted);
+        assert_ne!(namespace_session_id(Some("a"), Some("user-43".to_string())).unwrap(), salted);
+        assert_eq!(namespace_session_id(Some("a"), None), None);
+    }
+
+    // ── parse_api_keys ────────────────────────────────────────
+
+    #[test]
+    fn parse_api_keys_map() {
+        let keys = parse_api_keys(r#"{"laptop": "sk-2", "ci": "sk-1"}"#).unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                ApiKey {
+                    label: "ci".to_string(),
+                    key: "sk-1".to_string(),
+                },
+                ApiKey {
+                    label: "laptop".to_string(),
+                    key: "sk-2".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_api_keys_rejects_malformed() {
+        assert!(parse_api_keys("not json").is_err());
+        assert!(parse_api_keys(r#"["sk-1"]"#).is_err());
+        assert!(parse_api_keys(r#"{"ci": 1}"#).is_err());
+        assert!(parse_api_keys("{}").is_err());
+        assert!(parse_api_keys(r#"{"ci": ""}"#).is_err());
+    }
+
+    #[test]
+    fn load_api_keys_missing_file() {
+        let err = load_api_keys(std::path::Path::new("/nonexistent/keys.json")).unwrap_err();
+        assert!(err.contains("/nonexistent/keys.json"));
+    }
+
+    // ── parse_models ──────────────────────────────────────────
+
+    #[test]
+    fn parse_models_fills_defaults() {
+        let models = parse_models(
+            r#"[{"id": "claude-3-5-sonnet", "alias": "sonnet", "context_window": 200000,
+                 "max_tokens": 8192}]"#,
+        )
+        .unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].id, "claude-3-5-sonnet");
+        assert_eq!(models[0].alias, "sonnet");
+        assert_eq!(models[0].object, "model");
+        assert_eq!(models[0].owned_by, "anthropic");
+        assert!(models[0].created > 0);
+        assert_eq!(models[0].max_tokens, 8192);
+    }
+
+    #[test]
+    fn parse_models_keeps_explicit_fields() {
+        let models = parse_models(
+            r#"[{"id": "team-opus", "alias": "opus", "owned_by": "acme", "created": 42,
+                 "context_window": 1000, "max_tokens": 100}]"#,
+        )
+        .unwrap();
+        assert_eq!(models[0].owned_by, "acme");
+        assert_eq!(models[0].created, 42);
+    }
+
+    #[test]
+    fn parse_models_rejects_malformed() {
+        let entry = |id: &str, alias: &str| {
+            format!(r#"{{"id": "{id}", "alias": "{alias}", "context_window": 1, "max_tokens": 1}}"#)
+        };
+        assert!(parse_models("not json").is_err());
+        assert!(parse_models("[]").is_err());
+        assert!(parse_models(r#"[{"id": "x", "alias": "opus"}]"#).is_err());
+        assert!(parse_models(&format!("[{}]", entry("x", "gpt"))).is_err());
+        assert!(parse_models(&format!("[{}]", entry("", "opus"))).is_err());
+        let dup = parse_models(&format!("[{},{}]", entry("x", "opus"), entry("x", "haiku")));
+        assert!(dup.unwrap_err().contains("listed twice"));
+    }
+
+    #[test]
+    fn load_models_missing_file() {
+        let err = load_models(std::path::Path::new("/nonexistent/models.json")).unwrap_err();
+        assert!(err.contains("/nonexistent/models.json"));
+    }
+
+    #[test]
+    fn default_models_have_aliases() {
+        let aliases: Vec<_> = default_models().into_iter().map(|m| m.alias).collect();
+        assert_eq!(aliases, vec!["opus", "sonnet", "haiku"]);
+    }
+
+    // ── progress_interval ─────────────────────────────────────
+
+    #[test]
+    fn progress_interval_zero_disables() {
+        assert_eq!(progress_interval(0), None);
+    }
+
+    #[test]
+    fn progress_interval_default() {
+        assert_eq!(
+            Config::default().progress_log_interval,
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(progress_interval(5), Some(Duration::from_secs(5)));
+    }
+
+    // ── parse_header_env ──────────────────────────────────────
+
+    #[test]
+    fn parse_valid_mapping() {
+        let m = parse_header_env("X-Claude-Profile=CLAUDE_PROFILE").unwrap();
+        assert_eq!(m.header, "x-claude-profile");
+        assert_eq!(m.env, "CLAUDE_PROFILE");
+    }
+
+    #[test]
+    fn parse_rejects_missing_separator() {
+        assert!(parse_header_env("X-Claude-Profile").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_header() {
+        assert!(parse_header_env("X Bad Header=FOO").is_err());
+        assert!(parse_header_env("=FOO").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_env() {
+        assert!(parse_header_env("X-Foo=").is_err());
+        assert!(parse_header_env("X-Foo=1FOO").is_err());
+        assert!(parse_header_env("X-Foo=foo").is_err());
+        assert!(parse_header_env("X-Foo=FOO;rm").is_err());
+    }
+
+    // ── forwarded_env ─────────────────────────────────────────
+
+    #[test]
+    fn forwards_mapped_headers_only() {
+        let mappings = vec![parse_header_env("X-Claude-Profile=CLAUDE_PROFILE").unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert("x-claude-profile", HeaderValue::from_static("work"));
+        headers.insert("x-other", HeaderValue::from_static("ignored"));
+
+        let env = forwarded_env(&mappings, &headers);
+        assert_eq!(env, vec![("CLAUDE_PROFILE".to_string(), "work".to_string())]);
+    }
+
+    #[test]
+    fn missing_header_not_forwarded() {
+        let mappings = vec![parse_header_env("X-Claude-Profile=CLAUDE_PROFILE").unwrap()];
+        assert!(forwarded_env(&mappings, &HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn values_are_sanitized() {
+        let mappings = vec![parse_header_env("X-Claude-Profile=CLAUDE_PROFILE").unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-claude-profile",
+            HeaderValue::from_bytes(b"  a\tbc  ").unwrap(),
+        );
+        let env = forwarded_env(&mappings, &headers);
+        assert_eq!(env[0].1, "abc");
+
+        headers.insert(
+            "x-claude-profile",
+            HeaderValue::from_str(&"x".repeat(5000)).unwrap(),
+        );
+        let env = forwarded_env(&mappings, &headers);
+        assert_eq!(env[0].1.len(), MAX_FORWARDED_VALUE_LEN);
+    }
+
+    #[test]
+    fn forwarded_env_caps_multibyte_values_on_char_boundaries() {
+        let mappings = vec![parse_header_env("X-Claude-Cwd=CLAUDE_CWD").unwrap()];
+        let mut headers = HeaderMap::new();
+        let path = "/home/joaquín/项目".repeat(200);
+        headers.insert("x-claude-cwd", HeaderValue::from_bytes(path.as_bytes()).unwrap());
+
+        let env = forwarded_env(&mappings, &headers);
+        let value = &env[0].1;
+        assert_eq!(value.chars().count(), MAX_FORWARDED_VALUE_LEN);
+        assert!(path.starts_with(value.as_str()));
+    }
+
+    // ── cwd per model ─────────────────────────────────────────
+
+    fn model_cwd(model: &str, cwd: &str) -> ModelCwd {
+        ModelCwd {
+            model: model.to_string(),
+            cwd: cwd.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_model_cwd_valid() {
+        assert_eq!(parse_model_cwd("opus=/srv/code").unwrap(), model_cwd("opus", "/srv/code"));
+        assert_eq!(parse_model_cwd(" Haiku = docs ").unwrap(), model_cwd("haiku", "docs"));
+    }
+
+    #[test]
+    fn parse_model_cwd_invalid() {
+        assert!(parse_model_cwd("opus").is_err());
+        assert!(parse_model_cwd("gpt-4=/srv").is_err());
+        assert!(parse_model_cwd("sonnet=").is_err());
+    }
+
+    #[test]
+    fn resolve_model_cwds_checks_directories() {
+        let dir = std::env::temp_dir();
+        let resolved = resolve_model_cwds(vec![model_cwd("opus", dir.to_str().unwrap())]).unwrap();
+        assert_eq!(resolved[0].cwd, std::fs::canonicalize(&dir).unwrap().to_string_lossy());
+
+        let missing = model_cwd("opus", "/nonexistent/claude-max-api-test");
+        assert!(resolve_model_cwds(vec![missing]).unwrap_err().contains("opus="));
+
+        let file = dir.join(format!("model-cwd-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&file, "").unwrap();
+        let not_dir = model_cwd("haiku", file.to_str().unwrap());
+        assert!(resolve_model_cwds(vec![not_dir]).unwrap_err().contains("not a directory"));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn resolve_cwd_checks_the_directory() {
+        let dir = std::env::temp_dir();
+        let resolved = resolve_cwd(dir.to_str().unwrap()).unwrap();
+        assert_eq!(resolved, std::fs::canonicalize(&dir).unwrap().to_string_lossy());
+        assert!(std::path::Path::new(&resolve_cwd(".").unwrap()).is_absolute());
+
+        assert!(resolve_cwd("/nonexistent/claude-max-api-test").is_err());
+        let file = dir.join(format!("cwd-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&file, "").unwrap();
+        assert_eq!(resolve_cwd(file.to_str().unwrap()).unwrap_err(), "not a directory");
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_cwd_rejects_unreadable_directories() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("cwd-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Root can list it anyway
+        let listable = std::fs::read_dir(&dir).is_ok();
+        let result = resolve_cwd(dir.to_str().unwrap());
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+        if !listable {
+            assert!(result.unwrap_err().starts_with("not readable"));
+        }
+    }
+
+    #[test]
+    fn resolve_allowed_cwd_stays_within_roots() {
+        let root = std::env::temp_dir().join(format!("cwd-root-{}", uuid::Uuid::new_v4()));
+        let project = root.join("project");
+        std::fs::create_dir_all(&project).unwrap();
+        let roots = vec![resolve_cwd(root.to_str().unwrap()).unwrap()];
+
+        let resolved = resolve_allowed_cwd(project.to_str().unwrap(), &roots).unwrap();
+        assert_eq!(resolved, resolve_cwd(project.to_str().unwrap()).unwrap());
+        assert_eq!(resolve_allowed_cwd(root.to_str().unwrap(), &roots).unwrap(), roots[0]);
+
+        let escape = project.join("..").join("..");
+        let err = resolve_allowed_cwd(escape.to_str().unwrap(), &roots).unwrap_err();
+        assert_eq!(err, "outside the allowed cwd roots");
+        // A sibling sharing the root's name as a prefix is outside it
+        let sibling = format!("{}-other", root.display());
+        std::fs::create_dir_all(&sibling).unwrap();
+        assert!(resolve_allowed_cwd(&sibling, &roots).is_err());
+        assert!(resolve_allowed_cwd(project.to_str().unwrap(), &[]).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&sibling).unwrap();
+    }
+
+    #[test]
+    fn cwd_for_model_selects_mapping() {
+        let mappings = vec![model_cwd("opus", "/code"), model_cwd("haiku", "/docs")];
+        assert_eq!(cwd_for_model(&mappings, "opus", "/default"), "/code");
+        assert_eq!(cwd_for_model(&mappings, "haiku", "/default"), "/docs");
+        assert_eq!(cwd_for_model(&mappings, "sonnet", "/default"), "/default");
+        assert_eq!(cwd_for_model(&[], "opus", "/default"), "/default");
+    }
+
+    #[test]
+    fn cwd_for_model_last_mapping_wins() {
+        let mappings = vec![model_cwd("opus", "/old"), model_cwd("opus", "/new")];
+        assert_eq!(cwd_for_model(&mappings, "opus", "/default"), "/new");
+    }
+
+    // ── CORS ──────────────────────────────────────────────────
+
+    #[test]
+    fn parse_cors_origin_valid() {
+        let origin = "https://app.example.com";
+        assert_eq!(parse_cors_origin(origin).unwrap(), origin);
+        assert_eq!(parse_cors_origin("http://localhost:3000/").unwrap(), "http://localhost:3000");
+    }
+
+    #[test]
+    fn parse_cors_origin_invalid() {
+        assert!(parse_cors_origin("app.example.com").is_err());
+        assert!(parse_cors_origin("https://").is_err());
+        assert!(parse_cors_origin("https://app.example.com/path").is_err());
+        assert!(parse_cors_origin("ftp://app.example.com").is_err());
+    }
+
+    #[test]
+    fn cors_policy_permissive_only_on_loopback() {
+        let origin = HeaderValue::from_static("https://app.example.com");
+        assert_eq!(CorsPolicy::resolve(vec![], false, true), CorsPolicy::Permissive);
+        assert_eq!(CorsPolicy::resolve(vec![], false, false), CorsPolicy::Disabled);
+        assert_eq!(CorsPolicy::resolve(vec![], true, true), CorsPolicy::Disabled);
+        assert_eq!(
+            CorsPolicy::resolve(vec![origin.clone()], false, false),
+            CorsPolicy::Origins(vec![origin])
+        );
+    }
+
+    // ── SSE content type ──────────────────────────────────────
+
+    #[test]
+    fn parse_sse_content_type_accepts_parameters() {
+        let value = "text/event-stream; charset=utf-8";
+        assert_eq!(parse_sse_content_type(value).unwrap(), value);
+        assert_eq!(parse_sse_content_type(" Text/Event-Stream ").unwrap(), "Text/Event-Stream");
+        assert!(parse_sse_content_type("application/json").is_err());
+        assert!(parse_sse_content_type("text/event-stream; x=\x01y").is_err());
+    }
+
+    // ── CLI permissions ───────────────────────────────────────
+
+    #[test]
+    fn parse_tool_rule_accepts_names_and_rules() {
+        assert_eq!(parse_tool_rule(" Edit ").unwrap(), "Edit");
+        assert_eq!(parse_tool_rule("Bash(git diff:*)").unwrap(), "Bash(git diff:*)");
+        assert!(parse_tool_rule("").is_err());
+        assert!(parse_tool_rule("--dangerously-skip-permissions").is_err());
+        assert!(parse_tool_rule("Edit,Bash").is_err());
+    }
+
+    #[test]
+    fn permission_modes_parse_by_cli_name() {
+        use clap::ValueEnum;
+        for mode in PermissionMode::value_variants() {
+            assert_eq!(PermissionMode::from_str(mode.as_cli(), false).unwrap(), *mode);
+        }
+        assert!(PermissionMode::from_str("bypass", false).is_err());
+    }
+
+    // ── model fallback ────────────────────────────────────────
+
+    #[test]
+    fn parse_model_fallback_valid() {
+        let expected = ModelFallback {
+            model: "opus".to_string(),
+            fallback: "sonnet".to_string(),
+        };
+        assert_eq!(parse_model_fallback("opus=sonnet").unwrap(), expected);
+        assert_eq!(parse_model_fallback(" Opus = SONNET ").unwrap(), expected);
+    }
+
+    #[test]
+    fn parse_model_fallback_invalid() {
+        assert!(parse_model_fallback("opus").is_err());
+        assert!(parse_model_fallback("opus=gpt-4").is_err());
+        assert!(parse_model_fallback("=sonnet").is_err());
+        assert!(parse_model_fallback("opus=opus").unwrap_err().contains("itself"));
+    }
+
+    #[test]
+    fn parse_model_price_valid() {
+        let price = parse_model_price(" Opus = 15, 75 ").unwrap();
+        assert_eq!(
+            price,
+            ModelPrice {
+                model: "opus",
+                input: 15.0,
+                output: 75.0
+            }
+        );
+        assert_eq!(parse_model_price("haiku=0.8,4").unwrap().input, 0.8);
+    }
+
+    #[test]
+    fn parse_model_price_invalid() {
+        assert!(parse_model_price("opus").is_err());
+        assert!(parse_model_price("opus=15").is_err());
+        assert!(parse_model_price("gpt-4=1,2").is_err());
+        assert!(parse_model_price("opus=-1,2").is_err());
+        assert!(parse_model_price("opus=1,free").is_err());
+    }
+
+    #[test]
+    fn price_for_model_matches_cli_model_names() {
+        let prices = [
+            parse_model_price("opus=10,50").unwrap(),
+            parse_model_price("opus=15,75").unwrap(),
+        ];
+        assert_eq!(price_for_model(&prices, "claude-opus-4-1-20250805").unwrap().input, 15.0);
+        assert_eq!(price_for_model(&prices, "claude-sonnet-4"), None);
+    }
+
+    #[test]
+    fn fallback_for_model_uses_last_match() {
+        let mappings = [
+            parse_model_fallback("opus=haiku").unwrap(),
+            parse_model_fallback("opus=sonnet").unwrap(),
+        ];
+        assert_eq!(fallback_for_model(&mappings, "opus"), Some("sonnet"));
+        assert_eq!(fallback_for_model(&mappings, "sonnet"), None);
+    }
+}
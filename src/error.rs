@@ -1,4 +1,4 @@
-use axum::http::StatusCode;
+use axum::http::{HeaderValue, StatusCode, header};
 use axum::response::{IntoResponse, Response};
 use serde_json::json;
 
@@ -8,16 +8,42 @@ pub enum AppError {
     #[error("Invalid request: {0}")]
     BadRequest(String),
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
     #[error("Not found: {0}")]
     NotFound(String),
 
+    #[error("Model not found: {0}")]
+    ModelNotFound(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("Internal error: {0}")]
     Internal(String),
 
     #[error("Subprocess error: {0}")]
     Subprocess(String),
+
+    /// A CLI failure with a meaningful upstream status (auth, rate limit, quota).
+    #[error("Upstream error: {message}")]
+    Upstream {
+        status: StatusCode,
+        error_type: &'static str,
+        message: String,
+    },
+
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+
+    #[error("Not implemented: {0}")]
+    NotImplemented(String),
 }
 
+/// `Retry-After` sent with 503 and 429 responses, in seconds.
+const RETRY_AFTER_SECS: u64 = 1;
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, error_type, code, message) = match &self {
@@ -27,12 +53,30 @@ impl IntoResponse for AppError {
                 Some("invalid_messages"),
                 msg.clone(),
             ),
+            AppError::Unauthorized(msg) => (
+                StatusCode::UNAUTHORIZED,
+                "invalid_request_error",
+                Some("authentication_error"),
+                msg.clone(),
+            ),
             AppError::NotFound(msg) => (
                 StatusCode::NOT_FOUND,
                 "invalid_request_error",
                 Some("not_found"),
                 msg.clone(),
             ),
+            AppError::ModelNotFound(msg) => (
+                StatusCode::NOT_FOUND,
+                "invalid_request_error",
+                Some("model_not_found"),
+                msg.clone(),
+            ),
+            AppError::Conflict(msg) => (
+                StatusCode::CONFLICT,
+                "invalid_request_error",
+                Some("conflict"),
+                msg.clone(),
+            ),
             AppError::Internal(msg) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "server_error",
@@ -45,6 +89,23 @@ impl IntoResponse for AppError {
                 None,
                 msg.clone(),
             ),
+            AppError::Upstream {
+                status,
+                error_type,
+                message,
+            } => (*status, *error_type, None, message.clone()),
+            AppError::ServiceUnavailable(msg) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_error",
+                Some("overloaded"),
+                msg.clone(),
+            ),
+            AppError::NotImplemented(msg) => (
+                StatusCode::NOT_IMPLEMENTED,
+                "invalid_request_error",
+                Some("unsupported_endpoint"),
+                msg.clone(),
+            ),
         };
 
         let body = json!({
@@ -55,7 +116,13 @@ impl IntoResponse for AppError {
             }
         });
 
-        (status, axum::Json(body)).into_response()
+        let mut response = (status, axum::Json(body)).into_response();
+        if status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+        }
+        response
     }
 }
 
@@ -84,6 +151,17 @@ mod tests {
         assert_eq!(json["error"]["code"], "invalid_messages");
     }
 
+    #[tokio::test]
+    async fn unauthorized_returns_401() {
+        let err = AppError::Unauthorized("bad key".to_string());
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let json = body_to_json(response).await;
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["code"], "authentication_error");
+    }
+
     #[tokio::test]
     async fn not_found_returns_404() {
         let err = AppError::NotFound("endpoint not found".to_string());
@@ -95,6 +173,28 @@ mod tests {
         assert_eq!(json["error"]["code"], "not_found");
     }
 
+    #[tokio::test]
+    async fn conflict_returns_409() {
+        let err = AppError::Conflict("request id in use".to_string());
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let json = body_to_json(response).await;
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["code"], "conflict");
+    }
+
+    #[tokio::test]
+    async fn model_not_found_returns_404() {
+        let err = AppError::ModelNotFound("no such model".to_string());
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let json = body_to_json(response).await;
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["code"], "model_not_found");
+    }
+
     #[tokio::test]
     async fn internal_returns_500() {
         let err = AppError::Internal("something broke".to_string());
@@ -117,6 +217,46 @@ mod tests {
         assert_eq!(json["error"]["type"], "server_error");
     }
 
+    #[tokio::test]
+    async fn upstream_keeps_status_and_type() {
+        let err = AppError::Upstream {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            error_type: "rate_limit_error",
+            message: "slow down".to_string(),
+        };
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "1");
+
+        let json = body_to_json(response).await;
+        assert_eq!(json["error"]["type"], "rate_limit_error");
+        assert_eq!(json["error"]["message"], "slow down");
+        assert!(json["error"]["code"].is_null());
+    }
+
+    #[tokio::test]
+    async fn service_unavailable_returns_503_with_retry_after() {
+        let err = AppError::ServiceUnavailable("busy".to_string());
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "1");
+
+        let json = body_to_json(response).await;
+        assert_eq!(json["error"]["type"], "server_error");
+        assert_eq!(json["error"]["message"], "busy");
+    }
+
+    #[tokio::test]
+    async fn not_implemented_returns_501() {
+        let err = AppError::NotImplemented("no embeddings".to_string());
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+
+        let json = body_to_json(response).await;
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["code"], "unsupported_endpoint");
+    }
+
     #[test]
     fn display_trait() {
         assert_eq!(
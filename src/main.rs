@@ -1,15 +1,31 @@
 mod adapter;
+mod auth;
+mod cancel;
+mod config;
 mod error;
+mod metrics;
+mod pool;
 mod routes;
 mod server;
 mod session;
+mod sse;
 mod subprocess;
+mod telemetry;
 mod types;
 
 use clap::Parser;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
 use tokio::net::TcpListener;
-use tracing::{error, info};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// How long the server waits, once it has stopped, for the subprocesses of
+/// requests cut off at the end of the shutdown grace period to be killed.
+const SHUTDOWN_KILL_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
 
 #[derive(Parser)]
 #[command(name = "claude-max-api")]
@@ -19,60 +35,522 @@ struct Args {
     #[arg(default_value = "8080")]
     port: u16,
 
+    /// Address to bind, e.g. 0.0.0.0 to accept connections from other machines
+    #[arg(long = "host", default_value = "127.0.0.1")]
+    host: IpAddr,
+
+    /// Browser origin allowed to call the API (repeatable), e.g. https://app.example.com.
+    /// Without it, any origin is allowed on loopback and none elsewhere
+    #[arg(long = "cors-origin", value_name = "ORIGIN", value_parser = config::parse_cors_origin)]
+    cors_origin: Vec<axum::http::HeaderValue>,
+
+    /// Send no CORS headers at all, so browsers can't call the API cross-origin
+    #[arg(long = "cors-disabled", conflicts_with = "cors_origin")]
+    cors_disabled: bool,
+
+    /// The claude binary to run: a name looked up on PATH, or a path
+    #[arg(long = "claude-bin", value_name = "PATH", default_value = config::DEFAULT_CLAUDE_BIN)]
+    claude_bin: String,
+
+    /// Check for the claude binary this many more times before giving up at startup
+    #[arg(long = "startup-retries", value_name = "N", default_value = "0")]
+    startup_retries: u32,
+
+    /// Seconds between startup checks for the claude binary
+    #[arg(long = "startup-retry-delay", value_name = "SECS", default_value = "2")]
+    startup_retry_delay: u64,
+
     /// Working directory for the Claude CLI subprocess
     #[arg(long = "cwd", default_value = ".")]
     cwd: String,
+
+    /// Working directory for one model alias, overriding --cwd (repeatable),
+    /// e.g. haiku=~/docs
+    #[arg(long = "cwd-per-model", value_name = "MODEL=DIR", value_parser = config::parse_model_cwd)]
+    cwd_per_model: Vec<config::ModelCwd>,
+
+    /// Directory a request may choose its working directory under with the
+    /// X-Claude-Cwd header (repeatable); without one the header is rejected
+    #[arg(long = "allowed-cwd-root", value_name = "DIR")]
+    allowed_cwd_roots: Vec<String>,
+
+    /// Attach diagnostic x-debug-* headers to responses
+    #[arg(long = "debug-features")]
+    debug_features: bool,
+
+    /// Forward a request header to the subprocess environment (repeatable),
+    /// e.g. X-Claude-Profile=CLAUDE_PROFILE
+    #[arg(
+        long = "forward-header",
+        value_name = "HEADER=ENV",
+        value_parser = config::parse_header_env
+    )]
+    forward_headers: Vec<config::HeaderEnvMapping>,
+
+    /// Kill a CLI subprocess after this many seconds without output
+    #[arg(
+        long = "inactivity-secs",
+        default_value_t = config::DEFAULT_INACTIVITY_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    inactivity_secs: u64,
+
+    /// Kill a CLI subprocess this many seconds after it started, even while it is
+    /// still producing output (unlimited by default)
+    #[arg(long = "max-request-secs", value_parser = clap::value_parser!(u64).range(1..))]
+    max_request_secs: Option<u64>,
+
+    /// On SIGINT/SIGTERM, wait this many seconds for in-flight requests to finish
+    /// before killing their subprocesses
+    #[arg(
+        long = "shutdown-grace-secs",
+        default_value_t = config::DEFAULT_SHUTDOWN_GRACE.as_secs()
+    )]
+    shutdown_grace_secs: u64,
+
+    /// Reject requests with more system messages (or system blocks) than this
+    #[arg(long = "max-system-messages", default_value_t = config::DEFAULT_MAX_SYSTEM_MESSAGES)]
+    max_system_messages: usize,
+
+    /// Keep only the newest N non-system turns in the prompt (unlimited by default)
+    #[arg(long = "max-prompt-turns")]
+    max_prompt_turns: Option<usize>,
+
+    /// Drop the oldest turns until the prompt is under N estimated tokens (chars / 4)
+    #[arg(long = "max-prompt-tokens")]
+    max_prompt_tokens: Option<usize>,
+
+    /// Send `X-Accel-Buffering: no` on streaming responses so reverse proxies flush SSE
+    #[arg(long = "sse-no-buffering", default_value_t = true, action = clap::ArgAction::Set)]
+    sse_no_buffering: bool,
+
+    /// Content-Type of streaming responses, e.g. "text/event-stream; charset=utf-8"
+    #[arg(long = "sse-content-type", value_parser = config::parse_sse_content_type)]
+    sse_content_type: Option<axum::http::HeaderValue>,
+
+    /// Seconds between "Still running" logs for long requests (0 disables)
+    #[arg(long = "progress-log-secs", default_value_t = config::DEFAULT_PROGRESS_LOG_SECS)]
+    progress_log_secs: u64,
+
+    /// Accept allow-listed CLI flags per request via the X-Claude-Extra-Args header
+    #[arg(long = "allow-extra-args")]
+    allow_extra_args: bool,
+
+    /// Permission mode of every CLI run: default, acceptEdits, bypassPermissions or plan
+    #[arg(long = "permission-mode", value_enum, default_value_t = config::PermissionMode::Default)]
+    permission_mode: config::PermissionMode,
+
+    /// Tool the CLI may use without asking, e.g. Edit or "Bash(git diff:*)" (repeatable)
+    #[arg(long = "allowed-tools", value_name = "TOOL", value_parser = config::parse_tool_rule)]
+    allowed_tools: Vec<String>,
+
+    /// Tool the CLI may not use at all, e.g. Bash or WebFetch (repeatable)
+    #[arg(long = "disallowed-tools", value_name = "TOOL", value_parser = config::parse_tool_rule)]
+    disallowed_tools: Vec<String>,
+
+    /// Where a request's system prompt goes: flag (--append-system-prompt) or inline
+    /// (<system> tags in the prompt)
+    #[arg(
+        long = "system-prompt-mode",
+        value_enum,
+        default_value_t = config::SystemPromptMode::Flag
+    )]
+    system_prompt_mode: config::SystemPromptMode,
+
+    /// Log requests sent with `X-Debug: true` at trace level, prompt and events included
+    #[arg(long = "allow-debug-header")]
+    allow_debug_header: bool,
+
+    /// Leave the `usage` object out of non-streaming chat completion responses
+    #[arg(long = "omit-usage")]
+    omit_usage: bool,
+
+    /// Require `Authorization: Bearer <key>` on /v1/* routes
+    #[arg(long = "api-key", env = "CLAUDE_MAX_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Separate bearer token for /v1/admin/* routes; without one they aren't served
+    #[arg(long = "admin-key", env = "CLAUDE_MAX_ADMIN_KEY", hide_env_values = true)]
+    admin_key: Option<String>,
+
+    /// JSON file of labelled keys (`{"label": "key"}`) accepted like --api-key
+    #[arg(long = "api-keys-file", value_name = "PATH")]
+    api_keys_file: Option<std::path::PathBuf>,
+
+    /// JSON array of models to advertise and their CLI aliases, replacing the built-in list
+    #[arg(long = "models-file", value_name = "PATH")]
+    models_file: Option<std::path::PathBuf>,
+
+    /// JSON shape of error events on OpenAI streams, for gateways normalizing both protocols
+    #[arg(
+        long = "openai-stream-error-shape",
+        value_enum,
+        default_value_t = config::StreamErrorShape::Openai
+    )]
+    openai_stream_error_shape: config::StreamErrorShape,
+
+    /// Trailing whitespace in streamed deltas: preserve, or trim for clients that double-render it
+    #[arg(
+        long = "stream-trailing-whitespace",
+        value_enum,
+        default_value_t = config::TrailingWhitespace::Preserve
+    )]
+    stream_trailing_whitespace: config::TrailingWhitespace,
+
+    /// Decoding of the CLI's output: utf8 (lossy fallback with a warning), lossy, or latin1
+    #[arg(long = "output-encoding", value_enum, default_value_t = config::OutputEncoding::Utf8)]
+    output_encoding: config::OutputEncoding,
+
+    /// Merge streamed text deltas arriving within this many milliseconds (0 disables)
+    #[arg(long = "stream-coalesce-ms", default_value_t = 0)]
+    stream_coalesce_ms: u64,
+
+    /// Header used to accept an inbound request id and to echo it on responses
+    #[arg(
+        long = "request-id-header",
+        value_name = "NAME",
+        default_value = config::DEFAULT_REQUEST_ID_HEADER,
+        value_parser = config::parse_header_name
+    )]
+    request_id_header: axum::http::HeaderName,
+
+    /// Maximum number of claude subprocesses running at once
+    #[arg(
+        long = "max-concurrency",
+        default_value_t = config::DEFAULT_MAX_CONCURRENCY,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_concurrency: usize,
+
+    /// Milliseconds a request waits for a free subprocess slot before a 503
+    #[arg(long = "concurrency-wait-ms", default_value_t = config::DEFAULT_CONCURRENCY_WAIT_MS)]
+    concurrency_wait_ms: u64,
+
+    /// Seconds a request waits for an earlier run of its session to finish before a 409
+    #[arg(long = "session-wait-secs", default_value_t = config::DEFAULT_SESSION_WAIT_SECS)]
+    session_wait_secs: u64,
+
+    /// Kill a request's subprocess once its streamed content exceeds this many bytes
+    #[arg(long = "max-output-bytes")]
+    max_output_bytes: Option<usize>,
+
+    /// Reject session ids (`user` / `metadata.user_id`) longer than this
+    #[arg(long = "max-session-id-len", default_value_t = config::DEFAULT_MAX_SESSION_ID_LEN)]
+    max_session_id_len: usize,
+    /// Namespace client session ids with this salt before mapping them to CLI sessions
+    #[arg(long = "session-salt", value_parser = config::parse_session_salt)]
+    session_salt: Option<String>,
+
+    /// Keep at most this many session mappings, dropping the least recently used
+    #[arg(
+        long = "max-sessions",
+        default_value_t = config::DEFAULT_MAX_SESSIONS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_sessions: usize,
+
+    /// Back up and skip a sessions file larger than this many MiB at startup
+    #[arg(long = "max-sessions-file-mb", default_value_t = config::DEFAULT_MAX_SESSIONS_FILE_MB)]
+    max_sessions_file_mb: u64,
+
+    /// Write session changes to disk at most once per this many seconds
+    #[arg(
+        long = "session-save-secs",
+        default_value_t = config::DEFAULT_SESSION_SAVE_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    session_save_secs: u64,
+
+    /// Keep session mappings in memory only, never reading or writing the sessions file
+    #[arg(long = "no-session-persistence")]
+    no_session_persistence: bool,
+
+    /// Log format on stderr: pretty (multi-line), compact (one line, no timestamps/colors) or json
+    #[arg(long = "log-format", value_enum, default_value_t = config::LogFormat::Pretty)]
+    log_format: config::LogFormat,
+
+    /// Export request spans over OTLP/HTTP to this collector, e.g. http://localhost:4318
+    #[arg(long = "otlp-endpoint", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Retry a non-streaming request once if the CLI crashes before producing output
+    #[arg(long = "retry-on-crash")]
+    retry_on_crash: bool,
+
+    /// Retry a non-streaming request on another model when the CLI reports
+    /// its own unavailable (repeatable), e.g. opus=sonnet
+    #[arg(
+        long = "model-fallback",
+        value_name = "MODEL=FALLBACK",
+        value_parser = config::parse_model_fallback
+    )]
+    model_fallback: Vec<config::ModelFallback>,
+
+    /// USD per million input and output tokens for a model alias (repeatable),
+    /// e.g. opus=15,75; completion logs then carry an estimated cost
+    #[arg(
+        long = "model-price",
+        value_name = "MODEL=INPUT,OUTPUT",
+        value_parser = config::parse_model_price
+    )]
+    model_price: Vec<config::ModelPrice>,
+
+    /// Keep this many idle claude processes spawned ahead of requests (0 disables)
+    #[arg(long = "warm-pool", value_name = "N", default_value = "0")]
+    warm_pool: usize,
+
+    /// Warn when one result's usage lists more distinct models than this
+    #[arg(long = "max-usage-models", default_value_t = config::DEFAULT_MAX_USAGE_MODELS)]
+    max_usage_models: usize,
+
+    /// Report this `service_tier` (e.g. `default`) on chat completions and chunks
+    #[arg(long = "service-tier")]
+    service_tier: Option<String>,
+
+    /// Report the exact model the CLI ran (e.g. `claude-sonnet-4-5-20250929`)
+    /// instead of the normalized name (`claude-sonnet-4`)
+    #[arg(long = "echo-exact-model")]
+    echo_exact_model: bool,
 }
 
 #[tokio::main]
 async fn main() {
-    // Initialize tracing with compact format
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "claude_max_api=info".parse().unwrap()),
+    let args = Args::parse();
+
+    let tracer_provider = match args.otlp_endpoint.as_deref() {
+        Some(endpoint) => match telemetry::tracer_provider(endpoint) {
+            Ok(provider) => Some(provider),
+            Err(e) => {
+                eprintln!("--otlp-endpoint: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Initialize tracing in the `--log-format` format on stderr
+    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "claude_max_api=info".parse().unwrap());
+    let log_layer = telemetry::log_layer(args.log_format, env_filter, std::io::stderr);
+    let otel_layer = tracer_provider.as_ref().map(|provider| {
+        telemetry::layer(provider).with_filter(
+            tracing_subscriber::filter::Targets::new()
+                .with_target("claude_max_api", tracing::Level::INFO),
         )
-        .with_target(false)
-        .with_writer(std::io::stderr)
-        .compact()
+    });
+    tracing_subscriber::registry()
+        .with(log_layer)
+        .with(otel_layer)
         .init();
+    if let Some(endpoint) = &args.otlp_endpoint {
+        info!("Exporting request spans to {endpoint}");
+    }
 
-    let args = Args::parse();
+    // Fail fast on a bad keys file rather than starting unauthenticated
+    let mut api_keys = match &args.api_keys_file {
+        Some(path) => match config::load_api_keys(path) {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("--api-keys-file: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+    if let Some(key) = args.api_key.filter(|key| !key.is_empty()) {
+        api_keys.push(config::ApiKey {
+            label: config::DEFAULT_API_KEY_LABEL.to_string(),
+            key,
+        });
+    }
 
-    // Resolve cwd to absolute path
-    let cwd = std::fs::canonicalize(&args.cwd)
-        .unwrap_or_else(|_| std::path::PathBuf::from(&args.cwd))
-        .to_string_lossy()
-        .to_string();
+    let models = match &args.models_file {
+        Some(path) => match config::load_models(path) {
+            Ok(models) => models,
+            Err(e) => {
+                error!("--models-file: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => config::default_models(),
+    };
+
+    // Resolve cwd to an absolute path the CLI can run in
+    let cwd = match config::resolve_cwd(&args.cwd) {
+        Ok(cwd) => cwd,
+        Err(e) => {
+            error!("--cwd {}: {e}", args.cwd);
+            std::process::exit(1);
+        }
+    };
+    info!("Working directory: {cwd}");
+
+    let allowed_cwd_roots = args
+        .allowed_cwd_roots
+        .iter()
+        .map(|root| config::resolve_cwd(root).map_err(|e| format!("{root}: {e}")))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            error!("--allowed-cwd-root {e}");
+            std::process::exit(1);
+        });
+
+    let model_cwds = match config::resolve_model_cwds(args.cwd_per_model) {
+        Ok(model_cwds) => model_cwds,
+        Err(e) => {
+            error!("--cwd-per-model: {e}");
+            std::process::exit(1);
+        }
+    };
 
     // Verify claude CLI is available
-    match tokio::process::Command::new("claude")
-        .arg("--version")
-        .output()
-        .await
-    {
-        Ok(output) => {
-            let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let retry_delay = std::time::Duration::from_secs(args.startup_retry_delay);
+    let version = subprocess::cli_version(&args.claude_bin, args.startup_retries, retry_delay);
+    let cli_version = match version.await {
+        Ok(version) => {
             info!("Found claude CLI: {}", version);
+            version
         }
         Err(e) => {
-            error!("claude CLI not found: {}. Install it with: npm install -g @anthropic-ai/claude-code", e);
+            error!(
+                "claude CLI not found (tried '{}'): {}. Install it with: npm install -g @anthropic-ai/claude-code",
+                args.claude_bin, e
+            );
             std::process::exit(1);
         }
-    }
+    };
 
     // Set up session manager with cleanup task
-    let session_manager = session::SessionManager::new();
+    let persistence = if args.no_session_persistence {
+        session::SessionPersistence::Memory
+    } else {
+        session::SessionPersistence::File
+    };
+    let session_manager = session::SessionManager::new(
+        persistence,
+        session::SessionLimits {
+            max_sessions: args.max_sessions,
+            max_file_bytes: args.max_sessions_file_mb.saturating_mul(1024 * 1024),
+        },
+        std::time::Duration::from_secs(args.session_save_secs),
+    );
     session_manager.spawn_cleanup_task();
 
+    // SIGHUP re-resolves the sessions file under the current $HOME, which
+    // is otherwise fixed at startup
+    #[cfg(unix)]
+    {
+        let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to install SIGHUP handler");
+        let manager = session_manager.clone();
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP, re-resolving the sessions file");
+                manager.rebind(session::default_file_path()).await;
+            }
+        });
+    }
+
+    let config = config::Config {
+        claude_bin: args.claude_bin,
+        cli_version: Some(cli_version),
+        debug_features: args.debug_features,
+        inactivity_timeout: std::time::Duration::from_secs(args.inactivity_secs),
+        max_request_duration: args.max_request_secs.map(std::time::Duration::from_secs),
+        shutdown_grace: std::time::Duration::from_secs(args.shutdown_grace_secs),
+        forward_headers: args.forward_headers,
+        max_system_messages: args.max_system_messages,
+        max_prompt_turns: args.max_prompt_turns,
+        max_prompt_tokens: args.max_prompt_tokens,
+        sse_no_buffering: args.sse_no_buffering,
+        sse_content_type: args.sse_content_type,
+        progress_log_interval: config::progress_interval(args.progress_log_secs),
+        allow_extra_args: args.allow_extra_args,
+        permissions: config::CliPermissions {
+            mode: args.permission_mode,
+            allowed_tools: args.allowed_tools,
+            disallowed_tools: args.disallowed_tools,
+        },
+        system_prompt_mode: args.system_prompt_mode,
+        allow_debug_header: args.allow_debug_header,
+        omit_usage: args.omit_usage,
+        api_keys,
+        admin_key: args.admin_key.filter(|key| !key.is_empty()),
+        openai_stream_error_shape: args.openai_stream_error_shape,
+        stream_trailing_whitespace: args.stream_trailing_whitespace,
+        output_encoding: args.output_encoding,
+        stream_coalesce: (args.stream_coalesce_ms > 0)
+            .then(|| std::time::Duration::from_millis(args.stream_coalesce_ms)),
+        request_id_header: args.request_id_header,
+        cors: config::CorsPolicy::resolve(
+            args.cors_origin,
+            args.cors_disabled,
+            args.host.is_loopback(),
+        ),
+        max_concurrency: args.max_concurrency,
+        concurrency_wait: std::time::Duration::from_millis(args.concurrency_wait_ms),
+        session_wait: std::time::Duration::from_secs(args.session_wait_secs),
+        retry_on_crash: args.retry_on_crash,
+        model_fallbacks: args.model_fallback,
+        model_prices: args.model_price,
+        warm_pool: args.warm_pool,
+        max_usage_models: args.max_usage_models,
+        max_output_bytes: args.max_output_bytes,
+        max_session_id_len: args.max_session_id_len,
+        session_salt: args.session_salt,
+        service_tier: args.service_tier.filter(|tier| !tier.is_empty()),
+        echo_exact_model: args.echo_exact_model,
+        model_cwds,
+        allowed_cwd_roots,
+        models,
+    };
+    let has_api_keys = !config.api_keys.is_empty();
+    let admin = if config.admin_key.is_some() { ", /v1/admin/cancel-all" } else { "" };
+    if config.cors == config::CorsPolicy::Disabled && !args.cors_disabled {
+        warn!(
+            "Binding to {} without --cors-origin: CORS is off, so browser apps on other \
+             origins can't call the proxy",
+            args.host
+        );
+    }
+
+    // Warm processes start on opus and are only replaced once taken, so other
+    // models always spawn their own (see `WarmPool::take`)
+    let warm_pool = (config.warm_pool > 0).then(|| {
+        let pool = pool::WarmPool::new(
+            config.warm_pool,
+            config.claude_bin.clone(),
+            config.permissions.clone(),
+        );
+        pool.fill("opus", config::cwd_for_model(&config.model_cwds, "opus", &cwd));
+        info!("Warm pool: {} idle claude process(es)", config.warm_pool);
+        pool
+    });
+
+    let metrics = Arc::new(metrics::Metrics::default());
+    let shutdown_grace = config.shutdown_grace;
+    let (stop_runs, shutdown) = tokio::sync::watch::channel(false);
     let state = server::AppState {
         cwd: cwd.clone(),
-        session_manager,
+        session_manager: session_manager.clone(),
+        concurrency: Arc::new(Semaphore::new(config.max_concurrency)),
+        config: Arc::new(config),
+        metrics: metrics.clone(),
+        cli: Arc::new(subprocess::RealCliRunner::new(warm_pool)),
+        cancellations: Default::default(),
+        shutdown,
     };
 
     let app = server::create_router(state);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
+    let addr = SocketAddr::new(args.host, args.port);
+    if args.host.is_unspecified() && !has_api_keys {
+        warn!(
+            "Binding to {} without --api-key or --api-keys-file: anyone who can reach this \
+             host can use the proxy",
+            args.host
+        );
+    }
 
     let listener = match TcpListener::bind(addr).await {
         Ok(l) => l,
@@ -85,11 +563,19 @@ async fn main() {
         }
     };
 
-    info!("claude-max-proxy listening on http://127.0.0.1:{} (cwd: {})", args.port, cwd);
-    info!("endpoints: GET /health, /v1/models | POST /v1/chat/completions (OpenAI), /v1/messages (Anthropic)");
+    info!("claude-max-proxy listening on http://{} (cwd: {})", addr, cwd);
+    info!("endpoints: GET /health, /v1/config, /v1/models, /v1/models/{{id}}, /v1/sessions, /v1/sessions/{{id}} | POST /v1/chat/completions (OpenAI), /v1/messages (Anthropic), /v1/messages/count_tokens, /v1/complete (legacy), /v1/completions (legacy OpenAI), /v1/embeddings (unsupported), /v1/requests/{{id}}/cancel{admin} | DELETE /v1/sessions/{{id}}");
 
-    // Graceful shutdown on SIGINT/SIGTERM
-    let shutdown = async {
+    // Graceful shutdown on SIGINT/SIGTERM: stop accepting connections and give
+    // in-flight requests `--shutdown-grace-secs` to finish, then kill the rest.
+    // Counters at the signal, and how many runs were cut off, are kept to
+    // report how many in-flight requests drained.
+    let at_signal = Arc::new(OnceLock::new());
+    let cut_off = Arc::new(OnceLock::new());
+    let shutdown_metrics = metrics.clone();
+    let shutdown_snapshot = at_signal.clone();
+    let shutdown_cut_off = cut_off.clone();
+    let shutdown = async move {
         let ctrl_c = tokio::signal::ctrl_c();
         #[cfg(unix)]
         {
@@ -106,6 +592,24 @@ async fn main() {
             ctrl_c.await.ok();
             info!("Received SIGINT, shutting down...");
         }
+        let snapshot = shutdown_metrics.snapshot();
+        let _ = shutdown_snapshot.set(snapshot);
+        if snapshot.in_flight() > 0 {
+            info!(
+                "Waiting up to {}s for {} in-flight request(s)",
+                shutdown_grace.as_secs(),
+                snapshot.in_flight()
+            );
+        }
+        tokio::spawn(async move {
+            tokio::time::sleep(shutdown_grace).await;
+            let remaining = shutdown_metrics.snapshot().in_flight();
+            let _ = shutdown_cut_off.set(remaining);
+            if remaining > 0 {
+                warn!("Grace period over; killing {remaining} in-flight request(s)");
+            }
+            let _ = stop_runs.send(true);
+        });
     };
 
     axum::serve(listener, app)
@@ -116,5 +620,26 @@ async fn main() {
             std::process::exit(1);
         });
 
-    info!("Server stopped.");
+    // Runs cut off at the end of the grace period are still being killed
+    let kill_deadline = tokio::time::Instant::now() + SHUTDOWN_KILL_WAIT;
+    while metrics.snapshot().in_flight() > 0 && tokio::time::Instant::now() < kill_deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    // Write session changes the flusher hasn't picked up yet
+    session_manager.flush().await;
+
+    let at_exit = metrics.snapshot();
+    let at_signal = at_signal.get().copied().unwrap_or(at_exit);
+    let cut_off = cut_off.get().copied().unwrap_or(0);
+    info!(
+        "Server stopped: {}",
+        metrics::ShutdownSummary::new(&at_signal, &at_exit, cut_off)
+    );
+
+    // Flush spans still waiting for their batch
+    if let Some(provider) = tracer_provider
+        && let Err(e) = provider.shutdown()
+    {
+        warn!("Failed to flush spans: {e}");
+    }
 }
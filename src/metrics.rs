@@ -0,0 +1,281 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How a subprocess run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The CLI exited on its own (with any exit code).
+    Completed,
+    /// The client went away mid-request (nginx-style 499).
+    ClientClosed,
+    /// The subprocess was killed for exceeding a timeout.
+    TimedOut,
+    /// The subprocess could not be spawned.
+    SpawnFailed,
+    /// The subprocess was killed for exceeding the output limit.
+    OutputLimited,
+}
+
+/// Process-wide request counters and diagnostics, exposed via `/health`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    started: AtomicU64,
+    completed: AtomicU64,
+    client_closed: AtomicU64,
+    timed_out: AtomicU64,
+    spawn_failed: AtomicU64,
+    output_limited: AtomicU64,
+    sse_stalls: AtomicU64,
+    usage_model_overflows: AtomicU64,
+    last_error: Mutex<Option<LastError>>,
+}
+
+/// The most recent error reported by a subprocess.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LastError {
+    pub message: String,
+    /// Unix timestamp (seconds)
+    pub timestamp: u64,
+}
+
+/// Point-in-time copy of the counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MetricsSnapshot {
+    pub started: u64,
+    pub completed: u64,
+    pub client_closed: u64,
+    pub timed_out: u64,
+    pub spawn_failed: u64,
+    pub output_limited: u64,
+    /// Streams whose client stopped reading long enough to fill the SSE buffer
+    pub sse_stalls: u64,
+    /// Results whose usage listed more models than `--max-usage-models`
+    pub usage_model_overflows: u64,
+}
+
+impl MetricsSnapshot {
+    /// Runs that have ended, however they ended.
+    pub fn finished(&self) -> u64 {
+        self.completed
+            + self.client_closed
+            + self.timed_out
+            + self.spawn_failed
+            + self.output_limited
+    }
+
+    /// Runs still in progress.
+    pub fn in_flight(&self) -> u64 {
+        self.started.saturating_sub(self.finished())
+    }
+
+    /// Runs whose subprocess was killed instead of exiting on its own.
+    pub fn killed(&self) -> u64 {
+        self.client_closed + self.timed_out + self.output_limited
+    }
+}
+
+/// What happened to requests across a graceful shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Requests started over the whole lifetime of the server
+    pub served: u64,
+    /// Requests in flight when the shutdown signal arrived
+    pub in_flight_at_signal: u64,
+    /// Of those, how many finished before the server stopped
+    pub drained: u64,
+    /// Of those, how many were still running when the grace period ended
+    /// and were killed
+    pub cut_off: u64,
+    /// Subprocesses killed over the lifetime of the server
+    pub killed: u64,
+}
+
+impl ShutdownSummary {
+    /// Compare the counters taken at the shutdown signal with the final ones.
+    /// `cut_off` runs were killed when the grace period ended.
+    pub fn new(at_signal: &MetricsSnapshot, at_exit: &MetricsSnapshot, cut_off: u64) -> Self {
+        let in_flight_at_signal = at_signal.in_flight();
+        let finished = in_flight_at_signal.saturating_sub(at_exit.in_flight());
+        Self {
+            served: at_exit.started,
+            in_flight_at_signal,
+            drained: finished.saturating_sub(cut_off),
+            cut_off,
+            killed: at_exit.killed(),
+        }
+    }
+}
+
+impl std::fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "served={} drained={}/{} cut_off={} killed={}",
+            self.served, self.drained, self.in_flight_at_signal, self.cut_off, self.killed
+        )
+    }
+}
+
+impl Metrics {
+    pub fn record_start(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_outcome(&self, outcome: RunOutcome) {
+        let counter = match outcome {
+            RunOutcome::Completed => &self.completed,
+            RunOutcome::ClientClosed => &self.client_closed,
+            RunOutcome::TimedOut => &self.timed_out,
+            RunOutcome::SpawnFailed => &self.spawn_failed,
+            RunOutcome::OutputLimited => &self.output_limited,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sse_stall(&self) {
+        self.sse_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_usage_model_overflow(&self) {
+        self.usage_model_overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, message: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut last = self.last_error.lock().unwrap_or_else(|e| e.into_inner());
+        *last = Some(LastError {
+            message: message.to_string(),
+            timestamp,
+        });
+    }
+
+    pub fn last_error(&self) -> Option<LastError> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            started: self.started.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            client_closed: self.client_closed.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            spawn_failed: self.spawn_failed.load(Ordering::Relaxed),
+            output_limited: self.output_limited.load(Ordering::Relaxed),
+            sse_stalls: self.sse_stalls.load(Ordering::Relaxed),
+            usage_model_overflows: self.usage_model_overflows.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_at_zero() {
+        let m = Metrics::default();
+        assert_eq!(
+            m.snapshot(),
+            MetricsSnapshot {
+                started: 0,
+                completed: 0,
+                client_closed: 0,
+                timed_out: 0,
+                spawn_failed: 0,
+                output_limited: 0,
+                sse_stalls: 0,
+                usage_model_overflows: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn client_closed_counted_separately() {
+        let m = Metrics::default();
+        m.record_start();
+        m.record_start();
+        m.record_outcome(RunOutcome::Completed);
+        m.record_outcome(RunOutcome::ClientClosed);
+
+        let snap = m.snapshot();
+        assert_eq!(snap.started, 2);
+        assert_eq!(snap.completed, 1);
+        assert_eq!(snap.client_closed, 1);
+        assert_eq!(snap.timed_out, 0);
+    }
+
+    #[test]
+    fn record_error_replaces_previous() {
+        let m = Metrics::default();
+        assert_eq!(m.last_error(), None);
+        m.record_error("first");
+        m.record_error("Not logged in");
+        let last = m.last_error().unwrap();
+        assert_eq!(last.message, "Not logged in");
+        assert!(last.timestamp > 0);
+    }
+
+    #[test]
+    fn snapshot_serializes() {
+        let m = Metrics::default();
+        m.record_outcome(RunOutcome::TimedOut);
+        let json = serde_json::to_value(m.snapshot()).unwrap();
+        assert_eq!(json["timed_out"], 1);
+        assert_eq!(json["client_closed"], 0);
+    }
+
+    #[test]
+    fn shutdown_summary_reflects_counters() {
+        let m = Metrics::default();
+        for _ in 0..5 {
+            m.record_start();
+        }
+        m.record_outcome(RunOutcome::Completed);
+        m.record_outcome(RunOutcome::TimedOut);
+        // Three requests still running when the signal arrives
+        let at_signal = m.snapshot();
+        assert_eq!(at_signal.in_flight(), 3);
+
+        m.record_outcome(RunOutcome::Completed);
+        m.record_outcome(RunOutcome::ClientClosed);
+        let summary = ShutdownSummary::new(&at_signal, &m.snapshot(), 0);
+
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                served: 5,
+                in_flight_at_signal: 3,
+                drained: 2,
+                cut_off: 0,
+                killed: 2,
+            }
+        );
+        assert_eq!(summary.to_string(), "served=5 drained=2/3 cut_off=0 killed=2");
+    }
+
+    #[test]
+    fn shutdown_summary_separates_cut_off_runs() {
+        let m = Metrics::default();
+        for _ in 0..3 {
+            m.record_start();
+        }
+        let at_signal = m.snapshot();
+        m.record_outcome(RunOutcome::Completed);
+        // The grace period ends with two still running; both are killed
+        m.record_outcome(RunOutcome::ClientClosed);
+        m.record_outcome(RunOutcome::ClientClosed);
+        let summary = ShutdownSummary::new(&at_signal, &m.snapshot(), 2);
+
+        assert_eq!(summary.drained, 1);
+        assert_eq!(summary.cut_off, 2);
+        assert_eq!(summary.to_string(), "served=3 drained=1/3 cut_off=2 killed=2");
+    }
+}
@@ -0,0 +1,221 @@
+use crate::config::CliPermissions;
+use crate::subprocess::{self, SubprocessOptions};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::process::Child;
+use tracing::{debug, warn};
+
+/// An idle `claude` process waiting for its prompt on stdin.
+struct WarmProcess {
+    model: String,
+    cwd: String,
+    child: Child,
+}
+
+/// Pre-spawned `claude` processes (`--warm-pool`). A warm process has
+/// already paid the CLI's startup cost, so a request that gets one only
+/// waits for the model. Each process serves one request and exits; taking
+/// one spawns its replacement for the same model and cwd, so the pool keeps
+/// to the models it was filled with.
+pub struct WarmPool {
+    size: usize,
+    claude_bin: String,
+    permissions: CliPermissions,
+    idle: Mutex<VecDeque<WarmProcess>>,
+}
+
+impl WarmPool {
+    pub fn new(size: usize, claude_bin: String, permissions: CliPermissions) -> Self {
+        Self {
+            size,
+            claude_bin,
+            permissions,
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+        }
+    }
+
+    /// Spawn idle processes for `model` in `cwd` until the pool is full.
+    pub fn fill(&self, model: &str, cwd: &str) {
+        for _ in 0..self.size {
+            self.spawn(model, cwd);
+        }
+    }
+
+    /// An idle process able to serve `options`, replaced by a fresh one.
+    /// `None` when the run needs flags or environment a warm process was
+    /// not started with, or none is idle for its model and cwd; the caller
+    /// then spawns the CLI itself, and the pool is left as it was.
+    pub fn take(&self, options: &SubprocessOptions) -> Option<Child> {
+        if !self.serves(options) {
+            return None;
+        }
+        let child = {
+            let mut idle = self.idle.lock().unwrap();
+            // Drop processes that exited while idle
+            idle.retain_mut(|p| matches!(p.child.try_wait(), Ok(None)));
+            let pos = idle
+                .iter()
+                .position(|p| p.model == options.model && p.cwd == options.cwd);
+            pos.and_then(|pos| idle.remove(pos)).map(|p| p.child)
+        };
+        match child {
+            Some(_) => self.spawn(&options.model, &options.cwd),
+            None => debug!("No warm process for model={} cwd={}", options.model, options.cwd),
+        }
+        child
+    }
+
+    /// Whether a warm process runs exactly what `options` asks for: warm
+    /// processes carry no session, system prompt, extra flags or
+    /// per-request environment. A system prompt is a CLI flag, so with
+    /// `--system-prompt-mode flag` any request with one runs cold; and
+    /// `max_tokens` goes in the environment, so `/v1/messages`, which
+    /// requires it, always does.
+    fn serves(&self, options: &SubprocessOptions) -> bool {
+        self.size > 0
+            && options.claude_bin == self.claude_bin
+            && options.permissions == self.permissions
+            && options.session_id.is_none()
+            && options.system_prompt.is_none()
+            && options.extra_args.is_empty()
+            && subprocess::build_env(options).is_empty()
+    }
+
+    /// Add an idle process, evicting the oldest when the pool is full.
+    fn spawn(&self, model: &str, cwd: &str) {
+        if self.size == 0 {
+            return;
+        }
+        let args = subprocess::warm_args(model, &self.permissions);
+        let child = match subprocess::cli_command(&self.claude_bin, cwd, &args)
+            .stdin(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to spawn warm process model={model}: {e}");
+                return;
+            }
+        };
+        let evicted = {
+            let mut idle = self.idle.lock().unwrap();
+            let evicted = (idle.len() >= self.size).then(|| idle.pop_front()).flatten();
+            idle.push_back(WarmProcess {
+                model: model.to_string(),
+                cwd: cwd.to_string(),
+                child,
+            });
+            evicted
+        };
+        if let Some(mut p) = evicted {
+            tokio::spawn(async move { subprocess::kill_process_group(&mut p.child).await });
+        }
+    }
+
+    #[cfg(test)]
+    fn idle_models(&self) -> Vec<String> {
+        let idle = self.idle.lock().unwrap();
+        idle.iter().map(|p| p.model.clone()).collect()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::subprocess::{CliRunner, RealCliRunner, SubprocessEvent};
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// A stand-in `claude` that answers "warm" when given a prompt on stdin
+    /// and "cold" when started with the prompt as an argument.
+    fn fake_cli() -> String {
+        let path = std::env::temp_dir().join(format!("pool-test-{}.sh", uuid::Uuid::new_v4()));
+        let script = "#!/bin/sh\n\
+            if read -r line; then r=warm; else r=cold; fi\n\
+            echo \"{\\\"type\\\":\\\"result\\\",\\\"result\\\":\\\"$r\\\"}\"\n";
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn options(claude_bin: &str) -> SubprocessOptions {
+        SubprocessOptions {
+            claude_bin: claude_bin.to_string(),
+            inactivity_timeout: Duration::from_secs(10),
+            ..SubprocessOptions::for_test()
+        }
+    }
+
+    async fn run_result(runner: &RealCliRunner, options: SubprocessOptions) -> String {
+        let (tx, mut rx) = mpsc::channel(16);
+        runner.run("Hi".to_string(), options, tx).await;
+        while let Some(event) = rx.recv().await {
+            if let SubprocessEvent::Result(result) = event {
+                return result.result.unwrap_or_default();
+            }
+        }
+        panic!("run ended without a result");
+    }
+
+    #[tokio::test]
+    async fn pooled_runs_use_warm_processes() {
+        let bin = fake_cli();
+        let pool = WarmPool::new(1, bin.clone(), CliPermissions::default());
+        pool.fill("opus", "/tmp");
+        let runner = RealCliRunner::new(Some(pool));
+
+        assert_eq!(run_result(&runner, options(&bin)).await, "warm");
+        // The replacement spawned by the first run serves the second
+        assert_eq!(run_result(&runner, options(&bin)).await, "warm");
+        std::fs::remove_file(bin).unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_a_warm_process_cannot_serve_spawn_cold() {
+        let bin = fake_cli();
+        let pool = WarmPool::new(1, bin.clone(), CliPermissions::default());
+        pool.fill("opus", "/tmp");
+        let runner = RealCliRunner::new(Some(pool));
+
+        let session = SubprocessOptions {
+            session_id: Some("abc".to_string()),
+            ..options(&bin)
+        };
+        assert_eq!(run_result(&runner, session).await, "cold");
+        let sonnet = SubprocessOptions {
+            model: "sonnet".to_string(),
+            ..options(&bin)
+        };
+        assert_eq!(run_result(&runner, sonnet).await, "cold");
+        assert_eq!(run_result(&RealCliRunner::default(), options(&bin)).await, "cold");
+        std::fs::remove_file(bin).unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_pool_evicts_the_oldest_process() {
+        let bin = fake_cli();
+        let pool = WarmPool::new(2, bin.clone(), CliPermissions::default());
+        pool.fill("opus", "/tmp");
+        pool.fill("sonnet", "/tmp");
+        assert_eq!(pool.idle_models(), ["sonnet", "sonnet"]);
+
+        let mut sonnet = options(&bin);
+        sonnet.model = "sonnet".to_string();
+        assert!(pool.take(&sonnet).is_some());
+        assert_eq!(pool.idle_models(), ["sonnet", "sonnet"]);
+        // A miss leaves the pool alone
+        assert!(pool.take(&options(&bin)).is_none());
+        assert_eq!(pool.idle_models(), ["sonnet", "sonnet"]);
+        std::fs::remove_file(bin).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_pool_serves_nothing() {
+        let pool = WarmPool::new(0, "claude".to_string(), CliPermissions::default());
+        pool.fill("opus", "/tmp");
+        assert!(pool.idle_models().is_empty());
+        assert!(pool.take(&options("claude")).is_none());
+    }
+}
@@ -1,140 +1,209 @@
-use axum::extract::State;
-use axum::http::header;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, HeaderValue, header};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::{Extension, Json};
 use serde_json::json;
-use std::convert::Infallible;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{OwnedMutexGuard, OwnedSemaphorePermit, mpsc};
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{error, info};
+use tracing::{Instrument, Span, error, info, warn};
 
 use crate::adapter::anthropic_to_cli;
 use crate::adapter::cli_to_anthropic;
+use crate::adapter::cli_to_complete;
+use crate::adapter::cli_to_completions;
 use crate::adapter::cli_to_openai;
+use crate::adapter::complete_to_cli;
+use crate::adapter::completions_to_cli;
+use crate::adapter::history::{HistoryLimits, estimate_tokens};
 use crate::adapter::openai_to_cli;
+use crate::auth::ApiKeyLabel;
+use crate::cancel::CancelGuard;
+use crate::config::{self, Config, StreamErrorShape, TrailingWhitespace};
 use crate::error::AppError;
+use crate::metrics::Metrics;
 use crate::server::AppState;
-use crate::subprocess::{self, SubprocessEvent, SubprocessOptions};
-use crate::types::anthropic::{AnthropicErrorDetail, AnthropicErrorResponse, MessagesRequest};
-use crate::types::openai::{ChatCompletionRequest, ModelInfo, ModelsResponse};
+use crate::session::SessionMapping;
+use crate::sse::{self, Disconnected, SseSender};
+use crate::subprocess::{self, Sampling, SubprocessEvent, SubprocessOptions, UpstreamFailure};
+use crate::telemetry::RequestId;
+use crate::types::anthropic::{
+    AnthropicErrorDetail, AnthropicErrorResponse, CompleteRequest, ContentBlock,
+    CountTokensRequest, CountTokensResponse, MessagesRequest, ResponseUsage,
+};
+use crate::types::claude_cli::ResultMessage;
+use crate::types::openai::{
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, CompletionRequest,
+    ModelInfo, ModelsResponse,
+};
 
-fn generate_request_id() -> String {
-    uuid::Uuid::new_v4()
-        .to_string()
-        .replace('-', "")
-        .chars()
-        .take(8)
-        .collect()
-}
-
-pub async fn health() -> impl IntoResponse {
-    let uptime = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-
-    Json(json!({
-        "status": "ok",
-        "uptime": uptime,
-    }))
+/// Wait up to `--concurrency-wait-ms` for a free subprocess slot.
+async fn acquire_slot(state: &AppState) -> Result<OwnedSemaphorePermit, AppError> {
+    let acquire = state.concurrency.clone().acquire_owned();
+    match tokio::time::timeout(state.config.concurrency_wait, acquire).await {
+        Ok(Ok(permit)) => Ok(permit),
+        _ => {
+            let max = state.config.max_concurrency;
+            warn!("Rejected status=503: all {max} subprocess slots busy");
+            Err(AppError::ServiceUnavailable(format!(
+                "Too many concurrent requests (limit {max}), retry shortly"
+            )))
+        }
+    }
 }
 
-pub async fn models() -> impl IntoResponse {
-    let created = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-
-    Json(ModelsResponse {
-        object: "list".to_string(),
-        data: vec![
-            ModelInfo {
-                id: "claude-opus-4".to_string(),
-                object: "model".to_string(),
-                owned_by: "anthropic".to_string(),
-                created,
-                context_window: 1_000_000,
-                max_tokens: 128_000,
-            },
-            ModelInfo {
-                id: "claude-sonnet-4".to_string(),
-                object: "model".to_string(),
-                owned_by: "anthropic".to_string(),
-                created,
-                context_window: 200_000,
-                max_tokens: 64_000,
-            },
-            ModelInfo {
-                id: "claude-haiku-4".to_string(),
-                object: "model".to_string(),
-                owned_by: "anthropic".to_string(),
-                created,
-                context_window: 200_000,
-                max_tokens: 64_000,
-            },
-        ],
+/// Wait up to `--session-wait-secs` for exclusive use of a CLI session.
+async fn lock_session(state: &AppState, id: &str) -> Result<OwnedMutexGuard<()>, AppError> {
+    let lock = state.session_manager.lock_session(id);
+    tokio::time::timeout(state.config.session_wait, lock).await.map_err(|_| {
+        let secs = state.config.session_wait.as_secs();
+        warn!("Rejected status=409: session still busy after {secs}s");
+        AppError::Conflict(
+            "Another request for this session is still running, retry once it finishes"
+                .to_string(),
+        )
     })
 }
 
-pub async fn chat_completions(
-    State(state): State<AppState>,
-    Json(request): Json<ChatCompletionRequest>,
-) -> Result<Response, AppError> {
-    // Validate messages
-    let messages = request.messages.as_ref().ok_or_else(|| {
-        AppError::BadRequest("messages is required and must be a non-empty array".to_string())
-    })?;
-    if messages.is_empty() {
-        return Err(AppError::BadRequest(
-            "messages is required and must be a non-empty array".to_string(),
-        ));
-    }
-
-    let request_id = generate_request_id();
-    let is_streaming = request.stream;
-
-    let (model, prompt, session_id) = openai_to_cli::openai_to_cli(&request);
+/// Error reported to a request stopped through `cancel_request`.
+const CANCELLED: &str = "Request cancelled";
 
-    info!("[req={request_id}] OpenAI chat completions model={model} streaming={is_streaming}");
+/// Error reported to a request still running when a shutdown's grace period
+/// ends.
+const SHUTTING_DOWN: &str = "Server shutting down";
 
-    let options = SubprocessOptions {
-        request_id: request_id.clone(),
-        model: model.to_string(),
-        session_id,
-        cwd: state.cwd.clone(),
-        api: "openai",
+/// Spawn the CLI for a request and return its event stream. The outcome of
+/// the run (including client disconnects) is recorded in the metrics.
+///
+/// Runs under the same CLI session id are serialized. Fails with 409 when
+/// the session's previous run doesn't finish within `--session-wait-secs`,
+/// and with 503 when no subprocess slot frees up in time. The session lock
+/// and the slot are held until the CLI exits or is killed, whichever path
+/// ends the run.
+///
+/// Once spawned, the run is stopped when its request is cancelled (see
+/// `register_request`), or when a shutdown's grace period runs out
+/// (`--shutdown-grace-secs`): the handler gets a `CANCELLED` or
+/// `SHUTTING_DOWN` error event and the subprocess is killed.
+async fn start_subprocess(
+    state: &AppState,
+    cancel: &CancelGuard,
+    prompt: String,
+    options: SubprocessOptions,
+) -> Result<mpsc::Receiver<SubprocessEvent>, AppError> {
+    let session_guard = match &options.session_id {
+        Some(id) => Some(lock_session(state, id).await?),
+        None => None,
+    };
+    let permit = acquire_slot(state).await?;
+    let cancelled = cancel.cancelled();
+    let mut shutdown = state.shutdown.clone();
+    // Resolves to why the run must stop; never, if nothing can stop it
+    let stop = async move {
+        let stopping = async { shutdown.wait_for(|stopping| *stopping).await.map(|_| ()) };
+        tokio::select! {
+            () = cancelled => CANCELLED,
+            Ok(()) = stopping => SHUTTING_DOWN,
+            else => std::future::pending().await,
+        }
     };
+    let (tx, mut events) = mpsc::channel::<SubprocessEvent>(64);
+    let (out_tx, rx) = mpsc::channel::<SubprocessEvent>(64);
+    let metrics = state.metrics.clone();
+    let max_usage_models = state.config.max_usage_models;
+    metrics.record_start();
 
-    if is_streaming {
-        handle_streaming(request_id, prompt, options).await
-    } else {
-        let start = Instant::now();
-        let result = handle_non_streaming(request_id.clone(), prompt, options).await;
-        let elapsed = start.elapsed().as_secs_f64();
-        match &result {
-            Ok(_) => info!("[req={request_id}] Request complete after {elapsed:.2}s"),
-            Err(e) => error!("[req={request_id}] Request failed after {elapsed:.2}s: {e}"),
+    let outcome_metrics = metrics.clone();
+    let run = state.cli.run(prompt, options, tx);
+    // Inherit the `request` span, which the run's own spans and logs nest under
+    tokio::spawn(
+        async move {
+            let outcome = run.await;
+            drop(permit);
+            drop(session_guard);
+            outcome_metrics.record_outcome(outcome);
+        }
+        .in_current_span(),
+    );
+
+    // Observe events on their way to the handler. Dropping `events` when the
+    // handler goes away, or the run is stopped, propagates the disconnect
+    // to the subprocess.
+    tokio::spawn(
+        async move {
+            tokio::pin!(stop);
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    () = out_tx.closed() => break,
+                    reason = &mut stop => {
+                        info!("{reason}; stopping the subprocess");
+                        let _ = out_tx.send(SubprocessEvent::Error(reason.to_string())).await;
+                        break;
+                    }
+                };
+                let Some(event) = event else { break };
+                match &event {
+                    SubprocessEvent::Error(msg) => metrics.record_error(msg),
+                    SubprocessEvent::Result(result) => {
+                        check_usage_models(result, max_usage_models, &metrics);
+                    }
+                    _ => {}
+                }
+                if out_tx.send(event).await.is_err() {
+                    break;
+                }
+            }
         }
-        result
+        .in_current_span(),
+    );
+
+    Ok(rx)
+}
+
+/// Warn about, and count, a result whose `modelUsage` lists more distinct
+/// models than `max` (`--max-usage-models`). Agent-team runs report a few;
+/// many more points at a CLI bug. The adapters still sum all of them.
+fn check_usage_models(result: &ResultMessage, max: usize, metrics: &Metrics) {
+    let count = result.model_usage.as_ref().map_or(0, |mu| mu.len());
+    if count > max {
+        warn!("Result usage lists {count} models (more than {max}); possible CLI bug");
+        metrics.record_usage_model_overflow();
     }
 }
 
-async fn handle_non_streaming(
-    request_id: String,
+/// How a run collected by `collect_run` ended.
+enum RunEnd {
+    Result(Box<ResultMessage>),
+    Error(String),
+    /// Exited without a result; `produced_output` if any content came first
+    Exit {
+        code: i32,
+        stderr: Option<String>,
+        produced_output: bool,
+    },
+}
+
+/// Run the CLI and wait for it to finish, keeping only how it ended. A
+/// clean exit without a result line is not a failure: it ends as a result
+/// holding whatever text streamed, possibly none (flagged downstream by
+/// `empty_completion_headers`).
+async fn collect_run(
+    state: &AppState,
+    cancel: &CancelGuard,
     prompt: String,
     options: SubprocessOptions,
-) -> Result<Response, AppError> {
-    let (tx, mut rx) = mpsc::channel::<SubprocessEvent>(64);
-
-    tokio::spawn(async move {
-        subprocess::spawn_subprocess(prompt, options, tx).await;
-    });
+) -> Result<RunEnd, AppError> {
+    let mut rx = start_subprocess(state, cancel, prompt, options).await?;
 
     let mut result_msg = None;
     let mut error_msg = None;
     let mut exit_code = None;
+    let mut exit_stderr = None;
+    let mut produced_output = false;
+    let mut text = String::new();
 
     while let Some(event) = rx.recv().await {
         match event {
@@ -144,376 +213,1398 @@ async fn handle_non_streaming(
             SubprocessEvent::Error(msg) => {
                 error_msg = Some(msg);
             }
-            SubprocessEvent::Close(code) => {
+            SubprocessEvent::Close(code, stderr) => {
                 exit_code = Some(code);
+                exit_stderr = stderr;
             }
-            _ => {}
+            SubprocessEvent::ContentDelta(delta) => {
+                produced_output = true;
+                text.push_str(&delta);
+            }
+            // A tool call may already have had side effects, so it counts as
+            // output and rules out a retry
+            SubprocessEvent::ToolUse { .. } => produced_output = true,
+            SubprocessEvent::Model(_) => {}
         }
     }
 
-    if let Some(err) = error_msg {
-        return Err(AppError::Subprocess(err));
-    }
-
-    if let Some(result) = result_msg {
-        let response = cli_to_openai::cli_result_to_openai(&result, &request_id);
-        Ok((
-            [(header::HeaderName::from_static("x-request-id"), request_id)],
-            Json(response),
-        )
-            .into_response())
-    } else {
-        let code = exit_code.unwrap_or(-1);
-        Err(AppError::Subprocess(format!(
-            "Process exited with code {} without producing a response",
-            code
-        )))
-    }
+    Ok(match (error_msg, result_msg) {
+        (Some(err), _) => RunEnd::Error(err),
+        (None, Some(result)) => RunEnd::Result(Box::new(result)),
+        (None, None) if exit_code == Some(0) => {
+            warn!("CLI exited cleanly without a result; answering with the streamed text");
+            RunEnd::Result(Box::new(ResultMessage {
+                result: Some(text),
+                exit_code: Some(0),
+                ..Default::default()
+            }))
+        }
+        (None, None) => RunEnd::Exit {
+            code: exit_code.unwrap_or(-1),
+            stderr: exit_stderr,
+            produced_output,
+        },
+    })
 }
 
-async fn handle_streaming(
-    request_id: String,
+/// Run the CLI to completion for a non-streaming request. With
+/// `--retry-on-crash`, a run that exits non-zero before any output is started
+/// once more, unless stderr shows an auth, rate-limit or quota failure a
+/// retry can't fix. Streaming handlers never retry: by the time a run fails,
+/// part of the response may already be sent.
+async fn run_with_retry(
+    state: &AppState,
+    cancel: &CancelGuard,
     prompt: String,
     options: SubprocessOptions,
-) -> Result<Response, AppError> {
-    let (tx, mut rx) = mpsc::channel::<SubprocessEvent>(64);
+) -> Result<RunEnd, AppError> {
+    let retry = state
+        .config
+        .retry_on_crash
+        .then(|| (prompt.clone(), options.clone()));
+    let end = collect_run(state, cancel, prompt, options).await?;
 
-    tokio::spawn(async move {
-        subprocess::spawn_subprocess(prompt, options, tx).await;
-    });
+    if let Some((prompt, options)) = retry
+        && let RunEnd::Exit {
+            code,
+            stderr,
+            produced_output: false,
+        } = &end
+        && *code != 0
+        && stderr.as_deref().and_then(subprocess::classify_failure).is_none()
+    {
+        warn!("CLI crashed with code {code} before any output; retrying once");
+        return collect_run(state, cancel, prompt, options).await;
+    }
+    Ok(end)
+}
 
-    let req_id = request_id.clone();
-    let (sse_tx, sse_rx) = mpsc::channel::<Result<Event, Infallible>>(64);
+/// Whether a run failed because its model is unavailable (see
+/// `subprocess::model_unavailable`), before producing any output.
+fn model_unavailable(end: &RunEnd) -> bool {
+    match end {
+        RunEnd::Error(err) => subprocess::model_unavailable(err),
+        RunEnd::Exit {
+            code,
+            stderr: Some(stderr),
+            produced_output: false,
+        } => *code != 0 && subprocess::model_unavailable(stderr),
+        _ => false,
+    }
+}
 
-    // Spawn a task to convert subprocess events to SSE events
-    tokio::spawn(async move {
-        let mut is_first = true;
-        let mut last_model = "claude-sonnet-4".to_string();
-        let mut got_result = false;
+/// Run the CLI to completion for a non-streaming request and return its
+/// result (see `run_with_retry`). With `--model-fallback`, a run whose model
+/// is unavailable is started again on the fallback model, and the returned
+/// headers carry `x-model-fallback` naming it.
+async fn run_to_result(
+    state: &AppState,
+    cancel: &CancelGuard,
+    prompt: String,
+    options: SubprocessOptions,
+) -> Result<(ResultMessage, HeaderMap), AppError> {
+    let fallback = config::fallback_for_model(&state.config.model_fallbacks, &options.model)
+        .map(|model| (model, prompt.clone(), options.clone()));
+    let mut end = run_with_retry(state, cancel, prompt, options).await?;
 
-        // Send initial :ok comment
-        let ok_event = Event::default().comment("ok");
-        if sse_tx.send(Ok(ok_event)).await.is_err() {
-            return;
+    let mut headers = HeaderMap::new();
+    if let Some((model, prompt, mut options)) = fallback
+        && model_unavailable(&end)
+    {
+        warn!("Model {} unavailable; falling back to {model}", options.model);
+        // Follow the fallback's --cwd-per-model mapping, unless the request
+        // chose its own directory
+        if options.cwd == run_cwd(state, &options.model, None) {
+            options.cwd = run_cwd(state, model, None);
         }
+        options.model = model.to_string();
+        end = run_with_retry(state, cancel, prompt, options).await?;
+        let name = cli_to_openai::normalize_model_name(model);
+        headers.insert("x-model-fallback", HeaderValue::from_static(name));
+    }
 
-        while let Some(event) = rx.recv().await {
-            match event {
-                SubprocessEvent::Model(model) => {
-                    last_model = model;
-                }
-                SubprocessEvent::ContentDelta(text) => {
-                    let chunk =
-                        cli_to_openai::create_stream_chunk(&req_id, &last_model, &text, is_first);
-                    is_first = false;
-
-                    match serde_json::to_string(&chunk) {
-                        Ok(json) => {
-                            let event = Event::default().data(json);
-                            if sse_tx.send(Ok(event)).await.is_err() {
-                                return; // Client disconnected
-                            }
-                        }
-                        Err(e) => {
-                            error!("[req={req_id}] Failed to serialize chunk: {e}");
-                        }
-                    }
-                }
-                SubprocessEvent::Result(_result) => {
-                    got_result = true;
-
-                    // Send done chunk with finish_reason: "stop"
-                    let done_chunk = cli_to_openai::create_done_chunk(&req_id, &last_model);
-                    if let Ok(json) = serde_json::to_string(&done_chunk) {
-                        let event = Event::default().data(json);
-                        let _ = sse_tx.send(Ok(event)).await;
-                    }
+    match end {
+        RunEnd::Result(result) => Ok((*result, headers)),
+        RunEnd::Error(err) => Err(AppError::Subprocess(err)),
+        RunEnd::Exit { code, stderr, .. } => {
+            let msg = format!("Process exited with code {} without producing a response", code);
+            Err(exit_error(msg, stderr.as_deref()))
+        }
+    }
+}
 
-                    // Send [DONE] sentinel
-                    let done_event = Event::default().data("[DONE]");
-                    let _ = sse_tx.send(Ok(done_event)).await;
-                }
-                SubprocessEvent::Error(msg) => {
-                    let error_data = json!({
-                        "error": {
-                            "message": msg,
-                            "type": "server_error",
-                            "code": null,
-                        }
-                    });
-                    if let Ok(json) = serde_json::to_string(&error_data) {
-                        let event = Event::default().data(json);
-                        let _ = sse_tx.send(Ok(event)).await;
-                    }
-                }
-                SubprocessEvent::Close(code) => {
-                    if !got_result && code != 0 {
-                        let error_data = json!({
-                            "error": {
-                                "message": format!("Process exited with code {}", code),
-                                "type": "server_error",
-                                "code": null,
-                            }
-                        });
-                        if let Ok(json) = serde_json::to_string(&error_data) {
-                            let event = Event::default().data(json);
-                            let _ = sse_tx.send(Ok(event)).await;
-                        }
-                        let done_event = Event::default().data("[DONE]");
-                        let _ = sse_tx.send(Ok(done_event)).await;
-                    }
-                }
-            }
+/// Diagnostic headers attached when `--debug-features` is enabled: the
+/// inactivity timeout, and the overall limit (`--max-request-secs`) if any.
+fn debug_headers(config: &Config, options: &SubprocessOptions) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if config.debug_features {
+        headers.insert(
+            "x-debug-timeout-secs",
+            HeaderValue::from(options.inactivity_timeout.as_secs()),
+        );
+        if let Some(max_duration) = options.max_duration {
+            headers.insert(
+                "x-debug-max-request-secs",
+                HeaderValue::from(max_duration.as_secs()),
+            );
         }
-    });
+    }
+    headers
+}
 
-    let stream = ReceiverStream::new(sse_rx);
+/// Headers that stop intermediaries (e.g. nginx) from buffering SSE streams,
+/// and the configured content type replacing `Sse`'s default.
+fn streaming_headers(config: &Config) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if config.sse_no_buffering {
+        headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+    }
+    if let Some(content_type) = &config.sse_content_type {
+        headers.insert(header::CONTENT_TYPE, content_type.clone());
+    }
+    headers
+}
 
-    let sse = Sse::new(stream).keep_alive(KeepAlive::default());
+/// Flag a result with no content so clients can detect it and retry; the
+/// response itself still reports a normal stop.
+fn empty_completion_headers(result: &ResultMessage) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let is_empty = result.result.as_deref().is_none_or(|r| r.trim().is_empty());
+    if is_empty {
+        warn!("CLI returned an empty completion");
+        headers.insert("x-empty-completion", HeaderValue::from_static("true"));
+    }
+    headers
+}
 
-    Ok((
-        [
-            (
-                header::HeaderName::from_static("x-request-id"),
-                request_id,
-            ),
-            (
-                header::CACHE_CONTROL,
-                "no-cache".to_string(),
-            ),
-        ],
-        sse,
-    )
-        .into_response())
+/// Warn when the result carries U+FFFD replacement characters, a sign that
+/// CLI output was decoded lossily and the text may be imperfect.
+fn warn_lossy_result(result: &ResultMessage) -> bool {
+    let replaced = result
+        .result
+        .as_deref()
+        .map_or(0, |r| r.matches(char::REPLACEMENT_CHARACTER).count());
+    if replaced > 0 {
+        warn!(
+            "Result contains {replaced} replacement character(s); \
+             output may be corrupted"
+        );
+    }
+    replaced > 0
 }
 
-// ── Anthropic Messages API ──────────────────────────────────────
+/// Build the non-streaming chat completion, honouring `--omit-usage`,
+/// `--service-tier` and `--echo-exact-model`.
+fn openai_response(
+    config: &Config,
+    result: &ResultMessage,
+    request_id: &str,
+    requested_model: &str,
+) -> ChatCompletionResponse {
+    let mut response = cli_to_openai::cli_result_to_openai(result, request_id, requested_model);
+    if config.omit_usage {
+        response.usage = None;
+    }
+    response.service_tier = config.service_tier.clone();
+    if config.echo_exact_model
+        && let Some(model) = cli_to_openai::reported_model(result)
+    {
+        response.model = model.to_string();
+    }
+    response
+}
 
-pub async fn messages(
-    State(state): State<AppState>,
-    Json(request): Json<MessagesRequest>,
-) -> Result<Response, AppError> {
-    if request.messages.is_empty() {
-        return Err(AppError::BadRequest(
-            "messages is required and must be a non-empty array".to_string(),
-        ));
+/// Append the CLI's stderr tail to an exit error so clients see why it failed.
+fn with_stderr(msg: String, stderr: Option<&str>) -> String {
+    match stderr {
+        Some(stderr) => format!("{msg}. CLI stderr:\n{stderr}"),
+        None => msg,
     }
+}
 
-    let request_id = generate_request_id();
-    let is_streaming = request.stream;
+/// Error for a run that exited without a result. Auth, rate-limit and quota
+/// failures recognized in stderr keep their status; anything else is a 500.
+fn exit_error(msg: String, stderr: Option<&str>) -> AppError {
+    let message = with_stderr(msg, stderr);
+    match stderr.and_then(subprocess::classify_failure) {
+        Some(failure) => AppError::Upstream {
+            status: failure.status(),
+            error_type: failure.error_type(),
+            message,
+        },
+        None => AppError::Subprocess(message),
+    }
+}
 
-    let (model, prompt, session_id) = anthropic_to_cli::anthropic_to_cli(&request);
+/// Error `type` for a stream ending in a failed exit (see `exit_error`).
+fn exit_error_type(stderr: Option<&str>) -> &'static str {
+    stderr
+        .and_then(subprocess::classify_failure)
+        .map_or("server_error", UpstreamFailure::error_type)
+}
 
-    info!("[req={request_id}] Anthropic messages model={model} streaming={is_streaming}");
+/// Log an accepted request with the model as requested, the CLI alias it runs
+/// as, and the normalized name responses report, also as structured fields.
+/// The API, CLI alias and key label (if any) go on the `request` span.
+fn log_request(
+    client: Option<&ApiKeyLabel>,
+    api: &str,
+    requested_model: &str,
+    cli_model: &str,
+    streaming: bool,
+) {
+    let span = Span::current();
+    span.record("api", api);
+    span.record("model", cli_model);
+    if let Some(client) = client {
+        span.record("client", client.0.as_str());
+    }
+    let response_model = cli_to_openai::normalize_model_name(cli_model);
+    info!(
+        requested_model,
+        cli_model,
+        response_model,
+        streaming,
+        "{api} model={requested_model} cli_model={cli_model} response_model={response_model} \
+         streaming={streaming}"
+    );
+}
 
-    let options = SubprocessOptions {
-        request_id: request_id.clone(),
-        model: model.to_string(),
-        session_id,
-        cwd: state.cwd.clone(),
-        api: "anthropic",
-    };
+/// Tokens a non-streaming run used and, with `--model-price`, what they
+/// cost; attached to the response for `log_completion`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RunUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+    cost_usd: Option<f64>,
+}
 
-    if is_streaming {
-        handle_messages_streaming(request_id, prompt, options).await
-    } else {
-        let start = Instant::now();
-        let result = handle_messages_non_streaming(request_id.clone(), prompt, options).await;
-        let elapsed = start.elapsed().as_secs_f64();
-        match &result {
-            Ok(_) => info!("[req={request_id}] Request complete after {elapsed:.2}s"),
-            Err(e) => error!("[req={request_id}] Request failed after {elapsed:.2}s: {e}"),
+/// Sum the result's `modelUsage`, pricing each model by `--model-price`.
+/// No cost unless at least one of its models has a price.
+fn run_usage(result: &ResultMessage, prices: &[config::ModelPrice]) -> RunUsage {
+    let usage = cli_to_anthropic::usage(result);
+    let mut cost_usd = None;
+    for (model, u) in result.model_usage.iter().flatten() {
+        if let Some(price) = config::price_for_model(prices, model) {
+            let input = u.input_tokens.unwrap_or(0) as f64 * price.input;
+            let output = u.output_tokens.unwrap_or(0) as f64 * price.output;
+            *cost_usd.get_or_insert(0.0) += (input + output) / 1_000_000.0;
         }
-        result
+    }
+    RunUsage {
+        input_tokens: usage.input_tokens,
+        output_tokens: usage.output_tokens,
+        cost_usd,
     }
 }
 
-async fn handle_messages_non_streaming(
-    request_id: String,
-    prompt: String,
-    options: SubprocessOptions,
-) -> Result<Response, AppError> {
-    let (tx, mut rx) = mpsc::channel::<SubprocessEvent>(64);
-
-    tokio::spawn(async move {
-        subprocess::spawn_subprocess(prompt, options, tx).await;
-    });
-
-    let mut result_msg = None;
-    let mut error_msg = None;
-    let mut exit_code = None;
-
-    while let Some(event) = rx.recv().await {
-        match event {
-            SubprocessEvent::Result(result) => {
-                result_msg = Some(result);
-            }
-            SubprocessEvent::Error(msg) => {
-                error_msg = Some(msg);
-            }
-            SubprocessEvent::Close(code) => {
-                exit_code = Some(code);
-            }
-            _ => {}
+/// The final line of a non-streaming request: how long it took and, when it
+/// succeeded, its tokens and estimated cost, also as structured fields.
+fn log_completion(elapsed: f64, result: &Result<Response, AppError>) {
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Request failed after {elapsed:.2}s: {e}");
+            return;
         }
+    };
+    let Some(usage) = response.extensions().get::<RunUsage>() else {
+        info!("Request complete after {elapsed:.2}s");
+        return;
+    };
+    let RunUsage {
+        input_tokens,
+        output_tokens,
+        cost_usd,
+    } = *usage;
+    let cost = cost_usd.map(|c| format!(" cost=${c:.4}")).unwrap_or_default();
+    info!(
+        elapsed_secs = elapsed,
+        input_tokens,
+        output_tokens,
+        cost_usd,
+        "Request complete after {elapsed:.2}s input_tokens={input_tokens} \
+         output_tokens={output_tokens}{cost}"
+    );
+}
+
+/// Headers flagging that history trimming dropped part of the conversation.
+fn truncation_headers(dropped_turns: &[usize]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if !dropped_turns.is_empty() {
+        headers.insert("x-prompt-truncated", HeaderValue::from_static("true"));
     }
+    headers
+}
 
-    if let Some(err) = error_msg {
-        return Err(AppError::Subprocess(err));
+fn log_dropped_turns(dropped_turns: &[usize]) {
+    if !dropped_turns.is_empty() {
+        info!(
+            "Prompt truncated: dropped {} oldest turns (message indices {:?})",
+            dropped_turns.len(),
+            dropped_turns
+        );
     }
+}
 
-    if let Some(result) = result_msg {
-        let response = cli_to_anthropic::cli_result_to_anthropic(&result, &request_id);
-        Ok((
-            [(header::HeaderName::from_static("x-request-id"), request_id)],
-            Json(response),
-        )
-            .into_response())
-    } else {
-        let code = exit_code.unwrap_or(-1);
-        Err(AppError::Subprocess(format!(
-            "Process exited with code {} without producing a response",
-            code
-        )))
+/// History trimming limits from the server configuration.
+fn history_limits(config: &Config) -> HistoryLimits {
+    HistoryLimits {
+        max_turns: config.max_prompt_turns,
+        max_tokens: config.max_prompt_tokens,
     }
 }
 
-async fn handle_messages_streaming(
-    request_id: String,
-    prompt: String,
-    options: SubprocessOptions,
-) -> Result<Response, AppError> {
-    let (tx, mut rx) = mpsc::channel::<SubprocessEvent>(64);
+/// Validate the `X-Claude-Extra-Args` header, if present.
+fn extra_args(config: &Config, headers: &HeaderMap) -> Result<Vec<String>, AppError> {
+    let Some(raw) = headers.get("x-claude-extra-args") else {
+        return Ok(Vec::new());
+    };
+    if !config.allow_extra_args {
+        return Err(AppError::BadRequest(
+            "X-Claude-Extra-Args is not enabled on this server".to_string(),
+        ));
+    }
+    let raw = raw
+        .to_str()
+        .map_err(|_| AppError::BadRequest("X-Claude-Extra-Args must be ASCII".to_string()))?;
+    subprocess::parse_extra_args(raw).map_err(AppError::BadRequest)
+}
 
-    tokio::spawn(async move {
-        subprocess::spawn_subprocess(prompt, options, tx).await;
-    });
+/// The `X-Claude-Cwd` directory, if the request set one: resolved, and
+/// within an `--allowed-cwd-root`.
+fn request_cwd(config: &Config, headers: &HeaderMap) -> Result<Option<String>, AppError> {
+    let Some(raw) = headers.get("x-claude-cwd") else {
+        return Ok(None);
+    };
+    if config.allowed_cwd_roots.is_empty() {
+        return Err(AppError::BadRequest(
+            "X-Claude-Cwd is not enabled on this server".to_string(),
+        ));
+    }
+    let raw = raw
+        .to_str()
+        .map_err(|_| AppError::BadRequest("X-Claude-Cwd must be ASCII".to_string()))?;
+    config::resolve_allowed_cwd(raw.trim(), &config.allowed_cwd_roots)
+        .map(Some)
+        .map_err(|e| AppError::BadRequest(format!("X-Claude-Cwd {raw}: {e}")))
+}
 
-    let req_id = request_id.clone();
-    let (sse_tx, sse_rx) = mpsc::channel::<Result<Event, Infallible>>(64);
+/// The directory a run for `model` starts in: the request's own
+/// `X-Claude-Cwd`, else the model's `--cwd-per-model`, else `--cwd`.
+fn run_cwd(state: &AppState, model: &str, cwd_override: Option<String>) -> String {
+    cwd_override.unwrap_or_else(|| {
+        config::cwd_for_model(&state.config.model_cwds, model, &state.cwd).to_string()
+    })
+}
 
-    tokio::spawn(async move {
-        let mut last_model = "claude-sonnet-4".to_string();
-        let mut sent_start = false;
-        let mut output_tokens: u64 = 0;
+/// Options shared by every API's runs of `model`: the server settings, the
+/// client's session, and whatever the request headers validly ask for.
+/// Handlers fill in their own sampling, limits and system prompt.
+///
+/// A client session id (namespaced by `--session-salt`) is looked up in the
+/// session store, which maps it to the CLI session id the run is started
+/// with (`--session-id`), created on first use. The CLI keeps no session
+/// history (`--no-session-persistence`), so nothing is resumed: the id ties
+/// a client's runs together, and the prompt carries the conversation.
+async fn subprocess_options(
+    state: &AppState,
+    headers: &HeaderMap,
+    api: &'static str,
+    model: &str,
+    session_id: Option<String>,
+) -> Result<SubprocessOptions, AppError> {
+    let config = &state.config;
+    let extra_args = extra_args(config, headers)?;
+    let cwd_override = request_cwd(config, headers)?;
+    let trace_logging = trace_logging(config, headers)?;
+    let session_id = match session_id {
+        Some(id) => {
+            // Salted keys keep the client's id, so `/v1/sessions` lists it
+            let client_id = config.session_salt.is_some().then_some(id.as_str());
+            let key = session_key(config, &id);
+            Some(state.session_manager.get_or_create(&key, client_id, model).await)
+        }
+        None => None,
+    };
+    Ok(SubprocessOptions {
+        model: model.to_string(),
+        session_id,
+        claude_bin: config.claude_bin.clone(),
+        cwd: run_cwd(state, model, cwd_override),
+        api,
+        inactivity_timeout: config.inactivity_timeout,
+        max_duration: config.max_request_duration,
+        env: config::forwarded_env(&config.forward_headers, headers),
+        progress_interval: config.progress_log_interval,
+        extra_args,
+        trace_logging,
+        max_output_bytes: config.max_output_bytes,
+        output_encoding: config.output_encoding,
+        sampling: Sampling::default(),
+        max_tokens: None,
+        stop_sequences: Vec::new(),
+        permissions: config.permissions.clone(),
+        system_prompt: None,
+    })
+}
 
-        while let Some(event) = rx.recv().await {
-            match event {
-                SubprocessEvent::Model(model) => {
-                    last_model = model;
-                }
-                SubprocessEvent::ContentDelta(text) => {
-                    // Lazily emit message_start + ping + content_block_start on first delta
-                    if !sent_start {
-                        let start = cli_to_anthropic::create_message_start(&req_id, &last_model);
-                        if send_named_event(&sse_tx, "message_start", &start).await.is_err() {
-                            return;
-                        }
-                        let ping = cli_to_anthropic::create_ping();
-                        if send_named_event(&sse_tx, "ping", &ping).await.is_err() {
-                            return;
-                        }
-                        let block_start = cli_to_anthropic::create_content_block_start();
-                        if send_named_event(&sse_tx, "content_block_start", &block_start)
-                            .await
-                            .is_err()
-                        {
-                            return;
-                        }
-                        sent_start = true;
-                    }
+/// Whether the `X-Debug` header asks for trace-level logging of this request.
+fn trace_logging(config: &Config, headers: &HeaderMap) -> Result<bool, AppError> {
+    let Some(value) = headers.get("x-debug") else {
+        return Ok(false);
+    };
+    if !config.allow_debug_header {
+        return Err(AppError::BadRequest(
+            "X-Debug is not enabled on this server".to_string(),
+        ));
+    }
+    Ok(value.as_bytes().eq_ignore_ascii_case(b"true"))
+}
 
-                    let delta = cli_to_anthropic::create_content_block_delta(&text);
-                    if send_named_event(&sse_tx, "content_block_delta", &delta)
-                        .await
-                        .is_err()
-                    {
-                        return;
-                    }
-                }
-                SubprocessEvent::Result(result) => {
-                    // Extract output token count from result
-                    if let Some(mu) = &result.model_usage {
-                        for u in mu.values() {
-                            output_tokens += u.output_tokens.unwrap_or(0);
-                        }
-                    }
+/// Whether the client asked for the concatenated text at the end of a stream
+/// via `X-Stream-Trailer: true`.
+fn wants_stream_trailer(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-stream-trailer")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("true") || v.trim() == "1")
+}
 
-                    // If we never sent start (empty response), emit it now
-                    if !sent_start {
-                        let start = cli_to_anthropic::create_message_start(&req_id, &last_model);
-                        let _ = send_named_event(&sse_tx, "message_start", &start).await;
-                        let ping = cli_to_anthropic::create_ping();
-                        let _ = send_named_event(&sse_tx, "ping", &ping).await;
-                        let block_start = cli_to_anthropic::create_content_block_start();
-                        let _ =
-                            send_named_event(&sse_tx, "content_block_start", &block_start).await;
-                    }
+/// Reject session ids that aren't plain tokens (ASCII letters, digits, `-`
+/// and `_`) of bounded length. They are passed to the CLI as `--session-id`,
+/// so anything else could be misread as a flag or a path.
+fn check_session_id(session_id: Option<&str>, max_len: usize) -> Result<(), AppError> {
+    let Some(id) = session_id else {
+        return Ok(());
+    };
+    if id.is_empty() || id.len() > max_len {
+        return Err(AppError::BadRequest(format!(
+            "session id must be 1 to {max_len} characters"
+        )));
+    }
+    let plain = id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !plain || id.starts_with('-') {
+        return Err(AppError::BadRequest(
+            "session id may only contain letters, digits, '-' and '_', and must not start with '-'"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
 
-                    let block_stop = cli_to_anthropic::create_content_block_stop();
-                    let _ = send_named_event(&sse_tx, "content_block_stop", &block_stop).await;
+/// Validate sampling parameters against the API's documented ranges
+/// (`temperature` 0 to `max_temperature`, `top_p` 0 to 1).
+fn check_sampling(
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_temperature: f64,
+) -> Result<Sampling, AppError> {
+    if let Some(t) = temperature
+        && !(0.0..=max_temperature).contains(&t)
+    {
+        return Err(AppError::BadRequest(format!(
+            "temperature must be between 0 and {max_temperature}, got {t}"
+        )));
+    }
+    if let Some(p) = top_p
+        && !(0.0..=1.0).contains(&p)
+    {
+        return Err(AppError::BadRequest(format!(
+            "top_p must be between 0 and 1, got {p}"
+        )));
+    }
+    Ok(Sampling { temperature, top_p })
+}
 
-                    let msg_delta = cli_to_anthropic::create_message_delta(output_tokens);
-                    let _ = send_named_event(&sse_tx, "message_delta", &msg_delta).await;
+/// Most stop sequences a request may carry, as on the OpenAI and Anthropic APIs.
+const MAX_STOP_SEQUENCES: usize = 4;
 
-                    let msg_stop = cli_to_anthropic::create_message_stop();
-                    let _ = send_named_event(&sse_tx, "message_stop", &msg_stop).await;
-                }
-                SubprocessEvent::Error(msg) => {
-                    let err = to_anthropic_error("server_error", &msg);
-                    if let Ok(json) = serde_json::to_string(&err) {
-                        let event = Event::default().event("error").data(json);
-                        let _ = sse_tx.send(Ok(event)).await;
-                    }
-                }
-                SubprocessEvent::Close(code) => {
-                    if !sent_start && code != 0 {
-                        let err = to_anthropic_error(
-                            "server_error",
-                            &format!("Process exited with code {}", code),
-                        );
-                        if let Ok(json) = serde_json::to_string(&err) {
-                            let event = Event::default().event("error").data(json);
-                            let _ = sse_tx.send(Ok(event)).await;
-                        }
-                    }
-                }
-            }
-        }
-    });
+/// Validate client stop sequences: at most `MAX_STOP_SEQUENCES`, none empty.
+fn check_stop_sequences(stop: Option<&[String]>) -> Result<Vec<String>, AppError> {
+    let stop = stop.unwrap_or_default();
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(AppError::BadRequest(format!(
+            "At most {MAX_STOP_SEQUENCES} stop sequences are allowed, got {}",
+            stop.len()
+        )));
+    }
+    if stop.iter().any(String::is_empty) {
+        return Err(AppError::BadRequest("Stop sequences must not be empty".to_string()));
+    }
+    Ok(stop.to_vec())
+}
 
-    let stream = ReceiverStream::new(sse_rx);
-    let sse = Sse::new(stream).keep_alive(KeepAlive::default());
+/// Reject a zero token cap, which the CLI would treat as unset.
+fn check_max_tokens(max_tokens: Option<u64>) -> Result<Option<u64>, AppError> {
+    if max_tokens == Some(0) {
+        return Err(AppError::BadRequest("max_tokens must be at least 1".to_string()));
+    }
+    Ok(max_tokens)
+}
 
-    Ok((
-        [
-            (
-                header::HeaderName::from_static("x-request-id"),
-                request_id,
-            ),
-            (header::CACHE_CONTROL, "no-cache".to_string()),
-        ],
-        sse,
-    )
-        .into_response())
+/// The chat completion token cap: `max_completion_tokens`, OpenAI's newer
+/// name, or `max_tokens`. Sending both is allowed only with the same value.
+fn check_chat_max_tokens(
+    max_tokens: Option<u64>,
+    max_completion_tokens: Option<u64>,
+) -> Result<Option<u64>, AppError> {
+    match (max_tokens, max_completion_tokens) {
+        (Some(legacy), Some(newer)) if legacy != newer => Err(AppError::BadRequest(format!(
+            "max_tokens ({legacy}) and max_completion_tokens ({newer}) conflict; send only one"
+        ))),
+        (_, Some(0)) => Err(AppError::BadRequest(
+            "max_completion_tokens must be at least 1".to_string(),
+        )),
+        (legacy, None) => check_max_tokens(legacy),
+        (_, newer) => Ok(newer),
+    }
 }
 
-/// Serialize and send a named SSE event.
-async fn send_named_event<T: serde::Serialize>(
-    tx: &mpsc::Sender<Result<Event, Infallible>>,
-    event_name: &str,
-    data: &T,
-) -> Result<(), ()> {
-    match serde_json::to_string(data) {
-        Ok(json) => {
-            let event = Event::default().event(event_name).data(json);
-            tx.send(Ok(event)).await.map_err(|_| ())
-        }
-        Err(e) => {
-            error!("Failed to serialize {} event: {}", event_name, e);
-            Err(())
-        }
+/// Reject prompts that would carry an unreasonable number of `<system>` blocks.
+fn check_system_count(count: usize, max: usize) -> Result<(), AppError> {
+    if count > max {
+        return Err(AppError::BadRequest(format!(
+            "too many system messages: {count} (maximum is {max})"
+        )));
     }
+    Ok(())
+}
+
+/// Reject requests carrying images: the CLI is sent a text prompt, so they
+/// would be dropped and the model would answer without them.
+fn check_no_images(has_images: bool) -> Result<(), AppError> {
+    if has_images {
+        return Err(AppError::BadRequest(
+            "image content is not supported; send text only".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Longest inbound request id that is honored.
+const MAX_INBOUND_REQUEST_ID_LEN: usize = 128;
+
+/// Use the caller's request id from the configured header when it is a
+/// plain token, otherwise generate one.
+pub(crate) fn request_id(config: &Config, headers: &HeaderMap) -> String {
+    headers
+        .get(&config.request_id_header)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_INBOUND_REQUEST_ID_LEN
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+        })
+        .map(str::to_string)
+        .unwrap_or_else(generate_request_id)
+}
+
+fn generate_request_id() -> String {
+    uuid::Uuid::new_v4()
+        .to_string()
+        .replace('-', "")
+        .chars()
+        .take(8)
+        .collect()
+}
+
+/// Liveness and counters. Served without auth, so nothing about the host or
+/// request contents: the cwd and the last error are on `/v1/config`.
+pub async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    let uptime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    Json(json!({
+        "status": "ok",
+        "uptime": uptime,
+        "requests": state.metrics.snapshot(),
+        // False until saved sessions have been read after startup
+        "sessions_loaded": state.session_manager.is_loaded(),
+    }))
+}
+
+/// Versions and effective settings, for operators debugging a deployment.
+/// Secrets stay out: API keys are listed by label, and the admin key and
+/// session salt only as whether one is set.
+pub async fn runtime_config(State(state): State<AppState>) -> impl IntoResponse {
+    let config = &state.config;
+    let secs = |d: std::time::Duration| d.as_secs();
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "cli_version": config.cli_version,
+        "claude_bin": config.claude_bin,
+        // Working directory the CLI subprocess runs in
+        "cwd": state.cwd,
+        "last_error": state.metrics.last_error(),
+        "allowed_tools": config.permissions.allowed_tools,
+        "disallowed_tools": config.permissions.disallowed_tools,
+        "model_cwds": config
+            .model_cwds
+            .iter()
+            .map(|m| json!({"model": m.model, "cwd": m.cwd}))
+            .collect::<Vec<_>>(),
+        "allowed_cwd_roots": config.allowed_cwd_roots,
+        "models": config.models,
+        "api_key_labels": config.api_keys.iter().map(|k| &k.label).collect::<Vec<_>>(),
+        "session_salt_set": config.session_salt.is_some(),
+        "admin_key_set": config.admin_key.is_some(),
+        "max_concurrency": config.max_concurrency,
+        "concurrency_wait_ms": config.concurrency_wait.as_millis() as u64,
+        "session_wait_secs": secs(config.session_wait),
+        "inactivity_timeout_secs": secs(config.inactivity_timeout),
+        "max_request_secs": config.max_request_duration.map(secs),
+        "shutdown_grace_secs": secs(config.shutdown_grace),
+        "retry_on_crash": config.retry_on_crash,
+        "model_fallbacks": config
+            .model_fallbacks
+            .iter()
+            .map(|m| json!({"model": m.model, "fallback": m.fallback}))
+            .collect::<Vec<_>>(),
+        "model_prices": config
+            .model_prices
+            .iter()
+            .map(|p| json!({"model": p.model, "input": p.input, "output": p.output}))
+            .collect::<Vec<_>>(),
+        "warm_pool": config.warm_pool,
+        "max_usage_models": config.max_usage_models,
+        "max_output_bytes": config.max_output_bytes,
+        "max_system_messages": config.max_system_messages,
+        "max_prompt_turns": config.max_prompt_turns,
+        "max_prompt_tokens": config.max_prompt_tokens,
+        "max_session_id_len": config.max_session_id_len,
+        "forward_headers": config
+            .forward_headers
+            .iter()
+            .map(|f| json!({"header": f.header.as_str(), "env": f.env}))
+            .collect::<Vec<_>>(),
+        "allow_extra_args": config.allow_extra_args,
+        "allow_debug_header": config.allow_debug_header,
+        "omit_usage": config.omit_usage,
+        "service_tier": config.service_tier,
+        "echo_exact_model": config.echo_exact_model,
+        "request_id_header": config.request_id_header.as_str(),
+        "sse_no_buffering": config.sse_no_buffering,
+        "sse_content_type": config.sse_content_type.as_ref().and_then(|v| v.to_str().ok()),
+        "stream_coalesce_ms": config.stream_coalesce.map(|d| d.as_millis() as u64),
+        "debug_features": config.debug_features,
+    }))
+}
+
+pub async fn models(State(state): State<AppState>) -> impl IntoResponse {
+    Json(ModelsResponse {
+        object: "list".to_string(),
+        data: state.config.models.clone(),
+    })
+}
+
+pub async fn model(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ModelInfo>, AppError> {
+    state
+        .config
+        .models
+        .iter()
+        .find(|m| m.id == id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| AppError::ModelNotFound(format!("The model '{id}' does not exist")))
+}
+
+/// Register a request so its owner, the API key it authenticated with, can
+/// cancel it by id (see `cancel_request`). Fails with 409 while the same
+/// owner has another request with this id in flight.
+fn register_request(
+    state: &AppState,
+    client: Option<&ApiKeyLabel>,
+    request_id: &str,
+) -> Result<CancelGuard, AppError> {
+    let owner = client.map(|c| c.0.as_str());
+    state.cancellations.register(owner, request_id).ok_or_else(|| {
+        warn!("Rejected status=409: request id {request_id} is already in flight");
+        AppError::Conflict(format!("A request with id '{request_id}' is already in flight"))
+    })
+}
+
+/// Answer a non-streaming request: run the CLI to its result (see
+/// `run_to_result`) and `build` the API's response body from it and the
+/// requested model. Logs how the request ended.
+async fn respond<T: serde::Serialize>(
+    state: &AppState,
+    client: Option<&ApiKeyLabel>,
+    request_id: &str,
+    prompt: String,
+    options: SubprocessOptions,
+    extra_headers: HeaderMap,
+    build: impl FnOnce(&ResultMessage, &str) -> T,
+) -> Result<Response, AppError> {
+    let cancel = register_request(state, client, request_id)?;
+    let start = Instant::now();
+    let requested_model = options.model.clone();
+    let response = run_to_result(state, &cancel, prompt, options).await.map(
+        |(result, fallback_headers)| {
+            let body = build(&result, &requested_model);
+            warn_lossy_result(&result);
+            let empty_headers = empty_completion_headers(&result);
+            let usage = run_usage(&result, &state.config.model_prices);
+            (
+                extra_headers,
+                fallback_headers,
+                empty_headers,
+                Extension(usage),
+                Json(body),
+            )
+                .into_response()
+        },
+    );
+    log_completion(start.elapsed().as_secs_f64(), &response);
+    response
+}
+
+/// Answer a streaming request: start the CLI and hand its (coalesced)
+/// events to `stream`, which turns them into the API's SSE events given the
+/// request id and requested model. It runs in its own task, which keeps the
+/// request registered; returning early on disconnect drops the events, which
+/// kills the subprocess.
+async fn respond_streaming<F, Fut>(
+    state: &AppState,
+    client: Option<&ApiKeyLabel>,
+    request_id: String,
+    prompt: String,
+    options: SubprocessOptions,
+    extra_headers: HeaderMap,
+    stream: F,
+) -> Result<Response, AppError>
+where
+    F: FnOnce(mpsc::Receiver<SubprocessEvent>, SseSender, String, String) -> Fut,
+    Fut: Future<Output = Result<(), Disconnected>> + Send + 'static,
+{
+    let cancel = register_request(state, client, &request_id)?;
+    let requested_model = options.model.clone();
+    let rx = start_subprocess(state, &cancel, prompt, options).await?;
+    let rx = coalesce_deltas(rx, state.config.stream_coalesce);
+
+    let (sse_tx, sse_rx) =
+        SseSender::channel(state.metrics.clone(), sse::DEFAULT_STALL_THRESHOLD);
+    let forward = stream(rx, sse_tx, request_id, requested_model);
+    tokio::spawn(
+        async move {
+            let _registered = cancel;
+            let _ = forward.await;
+        }
+        .in_current_span(),
+    );
+
+    let sse = Sse::new(ReceiverStream::new(sse_rx)).keep_alive(KeepAlive::default());
+    Ok((
+        [(header::CACHE_CONTROL, "no-cache")],
+        extra_headers,
+        streaming_headers(&state.config),
+        sse,
+    )
+        .into_response())
+}
+
+pub async fn chat_completions(
+    State(state): State<AppState>,
+    client: Option<Extension<ApiKeyLabel>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, AppError> {
+    // Validate messages
+    let messages = request.messages.as_ref().ok_or_else(|| {
+        AppError::BadRequest("messages is required and must be a non-empty array".to_string())
+    })?;
+    if messages.is_empty() {
+        return Err(AppError::BadRequest(
+            "messages is required and must be a non-empty array".to_string(),
+        ));
+    }
+    check_system_count(
+        openai_to_cli::count_system_messages(messages),
+        state.config.max_system_messages,
+    )?;
+    check_no_images(openai_to_cli::has_image_content(messages))?;
+    let sampling = check_sampling(request.temperature, request.top_p, 2.0)?;
+    let max_tokens = check_chat_max_tokens(request.max_tokens, request.max_completion_tokens)?;
+    let stop_sequences = check_stop_sequences(request.stop.as_deref())?;
+
+    let is_streaming = request.stream;
+    let include_usage = request
+        .stream_options
+        .as_ref()
+        .is_some_and(|o| o.include_usage);
+
+    let tools = openai_to_cli::offers_tools(&request);
+
+    let limits = history_limits(&state.config);
+    let system_mode = state.config.system_prompt_mode;
+    let cli = openai_to_cli::openai_to_cli(&request, limits, &state.config.models, system_mode);
+    check_session_id(cli.session_id.as_deref(), state.config.max_session_id_len)?;
+    let model = cli.model;
+
+    let requested_model = request.model.as_deref().unwrap_or_default();
+    let api = "OpenAI chat completions";
+    log_request(client.as_deref(), api, requested_model, model, is_streaming);
+    log_dropped_turns(&cli.dropped_turns);
+
+    let prompt = cli.prompt;
+    let options = SubprocessOptions {
+        sampling,
+        max_tokens,
+        stop_sequences,
+        system_prompt: cli.system_prompt,
+        ..subprocess_options(&state, &headers, "openai", model, cli.session_id).await?
+    };
+    let mut extra_headers = debug_headers(&state.config, &options);
+    extra_headers.extend(truncation_headers(&cli.dropped_turns));
+
+    if is_streaming {
+        let stream_options = ChatStreamOptions {
+            trailer: wants_stream_trailer(&headers),
+            include_usage,
+            tools,
+        };
+        let config = state.config.clone();
+        let stream = move |rx, sse_tx, req_id, requested_model| {
+            stream_openai(rx, sse_tx, req_id, requested_model, config, stream_options)
+        };
+        let client = client.as_deref();
+        respond_streaming(&state, client, request_id, prompt, options, extra_headers, stream).await
+    } else {
+        let build = |result: &ResultMessage, requested_model: &str| {
+            let mut response = openai_response(&state.config, result, &request_id, requested_model);
+            if tools {
+                cli_to_openai::apply_tool_calls(&mut response);
+            }
+            response
+        };
+        let client = client.as_deref();
+        respond(&state, client, &request_id, prompt, options, extra_headers, build).await
+    }
+}
+
+/// Applies `--stream-trailing-whitespace` to streamed deltas. When trimming,
+/// each delta's trailing whitespace is held back and prepended to the next
+/// one, so only whitespace at the very end of the stream is dropped.
+struct DeltaTrimmer {
+    mode: TrailingWhitespace,
+    pending: String,
+}
+
+impl DeltaTrimmer {
+    fn new(mode: TrailingWhitespace) -> Self {
+        Self {
+            mode,
+            pending: String::new(),
+        }
+    }
+
+    /// The text to send for a delta, or `None` when there is nothing to send yet.
+    fn push(&mut self, text: String) -> Option<String> {
+        if self.mode == TrailingWhitespace::Preserve {
+            return Some(text);
+        }
+        let mut out = std::mem::take(&mut self.pending);
+        out.push_str(&text);
+        let kept = out.trim_end().len();
+        self.pending = out.split_off(kept);
+        (!out.is_empty()).then_some(out)
+    }
+}
+
+/// Once this much text is pending, coalesced deltas are sent without waiting.
+const COALESCE_MAX_BYTES: usize = 4096;
+
+/// Applies `--stream-coalesce-ms` to a streaming run's events. Content deltas
+/// are held for up to `window` after the first pending one (or until
+/// `COALESCE_MAX_BYTES` accumulate) and sent as one delta. Any other event
+/// flushes the pending text first, so nothing overtakes it. Without a window
+/// the events pass through untouched.
+fn coalesce_deltas(
+    mut rx: mpsc::Receiver<SubprocessEvent>,
+    window: Option<Duration>,
+) -> mpsc::Receiver<SubprocessEvent> {
+    let Some(window) = window else {
+        return rx;
+    };
+    let (tx, out) = mpsc::channel(64);
+    tokio::spawn(
+        async move {
+            let mut pending = String::new();
+            let mut deadline = None;
+            loop {
+                let flush_at = deadline;
+                let flush_timer = async move {
+                    match flush_at {
+                        Some(at) => tokio::time::sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                let mut finished = false;
+                // Returning drops `rx`, which propagates a disconnect
+                let event = tokio::select! {
+                    () = tx.closed() => return,
+                    () = flush_timer => None,
+                    event = rx.recv() => match event {
+                        Some(SubprocessEvent::ContentDelta(text)) => {
+                            pending.push_str(&text);
+                            if pending.len() < COALESCE_MAX_BYTES {
+                                if deadline.is_none() {
+                                    deadline = Some(tokio::time::Instant::now() + window);
+                                }
+                                continue;
+                            }
+                            None
+                        }
+                        Some(event) => Some(event),
+                        None => {
+                            finished = true;
+                            None
+                        }
+                    },
+                };
+                // The window closed, the size limit was hit or another event
+                // arrived: the pending text goes first
+                deadline = None;
+                if !pending.is_empty() {
+                    let text = std::mem::take(&mut pending);
+                    if tx.send(SubprocessEvent::ContentDelta(text)).await.is_err() {
+                        return;
+                    }
+                }
+                if let Some(event) = event
+                    && tx.send(event).await.is_err()
+                {
+                    return;
+                }
+                if finished {
+                    return;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    out
+}
+
+/// Error payload for an OpenAI stream in the configured shape.
+fn openai_stream_error(
+    shape: StreamErrorShape,
+    error_type: &str,
+    message: &str,
+) -> serde_json::Value {
+    match shape {
+        StreamErrorShape::Openai => json!({
+            "error": {
+                "message": message,
+                "type": error_type,
+                "code": null,
+            }
+        }),
+        StreamErrorShape::Anthropic => json!(to_anthropic_error(error_type, message)),
+    }
+}
+
+/// Per-request choices for an OpenAI chat stream.
+#[derive(Debug, Default, Clone, Copy)]
+struct ChatStreamOptions {
+    /// Follow the done chunk with one carrying the concatenated text
+    trailer: bool,
+    /// Send a usage chunk last before `[DONE]` (`stream_options.include_usage`)
+    include_usage: bool,
+    /// The prompt offered tools: hold the text back until the result so
+    /// `<tool_call>` blocks can be sent as `tool_calls` instead
+    tools: bool,
+}
+
+/// Forward subprocess events to the client as OpenAI chat.completion.chunk events,
+/// shaped by `options`. Error shape, whitespace handling, `service_tier` and
+/// whether the exact model is echoed come from `config`.
+async fn stream_openai(
+    mut rx: mpsc::Receiver<SubprocessEvent>,
+    sse_tx: SseSender,
+    req_id: String,
+    requested_model: String,
+    config: Arc<Config>,
+    options: ChatStreamOptions,
+) -> Result<(), Disconnected> {
+    let error_shape = config.openai_stream_error_shape;
+    let with_tier = |mut chunk: ChatCompletionChunk| {
+        chunk.service_tier = config.service_tier.clone();
+        chunk
+    };
+    let mut trimmer = DeltaTrimmer::new(config.stream_trailing_whitespace);
+    let mut is_first = true;
+    // Collected only when the client asked for a trailer
+    let mut full_content = options.trailer.then(String::new);
+    // Text held back until the result when tools were offered
+    let mut held = options.tools.then(String::new);
+    // Until the CLI reports a model, fall back to what the client asked for
+    let mut last_model = cli_to_openai::normalize_model_name(&requested_model).to_string();
+    let response_model =
+        |model: &str| cli_to_openai::response_model_name(model, config.echo_exact_model);
+    let mut saw_model = false;
+    let mut got_result = false;
+
+    // Send initial :ok comment
+    sse_tx.send(Event::default().comment("ok")).await?;
+
+    while let Some(event) = rx.recv().await {
+        match event {
+            // The CLI runs its own tools; clients only see the text
+            SubprocessEvent::ToolUse { .. } => {}
+            SubprocessEvent::Model(model) => {
+                last_model = model;
+                saw_model = true;
+            }
+            SubprocessEvent::ContentDelta(text) => {
+                let Some(text) = trimmer.push(text) else {
+                    continue;
+                };
+                if let Some(full) = full_content.as_mut() {
+                    full.push_str(&text);
+                }
+                if let Some(held) = held.as_mut() {
+                    held.push_str(&text);
+                    continue;
+                }
+                let chunk = with_tier(cli_to_openai::create_stream_chunk(
+                    &req_id,
+                    &response_model(&last_model),
+                    &text,
+                    is_first,
+                ));
+                is_first = false;
+                sse_tx.send_json(&chunk).await?;
+            }
+            SubprocessEvent::Result(result) => {
+                got_result = true;
+
+                // A result may arrive without any assistant message (error cases)
+                if !saw_model && let Some(model) = cli_to_openai::reported_model(&result) {
+                    last_model = model.to_string();
+                }
+                let model = response_model(&last_model);
+
+                let mut finish_reason = cli_to_openai::finish_reason(result.stop_reason.as_deref());
+                if let Some(held) = held.take() {
+                    let (text, calls) =
+                        cli_to_openai::extract_tool_calls(&held, &format!("chatcmpl-{req_id}"));
+                    if !text.is_empty() {
+                        let chunk = with_tier(cli_to_openai::create_stream_chunk(
+                            &req_id, &model, &text, is_first,
+                        ));
+                        is_first = false;
+                        sse_tx.send_json(&chunk).await?;
+                    }
+                    if !calls.is_empty() {
+                        let chunk = with_tier(cli_to_openai::create_tool_calls_chunk(
+                            &req_id, &model, calls, is_first,
+                        ));
+                        sse_tx.send_json(&chunk).await?;
+                        finish_reason = "tool_calls";
+                    }
+                }
+
+                // Send done chunk with the finish reason
+                let done_chunk = with_tier(cli_to_openai::create_done_chunk(
+                    &req_id,
+                    &model,
+                    finish_reason,
+                    result.stop_sequence.as_deref(),
+                ));
+                sse_tx.send_json(&done_chunk).await?;
+
+                if let Some(full) = full_content.take() {
+                    let chunk = with_tier(cli_to_openai::create_full_content_chunk(
+                        &req_id,
+                        &model,
+                        &full,
+                    ));
+                    sse_tx.send_json(&chunk).await?;
+                }
+
+                if options.include_usage {
+                    let chunk = with_tier(cli_to_openai::create_usage_chunk(
+                        &req_id,
+                        &model,
+                        &result,
+                    ));
+                    sse_tx.send_json(&chunk).await?;
+                }
+
+                // Send [DONE] sentinel
+                sse_tx.send(Event::default().data("[DONE]")).await?;
+            }
+            SubprocessEvent::Error(msg) => {
+                let err = openai_stream_error(error_shape, "server_error", &msg);
+                sse_tx.send_json(&err).await?;
+            }
+            SubprocessEvent::Close(code, stderr) => {
+                if !got_result && code != 0 {
+                    let msg = format!("Process exited with code {}", code);
+                    let error_type = exit_error_type(stderr.as_deref());
+                    let msg = with_stderr(msg, stderr.as_deref());
+                    sse_tx.send_json(&openai_stream_error(error_shape, error_type, &msg)).await?;
+                    sse_tx.send(Event::default().data("[DONE]")).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// ── Anthropic Messages API ──────────────────────────────────────
+
+pub async fn messages(
+    State(state): State<AppState>,
+    client: Option<Extension<ApiKeyLabel>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    Json(request): Json<MessagesRequest>,
+) -> Result<Response, AppError> {
+    if request.messages.is_empty() {
+        return Err(AppError::BadRequest(
+            "messages is required and must be a non-empty array".to_string(),
+        ));
+    }
+    check_system_count(
+        anthropic_to_cli::count_system_blocks(request.system.as_ref()),
+        state.config.max_system_messages,
+    )?;
+    check_no_images(anthropic_to_cli::has_image_content(
+        request.system.as_ref(),
+        &request.messages,
+    ))?;
+    let sampling = check_sampling(request.temperature, request.top_p, 1.0)?;
+    let max_tokens = check_max_tokens(Some(request.max_tokens))?;
+    let stop_sequences = check_stop_sequences(request.stop_sequences.as_deref())?;
+
+    let is_streaming = request.stream;
+    let tools = anthropic_to_cli::offers_tools(&request);
+
+    let limits = history_limits(&state.config);
+    let system_mode = state.config.system_prompt_mode;
+    let cli =
+        anthropic_to_cli::anthropic_to_cli(&request, limits, &state.config.models, system_mode);
+    check_session_id(cli.session_id.as_deref(), state.config.max_session_id_len)?;
+    let model = cli.model;
+
+    log_request(client.as_deref(), "Anthropic messages", &request.model, model, is_streaming);
+    log_dropped_turns(&cli.dropped_turns);
+
+    let prompt = cli.prompt;
+    let options = SubprocessOptions {
+        sampling,
+        max_tokens,
+        stop_sequences,
+        system_prompt: cli.system_prompt,
+        ..subprocess_options(&state, &headers, "anthropic", model, cli.session_id).await?
+    };
+    let mut extra_headers = debug_headers(&state.config, &options);
+    extra_headers.extend(truncation_headers(&cli.dropped_turns));
+
+    if is_streaming {
+        let trailer = wants_stream_trailer(&headers);
+        let config = state.config.clone();
+        let stream = move |rx, sse_tx, req_id, requested_model| {
+            stream_anthropic(rx, sse_tx, req_id, requested_model, config, trailer, tools)
+        };
+        let client = client.as_deref();
+        respond_streaming(&state, client, request_id, prompt, options, extra_headers, stream).await
+    } else {
+        let build = |result: &ResultMessage, requested_model: &str| {
+            let mut response =
+                cli_to_anthropic::cli_result_to_anthropic(result, &request_id, requested_model);
+            if tools {
+                cli_to_anthropic::apply_tool_use(&mut response);
+            }
+            if state.config.echo_exact_model
+                && let Some(model) = cli_to_openai::reported_model(result)
+            {
+                response.model = model.to_string();
+            }
+            response
+        };
+        let client = client.as_deref();
+        respond(&state, client, &request_id, prompt, options, extra_headers, build).await
+    }
+}
+
+/// Send message_start + ping.
+async fn send_message_preamble(
+    sse_tx: &SseSender,
+    req_id: &str,
+    model: &str,
+    usage: ResponseUsage,
+) -> Result<(), Disconnected> {
+    let start = cli_to_anthropic::create_message_start(req_id, model, usage);
+    sse_tx.send_named("message_start", &start).await?;
+    let ping = cli_to_anthropic::create_ping();
+    sse_tx.send_named("ping", &ping).await
+}
+
+/// Forward subprocess events to the client as Anthropic Messages stream events.
+/// With `trailer`, `message_delta` also carries the concatenated text. With
+/// `tools` (the prompt offered tools), text is held back until the result so
+/// `<tool_call>` blocks can be sent as `tool_use` blocks after it. Whitespace
+/// handling and whether the exact model is echoed come from `config`.
+async fn stream_anthropic(
+    mut rx: mpsc::Receiver<SubprocessEvent>,
+    sse_tx: SseSender,
+    req_id: String,
+    requested_model: String,
+    config: Arc<Config>,
+    trailer: bool,
+    tools: bool,
+) -> Result<(), Disconnected> {
+    let mut trimmer = DeltaTrimmer::new(config.stream_trailing_whitespace);
+    let response_model =
+        |model: &str| cli_to_openai::response_model_name(model, config.echo_exact_model);
+    // Until the CLI reports a model, fall back to what the client asked for
+    let mut last_model = cli_to_openai::normalize_model_name(&requested_model).to_string();
+    let mut saw_model = false;
+    // Whether message_start and the text block's start have been sent
+    let mut sent_start = false;
+    // Collected only when the client asked for a trailer
+    let mut full_content = trailer.then(String::new);
+    // Text held back until the result when tools were offered
+    let mut held = tools.then(String::new);
+
+    while let Some(event) = rx.recv().await {
+        match event {
+            // The CLI runs its own tools; clients only see the text
+            SubprocessEvent::ToolUse { .. } => {}
+            SubprocessEvent::Model(model) => {
+                last_model = model;
+                saw_model = true;
+            }
+            SubprocessEvent::ContentDelta(text) => {
+                let Some(text) = trimmer.push(text) else {
+                    continue;
+                };
+                if let Some(held) = held.as_mut() {
+                    held.push_str(&text);
+                    continue;
+                }
+                // Lazily emit the preamble on first delta
+                if !sent_start {
+                    let model = response_model(&last_model);
+                    send_message_preamble(&sse_tx, &req_id, &model, ResponseUsage::default())
+                        .await?;
+                    let block_start = cli_to_anthropic::create_content_block_start();
+                    sse_tx.send_named("content_block_start", &block_start).await?;
+                    sent_start = true;
+                }
+
+                if let Some(full) = full_content.as_mut() {
+                    full.push_str(&text);
+                }
+                let delta = cli_to_anthropic::create_content_block_delta(&text);
+                sse_tx.send_named("content_block_delta", &delta).await?;
+            }
+            SubprocessEvent::Result(result) => {
+                let usage = cli_to_anthropic::usage(&result);
+
+                // A result may arrive without any assistant message (error cases)
+                if !saw_model && let Some(model) = cli_to_openai::reported_model(&result) {
+                    last_model = model.to_string();
+                }
+
+                let message_id = format!("msg_{req_id}");
+                let (text, calls) = match held.take() {
+                    Some(held) => cli_to_anthropic::extract_tool_use(&held, &message_id),
+                    None => (String::new(), Vec::new()),
+                };
+                if let Some(full) = full_content.as_mut() {
+                    full.push_str(&text);
+                }
+
+                // If we never sent start (empty or held response), emit it now,
+                // with the input side of the usage already known
+                if !sent_start {
+                    let model = response_model(&last_model);
+                    let start_usage = ResponseUsage {
+                        output_tokens: 0,
+                        ..cli_to_anthropic::usage(&result)
+                    };
+                    send_message_preamble(&sse_tx, &req_id, &model, start_usage).await?;
+                }
+
+                // The text block comes first; a reply that is only tool calls has none
+                let mut index = 0;
+                if sent_start || !text.is_empty() || calls.is_empty() {
+                    if !sent_start {
+                        let block_start = cli_to_anthropic::create_content_block_start();
+                        sse_tx.send_named("content_block_start", &block_start).await?;
+                    }
+                    if !text.is_empty() {
+                        let delta = cli_to_anthropic::create_content_block_delta(&text);
+                        sse_tx.send_named("content_block_delta", &delta).await?;
+                    }
+                    let block_stop = cli_to_anthropic::create_content_block_stop(index);
+                    sse_tx.send_named("content_block_stop", &block_stop).await?;
+                    index += 1;
+                }
+                sent_start = true;
+
+                // Each call is a tool_use block with its whole input in one delta
+                for call in &calls {
+                    let ContentBlock::ToolUse { id, name, input } = call else {
+                        continue;
+                    };
+                    let start = cli_to_anthropic::create_tool_use_block_start(index, id, name);
+                    sse_tx.send_named("content_block_start", &start).await?;
+                    let json = input.to_string();
+                    let delta = cli_to_anthropic::create_input_json_delta(index, &json);
+                    sse_tx.send_named("content_block_delta", &delta).await?;
+                    let stop = cli_to_anthropic::create_content_block_stop(index);
+                    sse_tx.send_named("content_block_stop", &stop).await?;
+                    index += 1;
+                }
+
+                let stop_reason = if calls.is_empty() {
+                    cli_to_anthropic::stop_reason(result.stop_reason.as_deref())
+                } else {
+                    "tool_use"
+                };
+                // message_delta carries the final usage, input tokens included
+                let mut msg_delta = cli_to_anthropic::create_message_delta(
+                    usage,
+                    stop_reason,
+                    result.stop_sequence.as_deref(),
+                );
+                msg_delta.full_content = full_content.take();
+                sse_tx.send_named("message_delta", &msg_delta).await?;
+
+                let msg_stop = cli_to_anthropic::create_message_stop();
+                sse_tx.send_named("message_stop", &msg_stop).await?;
+            }
+            SubprocessEvent::Error(msg) => {
+                let err = to_anthropic_error("server_error", &msg);
+                sse_tx.send_named("error", &err).await?;
+            }
+            SubprocessEvent::Close(code, stderr) => {
+                if !sent_start && code != 0 {
+                    let msg = format!("Process exited with code {}", code);
+                    let err = to_anthropic_error(
+                        exit_error_type(stderr.as_deref()),
+                        &with_stderr(msg, stderr.as_deref()),
+                    );
+                    sse_tx.send_named("error", &err).await?;
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 /// Convert an error to an Anthropic-format error response.
@@ -527,6 +1618,2806 @@ fn to_anthropic_error(error_type: &str, message: &str) -> AnthropicErrorResponse
     }
 }
 
-pub async fn fallback() -> impl IntoResponse {
-    AppError::NotFound("The requested endpoint does not exist".to_string())
+/// Estimate a Messages request's input tokens from the prompt it would be
+/// sent as, without running the CLI.
+pub async fn count_tokens(
+    Json(request): Json<CountTokensRequest>,
+) -> Result<Json<CountTokensResponse>, AppError> {
+    if request.messages.is_empty() {
+        return Err(AppError::BadRequest(
+            "messages is required and must be a non-empty array".to_string(),
+        ));
+    }
+    let prompt = anthropic_to_cli::messages_to_prompt(request.system.as_ref(), &request.messages);
+    Ok(Json(CountTokensResponse {
+        input_tokens: estimate_tokens(&prompt) as u64,
+    }))
+}
+
+// ── Legacy Text Completions API ─────────────────────────────────
+
+pub async fn complete(
+    State(state): State<AppState>,
+    client: Option<Extension<ApiKeyLabel>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    Json(request): Json<CompleteRequest>,
+) -> Result<Response, AppError> {
+    if request.prompt.trim().is_empty() {
+        return Err(AppError::BadRequest(
+            "prompt is required and must be a non-empty string".to_string(),
+        ));
+    }
+    let max_tokens = check_max_tokens(Some(request.max_tokens_to_sample))?;
+
+    let is_streaming = request.stream;
+
+    let limits = history_limits(&state.config);
+    let system_mode = state.config.system_prompt_mode;
+    let cli = complete_to_cli::complete_to_cli(&request, limits, &state.config.models, system_mode);
+    check_session_id(cli.session_id.as_deref(), state.config.max_session_id_len)?;
+    let model = cli.model;
+
+    log_request(client.as_deref(), "Anthropic complete", &request.model, model, is_streaming);
+    log_dropped_turns(&cli.dropped_turns);
+
+    let prompt = cli.prompt;
+    let options = SubprocessOptions {
+        max_tokens,
+        system_prompt: cli.system_prompt,
+        ..subprocess_options(&state, &headers, "complete", model, cli.session_id).await?
+    };
+    let mut extra_headers = debug_headers(&state.config, &options);
+    extra_headers.extend(truncation_headers(&cli.dropped_turns));
+
+    if is_streaming {
+        let config = state.config.clone();
+        let stream = move |rx, sse_tx, req_id, requested_model| {
+            stream_complete(rx, sse_tx, req_id, requested_model, config)
+        };
+        let client = client.as_deref();
+        respond_streaming(&state, client, request_id, prompt, options, extra_headers, stream).await
+    } else {
+        let build = |result: &ResultMessage, requested_model: &str| {
+            let mut response =
+                cli_to_complete::cli_result_to_complete(result, &request_id, requested_model);
+            if state.config.echo_exact_model
+                && let Some(model) = cli_to_openai::reported_model(result)
+            {
+                response.model = model.to_string();
+            }
+            response
+        };
+        let client = client.as_deref();
+        respond(&state, client, &request_id, prompt, options, extra_headers, build).await
+    }
+}
+
+/// Forward subprocess events to the client as legacy `completion` events.
+async fn stream_complete(
+    mut rx: mpsc::Receiver<SubprocessEvent>,
+    sse_tx: SseSender,
+    req_id: String,
+    requested_model: String,
+    config: Arc<Config>,
+) -> Result<(), Disconnected> {
+    let mut trimmer = DeltaTrimmer::new(config.stream_trailing_whitespace);
+    let response_model =
+        |model: &str| cli_to_openai::response_model_name(model, config.echo_exact_model);
+    // Until the CLI reports a model, fall back to what the client asked for
+    let mut last_model = cli_to_openai::normalize_model_name(&requested_model).to_string();
+    let mut saw_model = false;
+    let mut got_result = false;
+
+    while let Some(event) = rx.recv().await {
+        match event {
+            // The CLI runs its own tools; clients only see the text
+            SubprocessEvent::ToolUse { .. } => {}
+            SubprocessEvent::Model(model) => {
+                last_model = model;
+                saw_model = true;
+            }
+            SubprocessEvent::ContentDelta(text) => {
+                let Some(text) = trimmer.push(text) else {
+                    continue;
+                };
+                let model = response_model(&last_model);
+                let chunk = cli_to_complete::create_completion_chunk(&req_id, &model, &text);
+                sse_tx.send_named("completion", &chunk).await?;
+            }
+            SubprocessEvent::Result(result) => {
+                got_result = true;
+
+                // A result may arrive without any assistant message (error cases)
+                if !saw_model {
+                    last_model =
+                        cli_to_openai::result_model_name(&result, &last_model).to_string();
+                }
+
+                let stop_reason = cli_to_complete::stop_reason(result.stop_reason.as_deref());
+                let model = response_model(&last_model);
+                let stop = cli_to_complete::create_completion_stop(&req_id, &model, stop_reason);
+                sse_tx.send_named("completion", &stop).await?;
+            }
+            SubprocessEvent::Error(msg) => {
+                let err = to_anthropic_error("server_error", &msg);
+                sse_tx.send_named("error", &err).await?;
+            }
+            SubprocessEvent::Close(code, stderr) => {
+                if !got_result && code != 0 {
+                    let msg = format!("Process exited with code {}", code);
+                    let err = to_anthropic_error(
+                        exit_error_type(stderr.as_deref()),
+                        &with_stderr(msg, stderr.as_deref()),
+                    );
+                    sse_tx.send_named("error", &err).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// ── Legacy OpenAI Completions API ───────────────────────────────
+
+pub async fn completions(
+    State(state): State<AppState>,
+    client: Option<Extension<ApiKeyLabel>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    Json(request): Json<CompletionRequest>,
+) -> Result<Response, AppError> {
+    let has_prompt = request
+        .prompt
+        .as_ref()
+        .is_some_and(|p| p.iter().any(|s| !s.trim().is_empty()));
+    if !has_prompt {
+        return Err(AppError::BadRequest(
+            "prompt is required and must be a non-empty string or array".to_string(),
+        ));
+    }
+    let max_tokens = check_max_tokens(request.max_tokens)?;
+
+    let is_streaming = request.stream;
+
+    let cli = completions_to_cli::completions_to_cli(&request, &state.config.models);
+    check_session_id(cli.session_id.as_deref(), state.config.max_session_id_len)?;
+    let model = cli.model;
+
+    let requested_model = request.model.as_deref().unwrap_or_default();
+    log_request(client.as_deref(), "OpenAI completions", requested_model, model, is_streaming);
+
+    let prompt = cli.prompt;
+    let options = SubprocessOptions {
+        max_tokens,
+        ..subprocess_options(&state, &headers, "completions", model, cli.session_id).await?
+    };
+    let extra_headers = debug_headers(&state.config, &options);
+
+    if is_streaming {
+        let error_shape = state.config.openai_stream_error_shape;
+        let whitespace = state.config.stream_trailing_whitespace;
+        let stream = move |rx, sse_tx, req_id, requested_model| {
+            stream_completions(rx, sse_tx, req_id, requested_model, error_shape, whitespace)
+        };
+        let client = client.as_deref();
+        respond_streaming(&state, client, request_id, prompt, options, extra_headers, stream).await
+    } else {
+        let build = |result: &ResultMessage, requested_model: &str| {
+            let mut response =
+                cli_to_completions::cli_result_to_completion(result, &request_id, requested_model);
+            if state.config.omit_usage {
+                response.usage = None;
+            }
+            response
+        };
+        let client = client.as_deref();
+        respond(&state, client, &request_id, prompt, options, extra_headers, build).await
+    }
+}
+
+/// Forward subprocess events to the client as `text_completion.chunk` events.
+async fn stream_completions(
+    mut rx: mpsc::Receiver<SubprocessEvent>,
+    sse_tx: SseSender,
+    req_id: String,
+    requested_model: String,
+    error_shape: StreamErrorShape,
+    whitespace: TrailingWhitespace,
+) -> Result<(), Disconnected> {
+    let mut trimmer = DeltaTrimmer::new(whitespace);
+    // Until the CLI reports a model, fall back to what the client asked for
+    let mut last_model = cli_to_openai::normalize_model_name(&requested_model).to_string();
+    let mut saw_model = false;
+    let mut got_result = false;
+
+    while let Some(event) = rx.recv().await {
+        match event {
+            // The CLI runs its own tools; clients only see the text
+            SubprocessEvent::ToolUse { .. } => {}
+            SubprocessEvent::Model(model) => {
+                last_model = model;
+                saw_model = true;
+            }
+            SubprocessEvent::ContentDelta(text) => {
+                let Some(text) = trimmer.push(text) else {
+                    continue;
+                };
+                let chunk =
+                    cli_to_completions::create_completion_chunk(&req_id, &last_model, &text);
+                sse_tx.send_json(&chunk).await?;
+            }
+            SubprocessEvent::Result(result) => {
+                got_result = true;
+
+                // A result may arrive without any assistant message (error cases)
+                if !saw_model {
+                    last_model =
+                        cli_to_openai::result_model_name(&result, &last_model).to_string();
+                }
+
+                let finish_reason = cli_to_openai::finish_reason(result.stop_reason.as_deref());
+                let done =
+                    cli_to_completions::create_completion_done(&req_id, &last_model, finish_reason);
+                sse_tx.send_json(&done).await?;
+                sse_tx.send(Event::default().data("[DONE]")).await?;
+            }
+            SubprocessEvent::Error(msg) => {
+                let err = openai_stream_error(error_shape, "server_error", &msg);
+                sse_tx.send_json(&err).await?;
+            }
+            SubprocessEvent::Close(code, stderr) => {
+                if !got_result && code != 0 {
+                    let msg = format!("Process exited with code {}", code);
+                    let error_type = exit_error_type(stderr.as_deref());
+                    let msg = with_stderr(msg, stderr.as_deref());
+                    sse_tx.send_json(&openai_stream_error(error_shape, error_type, &msg)).await?;
+                    sse_tx.send(Event::default().data("[DONE]")).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stop an in-flight request by its id (the request id response header).
+/// Its subprocess is killed; a stream ends with an error event, and a
+/// non-streaming request fails with that error. Only requests sent with the
+/// same API key can be cancelled; anyone else's are reported as not found.
+pub async fn cancel_request(
+    State(state): State<AppState>,
+    client: Option<Extension<ApiKeyLabel>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let owner = client.as_deref().map(|c| c.0.as_str());
+    if !state.cancellations.cancel(owner, &id) {
+        return Err(AppError::NotFound(format!("No in-flight request with id '{id}'")));
+    }
+    info!("Cancelling request {id}");
+    Ok(Json(json!({"id": id, "cancelled": true})))
+}
+
+/// Emergency stop: cancel every in-flight request, as if each had been
+/// sent to `cancel_request`.
+pub async fn cancel_all_requests(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let cancelled = state.cancellations.cancel_all();
+    warn!("Cancelling all in-flight requests: {cancelled} cancelled");
+    Json(json!({"cancelled": cancelled}))
+}
+
+/// A mapping as listed by `/v1/sessions`: under the id the client sent,
+/// which the `/v1/sessions/{id}` routes take, never the CLI's session id.
+fn session_json(session: &SessionMapping) -> serde_json::Value {
+    json!({
+        "id": session.client_id.as_ref().unwrap_or(&session.clawdbot_id),
+        "model": session.model,
+        "created_at": session.created_at,
+        "last_used_at": session.last_used_at,
+    })
+}
+
+pub async fn list_sessions(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let sessions = state.session_manager.list().await;
+    let data: Vec<_> = sessions.iter().map(session_json).collect();
+    Json(json!({"object": "list", "data": data}))
+}
+
+/// The session store key for a client session id (see `subprocess_options`).
+fn session_key(config: &Config, id: &str) -> String {
+    config::namespace_session_id(config.session_salt.as_deref(), Some(id.to_string()))
+        .unwrap_or_default()
+}
+
+/// The store key for a `/v1/sessions/{id}` path id, as `session_json`
+/// listed it: the client's id, salted when `--session-salt` is set, or else
+/// the stored key of a mapping listed under it for lack of a client id.
+async fn listed_session_key(state: &AppState, id: &str) -> String {
+    let key = session_key(&state.config, id);
+    if key != id && state.session_manager.get(&key).await.is_none() {
+        let stored = state.session_manager.get(id).await;
+        if stored.is_some_and(|session| session.client_id.is_none()) {
+            return id.to_string();
+        }
+    }
+    key
+}
+
+pub async fn get_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    match state.session_manager.get(&listed_session_key(&state, &id).await).await {
+        Some(session) => Ok(Json(session_json(&session))),
+        None => Err(AppError::NotFound(format!("No session with id '{id}'"))),
+    }
+}
+
+pub async fn delete_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let key = listed_session_key(&state, &id).await;
+    if state.session_manager.remove(&key).await.is_none() {
+        return Err(AppError::NotFound(format!("No session with id '{id}'")));
+    }
+    info!("Deleted session {id}");
+    Ok(Json(json!({"id": id, "deleted": true})))
+}
+
+/// The CLI can't produce embeddings; answer with an explicit error instead
+/// of the fallback 404 so integrators know to use another provider.
+pub async fn embeddings() -> AppError {
+    AppError::NotImplemented(
+        "Embeddings are not supported by this proxy: the Claude CLI cannot produce them. \
+         Use a dedicated embeddings provider instead."
+            .to_string(),
+    )
+}
+
+pub async fn fallback() -> impl IntoResponse {
+    AppError::NotFound("The requested endpoint does not exist".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::session::SessionManager;
+    use crate::subprocess::{CliRunner, MockCliRunner, RealCliRunner};
+    use crate::types::claude_cli::ModelUsage;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    fn test_state(config: Config) -> AppState {
+        test_state_with(config, Arc::new(RealCliRunner::default()))
+    }
+
+    /// Test state whose CLI runs are served by `cli`.
+    fn test_state_with(config: Config, cli: Arc<dyn CliRunner>) -> AppState {
+        let path =
+            std::env::temp_dir().join(format!("routes-test-{}.json", uuid::Uuid::new_v4()));
+        AppState {
+            cwd: "/tmp".to_string(),
+            session_manager: SessionManager::with_path(path),
+            concurrency: Arc::new(Semaphore::new(config.max_concurrency)),
+            config: Arc::new(config),
+            metrics: Arc::new(Metrics::default()),
+            cli,
+            cancellations: Default::default(),
+            shutdown: tokio::sync::watch::channel(false).1,
+        }
+    }
+
+    fn options(inactivity_timeout: Duration) -> SubprocessOptions {
+        SubprocessOptions {
+            inactivity_timeout,
+            ..SubprocessOptions::for_test()
+        }
+    }
+
+    // ── start_subprocess ─────────────────────────────────────
+
+    /// A cancel registration for a run started directly by a test.
+    fn registered(state: &AppState) -> CancelGuard {
+        state.cancellations.register(None, "req").unwrap()
+    }
+
+    #[tokio::test]
+    async fn start_subprocess_records_outcome() {
+        let state = test_state(Config::default());
+        let mut opts = options(Duration::from_secs(5));
+        opts.cwd = "/nonexistent/claude-max-api-test".to_string();
+
+        let cancel = registered(&state);
+        let mut rx = start_subprocess(&state, &cancel, "hi".to_string(), opts).await.unwrap();
+        let mut error = None;
+        while let Some(event) = rx.recv().await {
+            if let SubprocessEvent::Error(msg) = event {
+                error = Some(msg);
+            }
+        }
+        // The recording task finishes right after the channel closes
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let snap = state.metrics.snapshot();
+        assert_eq!(snap.started, 1);
+        assert_eq!(snap.spawn_failed, 1);
+        assert_eq!(snap.client_closed, 0);
+
+        // The error forwarded to the handler is also kept for /v1/config
+        let last = state.metrics.last_error().unwrap();
+        assert_eq!(Some(last.message), error);
+    }
+
+    #[tokio::test]
+    async fn dropped_receiver_is_counted_as_client_closed() {
+        let killed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let cli = Arc::new(HangingCli {
+            killed: killed.clone(),
+        });
+        let state = test_state_with(Config::default(), cli);
+        let metrics = state.metrics.clone();
+
+        let cancel = registered(&state);
+        let opts = options(Duration::from_secs(5));
+        let mut rx = start_subprocess(&state, &cancel, "hi".to_string(), opts).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(SubprocessEvent::ContentDelta(_))));
+        drop(rx);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.snapshot().client_closed == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("client close recorded");
+        assert!(killed.load(std::sync::atomic::Ordering::SeqCst));
+        let snap = metrics.snapshot();
+        assert_eq!(snap.client_closed, 1);
+        assert_eq!(snap.completed, 0);
+    }
+
+    #[tokio::test]
+    async fn missing_claude_bin_is_named_in_error() {
+        let state = test_state(Config::default());
+        let mut opts = options(Duration::from_secs(5));
+        opts.claude_bin = "/nonexistent/bin/claude".to_string();
+
+        let cancel = registered(&state);
+        let mut rx = start_subprocess(&state, &cancel, "hi".to_string(), opts).await.unwrap();
+        let mut error = None;
+        while let Some(event) = rx.recv().await {
+            if let SubprocessEvent::Error(msg) = event {
+                error = Some(msg);
+            }
+        }
+        let error = error.unwrap();
+        assert!(error.contains("tried '/nonexistent/bin/claude'"));
+        assert!(error.contains("npm install"));
+    }
+
+    fn failing_options() -> SubprocessOptions {
+        let mut opts = options(Duration::from_secs(5));
+        opts.cwd = "/nonexistent/claude-max-api-test".to_string();
+        opts
+    }
+
+    #[tokio::test]
+    async fn start_subprocess_rejects_when_slots_busy() {
+        let state = test_state(Config {
+            max_concurrency: 1,
+            concurrency_wait: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let _held = state.concurrency.clone().acquire_owned().await.unwrap();
+
+        let cancel = registered(&state);
+        let err = start_subprocess(&state, &cancel, "hi".to_string(), failing_options())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::ServiceUnavailable(_)));
+        assert_eq!(state.metrics.snapshot().started, 0);
+    }
+
+    #[tokio::test]
+    async fn start_subprocess_releases_slot_after_run() {
+        let state = test_state(Config {
+            max_concurrency: 1,
+            concurrency_wait: Duration::from_millis(10),
+            ..Default::default()
+        });
+
+        let cancel = registered(&state);
+        let mut rx =
+            start_subprocess(&state, &cancel, "hi".to_string(), failing_options()).await.unwrap();
+        assert_eq!(state.concurrency.available_permits(), 0);
+        while rx.recv().await.is_some() {}
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(state.concurrency.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn start_subprocess_releases_slot_on_disconnect() {
+        let state = test_state(Config {
+            max_concurrency: 1,
+            ..Default::default()
+        });
+
+        let cancel = registered(&state);
+        let rx =
+            start_subprocess(&state, &cancel, "hi".to_string(), failing_options()).await.unwrap();
+        drop(rx);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(state.concurrency.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn start_subprocess_waits_for_busy_session() {
+        let state = test_state(Config::default());
+        let held = state.session_manager.lock_session("sess-1").await;
+
+        let mut opts = failing_options();
+        opts.session_id = Some("sess-1".to_string());
+        let task_state = state.clone();
+        let second = tokio::spawn(async move {
+            let cancel = registered(&task_state);
+            let mut rx =
+                start_subprocess(&task_state, &cancel, "hi".to_string(), opts).await.unwrap();
+            while rx.recv().await.is_some() {}
+        });
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(state.metrics.snapshot().started, 0);
+
+        drop(held);
+        second.await.unwrap();
+        assert_eq!(state.metrics.snapshot().started, 1);
+    }
+
+    #[tokio::test]
+    async fn start_subprocess_gives_up_on_a_session_busy_too_long() {
+        let state = test_state(Config {
+            session_wait: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let _held = state.session_manager.lock_session("sess-1").await;
+
+        let mut opts = failing_options();
+        opts.session_id = Some("sess-1".to_string());
+        let cancel = registered(&state);
+        let err = start_subprocess(&state, &cancel, "hi".to_string(), opts).await.unwrap_err();
+        assert!(matches!(err, AppError::Conflict(_)));
+        assert_eq!(state.metrics.snapshot().started, 0);
+    }
+
+    // ── request_id ───────────────────────────────────────────
+
+    fn correlation_config() -> Config {
+        Config {
+            request_id_header: header::HeaderName::from_static("x-correlation-id"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn request_id_generated_without_header() {
+        let id = request_id(&Config::default(), &HeaderMap::new());
+        assert_eq!(id.len(), 8);
+    }
+
+    #[test]
+    fn request_id_honors_custom_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-correlation-id", HeaderValue::from_static("trace-42"));
+        headers.insert("x-request-id", HeaderValue::from_static("ignored"));
+        assert_eq!(request_id(&correlation_config(), &headers), "trace-42");
+        // The default header is no longer consulted
+        assert_ne!(request_id(&correlation_config(), &HeaderMap::new()), "ignored");
+    }
+
+    #[tokio::test]
+    async fn request_id_echoed_on_custom_header() {
+        use tower::ServiceExt;
+
+        let mut state = test_state(correlation_config());
+        // Make the spawn fail fast; the headers are sent before the CLI runs
+        state.cwd = "/nonexistent/cwd".to_string();
+        let request = axum::http::Request::post("/v1/chat/completions")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-correlation-id", "trace-42")
+            .body(axum::body::Body::from(
+                r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#,
+            ))
+            .unwrap();
+        let response = crate::server::create_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["x-correlation-id"], "trace-42");
+        assert!(response.headers().get("x-request-id").is_none());
+    }
+
+    #[tokio::test]
+    async fn request_id_echoed_on_rejected_request() {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::post("/v1/chat/completions")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-request-id", "gw-7")
+            .body(axum::body::Body::from(r#"{"messages":[]}"#))
+            .unwrap();
+        let router = crate::server::create_router(test_state(Config::default()));
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(response.headers()["x-request-id"], "gw-7");
+    }
+
+    #[test]
+    fn request_id_rejects_unsafe_values() {
+        let mut headers = HeaderMap::new();
+        for bad in ["", "has space", "semi;colon", &"x".repeat(129)] {
+            headers.insert("x-request-id", HeaderValue::from_str(bad).unwrap());
+            let id = request_id(&Config::default(), &headers);
+            assert_ne!(id, bad);
+            assert_eq!(id.len(), 8);
+        }
+    }
+
+    // ── end to end with a scripted CLI ───────────────────────
+
+    fn scripted_run() -> Vec<SubprocessEvent> {
+        vec![
+            SubprocessEvent::Model("claude-sonnet-4".to_string()),
+            SubprocessEvent::ContentDelta("Hello, ".to_string()),
+            SubprocessEvent::ContentDelta("world".to_string()),
+            SubprocessEvent::Result(result_with(Some("Hello, world"))),
+            SubprocessEvent::Close(0, None),
+        ]
+    }
+
+    /// POST `body` to `uri` on a router whose CLI plays `scripted_run`, and
+    /// return the status and body.
+    async fn post_scripted(uri: &str, body: &str) -> (axum::http::StatusCode, String) {
+        let cli = Arc::new(MockCliRunner::new(|_| scripted_run()));
+        let response = post_to(Config::default(), cli.clone(), uri, body).await;
+        assert_eq!(cli.runs(), 1);
+        response
+    }
+
+    /// POST `body` to `uri` on a router using `config` and `cli`.
+    async fn post_to(
+        config: Config,
+        cli: Arc<MockCliRunner>,
+        uri: &str,
+        body: &str,
+    ) -> (axum::http::StatusCode, String) {
+        post_state(test_state_with(config, cli), uri, body).await
+    }
+
+    /// POST `body` to `uri` on a router serving `state`.
+    async fn post_state(
+        state: AppState,
+        uri: &str,
+        body: &str,
+    ) -> (axum::http::StatusCode, String) {
+        use tower::ServiceExt;
+        let request = axum::http::Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(body.to_string()))
+            .unwrap();
+        let response = crate::server::create_router(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// The `data:` payloads of an SSE body, excluding `[DONE]`.
+    fn sse_data(body: &str) -> Vec<serde_json::Value> {
+        body.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn chat_completions_stream_end_to_end() {
+        let (status, body) = post_scripted(
+            "/v1/chat/completions",
+            r#"{"model":"claude-sonnet-4","messages":[{"role":"user","content":"hi"}],"stream":true}"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        let chunks = sse_data(&body);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0]["object"], "chat.completion.chunk");
+        assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "Hello, ");
+        assert_eq!(chunks[1]["choices"][0]["delta"]["content"], "world");
+        assert_eq!(chunks[2]["choices"][0]["finish_reason"], "stop");
+        assert!(body.trim_end().ends_with("data: [DONE]"));
+    }
+
+    #[tokio::test]
+    async fn chat_completions_non_streaming_end_to_end() {
+        let (status, body) = post_scripted(
+            "/v1/chat/completions",
+            r#"{"model":"claude-sonnet-4","messages":[{"role":"user","content":"hi"}]}"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["choices"][0]["message"]["content"], "Hello, world");
+    }
+
+    const TOOL_REPLY: &str = "Let me check.\n\
+        <tool_call>{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}</tool_call>";
+
+    /// A run whose reply calls `get_weather` (streamed in two deltas).
+    fn tool_call_run() -> Vec<SubprocessEvent> {
+        let (head, tail) = TOOL_REPLY.split_at(20);
+        vec![
+            SubprocessEvent::ContentDelta(head.to_string()),
+            SubprocessEvent::ContentDelta(tail.to_string()),
+            SubprocessEvent::Result(result_with(Some(TOOL_REPLY))),
+            SubprocessEvent::Close(0, None),
+        ]
+    }
+
+    async fn post_with_tools(stream: bool) -> String {
+        let cli = Arc::new(MockCliRunner::new(|_| tool_call_run()));
+        let body = json!({
+            "model": "claude-sonnet-4",
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "tools": [{
+                "type": "function",
+                "function": {"name": "get_weather", "parameters": {"type": "object"}},
+            }],
+            "stream": stream,
+        });
+        let (status, body) =
+            post_to(Config::default(), cli, "/v1/chat/completions", &body.to_string()).await;
+        assert_eq!(status, 200);
+        body
+    }
+
+    #[tokio::test]
+    async fn chat_completions_return_tool_calls() {
+        let json: serde_json::Value = serde_json::from_str(&post_with_tools(false).await).unwrap();
+        let choice = &json["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(choice["message"]["content"], "Let me check.");
+        let call = &choice["message"]["tool_calls"][0];
+        assert_eq!(call["type"], "function");
+        assert!(call["id"].as_str().unwrap().starts_with("call_"));
+        assert_eq!(call["function"]["name"], "get_weather");
+        assert_eq!(call["function"]["arguments"], r#"{"city":"Paris"}"#);
+    }
+
+    #[tokio::test]
+    async fn chat_completions_stream_tool_calls() {
+        let chunks = sse_data(&post_with_tools(true).await);
+        // Text, then the calls, then the done chunk
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "Let me check.");
+        let call = &chunks[1]["choices"][0]["delta"]["tool_calls"][0];
+        assert_eq!(call["index"], 0);
+        assert_eq!(call["function"]["name"], "get_weather");
+        assert_eq!(call["function"]["arguments"], r#"{"city":"Paris"}"#);
+        assert_eq!(chunks[2]["choices"][0]["finish_reason"], "tool_calls");
+    }
+
+    #[tokio::test]
+    async fn tool_call_text_is_left_alone_without_tools() {
+        let cli = Arc::new(MockCliRunner::new(|_| tool_call_run()));
+        let (_, body) = post_to(
+            Config::default(),
+            cli,
+            "/v1/chat/completions",
+            r#"{"model":"claude-sonnet-4","messages":[{"role":"user","content":"hi"}]}"#,
+        )
+        .await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["choices"][0]["finish_reason"], "stop");
+        assert_eq!(json["choices"][0]["message"]["content"], TOOL_REPLY);
+        assert!(json["choices"][0]["message"].get("tool_calls").is_none());
+    }
+
+    async fn post_messages_with_tools(stream: bool) -> String {
+        let cli = Arc::new(MockCliRunner::new(|_| tool_call_run()));
+        let body = json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 64,
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+            "stream": stream,
+        });
+        let (status, body) =
+            post_to(Config::default(), cli, "/v1/messages", &body.to_string()).await;
+        assert_eq!(status, 200);
+        body
+    }
+
+    #[tokio::test]
+    async fn messages_return_tool_use_blocks() {
+        let json: serde_json::Value =
+            serde_json::from_str(&post_messages_with_tools(false).await).unwrap();
+        assert_eq!(json["stop_reason"], "tool_use");
+        assert_eq!(json["content"][0], json!({"type": "text", "text": "Let me check."}));
+        let block = &json["content"][1];
+        assert_eq!(block["type"], "tool_use");
+        assert!(block["id"].as_str().unwrap().starts_with("toolu_"));
+        assert_eq!(block["name"], "get_weather");
+        assert_eq!(block["input"], json!({"city": "Paris"}));
+    }
+
+    #[tokio::test]
+    async fn messages_stream_tool_use_blocks() {
+        let body = post_messages_with_tools(true).await;
+        let events: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "message_start",
+                "ping",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        let data = sse_data(&body);
+        assert_eq!(data[3]["delta"]["text"], "Let me check.");
+        assert_eq!(data[5]["index"], 1);
+        assert_eq!(data[5]["content_block"]["name"], "get_weather");
+        assert_eq!(data[5]["content_block"]["input"], json!({}));
+        assert_eq!(data[6]["delta"]["type"], "input_json_delta");
+        assert_eq!(data[6]["delta"]["partial_json"], r#"{"city":"Paris"}"#);
+        assert_eq!(data[7]["index"], 1);
+        assert_eq!(data[8]["delta"]["stop_reason"], "tool_use");
+    }
+
+    #[tokio::test]
+    async fn tool_use_ids_match_across_modes_and_come_back_in_tool_results() {
+        let cli = Arc::new(MockCliRunner::new(|_| tool_call_run()));
+        let app = crate::server::create_router(test_state_with(Config::default(), cli.clone()));
+        let request = |stream: bool| {
+            json!({
+                "model": "claude-sonnet-4",
+                "max_tokens": 64,
+                "messages": [{"role": "user", "content": "Weather in Paris?"}],
+                "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+                "stream": stream,
+            })
+            .to_string()
+        };
+
+        let response = send_as(&app, "req-tools", "/v1/messages", &request(false)).await;
+        let json: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        let id = json["content"][1]["id"].as_str().unwrap().to_string();
+        let response = send_as(&app, "req-tools", "/v1/messages", &request(true)).await;
+        let data = sse_data(&body_text(response).await);
+        assert_eq!(data[5]["content_block"]["id"], id.as_str());
+
+        // The client answers the call by its id; the next prompt pairs them
+        let next = json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 64,
+            "messages": [
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": json["content"]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": id, "content": "Sunny"},
+                ]},
+            ],
+            "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+        });
+        let response = send_as(&app, "req-next", "/v1/messages", &next.to_string()).await;
+        assert_eq!(response.status(), 200);
+        let prompt = cli.prompts().pop().unwrap();
+        assert!(prompt.contains(&format!("\"id\":\"{id}\"")), "{prompt}");
+        assert!(prompt.contains(&format!("tool_call_id=\"{id}\"")), "{prompt}");
+    }
+
+    #[tokio::test]
+    async fn chat_completions_stream_usage_when_requested() {
+        let cli = Arc::new(MockCliRunner::new(|_| {
+            vec![
+                SubprocessEvent::ContentDelta("Hello".to_string()),
+                SubprocessEvent::Result(result_with_usage()),
+                SubprocessEvent::Close(0, None),
+            ]
+        }));
+        let (_, body) = post_to(
+            Config::default(),
+            cli,
+            "/v1/chat/completions",
+            r#"{"messages":[{"role":"user","content":"hi"}],"stream":true,"stream_options":{"include_usage":true}}"#,
+        )
+        .await;
+        let chunks = sse_data(&body);
+        let last = chunks.last().unwrap();
+        assert_eq!(last["choices"], json!([]));
+        assert_eq!(last["usage"]["prompt_tokens"], 10);
+        assert_eq!(last["usage"]["completion_tokens"], 5);
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.get("usage").is_none()));
+        assert!(body.trim_end().ends_with("data: [DONE]"));
+    }
+
+    #[tokio::test]
+    async fn messages_stream_reports_result_usage() {
+        let cli = Arc::new(MockCliRunner::new(|_| {
+            vec![
+                SubprocessEvent::ContentDelta("Hello".to_string()),
+                SubprocessEvent::Result(result_with_usage()),
+                SubprocessEvent::Close(0, None),
+            ]
+        }));
+        let (_, body) = post_to(
+            Config::default(),
+            cli,
+            "/v1/messages",
+            r#"{"model":"claude-sonnet-4","max_tokens":64,"messages":[{"role":"user","content":"hi"}],"stream":true}"#,
+        )
+        .await;
+        let data = sse_data(&body);
+        let delta = data.iter().find(|d| d["type"] == "message_delta").unwrap();
+        assert_eq!(delta["usage"]["input_tokens"], 10);
+        assert_eq!(delta["usage"]["output_tokens"], 5);
+    }
+
+    #[tokio::test]
+    async fn messages_stream_without_content_starts_with_input_tokens() {
+        let cli = Arc::new(MockCliRunner::new(|_| {
+            vec![
+                SubprocessEvent::Result(result_with_usage()),
+                SubprocessEvent::Close(0, None),
+            ]
+        }));
+        let (_, body) = post_to(
+            Config::default(),
+            cli,
+            "/v1/messages",
+            r#"{"model":"claude-sonnet-4","max_tokens":64,"messages":[{"role":"user","content":"hi"}],"stream":true}"#,
+        )
+        .await;
+        let data = sse_data(&body);
+        let start = data.iter().find(|d| d["type"] == "message_start").unwrap();
+        assert_eq!(start["message"]["usage"]["input_tokens"], 10);
+        assert_eq!(start["message"]["usage"]["output_tokens"], 0);
+    }
+
+    /// A run on a dated model, reported both as a `Model` event and in `modelUsage`.
+    fn dated_model_run() -> Vec<SubprocessEvent> {
+        let usage = ModelUsage {
+            input_tokens: Some(10),
+            output_tokens: Some(5),
+            cache_read_tokens: None,
+            cache_write_tokens: None,
+        };
+        let result = ResultMessage {
+            model_usage: Some(HashMap::from([(
+                "claude-sonnet-4-5-20250929".to_string(),
+                usage,
+            )])),
+            ..result_with(Some("Hello"))
+        };
+        vec![
+            SubprocessEvent::Model("claude-sonnet-4-5-20250929".to_string()),
+            SubprocessEvent::ContentDelta("Hello".to_string()),
+            SubprocessEvent::Result(result),
+            SubprocessEvent::Close(0, None),
+        ]
+    }
+
+    /// The `model` of every chunk/event carrying one, for each API and mode.
+    async fn reported_models(echo_exact_model: bool, uri: &str, stream: bool) -> Vec<String> {
+        let config = Config {
+            echo_exact_model,
+            ..Config::default()
+        };
+        let cli = Arc::new(MockCliRunner::new(|_| dated_model_run()));
+        let body = if uri == "/v1/complete" {
+            format!(
+                r#"{{"model":"claude-sonnet-4","max_tokens_to_sample":64,"prompt":"\n\nHuman: hi\n\nAssistant:","stream":{stream}}}"#
+            )
+        } else {
+            format!(
+                r#"{{"model":"claude-sonnet-4","max_tokens":64,"messages":[{{"role":"user","content":"hi"}}],"stream":{stream}}}"#
+            )
+        };
+        let (status, body) = post_to(config, cli, uri, &body).await;
+        assert_eq!(status, 200);
+        let values = if stream {
+            sse_data(&body)
+        } else {
+            vec![serde_json::from_str(&body).unwrap()]
+        };
+        values
+            .iter()
+            .filter_map(|v| v.get("model").or_else(|| v.pointer("/message/model")))
+            .map(|model| model.as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn responses_normalize_model_by_default() {
+        for uri in ["/v1/chat/completions", "/v1/messages", "/v1/complete"] {
+            for stream in [false, true] {
+                let models = reported_models(false, uri, stream).await;
+                assert!(!models.is_empty(), "{uri} stream={stream}");
+                assert!(models.iter().all(|m| m == "claude-sonnet-4"), "{uri}: {models:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_echo_exact_model_when_configured() {
+        for uri in ["/v1/chat/completions", "/v1/messages", "/v1/complete"] {
+            for stream in [false, true] {
+                let models = reported_models(true, uri, stream).await;
+                assert!(!models.is_empty(), "{uri} stream={stream}");
+                assert!(
+                    models.iter().all(|m| m == "claude-sonnet-4-5-20250929"),
+                    "{uri}: {models:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_stream_end_to_end() {
+        let (status, body) = post_scripted(
+            "/v1/messages",
+            r#"{"model":"claude-sonnet-4","max_tokens":64,"messages":[{"role":"user","content":"hi"}],"stream":true}"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        let events: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "message_start",
+                "ping",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        let data = sse_data(&body);
+        assert_eq!(data[3]["delta"]["text"], "Hello, ");
+        assert_eq!(data[4]["delta"]["text"], "world");
+        assert_eq!(data[6]["delta"]["stop_reason"], "end_turn");
+    }
+
+    // ── retry_on_crash ───────────────────────────────────────
+
+    const CHAT_BODY: &str = r#"{"messages":[{"role":"user","content":"hi"}]}"#;
+
+    /// A CLI that crashes on its first run and succeeds afterwards.
+    fn crashes_once() -> Arc<MockCliRunner> {
+        Arc::new(MockCliRunner::new(|run| match run {
+            0 => vec![SubprocessEvent::Close(139, Some("Segmentation fault".to_string()))],
+            _ => scripted_run(),
+        }))
+    }
+
+    fn retrying() -> Config {
+        Config {
+            retry_on_crash: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn crash_is_retried_when_enabled() {
+        let cli = crashes_once();
+        let (status, body) =
+            post_to(retrying(), cli.clone(), "/v1/chat/completions", CHAT_BODY).await;
+        assert_eq!(status, 200);
+        assert!(body.contains("Hello, world"));
+        assert_eq!(cli.runs(), 2);
+    }
+
+    #[tokio::test]
+    async fn clean_exit_without_result_is_an_empty_response() {
+        use tower::ServiceExt;
+        let cli = Arc::new(MockCliRunner::new(|_| vec![SubprocessEvent::Close(0, None)]));
+        let state = test_state_with(Config::default(), cli);
+        let request = axum::http::Request::post("/v1/chat/completions")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(CHAT_BODY))
+            .unwrap();
+        let response = crate::server::create_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-empty-completion"], "true");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["choices"][0]["message"]["content"], "");
+        assert_eq!(json["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn clean_exit_without_result_keeps_streamed_text() {
+        let cli = Arc::new(MockCliRunner::new(|_| {
+            vec![
+                SubprocessEvent::ContentDelta("Hello".to_string()),
+                SubprocessEvent::Close(0, None),
+            ]
+        }));
+        let body = r#"{"model":"opus","max_tokens":9,"messages":[{"role":"user","content":"hi"}]}"#;
+        let (status, body) = post_to(Config::default(), cli, "/v1/messages", body).await;
+        assert_eq!(status, 200);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["content"][0]["text"], "Hello");
+    }
+
+    #[tokio::test]
+    async fn crash_without_result_is_still_an_error() {
+        let cli = Arc::new(MockCliRunner::new(|_| vec![SubprocessEvent::Close(2, None)]));
+        let (status, body) =
+            post_to(Config::default(), cli, "/v1/chat/completions", CHAT_BODY).await;
+        assert_eq!(status, 500);
+        assert!(body.contains("exited with code 2"));
+    }
+
+    #[tokio::test]
+    async fn crash_is_not_retried_by_default() {
+        let cli = crashes_once();
+        let (status, _) =
+            post_to(Config::default(), cli.clone(), "/v1/chat/completions", CHAT_BODY).await;
+        assert_eq!(status, 500);
+        assert_eq!(cli.runs(), 1);
+    }
+
+    #[tokio::test]
+    async fn crash_after_output_or_with_known_failure_is_not_retried() {
+        let first_runs: [fn() -> Vec<SubprocessEvent>; 3] = [
+            || {
+                vec![
+                    SubprocessEvent::ContentDelta("partial".to_string()),
+                    SubprocessEvent::Close(1, None),
+                ]
+            },
+            || {
+                vec![
+                    SubprocessEvent::ToolUse {
+                        id: "toolu_1".to_string(),
+                        name: "Bash".to_string(),
+                        input: json!({"command": "touch file"}),
+                    },
+                    SubprocessEvent::Close(1, None),
+                ]
+            },
+            || vec![SubprocessEvent::Close(1, Some("Invalid API key".to_string()))],
+        ];
+        for first_run in first_runs {
+            let cli = Arc::new(MockCliRunner::new(move |run| match run {
+                0 => first_run(),
+                _ => scripted_run(),
+            }));
+            let (status, _) =
+                post_to(retrying(), cli.clone(), "/v1/chat/completions", CHAT_BODY).await;
+            assert_ne!(status, 200);
+            assert_eq!(cli.runs(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_crash_is_never_retried() {
+        let cli = crashes_once();
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#;
+        let (_, body) = post_to(retrying(), cli.clone(), "/v1/chat/completions", body).await;
+        assert!(body.contains("Segmentation fault"));
+        assert_eq!(cli.runs(), 1);
+    }
+
+    // ── model_fallbacks ──────────────────────────────────────
+
+    /// A CLI whose first run reports the model's usage limit reached.
+    fn quota_once() -> Arc<MockCliRunner> {
+        Arc::new(MockCliRunner::new(|run| match run {
+            0 => vec![SubprocessEvent::Close(
+                1,
+                Some("Claude AI usage limit reached|1760000000".to_string()),
+            )],
+            _ => scripted_run(),
+        }))
+    }
+
+    fn falling_back() -> Config {
+        Config {
+            model_fallbacks: vec![config::parse_model_fallback("opus=sonnet").unwrap()],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn unavailable_model_falls_back() {
+        use tower::ServiceExt;
+
+        let cli = quota_once();
+        let request = axum::http::Request::post("/v1/messages")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(
+                r#"{"model":"claude-opus-4","max_tokens":64,"messages":[{"role":"user","content":"hi"}]}"#,
+            ))
+            .unwrap();
+        let router = crate::server::create_router(test_state_with(falling_back(), cli.clone()));
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-model-fallback"], "claude-sonnet-4");
+        assert_eq!(cli.models(), ["opus", "sonnet"]);
+    }
+
+    #[tokio::test]
+    async fn fallback_needs_a_mapping_and_an_unavailable_model() {
+        // No mapping for the model: the quota error is returned as is
+        let body = r#"{"model":"claude-haiku-4","messages":[{"role":"user","content":"hi"}]}"#;
+        let cli = quota_once();
+        let (status, _) = post_to(falling_back(), cli.clone(), "/v1/chat/completions", body).await;
+        assert_eq!(status, 402);
+        assert_eq!(cli.runs(), 1);
+
+        // A crash isn't the model being unavailable
+        let cli = crashes_once();
+        let (status, _) =
+            post_to(falling_back(), cli.clone(), "/v1/chat/completions", CHAT_BODY).await;
+        assert_eq!(status, 500);
+        assert_eq!(cli.models(), ["opus"]);
+    }
+
+    // ── health ───────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn health_reports_status_and_counters_only() {
+        let mut state = test_state(Config::default());
+        state.cwd = "/srv/project".to_string();
+        state.metrics.record_error("claude exited: /srv/project/secret.txt");
+        let response = health(State(state)).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["status"], "ok");
+        assert_eq!(json["sessions_loaded"], true);
+        assert_eq!(json["requests"]["started"], 0);
+        assert!(json.get("cwd").is_none());
+        assert!(json.get("last_error").is_none());
+    }
+
+    // ── runtime_config ───────────────────────────────────────
+
+    #[tokio::test]
+    async fn config_reports_versions_without_secrets() {
+        use tower::ServiceExt;
+        let state = test_state(Config {
+            cli_version: Some("2.1.0 (Claude Code)".to_string()),
+            api_keys: vec![config::ApiKey {
+                label: "ci".to_string(),
+                key: "sk-secret-key".to_string(),
+            }],
+            session_salt: Some("tenant-salt".to_string()),
+            ..Default::default()
+        });
+        state.metrics.record_error("claude exited with code 1");
+        let router = crate::server::create_router(state);
+
+        let request = |auth: Option<&str>| {
+            let mut request = axum::http::Request::get("/v1/config");
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            request.body(axum::body::Body::empty()).unwrap()
+        };
+        let response = router.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = router.oneshot(request(Some("Bearer sk-secret-key"))).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["cli_version"], "2.1.0 (Claude Code)");
+        assert_eq!(json["api_key_labels"], json!(["ci"]));
+        assert_eq!(json["session_salt_set"], true);
+        assert_eq!(json["last_error"]["message"], "claude exited with code 1");
+        assert_eq!(json["models"][0]["id"], "claude-opus-4");
+        assert!(!text.contains("sk-secret-key"));
+        assert!(!text.contains("tenant-salt"));
+    }
+
+    // ── models ───────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn model_lookup_returns_listed_model() {
+        let state = test_state(Config::default());
+        let Json(info) = model(State(state), Path("claude-sonnet-4".to_string())).await.unwrap();
+        assert_eq!(info.id, "claude-sonnet-4");
+        assert_eq!(info.object, "model");
+        assert_eq!(info.context_window, 200_000);
+    }
+
+    #[tokio::test]
+    async fn models_come_from_config() {
+        let configured = config::parse_models(
+            r#"[{"id": "claude-3-5-sonnet", "alias": "sonnet", "context_window": 200000,
+                 "max_tokens": 8192}]"#,
+        )
+        .unwrap();
+        let state = test_state(Config {
+            models: configured,
+            ..Default::default()
+        });
+
+        let response = models(State(state.clone())).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["data"].as_array().unwrap().len(), 1);
+        assert_eq!(json["data"][0]["id"], "claude-3-5-sonnet");
+        assert_eq!(json["data"][0]["max_tokens"], 8192);
+        // The alias is internal
+        assert!(json["data"][0].get("alias").is_none());
+
+        let Json(info) =
+            model(State(state.clone()), Path("claude-3-5-sonnet".to_string())).await.unwrap();
+        assert_eq!(info.context_window, 200_000);
+        assert!(model(State(state), Path("claude-opus-4".to_string())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn model_lookup_unknown_id_is_404() {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::get("/v1/models/gpt-4")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let router = crate::server::create_router(test_state(Config::default()));
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"]["code"], "model_not_found");
+        assert_eq!(json["error"]["message"], "The model 'gpt-4' does not exist");
+    }
+
+    #[tokio::test]
+    async fn embeddings_returns_unsupported_error() {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::post("/v1/embeddings")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(r#"{"model":"text-embedding-3-small","input":"hi"}"#))
+            .unwrap();
+        let router = crate::server::create_router(test_state(Config::default()));
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::NOT_IMPLEMENTED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["code"], "unsupported_endpoint");
+        assert!(json["error"]["message"].as_str().unwrap().contains("Embeddings are not supported"));
+    }
+
+    // ── log_request ──────────────────────────────────────────
+
+    /// Collects formatted log output for assertions.
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_request_reports_all_model_names() {
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "request",
+                req_id = "req1",
+                api = tracing::field::Empty,
+                model = tracing::field::Empty,
+                client = tracing::field::Empty,
+            );
+            let client = ApiKeyLabel("ci".to_string());
+            span.in_scope(|| {
+                log_request(Some(&client), "OpenAI chat completions", "gpt-4o", "opus", true);
+                tracing::info!("later line");
+            });
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("model=gpt-4o cli_model=opus response_model=claude-opus-4"));
+        // The structured fields
+        assert!(output.contains("requested_model=\"gpt-4o\""), "{output}");
+        assert!(output.contains("cli_model=\"opus\""), "{output}");
+        assert!(output.contains("response_model=\"claude-opus-4\""), "{output}");
+        // Later lines inherit what was recorded on the request span
+        let later = output.lines().find(|l| l.contains("later line")).unwrap();
+        assert!(later.contains("req_id=\"req1\""), "{later}");
+        assert!(later.contains("api=\"OpenAI chat completions\""), "{later}");
+        assert!(later.contains("model=\"opus\""), "{later}");
+        assert!(later.contains("client=\"ci\""), "{later}");
+    }
+
+    // ── log_completion ───────────────────────────────────────
+
+    #[tokio::test]
+    async fn completion_log_reports_tokens_and_cost() {
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let config = Config {
+            model_prices: vec![config::parse_model_price("sonnet=3,15").unwrap()],
+            ..Default::default()
+        };
+        let cli = Arc::new(MockCliRunner::new(|_| {
+            vec![
+                SubprocessEvent::Result(result_with_usage()),
+                SubprocessEvent::Close(0, None),
+            ]
+        }));
+        let (status, _) = post_to(config, cli, "/v1/chat/completions", CHAT_BODY).await;
+        assert_eq!(status, 200);
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let line = output.lines().find(|l| l.contains("Request complete")).unwrap();
+        // 10 input tokens at $3/M and 5 output tokens at $15/M
+        assert!(line.contains("input_tokens=10 output_tokens=5 cost=$0.0001"), "{line}");
+        assert!(line.contains("input_tokens=10"), "{line}");
+        assert!(line.contains("cost_usd=0.000105"), "{line}");
+    }
+
+    #[test]
+    fn run_usage_prices_only_configured_models() {
+        let result = result_with_usage();
+        let unpriced = run_usage(&result, &[]);
+        assert_eq!((unpriced.input_tokens, unpriced.output_tokens), (10, 5));
+        assert_eq!(unpriced.cost_usd, None);
+        let opus_only = [config::parse_model_price("opus=15,75").unwrap()];
+        assert_eq!(run_usage(&result, &opus_only).cost_usd, None);
+    }
+
+    // ── openai_stream_error ──────────────────────────────────
+
+    #[test]
+    fn openai_stream_error_default_shape() {
+        let err = openai_stream_error(StreamErrorShape::default(), "server_error", "boom");
+        assert_eq!(
+            err,
+            json!({"error": {"message": "boom", "type": "server_error", "code": null}})
+        );
+    }
+
+    #[test]
+    fn openai_stream_error_anthropic_shape() {
+        let err = openai_stream_error(StreamErrorShape::Anthropic, "server_error", "boom");
+        assert_eq!(
+            err,
+            json!({"type": "error", "error": {"type": "server_error", "message": "boom"}})
+        );
+    }
+
+    // ── extra_args ───────────────────────────────────────────
+
+    fn extra_args_header(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-claude-extra-args", HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn extra_args_rejected_unless_enabled() {
+        let result = extra_args(&Config::default(), &extra_args_header("--max-turns 2"));
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn extra_args_parsed_when_enabled() {
+        let config = Config {
+            allow_extra_args: true,
+            ..Default::default()
+        };
+        let args = extra_args(&config, &extra_args_header("--max-turns 2")).unwrap();
+        assert_eq!(args, vec!["--max-turns", "2"]);
+
+        let bad = extra_args(&config, &extra_args_header("--permission-mode default"));
+        assert!(matches!(bad, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn extra_args_absent_header_is_empty() {
+        assert!(extra_args(&Config::default(), &HeaderMap::new()).unwrap().is_empty());
+    }
+
+    // ── request_cwd ──────────────────────────────────────────
+
+    fn cwd_header(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-claude-cwd", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    /// A fresh directory with a `project` subdirectory, and a config that
+    /// allows it as a cwd root.
+    fn cwd_root() -> (std::path::PathBuf, Config) {
+        let root = std::env::temp_dir().join(format!("cwd-root-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("project")).unwrap();
+        let config = Config {
+            allowed_cwd_roots: vec![config::resolve_cwd(root.to_str().unwrap()).unwrap()],
+            ..Default::default()
+        };
+        (root, config)
+    }
+
+    #[test]
+    fn request_cwd_rejected_unless_enabled() {
+        let result = request_cwd(&Config::default(), &cwd_header("/tmp"));
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+        assert_eq!(request_cwd(&Config::default(), &HeaderMap::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn request_cwd_must_be_within_a_root() {
+        let (root, config) = cwd_root();
+        let project = root.join("project");
+        let cwd = request_cwd(&config, &cwd_header(project.to_str().unwrap())).unwrap();
+        assert_eq!(cwd.unwrap(), config::resolve_cwd(project.to_str().unwrap()).unwrap());
+
+        for outside in ["/", "/nonexistent/claude-max-api-test", "project"] {
+            let result = request_cwd(&config, &cwd_header(outside));
+            assert!(matches!(result, Err(AppError::BadRequest(_))), "{outside}");
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cwd_header_sets_the_run_directory() {
+        use tower::ServiceExt;
+        let (root, config) = cwd_root();
+        let project = config::resolve_cwd(root.join("project").to_str().unwrap()).unwrap();
+        let cli = Arc::new(MockCliRunner::new(|_| scripted_run()));
+        let app = crate::server::create_router(test_state_with(config, cli.clone()));
+
+        let request = |cwd: &str| {
+            axum::http::Request::post("/v1/chat/completions")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-claude-cwd", cwd)
+                .body(axum::body::Body::from(CHAT_BODY))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(request(&project)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let response = app.oneshot(request("/")).await.unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(cli.cwds(), [project]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    // ── trace_logging ────────────────────────────────────────
+
+    fn debug_header(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-debug", HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn debug_header_rejected_unless_enabled() {
+        let result = trace_logging(&Config::default(), &debug_header("true"));
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+        assert!(!trace_logging(&Config::default(), &HeaderMap::new()).unwrap());
+    }
+
+    #[test]
+    fn debug_header_flags_only_true() {
+        let config = Config {
+            allow_debug_header: true,
+            ..Default::default()
+        };
+        assert!(trace_logging(&config, &debug_header("true")).unwrap());
+        assert!(trace_logging(&config, &debug_header("TRUE")).unwrap());
+        assert!(!trace_logging(&config, &debug_header("false")).unwrap());
+        assert!(!trace_logging(&config, &HeaderMap::new()).unwrap());
+    }
+
+    // ── check_system_count ───────────────────────────────────
+
+    #[test]
+    fn system_count_at_limit_is_allowed() {
+        assert!(check_system_count(4, 4).is_ok());
+        assert!(check_system_count(0, 4).is_ok());
+    }
+
+    #[test]
+    fn system_count_over_limit_is_rejected() {
+        match check_system_count(5, 4) {
+            Err(AppError::BadRequest(msg)) => assert!(msg.contains("maximum is 4")),
+            other => panic!("Expected BadRequest, got {:?}", other),
+        }
+    }
+
+    // ── check_session_id ─────────────────────────────────────
+
+    #[test]
+    fn valid_session_ids_are_accepted() {
+        assert!(check_session_id(None, 128).is_ok());
+        for id in ["user-1", "3f2a9c1e-8d4b-4c6a-9e1f-0b2c3d4e5f60", "a_b", "A"] {
+            assert!(check_session_id(Some(id), 128).is_ok(), "{id}");
+        }
+    }
+
+    #[test]
+    fn malformed_session_ids_are_rejected() {
+        let long = "a".repeat(129);
+        for id in ["", "../etc/passwd", "a b", "--resume", "id;rm", "é", long.as_str()] {
+            assert!(
+                matches!(check_session_id(Some(id), 128), Err(AppError::BadRequest(_))),
+                "{id}"
+            );
+        }
+    }
+
+    // ── check_sampling ───────────────────────────────────────
+
+    #[test]
+    fn sampling_within_range_is_passed_through() {
+        assert_eq!(check_sampling(None, None, 2.0).unwrap(), Sampling::default());
+        let sampling = check_sampling(Some(2.0), Some(0.0), 2.0).unwrap();
+        assert_eq!(sampling.temperature, Some(2.0));
+        assert_eq!(sampling.top_p, Some(0.0));
+    }
+
+    #[test]
+    fn sampling_out_of_range_is_rejected() {
+        for (temperature, top_p, max) in [
+            (Some(2.5), None, 2.0),
+            (Some(1.5), None, 1.0),
+            (Some(-0.1), None, 2.0),
+            (Some(f64::NAN), None, 2.0),
+            (None, Some(1.1), 2.0),
+            (None, Some(-1.0), 1.0),
+        ] {
+            assert!(
+                matches!(check_sampling(temperature, top_p, max), Err(AppError::BadRequest(_))),
+                "{temperature:?} {top_p:?}"
+            );
+        }
+    }
+
+    // ── stream trailer ───────────────────────────────────────
+
+    #[test]
+    fn stream_trailer_header_parsing() {
+        let mut headers = HeaderMap::new();
+        assert!(!wants_stream_trailer(&headers));
+        for (value, expected) in [("true", true), ("TRUE", true), ("1", true), ("no", false)] {
+            headers.insert("x-stream-trailer", HeaderValue::from_static(value));
+            assert_eq!(wants_stream_trailer(&headers), expected, "{value}");
+        }
+    }
+
+    /// Feed two deltas and a result through a stream function and return the SSE body.
+    async fn render_stream<F, Fut>(stream: F) -> String
+    where
+        F: FnOnce(mpsc::Receiver<SubprocessEvent>, SseSender) -> Fut,
+        Fut: Future<Output = Result<(), Disconnected>>,
+    {
+        let events = vec![
+            SubprocessEvent::ContentDelta("Hello, ".to_string()),
+            SubprocessEvent::ContentDelta("world".to_string()),
+            SubprocessEvent::Result(result_with(Some("Hello, world"))),
+            SubprocessEvent::Close(0, None),
+        ];
+        render_events(events, stream).await
+    }
+
+    /// Feed `events` through a stream function and return the SSE body.
+    async fn render_events<F, Fut>(events: Vec<SubprocessEvent>, stream: F) -> String
+    where
+        F: FnOnce(mpsc::Receiver<SubprocessEvent>, SseSender) -> Fut,
+        Fut: Future<Output = Result<(), Disconnected>>,
+    {
+        let (tx, rx) = mpsc::channel(8);
+        for event in events {
+            tx.send(event).await.unwrap();
+        }
+        drop(tx);
+
+        let (sse_tx, sse_rx) =
+            SseSender::channel(Arc::new(Metrics::default()), sse::DEFAULT_STALL_THRESHOLD);
+        // Few enough events to fit the channel, so the stream can finish first
+        stream(rx, sse_tx).await.unwrap();
+        let response = Sse::new(ReceiverStream::new(sse_rx)).into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn trailer_only() -> ChatStreamOptions {
+        ChatStreamOptions {
+            trailer: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn openai_stream_chunks_carry_service_tier() {
+        let config = Arc::new(Config {
+            service_tier: Some("default".to_string()),
+            ..Default::default()
+        });
+        let body = render_stream(|rx, tx| {
+            stream_openai(rx, tx, "req1".into(), "opus".into(), config, trailer_only())
+        })
+        .await;
+        let chunks: Vec<serde_json::Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        // Two deltas, the done chunk and the trailer
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| chunk["service_tier"] == "default"));
+
+        let body = render_stream(|rx, tx| {
+            stream_openai(rx, tx, "req1".into(), "opus".into(), Arc::default(), Default::default())
+        })
+        .await;
+        assert!(!body.contains("service_tier"));
+    }
+
+    #[tokio::test]
+    async fn openai_stream_trailer_carries_full_content() {
+        let body = render_stream(|rx, tx| {
+            stream_openai(rx, tx, "req1".into(), "opus".into(), Arc::default(), trailer_only())
+        })
+        .await;
+        let trailer = body
+            .lines()
+            .find(|line| line.contains("full_content"))
+            .expect("trailer chunk");
+        let json: serde_json::Value =
+            serde_json::from_str(trailer.trim_start_matches("data: ")).unwrap();
+        assert_eq!(json["full_content"], "Hello, world");
+        assert_eq!(json["choices"], json!([]));
+        // The trailer comes after the done chunk and before the sentinel
+        let done = body.find("\"finish_reason\":\"stop\"").unwrap();
+        assert!(done < body.find(trailer).unwrap());
+        assert!(body.find(trailer).unwrap() < body.find("[DONE]").unwrap());
+    }
+
+    #[tokio::test]
+    async fn anthropic_stream_trailer_reuses_message_delta() {
+        let body = render_stream(|rx, tx| {
+            stream_anthropic(rx, tx, "req1".into(), "opus".into(), Default::default(), true, false)
+        })
+        .await;
+        let delta = body
+            .lines()
+            .find(|line| line.contains("\"type\":\"message_delta\""))
+            .expect("message_delta event");
+        let json: serde_json::Value =
+            serde_json::from_str(delta.trim_start_matches("data: ")).unwrap();
+        assert_eq!(json["full_content"], "Hello, world");
+    }
+
+    #[tokio::test]
+    async fn stream_trailer_is_off_by_default() {
+        let body = render_stream(|rx, tx| {
+            stream_openai(rx, tx, "req1".into(), "opus".into(), Arc::default(), Default::default())
+        })
+        .await;
+        assert!(!body.contains("full_content"));
+        let body = render_stream(|rx, tx| {
+            stream_anthropic(rx, tx, "req1".into(), "opus".into(), Default::default(), false, false)
+        })
+        .await;
+        assert!(!body.contains("full_content"));
+    }
+
+    #[tokio::test]
+    async fn completions_stream_ends_with_finish_reason() {
+        let body = render_stream(|rx, tx| {
+            let shape = StreamErrorShape::Openai;
+            stream_completions(rx, tx, "req1".into(), "opus".into(), shape, Default::default())
+        })
+        .await;
+        let chunks: Vec<serde_json::Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| c["object"] == "text_completion.chunk"));
+        assert_eq!(chunks[0]["choices"][0]["text"], "Hello, ");
+        assert!(chunks[0]["choices"][0]["finish_reason"].is_null());
+        assert_eq!(chunks[2]["choices"][0]["finish_reason"], "stop");
+        assert!(body.trim_end().ends_with("data: [DONE]"));
+    }
+
+    #[tokio::test]
+    async fn completions_require_prompt() {
+        use tower::ServiceExt;
+
+        for body in [r#"{"model":"opus"}"#, r#"{"prompt":"  "}"#, r#"{"prompt":[]}"#] {
+            let request = axum::http::Request::post("/v1/completions")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body))
+                .unwrap();
+            let router = crate::server::create_router(test_state(Config::default()));
+            let response = router.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST, "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn image_content_is_rejected() {
+        let image_url = json!({
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0"}}
+            ]}]
+        });
+        let image_block = json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 64,
+            "messages": [{"role": "user", "content": [
+                {"type": "image", "source": {
+                    "type": "base64", "media_type": "image/png", "data": "iVBORw0"
+                }},
+                {"type": "text", "text": "What is this?"}
+            ]}]
+        });
+        for (uri, body) in [("/v1/chat/completions", image_url), ("/v1/messages", image_block)] {
+            let cli = Arc::new(MockCliRunner::new(|_| scripted_run()));
+            let (status, body) = post_to(Config::default(), cli.clone(), uri, &body.to_string())
+                .await;
+            assert_eq!(status, axum::http::StatusCode::BAD_REQUEST, "{uri}");
+            assert!(body.contains("image content is not supported"), "{body}");
+            assert_eq!(cli.runs(), 0);
+        }
+    }
+
+    // ── CLI stderr in errors ─────────────────────────────────
+
+    #[test]
+    fn with_stderr_appends_tail() {
+        assert_eq!(with_stderr("exited".to_string(), None), "exited");
+        assert_eq!(
+            with_stderr("exited".to_string(), Some("Invalid API key\nPlease run /login")),
+            "exited. CLI stderr:\nInvalid API key\nPlease run /login"
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_errors_carry_cli_stderr() {
+        let failed = || vec![SubprocessEvent::Close(1, Some("Invalid API key".to_string()))];
+        let body = render_events(failed(), |rx, tx| {
+            stream_openai(rx, tx, "req1".into(), "opus".into(), Arc::default(), Default::default())
+        })
+        .await;
+        assert!(body.contains("Process exited with code 1. CLI stderr:\\nInvalid API key"));
+
+        let body = render_events(failed(), |rx, tx| {
+            stream_anthropic(rx, tx, "req1".into(), "opus".into(), Default::default(), false, false)
+        })
+        .await;
+        assert!(body.contains("event: error"));
+        assert!(body.contains("Invalid API key"));
+    }
+
+    #[test]
+    fn exit_error_maps_classified_failures() {
+        let msg = || "Process exited with code 1".to_string();
+        let err = exit_error(msg(), Some("Invalid API key · Please run /login"));
+        assert!(matches!(
+            err,
+            AppError::Upstream { status: axum::http::StatusCode::UNAUTHORIZED, error_type, .. }
+                if error_type == "authentication_error"
+        ));
+        let err = exit_error(msg(), Some("API Error: 429 rate limited"));
+        assert!(matches!(
+            err,
+            AppError::Upstream { status: axum::http::StatusCode::TOO_MANY_REQUESTS, .. }
+        ));
+        assert!(matches!(exit_error(msg(), Some("segfault")), AppError::Subprocess(_)));
+        assert!(matches!(exit_error(msg(), None), AppError::Subprocess(_)));
+    }
+
+    #[tokio::test]
+    async fn stream_errors_carry_classified_type() {
+        let limited = || vec![SubprocessEvent::Close(1, Some("Too many requests".to_string()))];
+        let body = render_events(limited(), |rx, tx| {
+            stream_openai(rx, tx, "req1".into(), "opus".into(), Arc::default(), Default::default())
+        })
+        .await;
+        assert!(body.contains(r#""type":"rate_limit_error""#));
+
+        let body = render_events(limited(), |rx, tx| {
+            stream_anthropic(rx, tx, "req1".into(), "opus".into(), Default::default(), false, false)
+        })
+        .await;
+        assert!(body.contains(r#""type":"rate_limit_error""#));
+    }
+
+    // ── trailing whitespace ──────────────────────────────────
+
+    fn push_all(mode: TrailingWhitespace, deltas: &[&str]) -> Vec<String> {
+        let mut trimmer = DeltaTrimmer::new(mode);
+        deltas.iter().filter_map(|d| trimmer.push(d.to_string())).collect()
+    }
+
+    #[test]
+    fn preserve_sends_deltas_unchanged() {
+        let sent = push_all(TrailingWhitespace::Preserve, &["Hello, ", " ", "world \n"]);
+        assert_eq!(sent, vec!["Hello, ", " ", "world \n"]);
+    }
+
+    #[test]
+    fn trim_moves_trailing_whitespace_to_next_delta() {
+        let sent = push_all(TrailingWhitespace::Trim, &["Hello, ", " ", "world \n"]);
+        assert_eq!(sent, vec!["Hello,", "  world"]);
+    }
+
+    #[test]
+    fn trim_keeps_leading_whitespace() {
+        let sent = push_all(TrailingWhitespace::Trim, &["\n  code", "\t"]);
+        assert_eq!(sent, vec!["\n  code"]);
+    }
+
+    #[tokio::test]
+    async fn anthropic_stream_trims_trailing_whitespace() {
+        let body = render_stream(|rx, tx| {
+            stream_anthropic(
+                rx,
+                tx,
+                "req1".into(),
+                "opus".into(),
+                Arc::new(Config {
+                    stream_trailing_whitespace: TrailingWhitespace::Trim,
+                    ..Config::default()
+                }),
+                true,
+                false,
+            )
+        })
+        .await;
+        let texts: Vec<String> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap())
+            .filter(|json| json["type"] == "content_block_delta")
+            .map(|json| json["delta"]["text"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(texts, vec!["Hello,", " world"]);
+        // The trailer reflects what was sent
+        assert!(body.contains(r#""full_content":"Hello, world""#));
+    }
+
+    // ── coalesce_deltas ──────────────────────────────────────
+
+    fn delta(text: &str) -> SubprocessEvent {
+        SubprocessEvent::ContentDelta(text.to_string())
+    }
+
+    /// The next coalesced event, failing if none arrives within a second.
+    async fn next_event(rx: &mut mpsc::Receiver<SubprocessEvent>) -> Option<SubprocessEvent> {
+        tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.expect("no event")
+    }
+
+    async fn next_delta(rx: &mut mpsc::Receiver<SubprocessEvent>) -> String {
+        match next_event(rx).await {
+            Some(SubprocessEvent::ContentDelta(text)) => text,
+            other => panic!("expected a delta, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn coalescing_merges_deltas_and_flushes_before_result() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut out = coalesce_deltas(rx, Some(Duration::from_secs(60)));
+        for text in ["Hel", "lo, ", "world"] {
+            tx.send(delta(text)).await.unwrap();
+        }
+        tx.send(SubprocessEvent::Result(result_with(Some("Hello, world")))).await.unwrap();
+        tx.send(SubprocessEvent::Close(0, None)).await.unwrap();
+        drop(tx);
+
+        // The result ends the window early instead of waiting out the minute
+        assert_eq!(next_delta(&mut out).await, "Hello, world");
+        assert!(matches!(next_event(&mut out).await, Some(SubprocessEvent::Result(_))));
+        assert!(matches!(next_event(&mut out).await, Some(SubprocessEvent::Close(0, _))));
+        assert!(next_event(&mut out).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn coalescing_flushes_when_window_closes() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut out = coalesce_deltas(rx, Some(Duration::from_millis(20)));
+        tx.send(delta("a")).await.unwrap();
+        tx.send(delta("b")).await.unwrap();
+        // Sent while the CLI is still running
+        assert_eq!(next_delta(&mut out).await, "ab");
+        tx.send(delta("c")).await.unwrap();
+        assert_eq!(next_delta(&mut out).await, "c");
+    }
+
+    #[tokio::test]
+    async fn coalescing_flushes_at_size_limit() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut out = coalesce_deltas(rx, Some(Duration::from_secs(60)));
+        tx.send(delta("a")).await.unwrap();
+        tx.send(delta(&"b".repeat(COALESCE_MAX_BYTES))).await.unwrap();
+        assert_eq!(next_delta(&mut out).await.len(), COALESCE_MAX_BYTES + 1);
+    }
+
+    #[tokio::test]
+    async fn coalescing_off_passes_deltas_through() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut out = coalesce_deltas(rx, None);
+        tx.send(delta("a")).await.unwrap();
+        tx.send(delta("b")).await.unwrap();
+        assert_eq!(next_delta(&mut out).await, "a");
+        assert_eq!(next_delta(&mut out).await, "b");
+    }
+
+    // ── check_stop_sequences ─────────────────────────────────
+
+    #[test]
+    fn stop_sequences_are_limited_to_four() {
+        let stop = |n: usize| (0..n).map(|i| format!("s{i}")).collect::<Vec<_>>();
+        assert!(check_stop_sequences(None).unwrap().is_empty());
+        assert_eq!(check_stop_sequences(Some(&stop(4))).unwrap(), stop(4));
+        match check_stop_sequences(Some(&stop(5))) {
+            Err(AppError::BadRequest(msg)) => assert!(msg.contains("At most 4")),
+            other => panic!("Expected BadRequest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_stop_sequence_is_rejected() {
+        let stop = vec!["ok".to_string(), String::new()];
+        assert!(matches!(check_stop_sequences(Some(&stop)), Err(AppError::BadRequest(_))));
+    }
+
+    // ── check_max_tokens ─────────────────────────────────────
+
+    #[test]
+    fn max_tokens_must_be_positive() {
+        assert_eq!(check_max_tokens(None).unwrap(), None);
+        assert_eq!(check_max_tokens(Some(1)).unwrap(), Some(1));
+        assert!(matches!(check_max_tokens(Some(0)), Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn chat_max_tokens_accepts_either_name() {
+        assert_eq!(check_chat_max_tokens(None, None).unwrap(), None);
+        assert_eq!(check_chat_max_tokens(Some(64), None).unwrap(), Some(64));
+        assert_eq!(check_chat_max_tokens(None, Some(32)).unwrap(), Some(32));
+        assert_eq!(check_chat_max_tokens(Some(32), Some(32)).unwrap(), Some(32));
+    }
+
+    #[test]
+    fn chat_max_tokens_rejects_conflicts_and_zero() {
+        let err = check_chat_max_tokens(Some(64), Some(32)).unwrap_err();
+        assert!(err.to_string().contains("conflict"));
+        assert!(check_chat_max_tokens(None, Some(0)).is_err());
+        assert!(check_chat_max_tokens(Some(0), Some(0)).is_err());
+        assert!(check_chat_max_tokens(Some(0), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn conflicting_max_tokens_is_400() {
+        let cli = Arc::new(MockCliRunner::new(|_| scripted_run()));
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"max_tokens":64,"max_completion_tokens":32}"#;
+        let uri = "/v1/chat/completions";
+        let (status, body) = post_to(Config::default(), cli.clone(), uri, body).await;
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        assert!(body.contains("max_completion_tokens"));
+        assert_eq!(cli.runs(), 0);
+    }
+
+    // ── streaming_headers ────────────────────────────────────
+
+    #[test]
+    fn streaming_headers_disable_proxy_buffering() {
+        let headers = streaming_headers(&Config::default());
+        assert_eq!(headers["x-accel-buffering"], "no");
+    }
+
+    #[test]
+    fn streaming_headers_can_be_turned_off() {
+        let config = Config {
+            sse_no_buffering: false,
+            ..Default::default()
+        };
+        assert!(streaming_headers(&config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_use_the_configured_content_type() {
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#;
+        let (app, _) = hanging_app();
+        let response = send(&app, "/v1/chat/completions", body).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        let config = Config {
+            sse_content_type: Some(HeaderValue::from_static("text/event-stream; charset=utf-8")),
+            ..Default::default()
+        };
+        let (app, _) = hanging_app_with(config);
+        for uri in ["/v1/chat/completions", "/v1/messages"] {
+            let body = r#"{"model":"opus","max_tokens":9,"stream":true,"messages":[{"role":"user","content":"hi"}]}"#;
+            // Each stream hangs, keeping its request id in flight
+            let response = send_as(&app, uri, uri, body).await;
+            let content_type = &response.headers()[header::CONTENT_TYPE];
+            assert_eq!(content_type, "text/event-stream; charset=utf-8");
+        }
+    }
+
+    // ── empty_completion_headers ─────────────────────────────
+
+    fn result_with(text: Option<&str>) -> ResultMessage {
+        ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: text.map(str::to_string),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: None,
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn empty_result_is_flagged() {
+        for text in [None, Some(""), Some("  \n")] {
+            let headers = empty_completion_headers(&result_with(text));
+            assert_eq!(headers["x-empty-completion"], "true");
+        }
+    }
+
+    #[test]
+    fn non_empty_result_is_not_flagged() {
+        let headers = empty_completion_headers(&result_with(Some("Hello")));
+        assert!(headers.get("x-empty-completion").is_none());
+    }
+
+    // ── warn_lossy_result ────────────────────────────────────
+
+    #[test]
+    fn replacement_chars_are_flagged() {
+        assert!(warn_lossy_result(&result_with(Some("caf\u{FFFD} ok"))));
+        assert!(!warn_lossy_result(&result_with(Some("café ok"))));
+        assert!(!warn_lossy_result(&result_with(None)));
+    }
+
+    // ── openai_response ──────────────────────────────────────
+
+    fn result_with_usage() -> ResultMessage {
+        let usage = ModelUsage {
+            input_tokens: Some(10),
+            output_tokens: Some(5),
+            cache_read_tokens: None,
+            cache_write_tokens: None,
+        };
+        ResultMessage {
+            model_usage: Some(HashMap::from([("claude-sonnet-4".to_string(), usage)])),
+            ..result_with(Some("Hello"))
+        }
+    }
+
+    /// A result whose usage lists `count` models, 10 input tokens each.
+    fn result_with_models(count: usize) -> ResultMessage {
+        let usage = || ModelUsage {
+            input_tokens: Some(10),
+            output_tokens: Some(1),
+            cache_read_tokens: None,
+            cache_write_tokens: None,
+        };
+        let models = (0..count).map(|i| (format!("claude-model-{i}"), usage()));
+        ResultMessage {
+            model_usage: Some(models.collect()),
+            ..result_with(Some("Hello"))
+        }
+    }
+
+    #[test]
+    fn usage_models_counted_only_beyond_the_limit() {
+        let metrics = Metrics::default();
+        check_usage_models(&result_with_models(3), 3, &metrics);
+        check_usage_models(&result_with(Some("Hi")), 0, &metrics);
+        assert_eq!(metrics.snapshot().usage_model_overflows, 0);
+        check_usage_models(&result_with_models(4), 3, &metrics);
+        assert_eq!(metrics.snapshot().usage_model_overflows, 1);
+    }
+
+    #[tokio::test]
+    async fn many_usage_models_are_flagged_and_still_summed() {
+        use tower::ServiceExt;
+        let cli = Arc::new(MockCliRunner::new(|_| {
+            vec![
+                SubprocessEvent::Result(result_with_models(50)),
+                SubprocessEvent::Close(0, None),
+            ]
+        }));
+        let state = test_state_with(Config::default(), cli);
+        let metrics = state.metrics.clone();
+        let request = axum::http::Request::post("/v1/chat/completions")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(CHAT_BODY))
+            .unwrap();
+        let response = crate::server::create_router(state).oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(json["usage"]["prompt_tokens"], 500);
+        assert_eq!(json["usage"]["completion_tokens"], 50);
+        assert_eq!(metrics.snapshot().usage_model_overflows, 1);
+    }
+
+    #[test]
+    fn usage_included_by_default() {
+        let response = openai_response(&Config::default(), &result_with_usage(), "req1", "sonnet");
+        assert_eq!(response.usage.map(|u| u.total_tokens), Some(15));
+    }
+
+    #[test]
+    fn usage_omitted_when_configured() {
+        let config = Config {
+            omit_usage: true,
+            ..Default::default()
+        };
+        let response = openai_response(&config, &result_with_usage(), "req1", "sonnet");
+        let json = serde_json::to_value(&response).unwrap();
+        assert!(json.get("usage").is_none());
+        assert_eq!(json["choices"][0]["message"]["content"], "Hello");
+    }
+
+    #[test]
+    fn service_tier_only_when_configured() {
+        let response = openai_response(&Config::default(), &result_with_usage(), "req1", "sonnet");
+        assert_eq!(response.service_tier, None);
+
+        let config = Config {
+            service_tier: Some("default".to_string()),
+            ..Default::default()
+        };
+        let response = openai_response(&config, &result_with_usage(), "req1", "sonnet");
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["service_tier"], "default");
+    }
+
+    // ── truncation_headers ───────────────────────────────────
+
+    #[test]
+    fn truncation_header_only_when_trimmed() {
+        assert!(truncation_headers(&[]).get("x-prompt-truncated").is_none());
+        assert_eq!(truncation_headers(&[0, 1])["x-prompt-truncated"], "true");
+    }
+
+    // ── count_tokens ─────────────────────────────────────────
+
+    async fn count(body: serde_json::Value) -> Result<u64, AppError> {
+        let request = serde_json::from_value(body).unwrap();
+        Ok(count_tokens(Json(request)).await?.0.input_tokens)
+    }
+
+    #[tokio::test]
+    async fn count_tokens_text_content() {
+        // 12 characters
+        let body = json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 1024,
+            "messages": [{"role": "user", "content": "Hello there!"}],
+        });
+        assert_eq!(count(body).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn count_tokens_block_content() {
+        // Only text blocks count: "Hello " + "there!" is 12 characters
+        let body = json!({
+            "model": "claude-sonnet-4",
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "Hello "},
+                {"type": "image"},
+                {"type": "text", "text": "there!"},
+            ]}],
+        });
+        assert_eq!(count(body).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn count_tokens_includes_system_prompt() {
+        let without = json!({
+            "model": "claude-sonnet-4",
+            "messages": [{"role": "user", "content": "Hello there!"}],
+        });
+        let mut with = without.clone();
+        with["system"] = json!("You are terse.");
+        // "<system>\nYou are terse.\n</system>\n\nHello there!" is 47 characters
+        assert_eq!(count(with).await.unwrap(), 12);
+        assert!(count(without).await.unwrap() < 12);
+    }
+
+    #[tokio::test]
+    async fn count_tokens_requires_messages() {
+        let body = json!({"model": "claude-sonnet-4", "messages": []});
+        assert!(matches!(count(body).await, Err(AppError::BadRequest(_))));
+    }
+
+    // ── debug_headers ────────────────────────────────────────
+
+    #[test]
+    fn debug_headers_report_configured_timeout() {
+        let config = Config {
+            debug_features: true,
+            inactivity_timeout: Duration::from_secs(90),
+            ..Default::default()
+        };
+        let mut opts = options(config.inactivity_timeout);
+        let headers = debug_headers(&config, &opts);
+        assert_eq!(headers["x-debug-timeout-secs"], "90");
+        assert!(headers.get("x-debug-max-request-secs").is_none());
+
+        opts.max_duration = Some(Duration::from_secs(600));
+        let headers = debug_headers(&config, &opts);
+        assert_eq!(headers["x-debug-max-request-secs"], "600");
+    }
+
+    #[test]
+    fn debug_headers_absent_by_default() {
+        let config = Config::default();
+        let headers = debug_headers(&config, &options(config.inactivity_timeout));
+        assert!(headers.get("x-debug-timeout-secs").is_none());
+        assert!(headers.get("x-debug-max-request-secs").is_none());
+    }
+
+    // ── cancel_request ───────────────────────────────────────
+
+    /// A CLI that streams one delta and then runs until it is killed.
+    struct HangingCli {
+        killed: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl CliRunner for HangingCli {
+        fn run(
+            &self,
+            _prompt: String,
+            _options: SubprocessOptions,
+            tx: mpsc::Sender<SubprocessEvent>,
+        ) -> subprocess::BoxFuture<crate::metrics::RunOutcome> {
+            let killed = self.killed.clone();
+            Box::pin(async move {
+                let _ = tx.send(SubprocessEvent::ContentDelta("partial".to_string())).await;
+                tx.closed().await;
+                killed.store(true, std::sync::atomic::Ordering::SeqCst);
+                crate::metrics::RunOutcome::ClientClosed
+            })
+        }
+    }
+
+    fn hanging_app() -> (axum::Router, Arc<std::sync::atomic::AtomicBool>) {
+        hanging_app_with(Config::default())
+    }
+
+    fn hanging_app_with(config: Config) -> (axum::Router, Arc<std::sync::atomic::AtomicBool>) {
+        let killed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let cli = Arc::new(HangingCli {
+            killed: killed.clone(),
+        });
+        let app = crate::server::create_router(test_state_with(config, cli));
+        (app, killed)
+    }
+
+    async fn send(app: &axum::Router, uri: &str, body: &str) -> Response {
+        send_as(app, "req-cancel", uri, body).await
+    }
+
+    async fn send_as(app: &axum::Router, id: &str, uri: &str, body: &str) -> Response {
+        send_with_key(app, None, id, uri, body).await
+    }
+
+    /// Send with request id `id`, authenticating with `key` if given.
+    async fn send_with_key(
+        app: &axum::Router,
+        key: Option<&str>,
+        id: &str,
+        uri: &str,
+        body: &str,
+    ) -> Response {
+        use tower::ServiceExt;
+        let mut request = axum::http::Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-request-id", id);
+        if let Some(key) = key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {key}"));
+        }
+        let request = request.body(axum::body::Body::from(body.to_string())).unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn cancel(app: &axum::Router) -> axum::http::StatusCode {
+        send(app, "/v1/requests/req-cancel/cancel", "").await.status()
+    }
+
+    async fn body_text(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn cancel_ends_a_stream_with_an_error_event() {
+        let (app, killed) = hanging_app();
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#;
+        let response = send(&app, "/v1/chat/completions", body).await;
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+
+        assert_eq!(cancel(&app).await, axum::http::StatusCode::OK);
+        let events = sse_data(&body_text(response).await);
+        assert_eq!(events.last().unwrap()["error"]["message"], CANCELLED);
+        assert!(killed.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(cancel(&app).await, axum::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn cancel_fails_a_non_streaming_request() {
+        let (app, killed) = hanging_app();
+        let task_app = app.clone();
+        let body = r#"{"model":"opus","max_tokens":9,"messages":[{"role":"user","content":"hi"}]}"#;
+        let request = tokio::spawn(async move { send(&task_app, "/v1/messages", body).await });
+        // Wait for the run to register
+        let mut status = cancel(&app).await;
+        while status == axum::http::StatusCode::NOT_FOUND {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            status = cancel(&app).await;
+        }
+        assert_eq!(status, axum::http::StatusCode::OK);
+
+        let response = request.await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+        let error: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(error["error"]["message"], CANCELLED);
+        assert!(killed.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    fn two_client_config() -> Config {
+        let key = |label: &str| config::ApiKey {
+            label: label.to_string(),
+            key: format!("sk-{label}"),
+        };
+        Config {
+            api_keys: vec![key("alice"), key("bob")],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_sending_client_can_cancel() {
+        let (app, killed) = hanging_app_with(two_client_config());
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#;
+        let uri = "/v1/chat/completions";
+        let response = send_with_key(&app, Some("sk-alice"), "req-1", uri, body).await;
+        assert_eq!(response.status(), 200);
+
+        let cancel_uri = "/v1/requests/req-1/cancel";
+        let denied = send_with_key(&app, Some("sk-bob"), "c", cancel_uri, "").await;
+        assert_eq!(denied.status(), axum::http::StatusCode::NOT_FOUND);
+        assert!(!killed.load(std::sync::atomic::Ordering::SeqCst));
+
+        let allowed = send_with_key(&app, Some("sk-alice"), "c", cancel_uri, "").await;
+        assert_eq!(allowed.status(), axum::http::StatusCode::OK);
+        let events = sse_data(&body_text(response).await);
+        assert_eq!(events.last().unwrap()["error"]["message"], CANCELLED);
+    }
+
+    #[tokio::test]
+    async fn in_flight_request_ids_cannot_be_reused() {
+        let (app, _) = hanging_app_with(two_client_config());
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#;
+        let uri = "/v1/chat/completions";
+        let _first = send_with_key(&app, Some("sk-alice"), "req-1", uri, body).await;
+
+        let again = send_with_key(&app, Some("sk-alice"), "req-1", uri, body).await;
+        assert_eq!(again.status(), axum::http::StatusCode::CONFLICT);
+        // Ids are per client, so another one may use the same id
+        let other = send_with_key(&app, Some("sk-bob"), "req-1", uri, body).await;
+        assert_eq!(other.status(), axum::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_running_requests() {
+        let killed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let cli = Arc::new(HangingCli {
+            killed: killed.clone(),
+        });
+        let (stop_runs, shutdown) = tokio::sync::watch::channel(false);
+        let state = AppState {
+            shutdown,
+            ..test_state_with(Config::default(), cli)
+        };
+        let app = crate::server::create_router(state);
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#;
+        let response = send(&app, "/v1/chat/completions", body).await;
+
+        stop_runs.send(true).unwrap();
+        let events = sse_data(&body_text(response).await);
+        assert_eq!(events.last().unwrap()["error"]["message"], SHUTTING_DOWN);
+        assert!(killed.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    fn admin_config() -> Config {
+        Config {
+            admin_key: Some("sk-admin".to_string()),
+            ..two_client_config()
+        }
+    }
+
+    async fn cancel_all(app: &axum::Router, key: Option<&str>) -> Response {
+        send_with_key(app, key, "admin", "/v1/admin/cancel-all", "").await
+    }
+
+    #[tokio::test]
+    async fn cancel_all_stops_every_running_request() {
+        let (app, killed) = hanging_app_with(admin_config());
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#;
+        let uri = "/v1/chat/completions";
+        let first = send_with_key(&app, Some("sk-alice"), "req-1", uri, body).await;
+        let second = send_with_key(&app, Some("sk-bob"), "req-2", uri, body).await;
+
+        let response = cancel_all(&app, Some("sk-admin")).await;
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(reply["cancelled"], 2);
+        for response in [first, second] {
+            let events = sse_data(&body_text(response).await);
+            assert_eq!(events.last().unwrap()["error"]["message"], CANCELLED);
+        }
+        assert!(killed.load(std::sync::atomic::Ordering::SeqCst));
+
+        let response = cancel_all(&app, Some("sk-admin")).await;
+        let reply: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(reply["cancelled"], 0);
+    }
+
+    #[tokio::test]
+    async fn cancel_all_requires_the_admin_key() {
+        let (app, killed) = hanging_app_with(admin_config());
+        let body = r#"{"messages":[{"role":"user","content":"hi"}],"stream":true}"#;
+        let uri = "/v1/chat/completions";
+        let _running = send_with_key(&app, Some("sk-alice"), "req-1", uri, body).await;
+
+        for key in [None, Some("sk-alice"), Some("sk-wrong")] {
+            let status = cancel_all(&app, key).await.status();
+            assert_eq!(status, axum::http::StatusCode::UNAUTHORIZED, "{key:?}");
+        }
+        assert!(!killed.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn cancel_all_is_not_served_without_an_admin_key() {
+        let (app, _) = hanging_app_with(two_client_config());
+        let status = cancel_all(&app, Some("sk-alice")).await.status();
+        assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn cancel_unknown_request_is_404() {
+        let (app, _) = hanging_app();
+        assert_eq!(cancel(&app).await, axum::http::StatusCode::NOT_FOUND);
+    }
+
+    // ── sessions ─────────────────────────────────────────────
+
+    async fn session_request(
+        state: &AppState,
+        method: axum::http::Method,
+        uri: &str,
+    ) -> (axum::http::StatusCode, serde_json::Value) {
+        use tower::ServiceExt;
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let app = crate::server::create_router(state.clone());
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        (status, serde_json::from_str(&body_text(response).await).unwrap())
+    }
+
+    const SESSION_CHAT: &str =
+        r#"{"model":"opus","user":"client-1","messages":[{"role":"user","content":"hi"}]}"#;
+
+    #[tokio::test]
+    async fn requests_map_client_sessions_to_cli_sessions() {
+        use axum::http::Method;
+        let cli = Arc::new(MockCliRunner::new(|_| scripted_run()));
+        let state = test_state_with(Config::default(), cli.clone());
+        for _ in 0..2 {
+            let (status, _) = post_state(state.clone(), "/v1/chat/completions", SESSION_CHAT).await;
+            assert_eq!(status, 200);
+        }
+
+        // Both runs get the one CLI session id the store created for the client
+        let session_ids = cli.session_ids();
+        assert_eq!(session_ids.len(), 2);
+        assert_eq!(session_ids[0], session_ids[1]);
+        let cli_id = session_ids[0].clone().unwrap();
+        assert!(uuid::Uuid::parse_str(&cli_id).is_ok());
+
+        let (status, body) = session_request(&state, Method::GET, "/v1/sessions").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["data"][0]["id"], "client-1");
+        assert_eq!(body["data"][0]["model"], "opus");
+        assert!(!body.to_string().contains(&cli_id));
+        let (status, body) = session_request(&state, Method::GET, "/v1/sessions/client-1").await;
+        assert_eq!(status, 200);
+        assert!(!body.to_string().contains(&cli_id));
+        let (status, _) = session_request(&state, Method::GET, "/v1/sessions/nope").await;
+        assert_eq!(status, 404);
+
+        // Once deleted, the client's next request starts a fresh CLI session
+        let uri = "/v1/sessions/client-1";
+        let (status, body) = session_request(&state, Method::DELETE, uri).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["deleted"], true);
+        assert_eq!(session_request(&state, Method::DELETE, uri).await.0, 404);
+        post_state(state.clone(), "/v1/chat/completions", SESSION_CHAT).await;
+        assert_ne!(cli.session_ids()[2].as_deref(), Some(cli_id.as_str()));
+    }
+
+    #[tokio::test]
+    async fn salted_sessions_are_found_by_client_id() {
+        use axum::http::Method;
+        let cli = Arc::new(MockCliRunner::new(|_| scripted_run()));
+        let config = Config {
+            session_salt: Some("team-a".to_string()),
+            ..Default::default()
+        };
+        let state = test_state_with(config, cli.clone());
+        post_state(state.clone(), "/v1/chat/completions", SESSION_CHAT).await;
+
+        let (status, _) = session_request(&state, Method::GET, "/v1/sessions/client-1").await;
+        assert_eq!(status, 200);
+        assert!(uuid::Uuid::parse_str(cli.session_ids()[0].as_deref().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn listed_salted_sessions_can_be_deleted() {
+        use axum::http::Method;
+        let cli = Arc::new(MockCliRunner::new(|_| scripted_run()));
+        let config = Config {
+            session_salt: Some("team-a".to_string()),
+            ..Default::default()
+        };
+        let state = test_state_with(config, cli);
+        post_state(state.clone(), "/v1/chat/completions", SESSION_CHAT).await;
+
+        let (status, body) = session_request(&state, Method::GET, "/v1/sessions").await;
+        assert_eq!(status, 200);
+        let id = body["data"][0]["id"].as_str().unwrap().to_string();
+        assert_eq!(id, "client-1");
+        let uri = format!("/v1/sessions/{id}");
+        assert_eq!(session_request(&state, Method::GET, &uri).await.0, 200);
+        let (status, body) = session_request(&state, Method::DELETE, &uri).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["id"], "client-1");
+        let (_, body) = session_request(&state, Method::GET, "/v1/sessions").await;
+        assert_eq!(body["data"], json!([]));
+
+        // A mapping kept without its client id is listed, and found, by key
+        state.session_manager.get_or_create("stored-key", None, "opus").await;
+        let (_, body) = session_request(&state, Method::GET, "/v1/sessions").await;
+        assert_eq!(body["data"][0]["id"], "stored-key");
+        let uri = "/v1/sessions/stored-key";
+        assert_eq!(session_request(&state, Method::DELETE, uri).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn sessions_require_an_api_key() {
+        use axum::http::Method;
+        let state = test_state(Config {
+            api_keys: vec![config::ApiKey {
+                label: "ops".to_string(),
+                key: "sk-ops".to_string(),
+            }],
+            ..Default::default()
+        });
+        state.session_manager.get_or_create("client-1", None, "opus").await;
+        for (method, uri) in [
+            (Method::GET, "/v1/sessions"),
+            (Method::GET, "/v1/sessions/client-1"),
+            (Method::DELETE, "/v1/sessions/client-1"),
+        ] {
+            assert_eq!(session_request(&state, method, uri).await.0, 401);
+        }
+        assert!(state.session_manager.get("client-1").await.is_some());
+    }
+
+    // ── CORS ─────────────────────────────────────────────────
+
+    /// The CORS headers of a preflight for a streaming chat completion sent
+    /// with an API key, from `origin`.
+    async fn preflight(cors: config::CorsPolicy, origin: &str) -> HeaderMap {
+        use tower::ServiceExt;
+        let config = Config {
+            cors,
+            ..Default::default()
+        };
+        let request = axum::http::Request::builder()
+            .method(axum::http::Method::OPTIONS)
+            .uri("/v1/chat/completions")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization,content-type")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let app = crate::server::create_router(test_state(config));
+        app.oneshot(request).await.unwrap().headers().clone()
+    }
+
+    #[tokio::test]
+    async fn cors_allow_list_admits_listed_origins_only() {
+        let origins = vec![HeaderValue::from_static("https://app.example.com")];
+        let cors = config::CorsPolicy::Origins(origins);
+
+        let headers = preflight(cors.clone(), "https://app.example.com").await;
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "POST");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization,content-type"
+        );
+
+        let headers = preflight(cors, "https://evil.example.com").await;
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn cors_permissive_and_disabled() {
+        let origin = "https://any.example.com";
+        let headers = preflight(config::CorsPolicy::Permissive, origin).await;
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+
+        let headers = preflight(config::CorsPolicy::Disabled, origin).await;
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
 }
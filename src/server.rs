@@ -1,25 +1,97 @@
 use axum::Router;
+use axum::middleware;
 use axum::routing::{get, post};
-use tower_http::cors::CorsLayer;
+use std::sync::Arc;
+use tokio::sync::{Semaphore, watch};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
 
+use crate::auth;
+use crate::cancel::CancelRegistry;
+use crate::config::{Config, CorsPolicy};
+use crate::metrics::Metrics;
 use crate::routes;
 use crate::session::SessionManager;
+use crate::subprocess::CliRunner;
+use crate::telemetry;
 
 #[derive(Clone)]
 pub struct AppState {
     pub cwd: String,
-    #[allow(dead_code)]
     pub session_manager: SessionManager,
+    pub config: Arc<Config>,
+    pub metrics: Arc<Metrics>,
+    /// One permit per running CLI subprocess (`--max-concurrency`)
+    pub concurrency: Arc<Semaphore>,
+    /// Runs the CLI for each request
+    pub cli: Arc<dyn CliRunner>,
+    /// In-flight runs, for `POST /v1/requests/{id}/cancel`
+    pub cancellations: CancelRegistry,
+    /// Becomes true when a shutdown's grace period ends; runs still going
+    /// are then killed (`--shutdown-grace-secs`)
+    pub shutdown: watch::Receiver<bool>,
+}
+
+/// The CORS layer for `policy`. An allow-list still admits any method and
+/// mirrors the requested headers, so `Authorization`, `x-api-key` and the
+/// proxy's own headers pass preflight, and lets clients read every response
+/// header (e.g. the request id). Disabled, the layer allows no origin and
+/// adds no CORS headers.
+fn cors_layer(policy: &CorsPolicy) -> CorsLayer {
+    match policy {
+        CorsPolicy::Permissive => CorsLayer::permissive(),
+        CorsPolicy::Origins(origins) => CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins.iter().cloned()))
+            .allow_methods(AllowMethods::mirror_request())
+            .allow_headers(AllowHeaders::mirror_request())
+            .expose_headers(ExposeHeaders::any()),
+        CorsPolicy::Disabled => CorsLayer::new(),
+    }
 }
 
 pub fn create_router(state: AppState) -> Router {
-    let cors = CorsLayer::permissive();
+    let cors = cors_layer(&state.config.cors);
 
-    Router::new()
-        .route("/health", get(routes::health))
+    let mut api = Router::new()
+        .route("/v1/config", get(routes::runtime_config))
         .route("/v1/models", get(routes::models))
+        .route("/v1/models/{id}", get(routes::model))
         .route("/v1/chat/completions", post(routes::chat_completions))
         .route("/v1/messages", post(routes::messages))
+        .route("/v1/messages/count_tokens", post(routes::count_tokens))
+        .route("/v1/complete", post(routes::complete))
+        .route("/v1/completions", post(routes::completions))
+        .route("/v1/embeddings", post(routes::embeddings))
+        .route("/v1/requests/{id}/cancel", post(routes::cancel_request))
+        .route("/v1/sessions", get(routes::list_sessions))
+        .route(
+            "/v1/sessions/{id}",
+            get(routes::get_session).delete(routes::delete_session),
+        );
+    if !state.config.api_keys.is_empty() {
+        api = api.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_api_key,
+        ));
+    }
+    // Admin routes take their own key instead, and only exist with one
+    if state.config.admin_key.is_some() {
+        let admin = Router::new()
+            .route("/v1/admin/cancel-all", post(routes::cancel_all_requests))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::require_admin_key,
+            ));
+        api = api.merge(admin);
+    }
+    // Outside auth, so rejections carry the request id too
+    let api = api.layer(middleware::from_fn_with_state(
+        state.clone(),
+        telemetry::request_span,
+    ));
+
+    Router::new()
+        .route("/health", get(routes::health))
+        .merge(api)
         .fallback(routes::fallback)
         .layer(cors)
         .layer(axum::extract::DefaultBodyLimit::max(10 * 1024 * 1024)) // 10MB
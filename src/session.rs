@@ -2,15 +2,51 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::RwLock;
-use tracing::{error, info};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock, watch};
+use tracing::{error, info, warn};
 
 const SESSION_TTL_MS: u64 = 24 * 60 * 60 * 1000; // 24 hours
 
+/// How long a session lookup waits for the sessions file to finish loading.
+const SESSION_LOAD_WAIT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Bounds on what the session store holds, in memory and on disk.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionLimits {
+    /// Most mappings kept; the least recently used are dropped beyond this
+    pub max_sessions: usize,
+    /// A sessions file larger than this is backed up and skipped at startup
+    pub max_file_bytes: u64,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions: crate::config::DEFAULT_MAX_SESSIONS,
+            max_file_bytes: crate::config::DEFAULT_MAX_SESSIONS_FILE_MB * 1024 * 1024,
+        }
+    }
+}
+
+/// Where session mappings are kept between restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPersistence {
+    /// In `~/.claude-code-cli-sessions.json`, loaded at startup
+    File,
+    /// In memory only: nothing is read or written, and a restart forgets
+    /// every mapping (`--no-session-persistence`)
+    Memory,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionMapping {
     pub clawdbot_id: String,
+    /// The session id the client sent, when `--session-salt` made
+    /// `clawdbot_id` a derived one; absent otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
     pub claude_session_id: String,
     pub created_at: u64,
     pub last_used_at: u64,
@@ -20,7 +56,29 @@ pub struct SessionMapping {
 #[derive(Clone)]
 pub struct SessionManager {
     sessions: Arc<RwLock<HashMap<String, SessionMapping>>>,
-    file_path: PathBuf,
+    /// Where the sessions live on disk. Resolved once at construction, so a
+    /// later change to `$HOME` can't split loads and saves across two files;
+    /// only `rebind` moves it, and all clones follow.
+    file_path: Arc<std::sync::RwLock<PathBuf>>,
+    limits: SessionLimits,
+    /// Serializes writes so concurrent saves never interleave on disk
+    save_lock: Arc<Mutex<()>>,
+    /// One lock per CLI session id so runs under the same session don't overlap
+    session_locks: Arc<std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    /// Becomes true once the background load of the sessions file is done
+    loaded: watch::Receiver<bool>,
+    /// Set by mutations not yet written to disk; cleared by `flush`
+    dirty: Arc<AtomicBool>,
+    /// False in `SessionPersistence::Memory`, where loads and saves are skipped
+    persistent: bool,
+}
+
+/// Write `data` to `path` and flush it to disk.
+async fn write_synced(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await
 }
 
 fn now_ms() -> u64 {
@@ -30,37 +88,179 @@ fn now_ms() -> u64 {
         .as_millis() as u64
 }
 
+/// Drop the least recently used mappings until at most `max` remain,
+/// returning how many were dropped.
+fn evict_oldest(sessions: &mut HashMap<String, SessionMapping>, max: usize) -> usize {
+    let excess = sessions.len().saturating_sub(max);
+    if excess == 0 {
+        return 0;
+    }
+    let mut by_age: Vec<(u64, String)> = sessions
+        .iter()
+        .map(|(id, mapping)| (mapping.last_used_at, id.clone()))
+        .collect();
+    by_age.select_nth_unstable(excess - 1);
+    for (_, id) in &by_age[..excess] {
+        sessions.remove(id);
+    }
+    excess
+}
+
+/// The sessions file under the current home directory.
+pub fn default_file_path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join(".claude-code-cli-sessions.json")
+}
+
 impl SessionManager {
-    pub fn new() -> Self {
-        let file_path = dirs::home_dir()
-            .unwrap_or_else(|| PathBuf::from("/tmp"))
-            .join(".claude-code-cli-sessions.json");
+    pub fn new(
+        persistence: SessionPersistence,
+        limits: SessionLimits,
+        save_interval: Duration,
+    ) -> Self {
+        match persistence {
+            SessionPersistence::File => Self::open(default_file_path(), limits, save_interval),
+            SessionPersistence::Memory => {
+                info!("Session persistence disabled; sessions are kept in memory only");
+                Self::in_memory(limits)
+            }
+        }
+    }
+
+    /// Create a SessionManager that never touches disk.
+    fn in_memory(limits: SessionLimits) -> Self {
+        Self {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            file_path: Arc::new(std::sync::RwLock::new(PathBuf::new())),
+            limits,
+            save_lock: Arc::new(Mutex::new(())),
+            session_locks: Arc::default(),
+            loaded: watch::channel(true).1,
+            dirty: Arc::default(),
+            persistent: false,
+        }
+    }
 
+    /// Create a SessionManager, load `file_path` in the background and
+    /// write pending changes back at most once per `save_interval`.
+    fn open(file_path: PathBuf, limits: SessionLimits, save_interval: Duration) -> Self {
+        let (loaded_tx, loaded) = watch::channel(false);
         let manager = Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
-            file_path,
+            file_path: Arc::new(std::sync::RwLock::new(file_path)),
+            limits,
+            save_lock: Arc::new(Mutex::new(())),
+            session_locks: Arc::default(),
+            loaded,
+            dirty: Arc::default(),
+            persistent: true,
         };
 
-        // Fire-and-forget load
+        // Background load; lookups wait briefly for it via `loaded`
         let m = manager.clone();
         tokio::spawn(async move {
             m.load().await;
+            let _ = loaded_tx.send(true);
+        });
+
+        // Coalesce mutations into one save per interval
+        let m = manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(save_interval.max(Duration::from_millis(1)));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                m.flush().await;
+            }
         });
 
         manager
     }
 
+    /// Note that the sessions changed; the next flush writes them.
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Write the sessions if anything changed since the last flush. Called
+    /// by the background flusher and once more at shutdown.
+    pub async fn flush(&self) {
+        if self.dirty.swap(false, Ordering::AcqRel) {
+            self.save().await;
+        }
+    }
+
+    /// The sessions file currently in use.
+    fn file_path(&self) -> PathBuf {
+        self.file_path.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Move the sessions file to `file_path` at runtime (on SIGHUP, see
+    /// `main`). The current sessions are written there right away and later
+    /// saves follow; the old file is left as it was. Does nothing without
+    /// persistence or when the path is unchanged.
+    pub async fn rebind(&self, file_path: PathBuf) {
+        if !self.persistent || self.file_path() == file_path {
+            return;
+        }
+        {
+            // Let an in-flight save finish on the old path first
+            let _guard = self.save_lock.lock().await;
+            info!("Sessions file moved to {}", file_path.display());
+            *self.file_path.write().unwrap_or_else(|e| e.into_inner()) = file_path;
+        }
+        self.save().await;
+    }
+
+    /// Whether the sessions file has been loaded.
+    pub fn is_loaded(&self) -> bool {
+        *self.loaded.borrow()
+    }
+
+    /// Wait up to `SESSION_LOAD_WAIT` for the sessions file, so requests
+    /// right after a restart still find their existing sessions.
+    async fn wait_loaded(&self) {
+        let mut loaded = self.loaded.clone();
+        let wait = tokio::time::timeout(SESSION_LOAD_WAIT, loaded.wait_for(|l| *l)).await;
+        if wait.is_err() {
+            warn!(
+                "Sessions file not loaded after {}s; continuing without it",
+                SESSION_LOAD_WAIT.as_secs()
+            );
+        }
+    }
+
     async fn load(&self) {
-        match tokio::fs::read_to_string(&self.file_path).await {
+        if self.set_aside_oversized().await {
+            return;
+        }
+        let file_path = self.file_path();
+        match tokio::fs::read_to_string(&file_path).await {
             Ok(data) => match serde_json::from_str::<HashMap<String, SessionMapping>>(&data) {
                 Ok(sessions) => {
-                    let mut lock = self.sessions.write().await;
-                    *lock = sessions;
-                    info!(
-                        "Loaded {} sessions from {}",
-                        lock.len(),
-                        self.file_path.display()
-                    );
+                    let dropped = {
+                        let mut lock = self.sessions.write().await;
+                        // Sessions created while loading take precedence
+                        for (id, mapping) in sessions {
+                            lock.entry(id).or_insert(mapping);
+                        }
+                        let now = now_ms();
+                        let before = lock.len();
+                        lock.retain(|_, v| now.saturating_sub(v.last_used_at) < SESSION_TTL_MS);
+                        let expired = before - lock.len();
+                        let evicted = evict_oldest(&mut lock, self.limits.max_sessions);
+                        info!(
+                            "Loaded {} sessions from {} ({expired} expired, {evicted} over \
+                             --max-sessions dropped)",
+                            lock.len(),
+                            file_path.display()
+                        );
+                        expired + evicted
+                    };
+                    if dropped > 0 {
+                        self.save().await;
+                    }
                 }
                 Err(e) => {
                     error!("Failed to parse sessions file: {}", e);
@@ -75,27 +275,96 @@ impl SessionManager {
         }
     }
 
+    /// Move a sessions file over `max_file_bytes` to `<file>.bak` rather
+    /// than reading it all into memory. Returns true if the file was skipped.
+    async fn set_aside_oversized(&self) -> bool {
+        let file_path = self.file_path();
+        let Ok(metadata) = tokio::fs::metadata(&file_path).await else {
+            return false;
+        };
+        if metadata.len() <= self.limits.max_file_bytes {
+            return false;
+        }
+        let backup = file_path.with_extension("json.bak");
+        warn!(
+            "Sessions file {} is {} bytes, over the {}-byte limit; moving it to {} and \
+             starting with no sessions",
+            file_path.display(),
+            metadata.len(),
+            self.limits.max_file_bytes,
+            backup.display()
+        );
+        if let Err(e) = tokio::fs::rename(&file_path, &backup).await {
+            error!("Failed to back up oversized sessions file: {}", e);
+        }
+        true
+    }
+
+    /// Write the sessions file. Saves are serialized, and each one writes a
+    /// temporary file and renames it into place so readers never see a
+    /// partially written file. The temporary file is named after this
+    /// process, so another proxy sharing the file can't write into it, and is
+    /// flushed to disk before the rename, so a crash can't leave the renamed
+    /// file empty.
     async fn save(&self) {
-        let sessions = self.sessions.read().await;
-        match serde_json::to_string_pretty(&*sessions) {
-            Ok(data) => {
-                if let Err(e) = tokio::fs::write(&self.file_path, data).await {
-                    error!("Failed to write sessions file: {}", e);
+        if !self.persistent {
+            return;
+        }
+        let _guard = self.save_lock.lock().await;
+
+        // Snapshot under the lock so the newest state is what lands last
+        let data = {
+            let sessions = self.sessions.read().await;
+            match serde_json::to_string_pretty(&*sessions) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Failed to serialize sessions: {}", e);
+                    return;
                 }
             }
-            Err(e) => {
-                error!("Failed to serialize sessions: {}", e);
-            }
+        };
+
+        let file_path = self.file_path();
+        // The file may live under a directory that doesn't exist yet
+        if let Some(dir) = file_path.parent()
+            && let Err(e) = tokio::fs::create_dir_all(dir).await
+        {
+            error!("Failed to create sessions directory {}: {}", dir.display(), e);
+            return;
+        }
+
+        let tmp_path = file_path.with_extension(format!("json.{}.tmp", std::process::id()));
+        if let Err(e) = write_synced(&tmp_path, data.as_bytes()).await {
+            error!("Failed to write sessions file: {}", e);
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return;
+        }
+        if let Err(e) = tokio::fs::rename(&tmp_path, &file_path).await {
+            error!("Failed to replace sessions file: {}", e);
+            let _ = tokio::fs::remove_file(&tmp_path).await;
         }
     }
 
-    #[allow(dead_code)]
-    pub async fn get_or_create(&self, clawdbot_id: &str, model: &str) -> String {
+    /// The CLI session for `clawdbot_id`, created on first use. When the key
+    /// is derived from the client's own id (see
+    /// `config::namespace_session_id`), `client_id` is kept in the mapping so
+    /// `/v1/sessions` can list it under what the client sent.
+    pub async fn get_or_create(
+        &self,
+        clawdbot_id: &str,
+        client_id: Option<&str>,
+        model: &str,
+    ) -> String {
+        self.wait_loaded().await;
         {
             let mut sessions = self.sessions.write().await;
             if let Some(session) = sessions.get_mut(clawdbot_id) {
                 session.last_used_at = now_ms();
                 session.model = model.to_string();
+                // Mappings saved before client ids were kept learn theirs
+                if session.client_id.is_none() && client_id.is_some() {
+                    session.client_id = client_id.map(str::to_string);
+                }
                 return session.claude_session_id.clone();
             }
         }
@@ -103,6 +372,7 @@ impl SessionManager {
         let session_id = uuid::Uuid::new_v4().to_string();
         let mapping = SessionMapping {
             clawdbot_id: clawdbot_id.to_string(),
+            client_id: client_id.map(str::to_string),
             claude_session_id: session_id.clone(),
             created_at: now_ms(),
             last_used_at: now_ms(),
@@ -112,17 +382,57 @@ impl SessionManager {
         {
             let mut sessions = self.sessions.write().await;
             sessions.insert(clawdbot_id.to_string(), mapping);
+            evict_oldest(&mut sessions, self.limits.max_sessions);
         }
 
-        // Fire-and-forget save
-        let m = self.clone();
-        tokio::spawn(async move {
-            m.save().await;
-        });
-
+        self.mark_dirty();
         session_id
     }
 
+    /// All mappings, most recently used first.
+    pub async fn list(&self) -> Vec<SessionMapping> {
+        self.wait_loaded().await;
+        let mut sessions: Vec<_> = self.sessions.read().await.values().cloned().collect();
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.last_used_at));
+        sessions
+    }
+
+    /// The mapping for `clawdbot_id`, if any.
+    pub async fn get(&self, clawdbot_id: &str) -> Option<SessionMapping> {
+        self.wait_loaded().await;
+        self.sessions.read().await.get(clawdbot_id).cloned()
+    }
+
+    /// Forget the mapping for `clawdbot_id`, so the client's next request
+    /// gets a fresh CLI session id instead of this one, and drop
+    /// the session's lock. A lock a run still holds or waits on is kept,
+    /// so that run stays exclusive; `lock_session` prunes it once released.
+    pub async fn remove(&self, clawdbot_id: &str) -> Option<SessionMapping> {
+        self.wait_loaded().await;
+        let removed = self.sessions.write().await.remove(clawdbot_id)?;
+        {
+            let mut locks = self.session_locks.lock().unwrap_or_else(|e| e.into_inner());
+            let id = &removed.claude_session_id;
+            if locks.get(id).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+                locks.remove(id);
+            }
+        }
+        self.mark_dirty();
+        Some(removed)
+    }
+
+    /// Wait for exclusive use of a CLI session. Hold the guard until the run
+    /// using the session has finished; different sessions don't contend.
+    pub async fn lock_session(&self, session_id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.session_locks.lock().unwrap_or_else(|e| e.into_inner());
+            // Drop locks nobody holds or waits on
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(session_id.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
     pub async fn cleanup_expired(&self) {
         let now = now_ms();
         let mut removed = 0;
@@ -140,7 +450,7 @@ impl SessionManager {
 
         if removed > 0 {
             info!("Cleaned up {} expired sessions", removed);
-            self.save().await;
+            self.mark_dirty();
         }
     }
 
@@ -158,10 +468,22 @@ impl SessionManager {
 
     /// Create a SessionManager with a custom file path (for testing).
     #[cfg(test)]
-    fn with_path(file_path: PathBuf) -> Self {
+    pub(crate) fn with_path(file_path: PathBuf) -> Self {
+        Self::with_limits(file_path, SessionLimits::default())
+    }
+
+    /// Like `with_path`, with custom limits (for testing).
+    #[cfg(test)]
+    pub(crate) fn with_limits(file_path: PathBuf, limits: SessionLimits) -> Self {
         Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
-            file_path,
+            file_path: Arc::new(std::sync::RwLock::new(file_path)),
+            limits,
+            save_lock: Arc::new(Mutex::new(())),
+            session_locks: Arc::default(),
+            loaded: watch::channel(true).1,
+            dirty: Arc::default(),
+            persistent: true,
         }
     }
 }
@@ -180,7 +502,7 @@ mod tests {
     #[tokio::test]
     async fn get_or_create_new_session() {
         let mgr = SessionManager::with_path(temp_path());
-        let id = mgr.get_or_create("client-1", "opus").await;
+        let id = mgr.get_or_create("client-1", None, "opus").await;
         assert!(!id.is_empty());
         // UUID format
         assert_eq!(id.len(), 36);
@@ -189,24 +511,33 @@ mod tests {
     #[tokio::test]
     async fn get_or_create_returns_same_session() {
         let mgr = SessionManager::with_path(temp_path());
-        let id1 = mgr.get_or_create("client-1", "opus").await;
-        let id2 = mgr.get_or_create("client-1", "sonnet").await;
+        let id1 = mgr.get_or_create("client-1", None, "opus").await;
+        let id2 = mgr.get_or_create("client-1", None, "sonnet").await;
         assert_eq!(id1, id2);
     }
 
     #[tokio::test]
     async fn get_or_create_different_clients() {
         let mgr = SessionManager::with_path(temp_path());
-        let id1 = mgr.get_or_create("client-1", "opus").await;
-        let id2 = mgr.get_or_create("client-2", "opus").await;
+        let id1 = mgr.get_or_create("client-1", None, "opus").await;
+        let id2 = mgr.get_or_create("client-2", None, "opus").await;
         assert_ne!(id1, id2);
     }
 
+    #[tokio::test]
+    async fn get_or_create_keeps_the_client_id() {
+        let mgr = SessionManager::with_path(temp_path());
+        let id = mgr.get_or_create("derived-1", Some("client-1"), "opus").await;
+        let session = mgr.get("derived-1").await.unwrap();
+        assert_eq!(session.client_id.as_deref(), Some("client-1"));
+        assert_eq!(mgr.get_or_create("derived-1", None, "opus").await, id);
+    }
+
     #[tokio::test]
     async fn get_or_create_updates_model() {
         let mgr = SessionManager::with_path(temp_path());
-        mgr.get_or_create("client-1", "opus").await;
-        mgr.get_or_create("client-1", "sonnet").await;
+        mgr.get_or_create("client-1", None, "opus").await;
+        mgr.get_or_create("client-1", None, "sonnet").await;
 
         let sessions = mgr.sessions.read().await;
         assert_eq!(sessions["client-1"].model, "sonnet");
@@ -215,7 +546,7 @@ mod tests {
     #[tokio::test]
     async fn get_or_create_updates_last_used() {
         let mgr = SessionManager::with_path(temp_path());
-        mgr.get_or_create("client-1", "opus").await;
+        mgr.get_or_create("client-1", None, "opus").await;
         let t1 = {
             let sessions = mgr.sessions.read().await;
             sessions["client-1"].last_used_at
@@ -224,7 +555,7 @@ mod tests {
         // Small sleep to ensure timestamp changes
         tokio::time::sleep(std::time::Duration::from_millis(2)).await;
 
-        mgr.get_or_create("client-1", "opus").await;
+        mgr.get_or_create("client-1", None, "opus").await;
         let t2 = {
             let sessions = mgr.sessions.read().await;
             sessions["client-1"].last_used_at
@@ -244,6 +575,7 @@ mod tests {
                 "old-client".to_string(),
                 SessionMapping {
                     clawdbot_id: "old-client".to_string(),
+                    client_id: None,
                     claude_session_id: "old-session".to_string(),
                     created_at: 0,
                     last_used_at: 0, // epoch — definitely expired
@@ -254,6 +586,7 @@ mod tests {
                 "new-client".to_string(),
                 SessionMapping {
                     clawdbot_id: "new-client".to_string(),
+                    client_id: None,
                     claude_session_id: "new-session".to_string(),
                     created_at: now_ms(),
                     last_used_at: now_ms(),
@@ -267,12 +600,87 @@ mod tests {
         let sessions = mgr.sessions.read().await;
         assert!(!sessions.contains_key("old-client"));
         assert!(sessions.contains_key("new-client"));
+        drop(sessions);
+
+        // The removal reaches disk with the next flush
+        mgr.flush().await;
+        let data = std::fs::read_to_string(mgr.file_path()).unwrap();
+        assert!(!data.contains("old-client") && data.contains("new-client"));
+    }
+
+    #[tokio::test]
+    async fn remove_forgets_the_session_and_its_lock() {
+        let path = temp_path();
+        let mgr = SessionManager::with_path(path.clone());
+        let first = mgr.get_or_create("client-1", None, "opus").await;
+        mgr.get_or_create("client-2", None, "sonnet").await;
+        drop(mgr.lock_session(&first).await);
+        // Not yet pruned: nothing has locked a session since
+        assert!(mgr.session_locks.lock().unwrap().contains_key(&first));
+
+        assert_eq!(mgr.list().await.len(), 2);
+        assert_eq!(mgr.remove("client-1").await.unwrap().claude_session_id, first);
+        assert!(mgr.remove("client-1").await.is_none());
+        assert!(mgr.get("client-1").await.is_none());
+        assert_eq!(mgr.get("client-2").await.unwrap().model, "sonnet");
+        assert!(!mgr.session_locks.lock().unwrap().contains_key(&first));
+
+        mgr.flush().await;
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("client-1"));
+        // A new request for the client no longer gets the removed session id
+        assert_ne!(mgr.get_or_create("client-1", None, "opus").await, first);
+    }
+
+    #[tokio::test]
+    async fn remove_keeps_a_held_lock() {
+        let mgr = SessionManager::with_path(temp_path());
+        let id = mgr.get_or_create("client-1", None, "opus").await;
+        let guard = mgr.lock_session(&id).await;
+
+        mgr.remove("client-1").await.unwrap();
+        // A second run of the removed session still waits for the first
+        let waiting = tokio::time::timeout(Duration::from_millis(30), mgr.lock_session(&id));
+        assert!(waiting.await.is_err());
+        drop(guard);
+        let _second = mgr.lock_session(&id).await;
+    }
+
+    #[tokio::test]
+    async fn memory_mode_writes_no_file() {
+        let path = temp_path();
+        let mgr = SessionManager {
+            persistent: false,
+            ..SessionManager::with_path(path.clone())
+        };
+        mgr.get_or_create("client-1", None, "opus").await;
+        mgr.sessions.write().await.get_mut("client-1").unwrap().last_used_at = 0;
+        mgr.get_or_create("client-2", None, "opus").await;
+
+        mgr.cleanup_expired().await;
+        assert!(mgr.get("client-1").await.is_none());
+        assert!(mgr.get("client-2").await.is_some());
+        mgr.flush().await;
+        mgr.remove("client-2").await;
+        mgr.flush().await;
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn memory_mode_starts_empty_and_loaded() {
+        let mgr = SessionManager::new(
+            SessionPersistence::Memory,
+            SessionLimits::default(),
+            Duration::from_secs(60),
+        );
+        assert!(mgr.is_loaded());
+        assert!(mgr.list().await.is_empty());
+        assert_eq!(mgr.file_path(), PathBuf::new());
     }
 
     #[tokio::test]
     async fn cleanup_no_op_when_all_fresh() {
         let mgr = SessionManager::with_path(temp_path());
-        mgr.get_or_create("client-1", "opus").await;
+        mgr.get_or_create("client-1", None, "opus").await;
         mgr.cleanup_expired().await;
 
         let sessions = mgr.sessions.read().await;
@@ -280,13 +688,81 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn save_and_load_round_trip() {
+    async fn save_creates_missing_directory() {
+        let dir = std::env::temp_dir().join(format!("session-test-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("nested").join("sessions.json");
+        let mgr = SessionManager::with_path(path.clone());
+        mgr.get_or_create("client-1", None, "opus").await;
+        mgr.save().await;
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(data.contains("client-1"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rebind_saves_to_new_path() {
+        let old_path = temp_path();
+        let mgr = SessionManager::with_path(old_path.clone());
+        mgr.get_or_create("client-1", None, "opus").await;
+        mgr.save().await;
+
+        let new_path = temp_path();
+        // Clones share the path, like the one held by the cleanup task
+        mgr.clone().rebind(new_path.clone()).await;
+        assert_eq!(mgr.file_path(), new_path);
+        let data = std::fs::read_to_string(&new_path).unwrap();
+        assert!(data.contains("client-1"));
+
+        mgr.get_or_create("client-2", None, "opus").await;
+        mgr.save().await;
+        assert!(std::fs::read_to_string(&new_path).unwrap().contains("client-2"));
+        // The old file keeps what it had
+        let old = std::fs::read_to_string(&old_path).unwrap();
+        assert!(old.contains("client-1") && !old.contains("client-2"));
+    }
+
+    #[tokio::test]
+    async fn rebind_to_the_same_path_is_a_no_op() {
         let path = temp_path();
         let mgr = SessionManager::with_path(path.clone());
-        mgr.get_or_create("client-1", "opus").await;
+        mgr.rebind(path.clone()).await;
+        // Nothing was saved, so the file was never written
+        assert!(!path.exists());
+    }
 
-        // Wait for the fire-and-forget save
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    #[tokio::test]
+    async fn home_change_after_construction_does_not_redirect_saves() {
+        let home = temp_path().parent().unwrap().to_path_buf();
+        let later_home = temp_path().parent().unwrap().to_path_buf();
+        let original = std::env::var_os("HOME");
+        // SAFETY: no other test sets HOME or depends on its value
+        unsafe { std::env::set_var("HOME", &home) };
+        let mgr = SessionManager::new(
+            SessionPersistence::File,
+            SessionLimits::default(),
+            Duration::from_secs(3600),
+        );
+        unsafe { std::env::set_var("HOME", &later_home) };
+        mgr.get_or_create("client-1", None, "opus").await;
+        mgr.save().await;
+        match original {
+            Some(original) => unsafe { std::env::set_var("HOME", original) },
+            None => unsafe { std::env::remove_var("HOME") },
+        }
+
+        let file = ".claude-code-cli-sessions.json";
+        let data = std::fs::read_to_string(home.join(file)).unwrap();
+        assert!(data.contains("client-1"));
+        assert!(!later_home.join(file).exists());
+    }
+
+    #[tokio::test]
+    async fn save_and_load_round_trip() {
+        let path = temp_path();
+        let mgr = SessionManager::with_path(path.clone());
+        mgr.get_or_create("client-1", None, "opus").await;
+        mgr.flush().await;
 
         // Load into a new manager
         let mgr2 = SessionManager::with_path(path);
@@ -297,6 +773,254 @@ mod tests {
         assert_eq!(sessions["client-1"].model, "opus");
     }
 
+    #[tokio::test]
+    async fn changes_are_saved_on_flush_only() {
+        let path = temp_path();
+        let mgr = SessionManager::with_path(path.clone());
+        for i in 0..3 {
+            mgr.get_or_create(&format!("client-{i}"), None, "opus").await;
+        }
+        assert!(!path.exists());
+
+        mgr.flush().await;
+        let data = std::fs::read_to_string(&path).unwrap();
+        let sessions: HashMap<String, SessionMapping> = serde_json::from_str(&data).unwrap();
+        assert_eq!(sessions.len(), 3);
+
+        // Nothing changed since, so the next flush writes nothing
+        std::fs::remove_file(&path).unwrap();
+        mgr.flush().await;
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn background_flusher_saves_changes() {
+        let path = temp_path();
+        let limits = SessionLimits::default();
+        let mgr = SessionManager::open(path.clone(), limits, Duration::from_millis(20));
+        mgr.get_or_create("client-1", None, "opus").await;
+        for _ in 0..100 {
+            if path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(std::fs::read_to_string(&path).unwrap().contains("client-1"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_saves_leave_valid_json() {
+        let path = temp_path();
+        let mgr = SessionManager::with_path(path.clone());
+
+        let mut handles = Vec::new();
+        for i in 0..50 {
+            let m = mgr.clone();
+            handles.push(tokio::spawn(async move {
+                m.get_or_create(&format!("client-{i}"), None, "opus").await;
+                m.save().await;
+            }));
+        }
+        for h in handles {
+            h.await.unwrap();
+        }
+        mgr.flush().await;
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        let sessions: HashMap<String, SessionMapping> = serde_json::from_str(&data).unwrap();
+        assert_eq!(sessions.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn save_ignores_other_writers_temp_files() {
+        let path = temp_path();
+        // A half-written temp file left by another proxy sharing the file
+        let foreign = path.with_extension("json.99999999.tmp");
+        std::fs::write(&foreign, "{\"client-x\": {").unwrap();
+
+        let mgr = SessionManager::with_path(path.clone());
+        for i in 0..5 {
+            mgr.get_or_create(&format!("client-{i}"), None, "opus").await;
+            mgr.save().await;
+
+            let loaded = SessionManager::with_path(path.clone());
+            loaded.load().await;
+            assert_eq!(loaded.sessions.read().await.len(), i + 1);
+        }
+        let own = path.with_extension(format!("json.{}.tmp", std::process::id()));
+        assert!(!own.exists());
+        assert_eq!(std::fs::read_to_string(&foreign).unwrap(), "{\"client-x\": {");
+    }
+
+    #[tokio::test]
+    async fn same_session_runs_sequentially() {
+        let mgr = SessionManager::with_path(temp_path());
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let mut handles = Vec::new();
+        for i in 0..2 {
+            let mgr = mgr.clone();
+            let order = order.clone();
+            handles.push(tokio::spawn(async move {
+                let _guard = mgr.lock_session("sess-1").await;
+                order.lock().unwrap().push(format!("start-{i}"));
+                tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+                order.lock().unwrap().push(format!("end-{i}"));
+            }));
+        }
+        for h in handles {
+            h.await.unwrap();
+        }
+
+        let order = order.lock().unwrap();
+        // Each run ends before the next one starts
+        assert!(order[0].starts_with("start") && order[1].starts_with("end"));
+        assert!(order[2].starts_with("start") && order[3].starts_with("end"));
+    }
+
+    #[tokio::test]
+    async fn different_sessions_run_concurrently() {
+        let mgr = SessionManager::with_path(temp_path());
+        let _a = mgr.lock_session("sess-a").await;
+        let b = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            mgr.lock_session("sess-b"),
+        )
+        .await;
+        assert!(b.is_ok());
+    }
+
+    #[tokio::test]
+    async fn released_session_locks_are_pruned() {
+        let mgr = SessionManager::with_path(temp_path());
+        drop(mgr.lock_session("sess-a").await);
+        let _b = mgr.lock_session("sess-b").await;
+        let locks = mgr.session_locks.lock().unwrap();
+        assert!(!locks.contains_key("sess-a"));
+        assert!(locks.contains_key("sess-b"));
+    }
+
+    fn mapping(clawdbot_id: &str, claude_session_id: &str) -> SessionMapping {
+        SessionMapping {
+            clawdbot_id: clawdbot_id.to_string(),
+            client_id: None,
+            claude_session_id: claude_session_id.to_string(),
+            created_at: now_ms(),
+            last_used_at: now_ms(),
+            model: "opus".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn get_or_create_sees_preloaded_sessions() {
+        let path = temp_path();
+        let sessions = HashMap::from([("client-1".to_string(), mapping("client-1", "saved"))]);
+        std::fs::write(&path, serde_json::to_string(&sessions).unwrap()).unwrap();
+
+        // Repeat to catch a lookup racing the background load
+        for _ in 0..20 {
+            let limits = SessionLimits::default();
+            let mgr = SessionManager::open(path.clone(), limits, Duration::from_secs(60));
+            assert_eq!(mgr.get_or_create("client-1", None, "opus").await, "saved");
+            assert!(mgr.is_loaded());
+        }
+    }
+
+    #[tokio::test]
+    async fn load_keeps_sessions_created_meanwhile() {
+        let path = temp_path();
+        let sessions = HashMap::from([
+            ("client-1".to_string(), mapping("client-1", "saved-1")),
+            ("client-2".to_string(), mapping("client-2", "saved-2")),
+        ]);
+        std::fs::write(&path, serde_json::to_string(&sessions).unwrap()).unwrap();
+
+        let mgr = SessionManager::with_path(path);
+        mgr.sessions
+            .write()
+            .await
+            .insert("client-1".to_string(), mapping("client-1", "fresh"));
+        mgr.load().await;
+
+        let sessions = mgr.sessions.read().await;
+        assert_eq!(sessions["client-1"].claude_session_id, "fresh");
+        assert_eq!(sessions["client-2"].claude_session_id, "saved-2");
+    }
+
+    #[tokio::test]
+    async fn oversized_file_is_backed_up_not_loaded() {
+        let path = temp_path();
+        let sessions: HashMap<_, _> = (0..200)
+            .map(|i| {
+                let id = format!("client-{i}");
+                (id.clone(), mapping(&id, "saved"))
+            })
+            .collect();
+        let data = serde_json::to_string(&sessions).unwrap();
+        std::fs::write(&path, &data).unwrap();
+
+        let limits = SessionLimits {
+            max_file_bytes: 1024,
+            ..Default::default()
+        };
+        let mgr = SessionManager::with_limits(path.clone(), limits);
+        mgr.load().await;
+
+        assert!(mgr.sessions.read().await.is_empty());
+        assert!(!path.exists());
+        let backup = std::fs::read_to_string(path.with_extension("json.bak")).unwrap();
+        assert_eq!(backup, data);
+    }
+
+    #[tokio::test]
+    async fn load_drops_expired_and_caps_to_max_sessions() {
+        let path = temp_path();
+        let mut sessions = HashMap::new();
+        for i in 0..5u64 {
+            let id = format!("client-{i}");
+            let mut m = mapping(&id, "saved");
+            m.last_used_at -= (5 - i) * 1000; // client-4 used most recently
+            sessions.insert(id, m);
+        }
+        let mut expired = mapping("old-client", "old");
+        expired.last_used_at = 0;
+        sessions.insert("old-client".to_string(), expired);
+        std::fs::write(&path, serde_json::to_string(&sessions).unwrap()).unwrap();
+
+        let limits = SessionLimits {
+            max_sessions: 3,
+            ..Default::default()
+        };
+        let mgr = SessionManager::with_limits(path.clone(), limits);
+        mgr.load().await;
+
+        let mut kept: Vec<_> = mgr.sessions.read().await.keys().cloned().collect();
+        kept.sort();
+        assert_eq!(kept, ["client-2", "client-3", "client-4"]);
+        // The pruned set is written back
+        let data = std::fs::read_to_string(&path).unwrap();
+        let saved: HashMap<String, SessionMapping> = serde_json::from_str(&data).unwrap();
+        assert_eq!(saved.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn get_or_create_evicts_least_recently_used() {
+        let limits = SessionLimits {
+            max_sessions: 2,
+            ..Default::default()
+        };
+        let mgr = SessionManager::with_limits(temp_path(), limits);
+        mgr.get_or_create("client-1", None, "opus").await;
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        mgr.get_or_create("client-2", None, "opus").await;
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        mgr.get_or_create("client-3", None, "opus").await;
+
+        let sessions = mgr.sessions.read().await;
+        assert_eq!(sessions.len(), 2);
+        assert!(!sessions.contains_key("client-1"));
+    }
+
     #[tokio::test]
     async fn load_missing_file_is_ok() {
         let mgr = SessionManager::with_path(PathBuf::from("/tmp/nonexistent-session-file.json"));
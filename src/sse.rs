@@ -0,0 +1,168 @@
+use axum::response::sse::Event;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use crate::metrics::Metrics;
+
+/// Number of SSE events buffered between the bridge task and the client.
+const SSE_BUFFER: usize = 64;
+
+/// How long the buffer may stay full before the client is reported as stalled.
+pub const DEFAULT_STALL_THRESHOLD: Duration = Duration::from_secs(10);
+
+pub type SseItem = Result<Event, Infallible>;
+
+/// The client disconnected; the bridge should stop and let the subprocess be killed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+/// Sending half of an SSE response.
+///
+/// Sends wait for buffer space like a plain channel, but a client that stops
+/// reading for longer than the stall threshold is logged and counted instead
+/// of silently backing up the subprocess.
+pub struct SseSender {
+    tx: mpsc::Sender<SseItem>,
+    metrics: Arc<Metrics>,
+    stall_threshold: Duration,
+}
+
+impl SseSender {
+    pub fn channel(
+        metrics: Arc<Metrics>,
+        stall_threshold: Duration,
+    ) -> (Self, mpsc::Receiver<SseItem>) {
+        Self::with_capacity(metrics, stall_threshold, SSE_BUFFER)
+    }
+
+    fn with_capacity(
+        metrics: Arc<Metrics>,
+        stall_threshold: Duration,
+        capacity: usize,
+    ) -> (Self, mpsc::Receiver<SseItem>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let sender = Self {
+            tx,
+            metrics,
+            stall_threshold,
+        };
+        (sender, rx)
+    }
+
+    /// Send an event, waiting for the client to make room if needed.
+    pub async fn send(&self, event: Event) -> Result<(), Disconnected> {
+        let permit = match tokio::time::timeout(self.stall_threshold, self.tx.reserve()).await {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!(
+                    "SSE client stalled: buffer full for over {}s",
+                    self.stall_threshold.as_secs_f64()
+                );
+                self.metrics.record_sse_stall();
+                self.tx.reserve().await
+            }
+        };
+        permit.map_err(|_| Disconnected)?.send(Ok(event));
+        Ok(())
+    }
+
+    /// Serialize `data` and send it as an unnamed `data:` event.
+    pub async fn send_json<T: Serialize>(&self, data: &T) -> Result<(), Disconnected> {
+        match serde_json::to_string(data) {
+            Ok(json) => self.send(Event::default().data(json)).await,
+            Err(e) => {
+                error!("Failed to serialize chunk: {e}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Serialize `data` and send it as a named event.
+    pub async fn send_named<T: Serialize>(
+        &self,
+        event_name: &str,
+        data: &T,
+    ) -> Result<(), Disconnected> {
+        match serde_json::to_string(data) {
+            Ok(json) => self.send(Event::default().event(event_name).data(json)).await,
+            Err(e) => {
+                error!("Failed to serialize {event_name} event: {e}");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender(capacity: usize) -> (SseSender, mpsc::Receiver<SseItem>, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let (tx, rx) =
+            SseSender::with_capacity(metrics.clone(), Duration::from_millis(20), capacity);
+        (tx, rx, metrics)
+    }
+
+    #[tokio::test]
+    async fn stalled_consumer_is_counted_and_stream_resumes() {
+        let (tx, mut rx, metrics) = sender(1);
+        tx.send(Event::default().data("first")).await.unwrap();
+
+        // Buffer is full and nobody reads until well past the threshold
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let mut received = 0;
+            while rx.recv().await.is_some() {
+                received += 1;
+            }
+            received
+        });
+
+        tx.send(Event::default().data("second")).await.unwrap();
+        assert_eq!(metrics.snapshot().sse_stalls, 1);
+
+        drop(tx);
+        assert_eq!(reader.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn fast_consumer_is_not_counted() {
+        let (tx, mut rx, metrics) = sender(4);
+        for _ in 0..3 {
+            tx.send(Event::default().data("x")).await.unwrap();
+        }
+        drop(tx);
+        let mut received = 0;
+        while rx.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 3);
+        assert_eq!(metrics.snapshot().sse_stalls, 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_receiver_reports_disconnect() {
+        let (tx, rx, _) = sender(1);
+        drop(rx);
+        assert_eq!(tx.send(Event::default().data("x")).await, Err(Disconnected));
+    }
+
+    #[tokio::test]
+    async fn disconnect_while_stalled_reports_disconnect() {
+        let (tx, rx, metrics) = sender(1);
+        tx.send(Event::default().data("first")).await.unwrap();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            drop(rx);
+        });
+
+        assert_eq!(tx.send(Event::default().data("second")).await, Err(Disconnected));
+        assert_eq!(metrics.snapshot().sse_stalls, 1);
+    }
+}
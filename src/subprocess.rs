@@ -1,11 +1,18 @@
-use crate::types::claude_cli::{AssistantInner, ClaudeCliMessage, Delta, StreamEvent};
+use crate::adapter::history::estimate_tokens;
+use crate::config::{CliPermissions, OutputEncoding};
+use crate::metrics::RunOutcome;
+use crate::pool::WarmPool;
+use crate::telemetry;
+use crate::types::claude_cli::{
+    AssistantInner, ClaudeCliMessage, Delta, MessageDeltaInfo, ResultMessage, StreamEvent,
+};
+use std::future::Future;
+use std::pin::Pin;
 use std::time::{Duration, Instant};
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
 use tokio::sync::mpsc;
-use tracing::{debug, error, info, warn};
-
-const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(30 * 60); // 30 minutes
+use tracing::{Instrument, Span, debug, error, field, info, info_span, trace, warn};
 
 /// Events emitted by the subprocess to the route handler.
 #[derive(Debug)]
@@ -14,23 +21,523 @@ pub enum SubprocessEvent {
     Model(String),
     /// A content delta (streaming text)
     ContentDelta(String),
+    /// A tool call the CLI made. It runs its tools itself, so this is a
+    /// record of the call, not a request for the client to act on.
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
     /// The final result message
     Result(crate::types::claude_cli::ResultMessage),
     /// An error occurred
     Error(String),
-    /// Process exited (exit_code)
-    Close(i32),
+    /// Process exited (exit_code, last lines of stderr if any, secrets redacted)
+    Close(i32, Option<String>),
 }
 
+#[derive(Clone)]
 pub struct SubprocessOptions {
-    pub request_id: String,
     pub model: String,
     pub session_id: Option<String>,
+    /// The claude binary to run (`--claude-bin`)
+    pub claude_bin: String,
     pub cwd: String,
     pub api: &'static str, // "openai" or "anthropic"
+    pub inactivity_timeout: Duration,
+    /// Kill the run this long after spawning, even while output keeps coming
+    pub max_duration: Option<Duration>,
+    /// Extra environment variables forwarded from request headers
+    pub env: Vec<(String, String)>,
+    /// How often to log "Still running"; `None` disables the log
+    pub progress_interval: Option<Duration>,
+    /// Additional CLI flags requested per request (already validated)
+    pub extra_args: Vec<String>,
+    /// Log this run at trace level, prompt and events included (`X-Debug`)
+    pub trace_logging: bool,
+    /// Kill the run once streamed content exceeds this many bytes
+    pub max_output_bytes: Option<usize>,
+    /// How stdout and stderr bytes are decoded (`--output-encoding`)
+    pub output_encoding: OutputEncoding,
+    /// Sampling parameters from the request (validated, see `build_args`)
+    pub sampling: Sampling,
+    /// Cap on generated tokens, passed via `MAX_OUTPUT_TOKENS_ENV`
+    pub max_tokens: Option<u64>,
+    /// Client stop sequences, enforced here by `StopScanner`
+    pub stop_sequences: Vec<String>,
+    /// Permission mode and tool lists (`--permission-mode` and friends)
+    pub permissions: CliPermissions,
+    /// Passed as `--append-system-prompt` (`SystemPromptMode::Flag`)
+    pub system_prompt: Option<String>,
 }
 
-fn build_args(prompt: &str, options: &SubprocessOptions) -> Vec<String> {
+#[cfg(test)]
+impl SubprocessOptions {
+    /// A plain run of opus in `/tmp` with no per-request settings, for tests
+    /// to override what they exercise.
+    pub fn for_test() -> Self {
+        Self {
+            model: "opus".to_string(),
+            session_id: None,
+            claude_bin: "claude".to_string(),
+            cwd: "/tmp".to_string(),
+            api: "openai",
+            inactivity_timeout: Duration::from_secs(60),
+            max_duration: None,
+            env: vec![],
+            progress_interval: None,
+            extra_args: vec![],
+            trace_logging: false,
+            max_output_bytes: None,
+            output_encoding: OutputEncoding::default(),
+            sampling: Sampling::default(),
+            max_tokens: None,
+            stop_sequences: vec![],
+            permissions: CliPermissions::default(),
+            system_prompt: None,
+        }
+    }
+}
+
+/// Environment variable the claude CLI reads its output token cap from; it
+/// has no command-line flag for this.
+const MAX_OUTPUT_TOKENS_ENV: &str = "CLAUDE_CODE_MAX_OUTPUT_TOKENS";
+
+/// Client sampling parameters. The claude CLI exposes no flags for these,
+/// so they are validated and logged but not passed on.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sampling {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+}
+
+/// Decode one line of CLI output per `encoding`, dropping the `\r` of a
+/// CRLF ending as `lines()` would.
+fn decode_line(mut bytes: Vec<u8>, encoding: OutputEncoding) -> String {
+    if bytes.last() == Some(&b'\r') {
+        bytes.pop();
+    }
+    match encoding {
+        OutputEncoding::Utf8 => String::from_utf8(bytes).unwrap_or_else(|e| {
+            warn!("CLI output is not valid UTF-8; decoding the line lossily");
+            String::from_utf8_lossy(e.as_bytes()).into_owned()
+        }),
+        OutputEncoding::Lossy => String::from_utf8(bytes)
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()),
+        OutputEncoding::Latin1 => bytes.into_iter().map(char::from).collect(),
+    }
+}
+
+/// How long to keep reading stderr once stdout has closed.
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of trailing stderr lines kept to explain a failed run.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Longest stderr line kept, in characters.
+const STDERR_LINE_MAX_CHARS: usize = 500;
+
+/// The last `STDERR_TAIL_LINES` lines the CLI wrote to stderr, with anything
+/// that looks like a credential redacted.
+#[derive(Debug, Default)]
+struct StderrTail {
+    lines: std::collections::VecDeque<String>,
+}
+
+impl StderrTail {
+    fn push(&mut self, line: &str) {
+        let line = line.trim_end();
+        if line.is_empty() {
+            return;
+        }
+        if self.lines.len() == STDERR_TAIL_LINES {
+            self.lines.pop_front();
+        }
+        let line: String = line.chars().take(STDERR_LINE_MAX_CHARS).collect();
+        self.lines.push_back(redact_secrets(&line));
+    }
+
+    /// The kept lines joined by newlines, or `None` if stderr was empty.
+    fn text(&self) -> Option<String> {
+        (!self.lines.is_empty()).then(|| Vec::from(self.lines.clone()).join("\n"))
+    }
+}
+
+/// Replace token-like words in `line` with `[REDACTED]`: API keys (`sk-...`),
+/// bearer credentials, and long mixed letter/digit strings.
+fn redact_secrets(line: &str) -> String {
+    let is_word_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    let looks_secret = |word: &str, after_bearer: bool| {
+        let has_letter = word.chars().any(|c| c.is_ascii_alphabetic());
+        let has_digit = word.chars().any(|c| c.is_ascii_digit());
+        word.starts_with("sk-")
+            || (after_bearer && word.len() >= 8)
+            || (word.len() >= 32 && has_letter && has_digit)
+    };
+
+    let mut out = String::with_capacity(line.len());
+    let mut after_bearer = false;
+    let mut rest = line;
+    while let Some(start) = rest.find(is_word_char) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(|c: char| !is_word_char(c)).unwrap_or(rest.len());
+        let word = &rest[..end];
+        if looks_secret(word, after_bearer) {
+            out.push_str("[REDACTED]");
+        } else {
+            out.push_str(word);
+        }
+        after_bearer = word.eq_ignore_ascii_case("bearer");
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Failures of a CLI run that have their own HTTP status upstream, so clients
+/// can tell a bad login or rate limit from a crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamFailure {
+    /// Not logged in, or the stored credentials were rejected (401)
+    Auth,
+    /// Rate limited; retrying shortly may succeed (429)
+    RateLimit,
+    /// Usage limit or credit exhausted; retrying soon won't help (402)
+    Quota,
+}
+
+impl UpstreamFailure {
+    pub fn status(self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+        match self {
+            UpstreamFailure::Auth => StatusCode::UNAUTHORIZED,
+            UpstreamFailure::RateLimit => StatusCode::TOO_MANY_REQUESTS,
+            UpstreamFailure::Quota => StatusCode::PAYMENT_REQUIRED,
+        }
+    }
+
+    /// Error `type` shared by OpenAI and Anthropic error bodies.
+    pub fn error_type(self) -> &'static str {
+        match self {
+            UpstreamFailure::Auth => "authentication_error",
+            UpstreamFailure::RateLimit => "rate_limit_error",
+            UpstreamFailure::Quota => "billing_error",
+        }
+    }
+}
+
+/// Prefixes the CLI and its HTTP client put before an HTTP status code.
+const STATUS_MARKERS: &[&str] = &["api error: ", "status code ", "status: ", "status "];
+
+/// Whether lowercased `stderr` reports HTTP `status` after one of the
+/// `STATUS_MARKERS`, as a whole word. Bare digits aren't enough: they turn up
+/// in stack traces (`cli.js:1401:29`) and ids.
+fn reports_status(stderr: &str, status: &str) -> bool {
+    let word_char = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+    STATUS_MARKERS.iter().any(|marker| {
+        let needle = format!("{marker}{status}");
+        stderr.match_indices(&needle).any(|(i, _)| {
+            !word_char(stderr[..i].chars().next_back())
+                && !word_char(stderr[i + needle.len()..].chars().next())
+        })
+    })
+}
+
+/// Classify a failed run from its stderr tail, or `None` for anything that
+/// isn't a recognizable auth, rate-limit or quota failure.
+pub fn classify_failure(stderr: &str) -> Option<UpstreamFailure> {
+    let stderr = stderr.to_ascii_lowercase();
+    let mentions = |needles: &[&str]| needles.iter().any(|n| stderr.contains(n));
+    // Quota before rate limit: "usage limit reached" would also match "limit"
+    if mentions(&["usage limit", "quota", "credit balance", "insufficient credit"]) {
+        Some(UpstreamFailure::Quota)
+    } else if mentions(&["rate limit", "rate_limit", "too many requests"])
+        || reports_status(&stderr, "429")
+    {
+        Some(UpstreamFailure::RateLimit)
+    } else if mentions(&[
+        "not logged in",
+        "invalid api key",
+        "/login",
+        "authentication_error",
+        "oauth token has expired",
+        "unauthorized",
+    ]) || reports_status(&stderr, "401")
+    {
+        Some(UpstreamFailure::Auth)
+    } else {
+        None
+    }
+}
+
+/// Whether a failed run's error or stderr shows its model can't serve the
+/// request right now: that model's quota or rate limit, or it being
+/// overloaded or unavailable. Another model may still be able to.
+pub fn model_unavailable(message: &str) -> bool {
+    if matches!(
+        classify_failure(message),
+        Some(UpstreamFailure::Quota | UpstreamFailure::RateLimit)
+    ) {
+        return true;
+    }
+    let message = message.to_ascii_lowercase();
+    [
+        "overloaded",
+        "model is not available",
+        "model not available",
+        "model is unavailable",
+        "model not found",
+    ]
+    .iter()
+    .any(|needle| message.contains(needle))
+}
+
+/// Running total of streamed content checked against `max_output_bytes`,
+/// a safety valve against a CLI flooding output faster than it is consumed.
+#[derive(Debug)]
+struct OutputBudget {
+    limit: Option<usize>,
+    used: usize,
+}
+
+impl OutputBudget {
+    fn new(limit: Option<usize>) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    /// Account for an event; returns false once the limit has been exceeded.
+    fn admit(&mut self, event: &SubprocessEvent) -> bool {
+        if let (Some(limit), SubprocessEvent::ContentDelta(text)) = (self.limit, event) {
+            self.used += text.len();
+            return self.used <= limit;
+        }
+        true
+    }
+}
+
+/// Cuts streamed content at the first client stop sequence, since the CLI has
+/// no option for this. Text that might be the start of a sequence is held
+/// back until the next delta shows whether it is.
+///
+/// A match in a delta ends the run: the delta is followed by a result
+/// holding the text so far, and the run loop then kills the CLI (see
+/// `stopped`) instead of letting it generate what nobody will see.
+#[derive(Debug, Default)]
+struct StopScanner {
+    sequences: Vec<String>,
+    pending: String,
+    /// All text forwarded so far, for the result sent on a match
+    emitted: String,
+    matched: Option<String>,
+    /// A delta matched, so the run has its result and should stop
+    stopped: bool,
+}
+
+impl StopScanner {
+    fn new(sequences: Vec<String>) -> Self {
+        Self {
+            sequences,
+            ..Default::default()
+        }
+    }
+
+    /// Filter one event, returning the events to forward in its place.
+    fn filter(&mut self, event: SubprocessEvent) -> Vec<SubprocessEvent> {
+        if self.sequences.is_empty() {
+            return vec![event];
+        }
+        match event {
+            SubprocessEvent::ContentDelta(text) => {
+                if self.matched.is_some() {
+                    return vec![];
+                }
+                self.pending.push_str(&text);
+                let emit_to = match earliest_match(&self.pending, &self.sequences) {
+                    Some((at, seq)) => {
+                        self.matched = Some(seq.to_string());
+                        at
+                    }
+                    None => self.pending.len() - self.held_back(),
+                };
+                let rest = self.pending.split_off(emit_to);
+                let emit = std::mem::replace(&mut self.pending, rest);
+                self.emitted.push_str(&emit);
+                let mut events = Vec::new();
+                if !emit.is_empty() {
+                    events.push(SubprocessEvent::ContentDelta(emit));
+                }
+                if let Some(seq) = &self.matched {
+                    self.pending.clear();
+                    self.stopped = true;
+                    events.push(SubprocessEvent::Result(ResultMessage {
+                        result: Some(self.emitted.clone()),
+                        stop_reason: Some("stop_sequence".to_string()),
+                        stop_sequence: Some(seq.clone()),
+                        ..Default::default()
+                    }));
+                }
+                events
+            }
+            SubprocessEvent::Result(mut result) => {
+                let mut events: Vec<_> = self.finish().into_iter().collect();
+                if let Some(text) = result.result.as_mut()
+                    && let Some((at, seq)) = earliest_match(text, &self.sequences)
+                {
+                    self.matched.get_or_insert_with(|| seq.to_string());
+                    text.truncate(at);
+                }
+                if let Some(seq) = &self.matched {
+                    result.stop_reason = Some("stop_sequence".to_string());
+                    result.stop_sequence = Some(seq.clone());
+                    // The turns would still hold the text past the cut
+                    result.turns.clear();
+                }
+                events.push(SubprocessEvent::Result(result));
+                events
+            }
+            other => vec![other],
+        }
+    }
+
+    /// Whether a delta matched a stop sequence and its result was produced.
+    fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Release held-back text once no more deltas will arrive.
+    fn finish(&mut self) -> Option<SubprocessEvent> {
+        let text = std::mem::take(&mut self.pending);
+        (!text.is_empty()).then_some(SubprocessEvent::ContentDelta(text))
+    }
+
+    /// Length of the longest tail of `pending` that is a proper prefix of a sequence.
+    fn held_back(&self) -> usize {
+        self.sequences
+            .iter()
+            .flat_map(|seq| {
+                (1..seq.len())
+                    .filter(|&k| seq.is_char_boundary(k) && self.pending.ends_with(&seq[..k]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Position and value of the earliest stop sequence found in `text`.
+fn earliest_match<'a>(text: &str, sequences: &'a [String]) -> Option<(usize, &'a str)> {
+    sequences
+        .iter()
+        .filter_map(|seq| text.find(seq.as_str()).map(|at| (at, seq.as_str())))
+        .min_by_key(|(at, _)| *at)
+}
+
+/// CLI flags a client may add via `X-Claude-Extra-Args`, and whether each
+/// takes a value. Anything touching permissions, tools or the filesystem is
+/// deliberately absent.
+const ALLOWED_EXTRA_ARGS: &[(&str, bool)] = &[
+    ("--max-turns", true),
+    ("--fallback-model", true),
+    ("--append-system-prompt", true),
+];
+
+/// A word of `X-Claude-Extra-Args`, and whether any of it was quoted.
+struct Word {
+    text: String,
+    quoted: bool,
+}
+
+/// Split `raw` into words the way a shell would: on whitespace, except
+/// inside single quotes (taken literally) or double quotes (where `\"` and
+/// `\\` escape), with a backslash escaping the next character elsewhere.
+fn split_words(raw: &str) -> Result<Vec<Word>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<Word> = None;
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            words.extend(word.take());
+            continue;
+        }
+        let current = word.get_or_insert_with(|| Word {
+            text: String::new(),
+            quoted: false,
+        });
+        match c {
+            '\'' => {
+                current.quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.text.push(c),
+                        None => return Err("unterminated ' in extra arguments".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                current.quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => current.text.push(c),
+                            Some(c) => current.text.extend(['\\', c]),
+                            None => return Err("unterminated \" in extra arguments".to_string()),
+                        },
+                        Some(c) => current.text.push(c),
+                        None => return Err("unterminated \" in extra arguments".to_string()),
+                    }
+                }
+            }
+            '\\' => current.text.extend(chars.next()),
+            c => current.text.push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Parse and validate extra CLI flags, split like shell words so a value
+/// can be quoted (`--append-system-prompt "Answer in French."`). Accepts
+/// both `--flag value` and `--flag=value` forms. A value starting with `-`
+/// must be quoted, so a missing value can't pull in the next flag.
+pub fn parse_extra_args(raw: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut words = split_words(raw)?.into_iter();
+
+    while let Some(word) = words.next() {
+        let (flag, inline_value) = match word.text.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some((value.to_string(), word.quoted))),
+            None => (word.text, None),
+        };
+
+        let Some(&(_, takes_value)) = ALLOWED_EXTRA_ARGS.iter().find(|(f, _)| *f == flag) else {
+            return Err(format!("extra argument '{flag}' is not allowed"));
+        };
+
+        if takes_value {
+            let value = match inline_value {
+                Some(value) => Some(value),
+                None => words.next().map(|w| (w.text, w.quoted)),
+            };
+            match value {
+                Some((v, quoted)) if !v.is_empty() && (quoted || !v.starts_with('-')) => {
+                    args.extend([flag, v]);
+                }
+                _ => return Err(format!("extra argument '{flag}' requires a value")),
+            }
+        } else if inline_value.is_some() {
+            return Err(format!("extra argument '{flag}' does not take a value"));
+        }
+    }
+
+    Ok(args)
+}
+
+/// Flags every run starts with, whether its prompt comes as an argument or
+/// (for a warm process) on stdin.
+fn base_args(model: &str, permissions: &CliPermissions) -> Vec<String> {
     let mut args = vec![
         "--print".to_string(),
         "--output-format".to_string(),
@@ -38,150 +545,482 @@ fn build_args(prompt: &str, options: &SubprocessOptions) -> Vec<String> {
         "--verbose".to_string(),
         "--include-partial-messages".to_string(),
         "--model".to_string(),
-        options.model.clone(),
+        model.to_string(),
         "--no-session-persistence".to_string(),
         "--permission-mode".to_string(),
-        "bypassPermissions".to_string(),
-        prompt.to_string(),
+        permissions.mode.as_cli().to_string(),
     ];
+    // `=` form: the CLI reads following arguments, the prompt included,
+    // as more tools after a separate list argument
+    if !permissions.allowed_tools.is_empty() {
+        args.push(format!("--allowedTools={}", permissions.allowed_tools.join(",")));
+    }
+    if !permissions.disallowed_tools.is_empty() {
+        args.push(format!("--disallowedTools={}", permissions.disallowed_tools.join(",")));
+    }
+    args
+}
+
+fn build_args(prompt: &str, options: &SubprocessOptions) -> Vec<String> {
+    let mut args = base_args(&options.model, &options.permissions);
+    args.push(prompt.to_string());
 
     if let Some(ref session_id) = options.session_id {
         args.push("--session-id".to_string());
         args.push(session_id.clone());
     }
 
+    // One system prompt flag: a client's own `--append-system-prompt` (see
+    // `parse_extra_args`) goes after the request's system text
+    let mut extra_args = options.extra_args.clone();
+    let mut system_prompt = options.system_prompt.clone();
+    if let Some(at) = extra_args.iter().position(|a| a == "--append-system-prompt")
+        && at + 1 < extra_args.len()
+    {
+        let appended: Vec<String> = extra_args.drain(at..at + 2).collect();
+        system_prompt = Some(match system_prompt {
+            Some(system) => format!("{system}\n\n{}", appended[1]),
+            None => appended[1].clone(),
+        });
+    }
+    if let Some(system) = system_prompt {
+        args.extend(["--append-system-prompt".to_string(), system]);
+    }
+
+    args.extend(extra_args);
+
+    // No CLI flags exist for `options.sampling` (see `Sampling`) or
+    // `options.stop_sequences` (see `StopScanner`)
+
+    args
+}
+
+/// Arguments for a warm process (see `pool::WarmPool`), which waits for its
+/// prompt as a stream-json user message on stdin.
+pub fn warm_args(model: &str, permissions: &CliPermissions) -> Vec<String> {
+    let mut args = base_args(model, permissions);
+    args.extend(["--input-format".to_string(), "stream-json".to_string()]);
     args
 }
 
+/// The stdin line carrying `prompt` to a warm process.
+fn stdin_message(prompt: &str) -> String {
+    let message = serde_json::json!({
+        "type": "user",
+        "message": {"role": "user", "content": prompt},
+    });
+    format!("{message}\n")
+}
+
+/// A `claude` command with piped stdout and stderr, in its own process
+/// group so its node children die with it (see `kill_process_group`).
+/// The caller decides what stdin is.
+pub fn cli_command(claude_bin: &str, cwd: &str, args: &[String]) -> Command {
+    let mut command = Command::new(claude_bin);
+    #[cfg(unix)]
+    command.process_group(0);
+    command
+        .args(args)
+        .current_dir(cwd)
+        .env("CLAUDE_CODE_EXPERIMENTAL_AGENT_TEAMS", "1")
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    command
+}
+
+/// Environment for the CLI: forwarded headers plus the output token cap.
+pub fn build_env(options: &SubprocessOptions) -> Vec<(String, String)> {
+    let mut env = options.env.clone();
+    if let Some(max_tokens) = options.max_tokens {
+        env.push((MAX_OUTPUT_TOKENS_ENV.to_string(), max_tokens.to_string()));
+    }
+    env
+}
+
+/// A future boxed so it can be returned from `CliRunner`, which must stay
+/// object safe.
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Runs the CLI for one request, sending its events to `tx` and resolving to
+/// how the run ended. Route handlers only see this trait, so they can be
+/// tested against scripted events instead of a real `claude` binary.
+pub trait CliRunner: Send + Sync {
+    fn run(
+        &self,
+        prompt: String,
+        options: SubprocessOptions,
+        tx: mpsc::Sender<SubprocessEvent>,
+    ) -> BoxFuture<RunOutcome>;
+}
+
+/// `claude --version`, tried up to `retries` more times `delay` apart while
+/// the binary can't be run (e.g. a deploy is still installing it).
+pub async fn cli_version(
+    claude_bin: &str,
+    retries: u32,
+    delay: Duration,
+) -> std::io::Result<String> {
+    let mut attempt = 0;
+    loop {
+        match Command::new(claude_bin).arg("--version").output().await {
+            Ok(output) => {
+                return Ok(String::from_utf8_lossy(&output.stdout).trim().to_string());
+            }
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "claude CLI not available yet (tried '{claude_bin}'): {e}; \
+                     retry {attempt}/{retries} in {:.1}s",
+                    delay.as_secs_f64()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Runs the installed `claude` binary (see `spawn_subprocess`), on a warm
+/// process from `pool` when one fits the run.
+#[derive(Default)]
+pub struct RealCliRunner {
+    pool: Option<WarmPool>,
+}
+
+impl RealCliRunner {
+    pub fn new(pool: Option<WarmPool>) -> Self {
+        Self { pool }
+    }
+}
+
+impl CliRunner for RealCliRunner {
+    fn run(
+        &self,
+        prompt: String,
+        options: SubprocessOptions,
+        tx: mpsc::Sender<SubprocessEvent>,
+    ) -> BoxFuture<RunOutcome> {
+        let warm = self.pool.as_ref().and_then(|pool| pool.take(&options));
+        Box::pin(spawn_subprocess(prompt, options, warm, tx))
+    }
+}
+
+/// Plays back a scripted run instead of spawning the CLI. `script` is called
+/// once per run with the zero-based run number, so a test can vary runs.
+#[cfg(test)]
+pub struct MockCliRunner {
+    script: Box<dyn Fn(usize) -> Vec<SubprocessEvent> + Send + Sync>,
+    runs: std::sync::atomic::AtomicUsize,
+    prompts: std::sync::Mutex<Vec<String>>,
+    models: std::sync::Mutex<Vec<String>>,
+    cwds: std::sync::Mutex<Vec<String>>,
+    session_ids: std::sync::Mutex<Vec<Option<String>>>,
+}
+
+#[cfg(test)]
+impl MockCliRunner {
+    pub fn new(script: impl Fn(usize) -> Vec<SubprocessEvent> + Send + Sync + 'static) -> Self {
+        Self {
+            script: Box::new(script),
+            runs: Default::default(),
+            prompts: Default::default(),
+            models: Default::default(),
+            cwds: Default::default(),
+            session_ids: Default::default(),
+        }
+    }
+
+    /// How many runs have been started.
+    pub fn runs(&self) -> usize {
+        self.runs.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// The prompt each run was started with, in order.
+    pub fn prompts(&self) -> Vec<String> {
+        self.prompts.lock().unwrap().clone()
+    }
+
+    /// The model each run was started with, in order.
+    pub fn models(&self) -> Vec<String> {
+        self.models.lock().unwrap().clone()
+    }
+
+    /// The working directory each run was started in, in order.
+    pub fn cwds(&self) -> Vec<String> {
+        self.cwds.lock().unwrap().clone()
+    }
+
+    /// The CLI session id each run was started with, in order.
+    pub fn session_ids(&self) -> Vec<Option<String>> {
+        self.session_ids.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl CliRunner for MockCliRunner {
+    fn run(
+        &self,
+        prompt: String,
+        options: SubprocessOptions,
+        tx: mpsc::Sender<SubprocessEvent>,
+    ) -> BoxFuture<RunOutcome> {
+        self.prompts.lock().unwrap().push(prompt);
+        self.models.lock().unwrap().push(options.model);
+        self.cwds.lock().unwrap().push(options.cwd);
+        self.session_ids.lock().unwrap().push(options.session_id);
+        let run = self.runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let events = (self.script)(run);
+        Box::pin(async move {
+            for event in events {
+                if tx.send(event).await.is_err() {
+                    return RunOutcome::ClientClosed;
+                }
+            }
+            RunOutcome::Completed
+        })
+    }
+}
+
 /// Spawn the claude CLI subprocess and send events through the channel.
 /// Returns immediately; events are sent asynchronously.
 /// When the receiver is dropped (client disconnect), the sender will error and the subprocess
-/// will be killed.
+/// will be killed. Returns how the run ended.
+///
+/// The run is wrapped in a `cli_request` span carrying the pid, time to first
+/// token and duration, nested under the handler's `request` span (request id,
+/// API, model). Both are exported with `--otlp-endpoint` and attached to JSON
+/// log lines (`--log-format json`). With `trace_logging`
+/// the run also logs at trace level (see `telemetry::with_request_tracing`).
+///
+/// A `warm` process (see `pool::WarmPool`) is sent the prompt on stdin
+/// instead of spawning a new one, unless it turns out to be gone.
 pub async fn spawn_subprocess(
     prompt: String,
     options: SubprocessOptions,
+    warm: Option<Child>,
     tx: mpsc::Sender<SubprocessEvent>,
-) {
-    let args = build_args(&prompt, &options);
+) -> RunOutcome {
+    let span = info_span!(
+        "cli_request",
+        pid = field::Empty,
+        warm = field::Empty,
+        ttft_secs = field::Empty,
+        duration_secs = field::Empty,
+        outcome = field::Empty,
+    );
+    let start = Instant::now();
+    let trace = options.trace_logging;
+    let run = run_subprocess(prompt, options, warm, tx).instrument(span.clone());
+    let outcome = telemetry::with_request_tracing(trace, run).await;
+    span.record("duration_secs", start.elapsed().as_secs_f64());
+    span.record("outcome", field::debug(outcome));
+    outcome
+}
+
+/// Hand `prompt` to a warm process and close its stdin, so it exits after
+/// answering. `None` if it can't take the prompt (it exited while idle).
+async fn start_warm(mut child: Child, prompt: &str) -> Option<Child> {
+    let mut stdin = child.stdin.take()?;
+    match stdin.write_all(stdin_message(prompt).as_bytes()).await {
+        Ok(()) => Some(child),
+        Err(e) => {
+            warn!("Warm process unusable ({e}); spawning a new one");
+            kill_process_group(&mut child).await;
+            None
+        }
+    }
+}
+
+async fn run_subprocess(
+    prompt: String,
+    options: SubprocessOptions,
+    warm: Option<Child>,
+    tx: mpsc::Sender<SubprocessEvent>,
+) -> RunOutcome {
     let start = Instant::now();
-    let rid = &options.request_id;
     let api = options.api;
     let mut ttft_secs: Option<f64> = None;
+    let inactivity = options.inactivity_timeout;
 
-    info!("[req={rid}] Spawning subprocess model={} api={api}", options.model);
+    trace!("Prompt:\n{prompt}");
+    if options.sampling != Sampling::default() {
+        let Sampling { temperature, top_p } = options.sampling;
+        debug!("Not forwarded (no CLI flag): temperature={temperature:?} top_p={top_p:?}");
+    }
 
-    let mut child = match Command::new("claude")
-        .args(&args)
-        .current_dir(&options.cwd)
-        .env("CLAUDE_CODE_EXPERIMENTAL_AGENT_TEAMS", "1")
-        .stdin(std::process::Stdio::null())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-    {
+    let warm = match warm {
+        Some(child) => start_warm(child, &prompt).await,
+        None => None,
+    };
+    let is_warm = warm.is_some();
+    Span::current().record("warm", is_warm);
+    // Cold and warm starts are logged apart to compare time to first token
+    let launch = if is_warm { "warm" } else { "cold" };
+    let spawned = match warm {
+        Some(child) => {
+            info!("Using warm subprocess model={} api={api}", options.model);
+            Ok(child)
+        }
+        None => {
+            info!("Spawning subprocess model={} api={api}", options.model);
+            cli_command(&options.claude_bin, &options.cwd, &build_args(&prompt, &options))
+                .envs(build_env(&options))
+                .stdin(std::process::Stdio::null())
+                .spawn()
+        }
+    };
+    let mut child = match spawned {
         Ok(child) => child,
         Err(e) => {
+            let bin = &options.claude_bin;
             let msg = if e.kind() == std::io::ErrorKind::NotFound {
-                "claude CLI not found. Install it with: npm install -g @anthropic-ai/claude-code"
-                    .to_string()
+                format!(
+                    "claude CLI not found (tried '{bin}'). Install it with: npm install -g \
+                     @anthropic-ai/claude-code"
+                )
             } else {
-                format!("Failed to spawn claude: {}", e)
+                format!("Failed to spawn {bin}: {e}")
             };
-            error!("[req={rid}] Spawn failed: {msg}");
+            error!("Spawn failed: {msg}");
             let _ = tx.send(SubprocessEvent::Error(msg)).await;
-            return;
+            return RunOutcome::SpawnFailed;
         }
     };
 
     let pid = child.id().unwrap_or(0);
-    info!("[req={rid}][pid={pid}] Subprocess started");
+    Span::current().record("pid", pid);
+    info!("Subprocess started");
 
     let stdout = child.stdout.take().expect("stdout not captured");
     let stderr = child.stderr.take().expect("stderr not captured");
 
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
+    let encoding = options.output_encoding;
+    let mut stdout_reader = BufReader::new(stdout).split(b'\n');
+    let mut stderr_reader = BufReader::new(stderr).split(b'\n');
     let mut first_token = true;
     let mut chunk_count: u64 = 0;
     let mut line_count: u64 = 0;
-    let inactivity_timeout = tokio::time::sleep(INACTIVITY_TIMEOUT);
+    let mut line_state = LineState::default();
+    let mut budget = OutputBudget::new(options.max_output_bytes);
+    let mut stop_scanner = StopScanner::new(options.stop_sequences.clone());
+    let mut stderr_tail = StderrTail::default();
+    let inactivity_timeout = tokio::time::sleep(inactivity);
     tokio::pin!(inactivity_timeout);
-    let progress_interval = tokio::time::sleep(Duration::from_secs(30));
+    // Measured from the start and never reset; disabled when unset
+    let max_duration = options.max_duration;
+    let deadline = tokio::time::sleep_until(
+        tokio::time::Instant::from_std(start) + max_duration.unwrap_or_default(),
+    );
+    tokio::pin!(deadline);
+    // With no interval configured the progress branch below is disabled
+    let progress_every = options.progress_interval;
+    let progress_interval = tokio::time::sleep(progress_every.unwrap_or_default());
     tokio::pin!(progress_interval);
 
     loop {
         tokio::select! {
-            line = stdout_reader.next_line() => {
+            line = stdout_reader.next_segment() => {
                 match line {
                     Ok(Some(line)) => {
+                        let line = decode_line(line, encoding);
                         // Reset inactivity timer
-                        inactivity_timeout.as_mut().reset(tokio::time::Instant::now() + INACTIVITY_TIMEOUT);
+                        inactivity_timeout.as_mut().reset(tokio::time::Instant::now() + inactivity);
 
                         if line.trim().is_empty() {
                             continue;
                         }
 
                         line_count += 1;
-                        match process_line(&line) {
+                        match process_line(&line, &mut line_state) {
                             Some(events) => {
+                                let events: Vec<_> =
+                                    events.into_iter().flat_map(|e| stop_scanner.filter(e)).collect();
+                                let stopped = stop_scanner.stopped();
                                 for event in events {
-                                    if first_token {
-                                        if matches!(&event, SubprocessEvent::ContentDelta(_)) {
-                                            let ttft = start.elapsed().as_secs_f64();
-                                            ttft_secs = Some(ttft);
-                                            info!("[req={rid}][pid={pid}] First token after {ttft:.2}s");
-                                            first_token = false;
-                                        }
+                                    if first_token && matches!(&event, SubprocessEvent::ContentDelta(_)) {
+                                        let ttft = start.elapsed().as_secs_f64();
+                                        ttft_secs = Some(ttft);
+                                        Span::current().record("ttft_secs", ttft);
+                                        info!("First token after {ttft:.2}s launch={launch}");
+                                        first_token = false;
                                     }
                                     if matches!(&event, SubprocessEvent::ContentDelta(_)) {
                                         chunk_count += 1;
                                     }
+                                    if !budget.admit(&event) {
+                                        let limit = options.max_output_bytes.unwrap_or_default();
+                                        warn!("Output limit exceeded api={api} model={} limit={limit}B chunks={chunk_count}", options.model);
+                                        let _ = tx.send(SubprocessEvent::Error(format!("Output limit of {limit} bytes exceeded"))).await;
+                                        kill_process_group(&mut child).await;
+                                        return RunOutcome::OutputLimited;
+                                    }
+                                    if let SubprocessEvent::ToolUse { id, name, input } = &event {
+                                        debug!("Tool call {name} id={id} input={input}");
+                                    }
+                                    trace!("Event: {event:?}");
+                                    let is_result = matches!(&event, SubprocessEvent::Result(_));
                                     if tx.send(event).await.is_err() {
                                         let elapsed = start.elapsed().as_secs_f64();
                                         let ttft_str = match ttft_secs {
                                             Some(t) => format!("{t:.2}s"),
                                             None => "-".to_string(),
                                         };
-                                        warn!("[req={rid}][pid={pid}] Disconnected api={api} model={} ttft={ttft_str} total={elapsed:.2}s", options.model);
-                                        let _ = child.kill().await;
-                                        return;
+                                        warn!("Disconnected status=499 api={api} model={} ttft={ttft_str} total={elapsed:.2}s", options.model);
+                                        kill_process_group(&mut child).await;
+                                        return RunOutcome::ClientClosed;
+                                    }
+                                    if is_result && stopped {
+                                        let elapsed = start.elapsed().as_secs_f64();
+                                        info!("Stop sequence matched api={api} model={} total={elapsed:.2}s; stopping the CLI", options.model);
+                                        kill_process_group(&mut child).await;
+                                        return RunOutcome::Completed;
                                     }
                                 }
                             }
                             None => {
-                                debug!("[req={rid}][pid={pid}] Ignoring non-JSON line: {line}");
+                                debug!("Ignoring non-JSON line: {line}");
                             }
                         }
                     }
                     Ok(None) => {
-                        // stdout closed
+                        // stdout closed. Nothing is left to flush: `split()`
+                        // already yielded a final line missing its newline
                         break;
                     }
                     Err(e) => {
-                        error!("[req={rid}][pid={pid}] Error reading stdout: {e}");
+                        error!("Error reading stdout: {e}");
                         break;
                     }
                 }
             }
-            line = stderr_reader.next_line() => {
+            line = stderr_reader.next_segment() => {
                 match line {
                     Ok(Some(line)) => {
+                        let line = decode_line(line, encoding);
                         // Reset inactivity timer on stderr too
-                        inactivity_timeout.as_mut().reset(tokio::time::Instant::now() + INACTIVITY_TIMEOUT);
-                        debug!("[req={rid}][pid={pid}] stderr: {line}");
+                        inactivity_timeout.as_mut().reset(tokio::time::Instant::now() + inactivity);
+                        debug!("stderr: {line}");
+                        stderr_tail.push(&line);
                     }
                     Ok(None) => {
                         // stderr closed
                     }
                     Err(e) => {
-                        debug!("[req={rid}][pid={pid}] stderr read error: {e}");
+                        debug!("stderr read error: {e}");
                     }
                 }
             }
-            () = &mut progress_interval => {
+            () = &mut progress_interval, if progress_every.is_some() => {
+                let elapsed = start.elapsed().as_secs_f64();
+                info!("Still running {elapsed:.0}s lines={line_count} chunks={chunk_count}");
+                progress_interval.as_mut().reset(tokio::time::Instant::now() + progress_every.unwrap_or_default());
+            }
+            () = tx.closed() => {
+                // The client went away while the CLI was quiet
                 let elapsed = start.elapsed().as_secs_f64();
-                info!("[req={rid}][pid={pid}] Still running {elapsed:.0}s lines={line_count} chunks={chunk_count}");
-                progress_interval.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(30));
+                warn!("Disconnected status=499 api={api} model={} total={elapsed:.2}s (idle)", options.model);
+                kill_process_group(&mut child).await;
+                return RunOutcome::ClientClosed;
             }
             () = &mut inactivity_timeout => {
                 let elapsed = start.elapsed().as_secs_f64();
@@ -189,53 +1028,123 @@ pub async fn spawn_subprocess(
                     Some(t) => format!("{t:.2}s"),
                     None => "-".to_string(),
                 };
-                warn!("[req={rid}][pid={pid}] Timeout api={api} model={} ttft={ttft_str} total={elapsed:.2}s (30m inactivity)", options.model);
-                let _ = tx.send(SubprocessEvent::Error("Inactivity timeout after 30 minutes".to_string())).await;
-                let _ = child.kill().await;
-                return;
+                let secs = inactivity.as_secs();
+                warn!("Timeout api={api} model={} ttft={ttft_str} total={elapsed:.2}s ({secs}s inactivity)", options.model);
+                let _ = tx.send(SubprocessEvent::Error(format!("Inactivity timeout after {secs} seconds"))).await;
+                kill_process_group(&mut child).await;
+                return RunOutcome::TimedOut;
+            }
+            () = &mut deadline, if max_duration.is_some() => {
+                let elapsed = start.elapsed().as_secs_f64();
+                let ttft_str = match ttft_secs {
+                    Some(t) => format!("{t:.2}s"),
+                    None => "-".to_string(),
+                };
+                let secs = max_duration.unwrap_or_default().as_secs();
+                warn!("Timeout api={api} model={} ttft={ttft_str} total={elapsed:.2}s ({secs}s max duration)", options.model);
+                let _ = tx.send(SubprocessEvent::Error("Request exceeded max duration".to_string())).await;
+                kill_process_group(&mut child).await;
+                return RunOutcome::TimedOut;
             }
         }
     }
 
+    // Collect what's left on stderr; a failing CLI often explains itself last.
+    // Bounded, in case a grandchild process keeps the pipe open.
+    let _ = tokio::time::timeout(STDERR_DRAIN_TIMEOUT, async {
+        while let Ok(Some(line)) = stderr_reader.next_segment().await {
+            let line = decode_line(line, encoding);
+            debug!("stderr: {line}");
+            stderr_tail.push(&line);
+        }
+    })
+    .await;
+
     // Wait for process to exit
     let exit_code = match child.wait().await {
         Ok(status) => status.code().unwrap_or(-1),
         Err(e) => {
-            error!("[req={rid}][pid={pid}] Error waiting for subprocess: {e}");
+            error!("Error waiting for subprocess: {e}");
             -1
         }
     };
 
     let elapsed = start.elapsed().as_secs_f64();
+    // Recorded before the Done line so JSON logs carry it too
+    Span::current().record("duration_secs", elapsed);
     let ttft_str = match ttft_secs {
         Some(t) => format!("{t:.2}s"),
         None => "-".to_string(),
     };
     info!(
-        "[req={rid}][pid={pid}] Done api={api} model={} ttft={ttft_str} total={elapsed:.2}s exit={exit_code}",
+        "Done api={api} model={} ttft={ttft_str} total={elapsed:.2}s exit={exit_code} \
+         launch={launch}",
         options.model
     );
 
-    let _ = tx.send(SubprocessEvent::Close(exit_code)).await;
+    // Text held back by the stop scanner when the CLI ended without a result
+    if let Some(tail) = stop_scanner.finish() {
+        let _ = tx.send(tail).await;
+    }
+
+    let stderr = stderr_tail.text();
+    if exit_code != 0
+        && let Some(stderr) = &stderr
+    {
+        warn!("Exited with code {exit_code}, stderr:\n{stderr}");
+    }
+    if tx.send(SubprocessEvent::Close(exit_code, stderr)).await.is_err() {
+        warn!("Disconnected status=499 before close");
+        return RunOutcome::ClientClosed;
+    }
+    RunOutcome::Completed
+}
+
+/// Kill the CLI and everything it started. The CLI runs node helpers that
+/// outlive a plain `kill` of the direct child and keep the session busy, so on
+/// Unix the whole process group (led by the CLI, see `process_group(0)`) is
+/// sent SIGKILL before the child itself is killed and reaped.
+pub async fn kill_process_group(child: &mut tokio::process::Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: kill(2) has no memory-safety preconditions
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    let _ = child.kill().await;
+}
+
+/// What `process_line` carries across the lines of a run to attach to its result.
+#[derive(Debug, Default)]
+struct LineState {
+    /// The latest reported stop reason
+    stop_reason: Option<String>,
+    /// Text of each assistant message so far
+    turns: Vec<String>,
+    /// Estimated tokens of the thinking blocks so far
+    reasoning_tokens: u64,
 }
 
 /// Parse a single line of NDJSON output and return subprocess events.
-fn process_line(line: &str) -> Option<Vec<SubprocessEvent>> {
+/// `state` carries the latest stop reason and the assistant turns across
+/// lines so they can be attached to the result.
+fn process_line(line: &str, state: &mut LineState) -> Option<Vec<SubprocessEvent>> {
     // First, try to parse as a top-level message
     if let Ok(msg) = serde_json::from_str::<ClaudeCliMessage>(line) {
-        return Some(process_cli_message(msg));
+        return Some(process_cli_message(msg, state));
     }
 
     // Try to parse as a stream event (partial message content)
     if let Ok(event) = serde_json::from_str::<StreamEvent>(line) {
-        return Some(process_stream_event(event));
+        return Some(process_stream_event(event, &mut state.stop_reason));
     }
 
     // Not JSON we recognize
     None
 }
 
-fn process_cli_message(msg: ClaudeCliMessage) -> Vec<SubprocessEvent> {
+fn process_cli_message(msg: ClaudeCliMessage, state: &mut LineState) -> Vec<SubprocessEvent> {
     match msg {
         ClaudeCliMessage::System(_) => {
             // System messages are informational
@@ -252,6 +1161,14 @@ fn process_cli_message(msg: ClaudeCliMessage) -> Vec<SubprocessEvent> {
                 events.push(SubprocessEvent::Model(model.clone()));
             }
 
+            if let Some(AssistantInner {
+                stop_reason: Some(reason),
+                ..
+            }) = &assistant_msg.message
+            {
+                state.stop_reason = Some(reason.clone());
+            }
+
             // Check for inline content (non-streaming assistant messages)
             if let Some(AssistantInner {
                 content: Some(blocks),
@@ -259,23 +1176,49 @@ fn process_cli_message(msg: ClaudeCliMessage) -> Vec<SubprocessEvent> {
             }) = &assistant_msg.message
             {
                 for block in blocks {
-                    if let Some(text) = &block.text {
-                        if !text.is_empty() {
-                            events.push(SubprocessEvent::ContentDelta(text.clone()));
-                        }
+                    if block.block_type.as_deref() == Some("tool_use")
+                        && let Some(name) = &block.name
+                    {
+                        events.push(SubprocessEvent::ToolUse {
+                            id: block.id.clone().unwrap_or_default(),
+                            name: name.clone(),
+                            input: block.input.clone().unwrap_or_else(|| serde_json::json!({})),
+                        });
+                    } else if let Some(text) = &block.text
+                        && !text.is_empty()
+                    {
+                        events.push(SubprocessEvent::ContentDelta(text.clone()));
                     }
                 }
+                let thinking = blocks.iter().filter_map(|b| b.thinking.as_deref());
+                state.reasoning_tokens += thinking.map(|t| estimate_tokens(t) as u64).sum::<u64>();
+                let turn: String = blocks.iter().filter_map(|b| b.text.as_deref()).collect();
+                if !turn.is_empty() {
+                    state.turns.push(turn);
+                }
             }
 
             events
         }
-        ClaudeCliMessage::Result(result) => {
+        ClaudeCliMessage::Result(mut result) => {
+            // A failed run still ends in a result line; don't pass it off as a reply
+            if let Some(message) = result.error_message() {
+                return vec![SubprocessEvent::Error(message)];
+            }
+            if result.stop_reason.is_none() {
+                result.stop_reason = state.stop_reason.take();
+            }
+            result.turns = std::mem::take(&mut state.turns);
+            result.reasoning_tokens = std::mem::take(&mut state.reasoning_tokens);
             vec![SubprocessEvent::Result(result)]
         }
     }
 }
 
-fn process_stream_event(event: StreamEvent) -> Vec<SubprocessEvent> {
+fn process_stream_event(
+    event: StreamEvent,
+    stop_reason: &mut Option<String>,
+) -> Vec<SubprocessEvent> {
     match event {
         StreamEvent::ContentBlockDelta {
             delta: Delta {
@@ -285,6 +1228,14 @@ fn process_stream_event(event: StreamEvent) -> Vec<SubprocessEvent> {
         } if !text.is_empty() => {
             vec![SubprocessEvent::ContentDelta(text)]
         }
+        StreamEvent::MessageDelta {
+            delta: Some(MessageDeltaInfo {
+                stop_reason: Some(reason),
+            }),
+        } => {
+            *stop_reason = Some(reason);
+            vec![]
+        }
         _ => vec![],
     }
 }
@@ -292,17 +1243,82 @@ fn process_stream_event(event: StreamEvent) -> Vec<SubprocessEvent> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::claude_cli::ResultMessage;
+
+    // ── kill_process_group ───────────────────────────────────
+
+    /// Whether `pid` is still running (not gone and not a zombie).
+    #[cfg(target_os = "linux")]
+    fn is_running(pid: &str) -> bool {
+        // The state letter follows the parenthesized command name
+        std::fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+            stat.rsplit(')')
+                .next()
+                .is_some_and(|rest| !rest.trim_start().starts_with('Z'))
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn kill_process_group_leaves_no_orphans() {
+        // Stands in for the CLI: a shell with a long-running grandchild
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 30 & echo $!; wait"])
+            .process_group(0)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let grandchild = BufReader::new(stdout).lines().next_line().await.unwrap().unwrap();
+        assert!(is_running(&grandchild));
+
+        kill_process_group(&mut child).await;
+
+        // Give init a moment to reap the orphan
+        for _ in 0..50 {
+            if !is_running(&grandchild) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("grandchild {grandchild} survived the kill");
+    }
+
+    // ── cli_version ───────────────────────────────────────────
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cli_version_waits_for_the_binary() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("cli-version-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let bin = dir.join("claude");
+        let bin_str = bin.to_string_lossy().into_owned();
+
+        let missing = cli_version(&bin_str, 0, Duration::ZERO).await;
+        assert_eq!(missing.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // The binary shows up while the check is retrying
+        let staged = dir.join("claude.tmp");
+        let install = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            std::fs::write(&staged, "#!/bin/sh\necho '2.1.0 (Claude Code)'\n").unwrap();
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755)).unwrap();
+            std::fs::rename(&staged, &bin).unwrap();
+        });
+        let version = cli_version(&bin_str, 50, Duration::from_millis(20)).await.unwrap();
+        assert_eq!(version, "2.1.0 (Claude Code)");
+        install.await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
     // ── build_args ────────────────────────────────────────────
 
     #[test]
     fn build_args_basic() {
         let options = SubprocessOptions {
-            request_id: "abc".to_string(),
-            model: "opus".to_string(),
-            session_id: None,
-            cwd: "/tmp".to_string(),
             api: "anthropic",
+            ..SubprocessOptions::for_test()
         };
         let args = build_args("Hello world", &options);
         assert!(args.contains(&"--print".to_string()));
@@ -311,7 +1327,9 @@ mod tests {
         assert!(args.contains(&"--model".to_string()));
         assert!(args.contains(&"opus".to_string()));
         assert!(args.contains(&"--permission-mode".to_string()));
-        assert!(args.contains(&"bypassPermissions".to_string()));
+        assert!(args.contains(&"default".to_string()));
+        assert!(!args.iter().any(|a| a.starts_with("--allowedTools")));
+        assert!(!args.iter().any(|a| a.starts_with("--disallowedTools")));
         assert!(args.contains(&"Hello world".to_string()));
         assert!(!args.contains(&"--session-id".to_string()));
     }
@@ -319,30 +1337,555 @@ mod tests {
     #[test]
     fn build_args_with_session_id() {
         let options = SubprocessOptions {
-            request_id: "abc".to_string(),
             model: "sonnet".to_string(),
             session_id: Some("sess-123".to_string()),
-            cwd: "/tmp".to_string(),
-            api: "openai",
+            ..SubprocessOptions::for_test()
         };
         let args = build_args("test", &options);
         assert!(args.contains(&"--session-id".to_string()));
         assert!(args.contains(&"sess-123".to_string()));
     }
 
+    #[test]
+    fn build_args_appends_extra_args() {
+        let options = SubprocessOptions {
+            extra_args: vec!["--max-turns".to_string(), "3".to_string()],
+            ..SubprocessOptions::for_test()
+        };
+        let args = build_args("hi", &options);
+        assert_eq!(&args[args.len() - 2..], ["--max-turns", "3"]);
+    }
+
+    #[test]
+    fn build_args_passes_permissions() {
+        use crate::config::PermissionMode;
+        let tools = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let cases = [
+            (PermissionMode::Default, tools(&[]), tools(&[]), None, None),
+            (
+                PermissionMode::AcceptEdits,
+                tools(&["Edit", "Bash(git diff:*)"]),
+                tools(&[]),
+                Some("--allowedTools=Edit,Bash(git diff:*)"),
+                None,
+            ),
+            (
+                PermissionMode::Plan,
+                tools(&[]),
+                tools(&["Bash", "WebFetch"]),
+                None,
+                Some("--disallowedTools=Bash,WebFetch"),
+            ),
+            (
+                PermissionMode::BypassPermissions,
+                tools(&["Read"]),
+                tools(&["Bash"]),
+                Some("--allowedTools=Read"),
+                Some("--disallowedTools=Bash"),
+            ),
+        ];
+        for (mode, allowed_tools, disallowed_tools, allowed, disallowed) in cases {
+            let options = SubprocessOptions {
+                permissions: CliPermissions {
+                    mode,
+                    allowed_tools,
+                    disallowed_tools,
+                },
+                ..SubprocessOptions::for_test()
+            };
+            let args = build_args("hi", &options);
+            let at = args.iter().position(|a| a == "--permission-mode").unwrap();
+            assert_eq!(args[at + 1], mode.as_cli());
+            let flag = |prefix: &str| args.iter().find(|a| a.starts_with(prefix)).cloned();
+            assert_eq!(flag("--allowedTools").as_deref(), allowed);
+            assert_eq!(flag("--disallowedTools").as_deref(), disallowed);
+            // Warm processes start with the same flags, up to the prompt
+            let prompt = args.iter().position(|a| a == "hi").unwrap();
+            assert_eq!(warm_args("opus", &options.permissions)[..prompt], args[..prompt]);
+        }
+    }
+
+    #[test]
+    fn build_args_passes_the_system_prompt() {
+        let mut options = SubprocessOptions {
+            system_prompt: Some("Be brief".to_string()),
+            ..SubprocessOptions::for_test()
+        };
+        let flag_value = |args: &[String]| {
+            let at = args.iter().position(|a| a == "--append-system-prompt")?;
+            Some(args[at + 1].clone())
+        };
+        let args = build_args("hi", &options);
+        assert_eq!(flag_value(&args).as_deref(), Some("Be brief"));
+
+        // A client's own flag is merged in rather than overriding it
+        options.extra_args = ["--append-system-prompt", "Use French", "--max-turns", "2"]
+            .map(str::to_string)
+            .to_vec();
+        let args = build_args("hi", &options);
+        assert_eq!(flag_value(&args).as_deref(), Some("Be brief\n\nUse French"));
+        let count = args.iter().filter(|a| *a == "--append-system-prompt").count();
+        assert_eq!(count, 1);
+        assert_eq!(&args[args.len() - 2..], ["--max-turns", "2"]);
+
+        options.system_prompt = None;
+        let args = build_args("hi", &options);
+        assert_eq!(flag_value(&args).as_deref(), Some("Use French"));
+        options.extra_args.clear();
+        assert_eq!(flag_value(&build_args("hi", &options)), None);
+    }
+
+    #[test]
+    fn build_args_never_emits_sampling_flags() {
+        let mut options = SubprocessOptions {
+            ..SubprocessOptions::for_test()
+        };
+        let without = build_args("hi", &options);
+
+        options.sampling = Sampling {
+            temperature: Some(0.2),
+            top_p: Some(0.9),
+        };
+        let with = build_args("hi", &options);
+
+        assert_eq!(with, without);
+        assert!(!with.iter().any(|a| a.contains("temperature") || a.contains("top-p")));
+    }
+
+    // ── build_env ─────────────────────────────────────────────
+
+    #[test]
+    fn build_env_passes_max_tokens() {
+        let mut options = SubprocessOptions {
+            env: vec![("CLAUDE_PROFILE".to_string(), "work".to_string())],
+            ..SubprocessOptions::for_test()
+        };
+        assert_eq!(build_env(&options), options.env);
+
+        options.max_tokens = Some(256);
+        let env = build_env(&options);
+        assert_eq!(env.len(), 2);
+        assert_eq!(env[1], (MAX_OUTPUT_TOKENS_ENV.to_string(), "256".to_string()));
+    }
+
+    // ── parse_extra_args ──────────────────────────────────────
+
+    #[test]
+    fn extra_args_allowed_flags() {
+        assert_eq!(
+            parse_extra_args("--max-turns 3 --fallback-model=sonnet").unwrap(),
+            vec!["--max-turns", "3", "--fallback-model", "sonnet"]
+        );
+        assert!(parse_extra_args("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn extra_args_disallowed_flags() {
+        assert!(parse_extra_args("--dangerously-skip-permissions").is_err());
+        assert!(parse_extra_args("--permission-mode bypassPermissions").is_err());
+        assert!(parse_extra_args("--add-dir /").is_err());
+        assert!(parse_extra_args("--max-turns 3 --allowedTools Bash").is_err());
+    }
+
+    #[test]
+    fn extra_args_value_cannot_smuggle_a_flag() {
+        assert!(parse_extra_args("--max-turns").is_err());
+        assert!(parse_extra_args("--max-turns --add-dir").is_err());
+        assert!(parse_extra_args("--max-turns=").is_err());
+        assert!(parse_extra_args("--max-turns ''").is_err());
+    }
+
+    #[test]
+    fn extra_args_take_quoted_values() {
+        let raw = r#"--append-system-prompt "Answer in French. - Be terse" --max-turns 2"#;
+        assert_eq!(
+            parse_extra_args(raw).unwrap(),
+            vec!["--append-system-prompt", "Answer in French. - Be terse", "--max-turns", "2"]
+        );
+        assert_eq!(
+            parse_extra_args(r#"--append-system-prompt='- list, "quoted"'"#).unwrap(),
+            vec!["--append-system-prompt", r#"- list, "quoted""#]
+        );
+        assert_eq!(
+            parse_extra_args(r#"--append-system-prompt "say \"hi\"" "#).unwrap(),
+            vec!["--append-system-prompt", r#"say "hi""#]
+        );
+        assert_eq!(
+            parse_extra_args(r"--append-system-prompt one\ two").unwrap(),
+            vec!["--append-system-prompt", "one two"]
+        );
+        assert!(parse_extra_args(r#"--append-system-prompt "unclosed"#).is_err());
+    }
+
+    // ── StopScanner ───────────────────────────────────────────
+
+    fn deltas(events: &[SubprocessEvent]) -> String {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                SubprocessEvent::ContentDelta(t) => Some(t.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn result(text: &str) -> SubprocessEvent {
+        SubprocessEvent::Result(ResultMessage {
+            subtype: None,
+            is_error: None,
+            result: Some(text.to_string()),
+            exit_code: Some(0),
+            duration_ms: None,
+            duration_api_ms: None,
+            num_turns: None,
+            model_usage: None,
+            stop_reason: Some("end_turn".to_string()),
+            stop_sequence: None,
+            turns: Vec::new(),
+            reasoning_tokens: 0,
+        })
+    }
+
+    fn scan(scanner: &mut StopScanner, parts: &[&str]) -> Vec<SubprocessEvent> {
+        parts
+            .iter()
+            .flat_map(|p| scanner.filter(SubprocessEvent::ContentDelta(p.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn no_sequences_passes_everything_through() {
+        let mut scanner = StopScanner::new(vec![]);
+        let events = scan(&mut scanner, &["Hel", "lo"]);
+        assert_eq!(events.len(), 2);
+        assert!(scanner.finish().is_none());
+    }
+
+    #[test]
+    fn cuts_at_sequence_within_one_delta() {
+        let mut scanner = StopScanner::new(vec!["END".to_string()]);
+        let events = scan(&mut scanner, &["Hello END world", " more"]);
+        assert_eq!(deltas(&events), "Hello ");
+        assert!(scanner.finish().is_none());
+    }
+
+    #[test]
+    fn cuts_at_sequence_split_across_deltas() {
+        let mut scanner = StopScanner::new(vec!["\n\nUser:".to_string()]);
+        let events = scan(&mut scanner, &["Sure.\n", "\nUs", "er: hi"]);
+        assert_eq!(deltas(&events), "Sure.");
+    }
+
+    #[test]
+    fn match_in_a_delta_ends_with_a_result() {
+        let mut scanner = StopScanner::new(vec!["END".to_string()]);
+        let events = scan(&mut scanner, &["Hi ", "there END", " ignored"]);
+        assert!(scanner.stopped());
+        match &events[..] {
+            [.., SubprocessEvent::Result(r)] => {
+                assert_eq!(r.result.as_deref(), Some("Hi there "));
+                assert_eq!(r.stop_reason.as_deref(), Some("stop_sequence"));
+                assert_eq!(r.stop_sequence.as_deref(), Some("END"));
+            }
+            other => panic!("Expected a final Result, got {:?}", other),
+        }
+        assert_eq!(deltas(&events), "Hi there ");
+    }
+
+    /// Whether `pid` is running (see `is_running`), for a stand-in CLI that
+    /// wrote its pid to `pid_file`.
+    #[cfg(target_os = "linux")]
+    fn pid_running(pid_file: &std::path::Path) -> bool {
+        is_running(std::fs::read_to_string(pid_file).unwrap().trim())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn stop_sequence_match_kills_the_cli() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("stop-seq-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let pid_file = dir.join("pid");
+        // Stands in for the CLI: matches early, then keeps generating forever
+        let delta = |text: &str| {
+            let delta = serde_json::json!({"type": "text_delta", "text": text});
+            let event = serde_json::json!({"type": "content_block_delta", "delta": delta});
+            format!("echo '{event}'")
+        };
+        let script = format!(
+            "#!/bin/sh\necho $$ > {}\n{}\nwhile true; do {}; sleep 0.01; done\n",
+            pid_file.display(),
+            delta("Hello END"),
+            delta(" more"),
+        );
+        let bin = dir.join("claude");
+        std::fs::write(&bin, script).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let options = SubprocessOptions {
+            claude_bin: bin.to_string_lossy().into_owned(),
+            inactivity_timeout: Duration::from_secs(10),
+            stop_sequences: vec!["END".to_string()],
+            ..SubprocessOptions::for_test()
+        };
+
+        let (tx, mut rx) = mpsc::channel(64);
+        let run = spawn_subprocess("hi".to_string(), options, None, tx);
+        let outcome = tokio::time::timeout(Duration::from_secs(5), run).await.unwrap();
+        assert_eq!(outcome, RunOutcome::Completed);
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert_eq!(deltas(&events), "Hello ");
+        match events.last() {
+            Some(SubprocessEvent::Result(r)) => {
+                assert_eq!(r.result.as_deref(), Some("Hello "));
+                assert_eq!(r.stop_reason.as_deref(), Some("stop_sequence"));
+            }
+            other => panic!("Expected Result, got {:?}", other),
+        }
+        assert!(!pid_running(&pid_file));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn releases_held_back_text_that_was_not_a_sequence() {
+        let mut scanner = StopScanner::new(vec!["END".to_string()]);
+        let mut events = scan(&mut scanner, &["The EN", "ding"]);
+        events.extend(scanner.finish());
+        assert_eq!(deltas(&events), "The ENding");
+    }
+
+    #[test]
+    fn held_back_text_respects_char_boundaries() {
+        let mut scanner = StopScanner::new(vec!["éé".to_string()]);
+        let mut events = scan(&mut scanner, &["café"]);
+        assert_eq!(deltas(&events), "caf");
+        events.extend(scan(&mut scanner, &["!"]));
+        assert_eq!(deltas(&events), "café!");
+    }
+
+    #[test]
+    fn result_is_truncated_and_marked() {
+        let mut scanner = StopScanner::new(vec!["STOP".to_string(), "END".to_string()]);
+        scan(&mut scanner, &["a END b"]);
+        let SubprocessEvent::Result(mut full) = result("a END b STOP") else {
+            unreachable!()
+        };
+        full.turns = vec!["a END".to_string(), "b STOP".to_string()];
+        let events = scanner.filter(SubprocessEvent::Result(full));
+        match &events[..] {
+            [SubprocessEvent::Result(r)] => {
+                assert_eq!(r.result.as_deref(), Some("a "));
+                assert_eq!(r.stop_reason.as_deref(), Some("stop_sequence"));
+                assert_eq!(r.stop_sequence.as_deref(), Some("END"));
+                assert!(r.turns.is_empty());
+            }
+            other => panic!("Expected one Result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn result_without_match_flushes_and_keeps_stop_reason() {
+        let mut scanner = StopScanner::new(vec!["END".to_string()]);
+        scan(&mut scanner, &["The EN"]);
+        let events = scanner.filter(result("The EN"));
+        assert_eq!(deltas(&events), "EN");
+        match events.last() {
+            Some(SubprocessEvent::Result(r)) => {
+                assert_eq!(r.stop_reason.as_deref(), Some("end_turn"));
+                assert_eq!(r.stop_sequence, None);
+            }
+            other => panic!("Expected Result, got {:?}", other),
+        }
+    }
+
+    // ── OutputBudget ──────────────────────────────────────────
+
+    #[test]
+    fn unlimited_budget_admits_everything() {
+        let mut budget = OutputBudget::new(None);
+        let delta = SubprocessEvent::ContentDelta("x".repeat(1 << 20));
+        assert!((0..100).all(|_| budget.admit(&delta)));
+    }
+
+    #[test]
+    fn budget_counts_only_content() {
+        let mut budget = OutputBudget::new(Some(4));
+        assert!(budget.admit(&SubprocessEvent::Model("claude-opus-4".to_string())));
+        assert!(budget.admit(&SubprocessEvent::ContentDelta("abcd".to_string())));
+        assert!(budget.admit(&SubprocessEvent::Close(0, None)));
+        assert!(!budget.admit(&SubprocessEvent::ContentDelta("e".to_string())));
+    }
+
+    #[tokio::test]
+    async fn budget_stops_high_rate_source() {
+        // A producer emitting deltas as fast as the channel allows
+        let (tx, mut rx) = mpsc::channel(64);
+        let producer = tokio::spawn(async move {
+            let mut sent = 0u64;
+            while tx.send(SubprocessEvent::ContentDelta("0123456789".to_string())).await.is_ok() {
+                sent += 1;
+            }
+            sent
+        });
+
+        let mut budget = OutputBudget::new(Some(10_000));
+        let mut admitted = 0;
+        while let Some(event) = rx.recv().await {
+            if !budget.admit(&event) {
+                break;
+            }
+            admitted += 1;
+        }
+        drop(rx);
+
+        assert_eq!(admitted, 1_000);
+        // The producer is cut off shortly after the limit, not left running
+        assert!(producer.await.unwrap() < 1_000 + 64 + 2);
+    }
+
+    // ── StderrTail ────────────────────────────────────────────
+
+    #[test]
+    fn stderr_tail_keeps_last_lines() {
+        let mut tail = StderrTail::default();
+        assert_eq!(tail.text(), None);
+        for i in 0..25 {
+            tail.push(&format!("line {i}"));
+        }
+        tail.push("   ");
+        let text = tail.text().unwrap();
+        assert_eq!(text.lines().count(), STDERR_TAIL_LINES);
+        assert!(text.starts_with("line 5\n"));
+        assert!(text.ends_with("line 24"));
+    }
+
+    #[test]
+    fn stderr_tail_truncates_long_lines() {
+        let mut tail = StderrTail::default();
+        tail.push(&"x ".repeat(STDERR_LINE_MAX_CHARS));
+        assert_eq!(tail.text().unwrap().chars().count(), STDERR_LINE_MAX_CHARS);
+    }
+
+    #[test]
+    fn redact_api_keys_and_bearer_tokens() {
+        assert_eq!(
+            redact_secrets("Invalid API key: sk-ant-api03-abc_DEF-123"),
+            "Invalid API key: [REDACTED]"
+        );
+        assert_eq!(
+            redact_secrets("Authorization: Bearer abcdefghij; retry"),
+            "Authorization: Bearer [REDACTED]; retry"
+        );
+        assert_eq!(
+            redact_secrets("token=a1b2c3d4e5f6a7b8c9d0e1f2a3b4c5d6e7f8 expired"),
+            "token=[REDACTED] expired"
+        );
+    }
+
+    #[test]
+    fn redact_keeps_ordinary_text() {
+        let line = "Error: rate limit exceeded (429), retry after 30s at /usr/lib/node_modules";
+        assert_eq!(redact_secrets(line), line);
+        assert_eq!(redact_secrets("unknown option '--foo'"), "unknown option '--foo'");
+    }
+
+    // ── classify_failure ──────────────────────────────────────
+
+    #[test]
+    fn classify_auth_failures() {
+        for stderr in [
+            "Invalid API key · Please run /login",
+            "Error: Not logged in",
+            "API Error: 401 {\"type\":\"error\",\"error\":{\"type\":\"authentication_error\"}}",
+            "OAuth token has expired. Please obtain a new token",
+            "API Error: 401 {}",
+            "Request failed with status code 401",
+        ] {
+            assert_eq!(classify_failure(stderr), Some(UpstreamFailure::Auth), "{stderr}");
+        }
+    }
+
+    #[test]
+    fn classify_rate_limit_and_quota() {
+        assert_eq!(
+            classify_failure("API Error: 429 rate_limit_error: Number of requests exceeded"),
+            Some(UpstreamFailure::RateLimit)
+        );
+        assert_eq!(
+            classify_failure("Error: Too Many Requests"),
+            Some(UpstreamFailure::RateLimit)
+        );
+        assert_eq!(
+            classify_failure("API Error: 429 {}"),
+            Some(UpstreamFailure::RateLimit)
+        );
+        assert_eq!(
+            classify_failure("upstream returned status 429."),
+            Some(UpstreamFailure::RateLimit)
+        );
+        assert_eq!(
+            classify_failure("Claude AI usage limit reached|1760000000"),
+            Some(UpstreamFailure::Quota)
+        );
+        assert_eq!(
+            classify_failure("Your credit balance is too low"),
+            Some(UpstreamFailure::Quota)
+        );
+    }
+
+    #[test]
+    fn classify_other_failures() {
+        assert_eq!(classify_failure("TypeError: cannot read properties of undefined"), None);
+        assert_eq!(classify_failure(""), None);
+    }
+
+    #[test]
+    fn classify_ignores_status_digits_in_traces_and_ids() {
+        for stderr in [
+            "TypeError: x is undefined\n    at run (/usr/lib/cli.js:1401:29)",
+            "    at main (file:///opt/claude/cli.js:429:7)",
+            "Error: session 4290-abc not found",
+            "API Error: 4011 unexpected",
+            "API Error: 500 status 4290",
+        ] {
+            assert_eq!(classify_failure(stderr), None, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn model_unavailable_failures() {
+        assert!(model_unavailable("Claude AI usage limit reached|1760000000"));
+        assert!(model_unavailable("API Error: 429 rate_limit_error"));
+        assert!(model_unavailable("API Error: 529 {\"type\":\"overloaded_error\"}"));
+        assert!(model_unavailable("Error: Model is not available for your plan"));
+        assert!(!model_unavailable("Error: Not logged in. Please run /login"));
+        assert!(!model_unavailable("Inactivity timeout after 30 seconds"));
+    }
+
+    #[test]
+    fn upstream_failure_statuses() {
+        use axum::http::StatusCode;
+        assert_eq!(UpstreamFailure::Auth.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(UpstreamFailure::RateLimit.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(UpstreamFailure::Quota.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(UpstreamFailure::RateLimit.error_type(), "rate_limit_error");
+    }
+
     // ── process_line ──────────────────────────────────────────
 
     #[test]
     fn process_line_system_message() {
         let line = r#"{"type":"system","subtype":"init"}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert!(events.is_empty());
     }
 
     #[test]
     fn process_line_assistant_with_model() {
         let line = r#"{"type":"assistant","message":{"model":"claude-opus-4-20250514","content":[]}}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert_eq!(events.len(), 1);
         match &events[0] {
             SubprocessEvent::Model(m) => assert_eq!(m, "claude-opus-4-20250514"),
@@ -353,7 +1896,7 @@ mod tests {
     #[test]
     fn process_line_assistant_with_content() {
         let line = r#"{"type":"assistant","message":{"model":"claude-sonnet-4","content":[{"type":"text","text":"Hello"}]}}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert_eq!(events.len(), 2);
         match &events[0] {
             SubprocessEvent::Model(m) => assert_eq!(m, "claude-sonnet-4"),
@@ -368,15 +1911,45 @@ mod tests {
     #[test]
     fn process_line_assistant_empty_content_skipped() {
         let line = r#"{"type":"assistant","message":{"model":"opus","content":[{"type":"text","text":""}]}}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert_eq!(events.len(), 1);
         assert!(matches!(&events[0], SubprocessEvent::Model(_)));
     }
 
+    #[test]
+    fn process_line_assistant_with_tool_use() {
+        let line = r#"{"type":"assistant","message":{"model":"opus","content":[{"type":"text","text":"Listing"},{"type":"tool_use","id":"toolu_1","name":"Bash","input":{"command":"ls"}}]}}"#;
+        let events = process_line(line, &mut LineState::default()).unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[1], SubprocessEvent::ContentDelta(t) if t == "Listing"));
+        match &events[2] {
+            SubprocessEvent::ToolUse { id, name, input } => {
+                assert_eq!(id, "toolu_1");
+                assert_eq!(name, "Bash");
+                assert_eq!(input["command"], "ls");
+            }
+            other => panic!("Expected ToolUse, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn process_line_tool_use_without_input() {
+        let line = r#"{"type":"assistant","message":{"content":[{"type":"tool_use","id":"toolu_2","name":"Read"}]}}"#;
+        let events = process_line(line, &mut LineState::default()).unwrap();
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            SubprocessEvent::ToolUse { name, input, .. } => {
+                assert_eq!(name, "Read");
+                assert_eq!(input, &serde_json::json!({}));
+            }
+            other => panic!("Expected ToolUse, got {:?}", other),
+        }
+    }
+
     #[test]
     fn process_line_result() {
         let line = r#"{"type":"result","result":"Done","exitCode":0,"duration_ms":1234,"duration_api_ms":1000,"num_turns":1,"modelUsage":{"claude-opus-4":{"input_tokens":50,"output_tokens":25}}}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert_eq!(events.len(), 1);
         match &events[0] {
             SubprocessEvent::Result(r) => {
@@ -391,10 +1964,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn process_line_error_result() {
+        let line = r#"{"type":"result","subtype":"error_during_execution","is_error":true,"result":"Tool crashed"}"#;
+        let events = process_line(line, &mut LineState::default()).unwrap();
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            SubprocessEvent::Error(msg) => {
+                assert_eq!(msg, "CLI reported error_during_execution: Tool crashed")
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
     #[test]
     fn process_line_content_block_delta() {
         let line = r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"streaming text"}}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert_eq!(events.len(), 1);
         match &events[0] {
             SubprocessEvent::ContentDelta(t) => assert_eq!(t, "streaming text"),
@@ -405,53 +1991,159 @@ mod tests {
     #[test]
     fn process_line_content_block_delta_empty_text() {
         let line = r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":""}}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert!(events.is_empty());
     }
 
     #[test]
     fn process_line_content_block_start() {
         let line = r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert!(events.is_empty());
     }
 
     #[test]
     fn process_line_content_block_stop() {
         let line = r#"{"type":"content_block_stop","index":0}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert!(events.is_empty());
     }
 
     #[test]
     fn process_line_message_start() {
         let line = r#"{"type":"message_start"}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert!(events.is_empty());
     }
 
     #[test]
     fn process_line_message_delta() {
         let line = r#"{"type":"message_delta"}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert!(events.is_empty());
     }
 
     #[test]
     fn process_line_message_stop() {
         let line = r#"{"type":"message_stop"}"#;
-        let events = process_line(line).unwrap();
+        let events = process_line(line, &mut LineState::default()).unwrap();
         assert!(events.is_empty());
     }
 
+    #[test]
+    fn process_line_attaches_stop_reason_to_result() {
+        let mut state = LineState::default();
+        let delta = r#"{"type":"message_delta","delta":{"stop_reason":"max_tokens"}}"#;
+        assert!(process_line(delta, &mut state).unwrap().is_empty());
+        assert_eq!(state.stop_reason.as_deref(), Some("max_tokens"));
+
+        let events = process_line(r#"{"type":"result","result":"Cut"}"#, &mut state).unwrap();
+        match &events[0] {
+            SubprocessEvent::Result(r) => assert_eq!(r.stop_reason.as_deref(), Some("max_tokens")),
+            other => panic!("Expected Result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn process_line_attaches_turns_to_result() {
+        let mut state = LineState::default();
+        for line in [
+            r#"{"type":"assistant","message":{"content":[{"type":"text","text":"Let me look."}]}}"#,
+            r#"{"type":"assistant","message":{"content":[{"type":"tool_use","id":"t","name":"Read"}]}}"#,
+            r#"{"type":"assistant","message":{"content":[{"type":"text","text":"Done."}]}}"#,
+        ] {
+            process_line(line, &mut state).unwrap();
+        }
+        let events = process_line(r#"{"type":"result","result":"Done."}"#, &mut state).unwrap();
+        match &events[0] {
+            SubprocessEvent::Result(r) => assert_eq!(r.turns, ["Let me look.", "Done."]),
+            other => panic!("Expected Result, got {:?}", other),
+        }
+        assert!(state.turns.is_empty());
+    }
+
+    #[test]
+    fn process_line_estimates_reasoning_tokens() {
+        let mut state = LineState::default();
+        let line = r#"{"type":"assistant","message":{"content":[
+            {"type":"thinking","thinking":"Twelve chars"},
+            {"type":"text","text":"Hi"}
+        ]}}"#;
+        let events = process_line(line, &mut state).unwrap();
+        // The thinking isn't part of the reply
+        assert!(matches!(&events[..], [SubprocessEvent::ContentDelta(t)] if t == "Hi"));
+        let events = process_line(r#"{"type":"result","result":"Hi"}"#, &mut state).unwrap();
+        match &events[0] {
+            SubprocessEvent::Result(r) => assert_eq!(r.reasoning_tokens, 3),
+            other => panic!("Expected Result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn process_line_stop_reason_from_assistant_message() {
+        let mut state = LineState::default();
+        let line = r#"{"type":"assistant","message":{"content":[],"stop_reason":"end_turn"}}"#;
+        process_line(line, &mut state).unwrap();
+        assert_eq!(state.stop_reason.as_deref(), Some("end_turn"));
+    }
+
+    #[tokio::test]
+    async fn final_line_without_newline_is_parsed() {
+        // The CLI's last line (the result) can arrive without a trailing newline
+        let stdout = concat!(
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hi"}}"#,
+            "\n",
+            r#"{"type":"result","result":"Hi"}"#,
+        );
+        let mut lines = BufReader::new(stdout.as_bytes()).split(b'\n');
+        let mut events = Vec::new();
+        while let Some(line) = lines.next_segment().await.unwrap() {
+            let line = decode_line(line, OutputEncoding::default());
+            events.extend(process_line(&line, &mut LineState::default()).unwrap());
+        }
+        assert_eq!(events.len(), 2);
+        match &events[1] {
+            SubprocessEvent::Result(r) => assert_eq!(r.result.as_deref(), Some("Hi")),
+            other => panic!("Expected Result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_line_per_encoding() {
+        let invalid = b"caf\xe9 \xff\r".to_vec();
+        assert_eq!(decode_line(invalid.clone(), OutputEncoding::Utf8), "caf\u{fffd} \u{fffd}");
+        assert_eq!(decode_line(invalid.clone(), OutputEncoding::Lossy), "caf\u{fffd} \u{fffd}");
+        assert_eq!(decode_line(invalid, OutputEncoding::Latin1), "caf\u{e9} \u{ff}");
+        let valid = "caf\u{e9}\r".as_bytes().to_vec();
+        assert_eq!(decode_line(valid, OutputEncoding::Utf8), "caf\u{e9}");
+    }
+
+    #[tokio::test]
+    async fn lossy_output_never_fails_on_invalid_bytes() {
+        // Invalid bytes inside a JSON string still yield the line's event
+        let mut stdout = br#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"a"#
+            .to_vec();
+        stdout.extend_from_slice(b"\xc3\x28\xff");
+        stdout.extend_from_slice(br#"b"}}"#);
+        let mut lines = BufReader::new(stdout.as_slice()).split(b'\n');
+        let line = lines.next_segment().await.unwrap().unwrap();
+        let line = decode_line(line, OutputEncoding::Lossy);
+        let events = process_line(&line, &mut LineState::default()).unwrap();
+        match &events[..] {
+            [SubprocessEvent::ContentDelta(text)] => assert_eq!(text, "a\u{fffd}(\u{fffd}b"),
+            other => panic!("Expected one delta, got {:?}", other),
+        }
+    }
+
     #[test]
     fn process_line_not_json() {
-        assert!(process_line("not json at all").is_none());
-        assert!(process_line("").is_none());
+        assert!(process_line("not json at all", &mut LineState::default()).is_none());
+        assert!(process_line("", &mut LineState::default()).is_none());
     }
 
     #[test]
     fn process_line_unknown_json() {
-        assert!(process_line(r#"{"type":"unknown","data":123}"#).is_none());
+        let line = r#"{"type":"unknown","data":123}"#;
+        assert!(process_line(line, &mut LineState::default()).is_none());
     }
 }
@@ -0,0 +1,254 @@
+use axum::extract::{Request, State};
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use tracing::{Instrument, Subscriber, field, info_span};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::filter::{EnvFilter, FilterExt, filter_fn};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, fmt};
+
+use crate::config::LogFormat;
+use crate::routes;
+use crate::server::AppState;
+
+/// `service.name` reported on exported spans.
+const SERVICE_NAME: &str = "claude-max-api";
+
+/// Path of the OTLP/HTTP traces receiver, appended to `--otlp-endpoint`.
+const TRACES_PATH: &str = "/v1/traces";
+
+/// Log target prefix of this crate's events.
+const CRATE_TARGET: &str = env!("CARGO_CRATE_NAME");
+
+tokio::task_local! {
+    /// Set while running a request sent with `X-Debug: true`.
+    static TRACED_REQUEST: ();
+}
+
+/// Run `future` with this crate's logging raised to trace level when `trace`
+/// is set (`X-Debug: true`), whatever the global filter says.
+pub async fn with_request_tracing<F: Future>(trace: bool, future: F) -> F::Output {
+    if trace {
+        TRACED_REQUEST.scope((), future).await
+    } else {
+        future.await
+    }
+}
+
+fn in_traced_request() -> bool {
+    TRACED_REQUEST.try_with(|_| ()).is_ok()
+}
+
+/// Id of the request being handled, attached as a request extension by
+/// [`request_span`].
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Middleware running each request inside a `request` span, so every log line
+/// and span below it carries `req_id` (plus `api`, `model` and `client` once
+/// the handler records them). The id comes from `--request-id-header` when the
+/// caller sent a usable one and is echoed back on the response.
+pub async fn request_span(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let id = routes::request_id(&state.config, req.headers());
+    let span = info_span!(
+        "request",
+        req_id = %id,
+        api = field::Empty,
+        model = field::Empty,
+        client = field::Empty,
+    );
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let mut response = next.run(req).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response
+            .headers_mut()
+            .insert(state.config.request_id_header.clone(), value);
+    }
+    response
+}
+
+/// Tracer provider exporting spans in batches over OTLP/HTTP to the collector
+/// at `endpoint` (e.g. `http://localhost:4318`). Nothing is contacted until
+/// the first batch is sent, so an unreachable collector only loses spans.
+pub fn tracer_provider(endpoint: &str) -> Result<SdkTracerProvider, String> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let endpoint = if endpoint.ends_with(TRACES_PATH) {
+        endpoint.to_string()
+    } else {
+        format!("{endpoint}{TRACES_PATH}")
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| format!("failed to build OTLP exporter: {e}"))?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build())
+}
+
+/// Tracing layer forwarding spans to `provider`.
+pub fn layer<S>(provider: &SdkTracerProvider) -> OpenTelemetryLayer<S, SdkTracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME))
+}
+
+/// Layer writing log lines to `writer` in `format`. Lines carry the fields of
+/// their enclosing spans (`request`: req_id, api, model; `cli_request`: pid,
+/// ...), as a `spans` list in JSON. Inside `with_request_tracing`, this crate's
+/// events pass at every level.
+pub fn log_layer<S, W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_target(false).with_writer(writer);
+    let traced = filter_fn(|meta| meta.target().starts_with(CRATE_TARGET) && in_traced_request());
+    let filter = filter.or(traced);
+    match format {
+        LogFormat::Pretty => layer.pretty().with_filter(filter).boxed(),
+        LogFormat::Compact => layer
+            .compact()
+            .without_time()
+            .with_ansi(false)
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_filter(filter)
+            .boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Collects everything written through it.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'w> MakeWriter<'w> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'w self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    /// Log one event inside `request` and `cli_request` spans and return the output.
+    fn log_in_span(format: LogFormat) -> String {
+        let buffer = Buffer::default();
+        let filter = EnvFilter::new("info");
+        let subscriber =
+            tracing_subscriber::registry().with(log_layer(format, filter, buffer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", req_id = "req1", model = "opus");
+            let cli = request
+                .in_scope(|| tracing::info_span!("cli_request", pid = tracing::field::Empty));
+            cli.record("pid", 42);
+            cli.in_scope(|| tracing::info!(exit_code = 0, "Done"));
+        });
+        String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn json_log_lines_carry_span_fields() {
+        let output = log_in_span(LogFormat::Json);
+        let line: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "Done");
+        assert_eq!(line["exit_code"], 0);
+        assert_eq!(line["spans"][0]["name"], "request");
+        assert_eq!(line["spans"][0]["req_id"], "req1");
+        assert_eq!(line["spans"][0]["model"], "opus");
+        assert_eq!(line["spans"][1]["name"], "cli_request");
+        assert_eq!(line["spans"][1]["pid"], 42);
+    }
+
+    #[tokio::test]
+    async fn traced_requests_log_at_trace_level() {
+        let buffer = Buffer::default();
+        let filter = EnvFilter::new(format!("{CRATE_TARGET}=info"));
+        let subscriber = tracing_subscriber::registry().with(log_layer(
+            LogFormat::Compact,
+            filter,
+            buffer.clone(),
+        ));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        with_request_tracing(true, async { tracing::trace!("flagged detail") }).await;
+        with_request_tracing(false, async { tracing::trace!("unflagged detail") }).await;
+        tracing::info!("regular line");
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("flagged detail"), "{output}");
+        assert!(!output.contains("unflagged detail"), "{output}");
+        assert!(output.contains("regular line"), "{output}");
+    }
+
+    #[test]
+    fn text_log_lines_carry_span_fields() {
+        let output = log_in_span(LogFormat::Compact);
+        assert!(output.contains("request:cli_request: Done"), "{output}");
+        assert!(output.contains("req_id=\"req1\""), "{output}");
+        assert!(output.contains("pid=42"), "{output}");
+        // Compact has no timestamp or colors, so the line starts with the level
+        assert!(output.trim_start().starts_with("INFO"), "{output}");
+
+        // Pretty spreads an event over several lines, one per span
+        let output = log_in_span(LogFormat::Pretty);
+        assert!(
+            output.contains("cli_request") && output.contains("req1"),
+            "{output}"
+        );
+        assert!(output.trim_end().lines().count() > 1, "{output}");
+    }
+
+    #[test]
+    fn layer_initializes_without_reachable_collector() {
+        // Nothing listens on port 1; spans are dropped, not an error
+        let provider = tracer_provider("http://127.0.0.1:1").unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("cli_request", request_id = "req1");
+            span.in_scope(|| tracing::info!("inside span"));
+        });
+        // Flushing fails to connect but must not panic or hang
+        let _ = provider.shutdown();
+    }
+
+    #[test]
+    fn invalid_endpoint_is_rejected() {
+        assert!(tracer_provider("not a url").is_err());
+    }
+}
@@ -13,6 +13,31 @@ pub struct MessagesRequest {
     pub stream: bool,
     pub system: Option<ContentInput>,
     pub metadata: Option<RequestMetadata>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub stop_sequences: Option<Vec<String>>,
+    /// Client tools; described to the CLI in the prompt
+    pub tools: Option<Vec<Tool>>,
+    pub tool_choice: Option<ToolChoice>,
+}
+
+/// A client tool. Anthropic-defined tools (`type` other than `custom`) have
+/// no schema and aren't offered to the model.
+#[derive(Debug, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tool_type: Option<String>,
+    pub name: String,
+    pub description: Option<String>,
+    pub input_schema: Option<serde_json::Value>,
+}
+
+/// `tool_choice`: `auto`, `any`, `tool` (with `name`) or `none`
+#[derive(Debug, Deserialize)]
+pub struct ToolChoice {
+    #[serde(rename = "type")]
+    pub choice_type: String,
+    pub name: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,11 +53,18 @@ pub enum ContentInput {
     Blocks(Vec<ContentBlockInput>),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct ContentBlockInput {
     #[serde(rename = "type")]
     pub block_type: String,
     pub text: Option<String>,
+    /// `tool_use` blocks: the call id, tool name and arguments
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub input: Option<serde_json::Value>,
+    /// `tool_result` blocks: the call answered and its output
+    pub tool_use_id: Option<String>,
+    pub content: Option<ContentInput>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,14 +87,21 @@ pub struct MessagesResponse {
     pub usage: ResponseUsage,
 }
 
-#[derive(Debug, Serialize)]
-pub struct ContentBlock {
-    #[serde(rename = "type")]
-    pub block_type: String,
-    pub text: String,
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    /// A call to one of the request's tools, for the client to run
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
 pub struct ResponseUsage {
     pub input_tokens: u64,
     pub output_tokens: u64,
@@ -111,14 +150,15 @@ pub struct ContentBlockDeltaEvent {
     #[serde(rename = "type")]
     pub event_type: String,
     pub index: u32,
-    pub delta: TextDelta,
+    pub delta: BlockDelta,
 }
 
-#[derive(Debug, Serialize)]
-pub struct TextDelta {
-    #[serde(rename = "type")]
-    pub delta_type: String,
-    pub text: String,
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BlockDelta {
+    TextDelta { text: String },
+    /// A piece of a `tool_use` block's input, as JSON text
+    InputJsonDelta { partial_json: String },
 }
 
 #[derive(Debug, Serialize)]
@@ -133,7 +173,11 @@ pub struct MessageDeltaEvent {
     #[serde(rename = "type")]
     pub event_type: String,
     pub delta: MessageDeltaPayload,
-    pub usage: OutputUsage,
+    /// Final usage for the whole message, not an increment
+    pub usage: ResponseUsage,
+    /// Whole streamed text, set only when the client asked for a trailer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_content: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -143,14 +187,49 @@ pub struct MessageDeltaPayload {
 }
 
 #[derive(Debug, Serialize)]
-pub struct OutputUsage {
-    pub output_tokens: u64,
+pub struct MessageStopEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+}
+
+// ── Token counting (/v1/messages/count_tokens) ────────────────
+
+/// A Messages request without the generation parameters; any extra fields a
+/// client sends along (e.g. `max_tokens`) are ignored.
+#[derive(Debug, Deserialize)]
+pub struct CountTokensRequest {
+    pub model: String,
+    pub messages: Vec<MessageInput>,
+    pub system: Option<ContentInput>,
 }
 
 #[derive(Debug, Serialize)]
-pub struct MessageStopEvent {
+pub struct CountTokensResponse {
+    pub input_tokens: u64,
+}
+
+// ── Legacy text completions (/v1/complete) ────────────────────
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteRequest {
+    pub model: String,
+    /// `\n\nHuman: ...\n\nAssistant:` formatted conversation
+    pub prompt: String,
+    pub max_tokens_to_sample: u64,
+    #[serde(default)]
+    pub stream: bool,
+    pub metadata: Option<RequestMetadata>,
+}
+
+/// Non-streaming response, and also the payload of each streamed `completion` event.
+#[derive(Debug, Serialize)]
+pub struct CompleteResponse {
     #[serde(rename = "type")]
-    pub event_type: String,
+    pub response_type: String,
+    pub id: String,
+    pub completion: String,
+    pub stop_reason: Option<String>,
+    pub model: String,
 }
 
 // ── Error response ─────────────────────────────────────────────
@@ -187,6 +266,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn deserialize_legacy_complete_request() {
+        let json = r#"{"model":"claude-2","prompt":"\n\nHuman: Hi\n\nAssistant:","max_tokens_to_sample":256,"stream":true}"#;
+        let req: CompleteRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.model, "claude-2");
+        assert_eq!(req.prompt, "\n\nHuman: Hi\n\nAssistant:");
+        assert_eq!(req.max_tokens_to_sample, 256);
+        assert!(req.stream);
+    }
+
     #[test]
     fn deserialize_block_content() {
         let json = r#"{"model":"opus","max_tokens":50,"messages":[{"role":"user","content":[{"type":"text","text":"hi"},{"type":"image","source":{"type":"base64"}}]}]}"#;
@@ -252,6 +341,28 @@ mod tests {
         assert_eq!(req.messages[2].role, "user");
     }
 
+    #[test]
+    fn deserialize_tools_and_tool_blocks() {
+        let json = r#"{"model":"opus","max_tokens":50,"tools":[{"name":"get_weather","input_schema":{"type":"object"}}],"tool_choice":{"type":"tool","name":"get_weather"},"messages":[{"role":"assistant","content":[{"type":"tool_use","id":"toolu_1","name":"get_weather","input":{"city":"Paris"}}]},{"role":"user","content":[{"type":"tool_result","tool_use_id":"toolu_1","content":"Sunny"}]}]}"#;
+        let req: MessagesRequest = serde_json::from_str(json).unwrap();
+        let tools = req.tools.unwrap();
+        assert_eq!(tools[0].name, "get_weather");
+        assert!(tools[0].tool_type.is_none());
+        let choice = req.tool_choice.unwrap();
+        assert_eq!(choice.choice_type, "tool");
+        assert_eq!(choice.name.as_deref(), Some("get_weather"));
+        let ContentInput::Blocks(call) = &req.messages[0].content else {
+            panic!("Expected Blocks");
+        };
+        assert_eq!(call[0].id.as_deref(), Some("toolu_1"));
+        assert_eq!(call[0].input.as_ref().unwrap()["city"], "Paris");
+        let ContentInput::Blocks(result) = &req.messages[1].content else {
+            panic!("Expected Blocks");
+        };
+        assert_eq!(result[0].tool_use_id.as_deref(), Some("toolu_1"));
+        assert!(matches!(&result[0].content, Some(ContentInput::Text(t)) if t == "Sunny"));
+    }
+
     // ── Serialization tests ──────────────────────────────────
 
     #[test]
@@ -260,8 +371,7 @@ mod tests {
             id: "msg_abc".to_string(),
             response_type: "message".to_string(),
             role: "assistant".to_string(),
-            content: vec![ContentBlock {
-                block_type: "text".to_string(),
+            content: vec![ContentBlock::Text {
                 text: "Hello".to_string(),
             }],
             model: "claude-sonnet-4".to_string(),
@@ -282,6 +392,25 @@ mod tests {
         assert_eq!(json["usage"]["input_tokens"], 10);
     }
 
+    #[test]
+    fn serialize_tool_use_block() {
+        let block = ContentBlock::ToolUse {
+            id: "toolu_1".to_string(),
+            name: "get_weather".to_string(),
+            input: serde_json::json!({"city": "Paris"}),
+        };
+        let json = serde_json::to_value(&block).unwrap();
+        assert_eq!(json["type"], "tool_use");
+        assert_eq!(json["id"], "toolu_1");
+        assert_eq!(json["input"]["city"], "Paris");
+        let delta = BlockDelta::InputJsonDelta {
+            partial_json: "{}".to_string(),
+        };
+        let json = serde_json::to_value(&delta).unwrap();
+        assert_eq!(json["type"], "input_json_delta");
+        assert_eq!(json["partial_json"], "{}");
+    }
+
     #[test]
     fn serialize_error_response() {
         let err = AnthropicErrorResponse {
@@ -39,7 +39,7 @@ pub enum StreamEvent {
     MessageStart {},
 
     #[serde(rename = "message_delta")]
-    MessageDelta {},
+    MessageDelta { delta: Option<MessageDeltaInfo> },
 
     #[serde(rename = "message_stop")]
     MessageStop {},
@@ -50,6 +50,12 @@ pub struct ContentBlock {
     #[serde(rename = "type")]
     pub block_type: Option<String>,
     pub text: Option<String>,
+    /// `thinking` blocks: the model's reasoning
+    pub thinking: Option<String>,
+    /// `tool_use` blocks: the call id, tool name and its arguments
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub input: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,6 +65,11 @@ pub struct Delta {
     pub text: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MessageDeltaInfo {
+    pub stop_reason: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct SystemMessage {
     pub subtype: Option<String>,
@@ -73,10 +84,15 @@ pub struct AssistantMessage {
 pub struct AssistantInner {
     pub model: Option<String>,
     pub content: Option<Vec<ContentBlock>>,
+    pub stop_reason: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct ResultMessage {
+    /// `success`, or the failure kind such as `error_during_execution`
+    pub subtype: Option<String>,
+    /// Set by the CLI when the run failed despite producing a result line
+    pub is_error: Option<bool>,
     pub result: Option<String>,
     #[serde(rename = "exitCode")]
     pub exit_code: Option<i32>,
@@ -85,6 +101,37 @@ pub struct ResultMessage {
     pub num_turns: Option<u64>,
     #[serde(rename = "modelUsage")]
     pub model_usage: Option<HashMap<String, ModelUsage>>,
+    /// Why generation stopped (e.g. `max_tokens`). Filled in from the
+    /// assistant message when the result line doesn't carry it.
+    pub stop_reason: Option<String>,
+    /// The client stop sequence the output was cut at, set by the proxy
+    #[serde(skip)]
+    pub stop_sequence: Option<String>,
+    /// Text of each assistant message in the run, in order, set by the proxy.
+    /// `result` only holds the last one.
+    #[serde(skip)]
+    pub turns: Vec<String>,
+    /// Estimated tokens of the run's thinking blocks, set by the proxy. The
+    /// CLI counts them within the output tokens without breaking them out.
+    #[serde(skip)]
+    pub reasoning_tokens: u64,
+}
+
+impl ResultMessage {
+    /// Error message for a result the CLI flagged with `is_error`, naming the
+    /// subtype and any text it gave; `None` for a successful result.
+    pub fn error_message(&self) -> Option<String> {
+        if self.is_error != Some(true) {
+            return None;
+        }
+        let subtype = self.subtype.as_deref().unwrap_or("error");
+        let mut message = format!("CLI reported {subtype}");
+        if let Some(text) = self.result.as_deref().filter(|t| !t.trim().is_empty()) {
+            message.push_str(": ");
+            message.push_str(text);
+        }
+        Some(message)
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
@@ -181,6 +228,25 @@ mod tests {
                 assert_eq!(r.result, None);
                 assert_eq!(r.exit_code, None);
                 assert_eq!(r.model_usage, None);
+                assert_eq!(r.stop_reason, None);
+                assert_eq!(r.error_message(), None);
+            }
+            other => panic!("Expected Result, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deserialize_result_error() {
+        let json = r#"{"type":"result","subtype":"error_during_execution","is_error":true}"#;
+        let msg: ClaudeCliMessage = serde_json::from_str(json).unwrap();
+        match msg {
+            ClaudeCliMessage::Result(r) => {
+                assert_eq!(r.subtype.as_deref(), Some("error_during_execution"));
+                assert_eq!(r.is_error, Some(true));
+                assert_eq!(
+                    r.error_message().as_deref(),
+                    Some("CLI reported error_during_execution")
+                );
             }
             other => panic!("Expected Result, got {:?}", other),
         }
@@ -259,7 +325,19 @@ mod tests {
     fn deserialize_message_delta() {
         let json = r#"{"type":"message_delta"}"#;
         let event: StreamEvent = serde_json::from_str(json).unwrap();
-        assert!(matches!(event, StreamEvent::MessageDelta {}));
+        assert!(matches!(event, StreamEvent::MessageDelta { delta: None }));
+    }
+
+    #[test]
+    fn deserialize_message_delta_stop_reason() {
+        let json = r#"{"type":"message_delta","delta":{"stop_reason":"max_tokens"}}"#;
+        let event: StreamEvent = serde_json::from_str(json).unwrap();
+        match event {
+            StreamEvent::MessageDelta { delta: Some(d) } => {
+                assert_eq!(d.stop_reason.as_deref(), Some("max_tokens"))
+            }
+            other => panic!("Expected MessageDelta, got {:?}", other),
+        }
     }
 
     #[test]
@@ -1,19 +1,122 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 /// OpenAI chat completion request
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct ChatCompletionRequest {
     pub model: Option<String>,
     pub messages: Option<Vec<Message>>,
     #[serde(default)]
     pub stream: bool,
+    pub stream_options: Option<StreamOptions>,
     pub user: Option<String>,
+    /// Functions the model may call; described to the CLI in the prompt
+    pub tools: Option<Vec<Tool>>,
+    pub tool_choice: Option<ToolChoice>,
+    /// Whether the model may issue several tool calls at once (OpenAI default: true)
+    pub parallel_tool_calls: Option<bool>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u64>,
+    /// Newer name for `max_tokens`; wins when both are sent
+    pub max_completion_tokens: Option<u64>,
+    /// Stop sequences; OpenAI accepts a single string or an array
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub stop: Option<Vec<String>>,
+}
+
+/// Options for streamed chat completions
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamOptions {
+    /// Send a final chunk with `usage` and no choices before `[DONE]`
+    #[serde(default)]
+    pub include_usage: bool,
 }
 
+/// Legacy OpenAI completion request (`/v1/completions`)
+#[derive(Debug, Default, Deserialize)]
+pub struct CompletionRequest {
+    pub model: Option<String>,
+    /// Prompt text; a single string or an array of strings
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub prompt: Option<Vec<String>>,
+    #[serde(default)]
+    pub stream: bool,
+    pub max_tokens: Option<u64>,
+    pub user: Option<String>,
+}
+
+/// Deserialize a string or an array of strings into a list.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(Option::<OneOrMany>::deserialize(deserializer)?.map(|v| match v {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    }))
+}
+
+/// A tool the client offers the model. Only `function` tools exist.
 #[derive(Debug, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON Schema for the arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// `tool_choice`: `"none"`, `"auto"`, `"required"`, or a specific function
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function { function: FunctionName },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+/// A function call made by the assistant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default)]
+    pub call_type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON-encoded string
+    pub arguments: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: Option<MessageContent>,
+    /// Calls made by an earlier assistant turn
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The call a `tool` message answers
+    pub tool_call_id: Option<String>,
 }
 
 /// Message content can be a simple string or an array of content parts
@@ -41,6 +144,9 @@ pub struct ChatCompletionResponse {
     pub choices: Vec<Choice>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub usage: Option<Usage>,
+    /// Set from `--service-tier` for clients that expect the field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -48,12 +154,21 @@ pub struct Choice {
     pub index: u32,
     pub message: ResponseMessage,
     pub finish_reason: String,
+    /// The stop sequence that ended generation (vLLM-style extension)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct ResponseMessage {
     pub role: String,
-    pub content: String,
+    /// `null` when the model refused
+    pub content: Option<String>,
+    /// The refusal text, set instead of `content`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -61,6 +176,23 @@ pub struct Usage {
     pub prompt_tokens: u64,
     pub completion_tokens: u64,
     pub total_tokens: u64,
+    /// Set only when part of the prompt was read from cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+    /// Set only when the model produced thinking content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PromptTokensDetails {
+    pub cached_tokens: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompletionTokensDetails {
+    /// Estimated from the thinking text; part of `completion_tokens`
+    pub reasoning_tokens: u64,
 }
 
 /// OpenAI streaming chunk
@@ -71,6 +203,15 @@ pub struct ChatCompletionChunk {
     pub created: u64,
     pub model: String,
     pub choices: Vec<ChunkChoice>,
+    /// Whole streamed text, set only on the opt-in trailer chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_content: Option<String>,
+    /// Set from `--service-tier` for clients that expect the field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+    /// Token usage, set only on the chunk requested via `stream_options`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 #[derive(Debug, Serialize)]
@@ -78,6 +219,9 @@ pub struct ChunkChoice {
     pub index: u32,
     pub delta: ChunkDelta,
     pub finish_reason: Option<String>,
+    /// The stop sequence that ended generation, on the final chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -86,6 +230,36 @@ pub struct ChunkDelta {
     pub role: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A tool call in a streamed chunk; sent whole, in a single delta
+#[derive(Debug, Serialize)]
+pub struct ToolCallDelta {
+    pub index: u32,
+    #[serde(flatten)]
+    pub call: ToolCall,
+}
+
+/// Legacy completion response, and also the shape of each streamed chunk
+#[derive(Debug, Serialize)]
+pub struct TextCompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<TextChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TextChoice {
+    pub text: String,
+    pub index: u32,
+    pub logprobs: Option<()>,
+    pub finish_reason: Option<String>,
 }
 
 /// OpenAI error response format
@@ -111,14 +285,30 @@ pub struct ModelsResponse {
     pub data: Vec<ModelInfo>,
 }
 
-#[derive(Debug, Serialize)]
+/// A listed model. Also the entry format of `--models-file`, where `object`,
+/// `owned_by` and `created` may be omitted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ModelInfo {
     pub id: String,
+    #[serde(default = "default_model_object")]
     pub object: String,
+    #[serde(default = "default_owned_by")]
     pub owned_by: String,
+    #[serde(default)]
     pub created: u64,
     pub context_window: u64,
     pub max_tokens: u64,
+    /// CLI model alias (`opus`, `sonnet`, `haiku`) requests for `id` run as
+    #[serde(skip_serializing)]
+    pub alias: String,
+}
+
+fn default_model_object() -> String {
+    "model".to_string()
+}
+
+fn default_owned_by() -> String {
+    "anthropic".to_string()
 }
 
 #[cfg(test)]
@@ -164,6 +354,64 @@ mod tests {
         assert_eq!(req.model, None);
         assert!(!req.stream); // default
         assert_eq!(req.user, None);
+        assert_eq!(req.parallel_tool_calls, None);
+    }
+
+    #[test]
+    fn deserialize_parallel_tool_calls() {
+        let json = r#"{"messages":[{"role":"user","content":"hi"}],"parallel_tool_calls":false}"#;
+        let req: ChatCompletionRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.parallel_tool_calls, Some(false));
+    }
+
+    #[test]
+    fn deserialize_max_completion_tokens() {
+        let json = r#"{"messages":[{"role":"user","content":"hi"}],"max_completion_tokens":128}"#;
+        let req: ChatCompletionRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.max_completion_tokens, Some(128));
+        assert_eq!(req.max_tokens, None);
+    }
+
+    #[test]
+    fn deserialize_stream_options() {
+        let json = r#"{"messages":[],"stream":true,"stream_options":{"include_usage":true}}"#;
+        let req: ChatCompletionRequest = serde_json::from_str(json).unwrap();
+        assert!(req.stream_options.unwrap().include_usage);
+
+        let req: ChatCompletionRequest = serde_json::from_str(r#"{"messages":[]}"#).unwrap();
+        assert!(req.stream_options.is_none());
+    }
+
+    #[test]
+    fn deserialize_stop_string_or_array() {
+        let json = r#"{"messages":[],"stop":"END"}"#;
+        let req: ChatCompletionRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.stop, Some(vec!["END".to_string()]));
+
+        let json = r#"{"messages":[],"stop":["a","b"]}"#;
+        let req: ChatCompletionRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.stop, Some(vec!["a".to_string(), "b".to_string()]));
+
+        let json = r#"{"messages":[],"stop":null}"#;
+        let req: ChatCompletionRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.stop, None);
+        let req: ChatCompletionRequest = serde_json::from_str(r#"{"messages":[]}"#).unwrap();
+        assert_eq!(req.stop, None);
+    }
+
+    #[test]
+    fn deserialize_completion_request() {
+        let json = r#"{"model":"opus","prompt":"Say hi","max_tokens":16,"stream":true}"#;
+        let req: CompletionRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.prompt, Some(vec!["Say hi".to_string()]));
+        assert_eq!(req.max_tokens, Some(16));
+        assert!(req.stream);
+
+        let json = r#"{"prompt":["a","b"],"user":"session-1"}"#;
+        let req: CompletionRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.prompt, Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(req.user.as_deref(), Some("session-1"));
+        assert!(!req.stream);
     }
 
     #[test]
@@ -186,6 +434,24 @@ mod tests {
         assert_eq!(msgs[3].role, "user");
     }
 
+    #[test]
+    fn deserialize_tools_and_tool_choice() {
+        let json = r#"{"messages":[],"tools":[{"type":"function","function":{"name":"f","parameters":{"type":"object"}}}],"tool_choice":{"type":"function","function":{"name":"f"}}}"#;
+        let req: ChatCompletionRequest = serde_json::from_str(json).unwrap();
+        let tools = req.tools.unwrap();
+        assert_eq!(tools[0].tool_type, "function");
+        assert_eq!(tools[0].function.name, "f");
+        assert!(tools[0].function.description.is_none());
+        assert!(matches!(
+            req.tool_choice,
+            Some(ToolChoice::Function { function }) if function.name == "f"
+        ));
+
+        let json = r#"{"messages":[],"tool_choice":"required"}"#;
+        let req: ChatCompletionRequest = serde_json::from_str(json).unwrap();
+        assert!(matches!(req.tool_choice, Some(ToolChoice::Mode(mode)) if mode == "required"));
+    }
+
     #[test]
     fn serialize_response() {
         let resp = ChatCompletionResponse {
@@ -197,20 +463,55 @@ mod tests {
                 index: 0,
                 message: ResponseMessage {
                     role: "assistant".to_string(),
-                    content: "Hello".to_string(),
+                    content: Some("Hello".to_string()),
+                    refusal: None,
+                    tool_calls: None,
                 },
                 finish_reason: "stop".to_string(),
+                stop_reason: None,
             }],
             usage: Some(Usage {
                 prompt_tokens: 10,
                 completion_tokens: 5,
                 total_tokens: 15,
+                prompt_tokens_details: None,
+                completion_tokens_details: None,
             }),
+            service_tier: None,
         };
         let json = serde_json::to_value(&resp).unwrap();
         assert_eq!(json["id"], "chatcmpl-abc");
         assert_eq!(json["choices"][0]["finish_reason"], "stop");
+        assert!(json["choices"][0]["message"].get("refusal").is_none());
         assert_eq!(json["usage"]["total_tokens"], 15);
+        assert!(json.get("service_tier").is_none());
+    }
+
+    #[test]
+    fn serialize_detailed_usage() {
+        let usage = Usage {
+            prompt_tokens: 100,
+            completion_tokens: 50,
+            total_tokens: 150,
+            prompt_tokens_details: Some(PromptTokensDetails { cached_tokens: 80 }),
+            completion_tokens_details: Some(CompletionTokensDetails {
+                reasoning_tokens: 30,
+            }),
+        };
+        let json = serde_json::to_value(&usage).unwrap();
+        assert_eq!(json["prompt_tokens_details"], serde_json::json!({"cached_tokens": 80}));
+        assert_eq!(json["completion_tokens_details"], serde_json::json!({"reasoning_tokens": 30}));
+
+        let plain = Usage {
+            prompt_tokens: 1,
+            completion_tokens: 1,
+            total_tokens: 2,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        };
+        let json = serde_json::to_value(&plain).unwrap();
+        assert!(json.get("prompt_tokens_details").is_none());
+        assert!(json.get("completion_tokens_details").is_none());
     }
 
     #[test]
@@ -222,9 +523,11 @@ mod tests {
             model: "claude-sonnet-4".to_string(),
             choices: vec![],
             usage: None,
+            service_tier: Some("default".to_string()),
         };
         let json = serde_json::to_value(&resp).unwrap();
         assert!(json.get("usage").is_none()); // skip_serializing_if
+        assert_eq!(json["service_tier"], "default");
     }
 
     #[test]
@@ -239,11 +542,18 @@ mod tests {
                 delta: ChunkDelta {
                     role: None,
                     content: None,
+                    tool_calls: None,
                 },
                 finish_reason: Some("stop".to_string()),
+                stop_reason: None,
             }],
+            full_content: None,
+            service_tier: None,
+            usage: None,
         };
         let json = serde_json::to_value(&chunk).unwrap();
+        assert!(json.get("full_content").is_none());
+        assert!(json.get("service_tier").is_none());
         assert!(json["choices"][0]["delta"].get("role").is_none());
         assert!(json["choices"][0]["delta"].get("content").is_none());
     }